@@ -0,0 +1,257 @@
+//! Parses the payloads of packets sent by gdb into commands.
+//! See the gdb manual, Section E.2 "Packets" and Section E.4 "General Query
+//! Packets".
+use crate::hex;
+
+/// Identifies a thread. The stub reports each vCPU as a thread.
+/// See the gdb manual, Section E.1 "Overview", under thread-id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadId {
+    /// Any thread, written as 0.
+    Any,
+    /// All threads, written as -1.
+    All,
+    /// A particular thread. Never 0.
+    Id(u64),
+}
+
+/// The kinds of breakpoints and watchpoints gdb can ask for.
+/// See the gdb manual, Section E.2 "Packets", under z type,addr,kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// A breakpoint the stub implements by patching in int3.
+    Software,
+    /// A breakpoint implemented with the debug registers.
+    Hardware,
+    /// Stop when the memory is written.
+    WriteWatchpoint,
+    /// Stop when the memory is read.
+    ReadWatchpoint,
+    /// Stop when the memory is read or written.
+    AccessWatchpoint,
+}
+
+/// A command from gdb.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// `qSupported`: exchange the features gdb and the stub support. Holds
+    /// gdb's features, separated by semicolons.
+    QuerySupported(&'a [u8]),
+    /// `?`: why the target stopped.
+    HaltReason,
+    /// `g`: read the general registers.
+    ReadRegisters,
+    /// `G`: write the general registers, given in hexadecimal.
+    WriteRegisters(&'a [u8]),
+    /// `p`: read one register.
+    ReadRegister(usize),
+    /// `P`: write one register, given in hexadecimal.
+    WriteRegister(usize, &'a [u8]),
+    /// `m`: read memory.
+    ReadMemory {
+        /// The virtual address to read from.
+        address: u64,
+        /// The number of bytes to read.
+        length: u64,
+    },
+    /// `M`: write memory.
+    WriteMemory {
+        /// The virtual address to write to.
+        address: u64,
+        /// The data to write, in hexadecimal.
+        data: &'a [u8],
+    },
+    /// `c` or `C`: continue, optionally from a new address. Signals can't be
+    /// delivered to the guest, so `C` is treated like `c`.
+    Continue(Option<u64>),
+    /// `s` or `S`: execute one instruction, optionally from a new address.
+    Step(Option<u64>),
+    /// `Z`: add a breakpoint or watchpoint.
+    InsertBreakpoint {
+        /// The kind of breakpoint.
+        kind: BreakpointKind,
+        /// The address of the breakpoint.
+        address: u64,
+        /// The number of bytes watched, or the size of the breakpoint
+        /// instruction for breakpoints.
+        length: u64,
+    },
+    /// `z`: remove a breakpoint or watchpoint.
+    RemoveBreakpoint {
+        /// The kind of breakpoint.
+        kind: BreakpointKind,
+        /// The address of the breakpoint.
+        address: u64,
+        /// The length given when the breakpoint was added.
+        length: u64,
+    },
+    /// `H`: choose the thread for later commands. The operation is `g` for
+    /// register and memory accesses, or `c` for continue and step.
+    SetThread {
+        /// The commands the thread applies to.
+        operation: u8,
+        /// The thread.
+        thread: ThreadId,
+    },
+    /// `T`: is the thread alive.
+    ThreadAlive(ThreadId),
+    /// `qfThreadInfo`: start listing the threads.
+    FirstThreadInfo,
+    /// `qsThreadInfo`: continue listing the threads.
+    SubsequentThreadInfo,
+    /// `qC`: which thread stopped.
+    CurrentThread,
+    /// `qAttached`: whether the stub attached to an existing process.
+    Attached,
+    /// `D`: detach, and let the target run freely.
+    Detach,
+    /// `k`: kill the target. The guest can't be killed, so this detaches.
+    Kill,
+    /// A command the stub knows but which was malformed.
+    Invalid,
+    /// A command the stub doesn't implement. The reply is empty.
+    Unsupported,
+}
+
+/// Split off the bytes up to a separator.
+fn split_at_byte(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|byte| *byte == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+fn parse_thread_id(bytes: &[u8]) -> Option<ThreadId> {
+    // Ignore the process id of the multiprocess extensions, if gdb sends one.
+    let bytes = match bytes.first() {
+        Some(b'p') => match split_at_byte(&bytes[1..], b'.') {
+            Some((_process, thread)) => thread,
+            None => b"-1",
+        },
+        _ => bytes,
+    };
+    match bytes {
+        b"-1" => Some(ThreadId::All),
+        b"0" => Some(ThreadId::Any),
+        _ => Some(ThreadId::Id(hex::parse_u64(bytes)?)),
+    }
+}
+
+fn parse_optional_address(bytes: &[u8]) -> Option<Option<u64>> {
+    if bytes.is_empty() {
+        Some(None)
+    } else {
+        Some(Some(hex::parse_u64(bytes)?))
+    }
+}
+
+/// Parse the optional address of a `C` or `S` packet, which follows the
+/// signal number and a semicolon.
+fn parse_signal_and_address(bytes: &[u8]) -> Option<Option<u64>> {
+    match split_at_byte(bytes, b';') {
+        Some((_signal, address)) => parse_optional_address(address),
+        None => {
+            hex::parse_u64(bytes)?;
+            Some(None)
+        }
+    }
+}
+
+fn parse_breakpoint(bytes: &[u8]) -> Option<(BreakpointKind, u64, u64)> {
+    let (kind, rest) = split_at_byte(bytes, b',')?;
+    let (address, length) = split_at_byte(rest, b',')?;
+    // Ignore any conditions or commands after the length.
+    let length = split_at_byte(length, b';').map_or(length, |(length, _)| length);
+    let kind = match kind {
+        b"0" => BreakpointKind::Software,
+        b"1" => BreakpointKind::Hardware,
+        b"2" => BreakpointKind::WriteWatchpoint,
+        b"3" => BreakpointKind::ReadWatchpoint,
+        b"4" => BreakpointKind::AccessWatchpoint,
+        _ => return None,
+    };
+    Some((kind, hex::parse_u64(address)?, hex::parse_u64(length)?))
+}
+
+fn parse_query(bytes: &[u8]) -> Command<'_> {
+    let (name, arguments) = split_at_byte(bytes, b':').unwrap_or((bytes, &[]));
+    match name {
+        b"Supported" => Command::QuerySupported(arguments),
+        b"fThreadInfo" => Command::FirstThreadInfo,
+        b"sThreadInfo" => Command::SubsequentThreadInfo,
+        b"C" => Command::CurrentThread,
+        b"Attached" => Command::Attached,
+        _ => Command::Unsupported,
+    }
+}
+
+fn parse_known(payload: &[u8]) -> Option<Command<'_>> {
+    let (first, rest) = payload.split_first()?;
+    let command = match first {
+        b'?' => Command::HaltReason,
+        b'g' => Command::ReadRegisters,
+        b'G' => Command::WriteRegisters(rest),
+        b'p' => Command::ReadRegister(hex::parse_u64(rest)? as usize),
+        b'P' => {
+            let (register, value) = split_at_byte(rest, b'=')?;
+            Command::WriteRegister(hex::parse_u64(register)? as usize, value)
+        }
+        b'm' => {
+            let (address, length) = split_at_byte(rest, b',')?;
+            Command::ReadMemory {
+                address: hex::parse_u64(address)?,
+                length: hex::parse_u64(length)?,
+            }
+        }
+        b'M' => {
+            let (address, rest) = split_at_byte(rest, b',')?;
+            let (length, data) = split_at_byte(rest, b':')?;
+            if hex::parse_u64(length)?.checked_mul(2)? != data.len() as u64 {
+                return None;
+            }
+            Command::WriteMemory {
+                address: hex::parse_u64(address)?,
+                data,
+            }
+        }
+        b'c' => Command::Continue(parse_optional_address(rest)?),
+        b'C' => Command::Continue(parse_signal_and_address(rest)?),
+        b's' => Command::Step(parse_optional_address(rest)?),
+        b'S' => Command::Step(parse_signal_and_address(rest)?),
+        b'Z' | b'z' => {
+            let (kind, address, length) = parse_breakpoint(rest)?;
+            if *first == b'Z' {
+                Command::InsertBreakpoint {
+                    kind,
+                    address,
+                    length,
+                }
+            } else {
+                Command::RemoveBreakpoint {
+                    kind,
+                    address,
+                    length,
+                }
+            }
+        }
+        b'H' => {
+            let (operation, thread) = rest.split_first()?;
+            Command::SetThread {
+                operation: *operation,
+                thread: parse_thread_id(thread)?,
+            }
+        }
+        b'T' => Command::ThreadAlive(parse_thread_id(rest)?),
+        b'q' => parse_query(rest),
+        b'D' => Command::Detach,
+        b'k' => Command::Kill,
+        _ => Command::Unsupported,
+    };
+    Some(command)
+}
+
+/// Parse the payload of a packet from gdb.
+pub fn parse(payload: &[u8]) -> Command<'_> {
+    if payload.is_empty() {
+        return Command::Unsupported;
+    }
+    parse_known(payload).unwrap_or(Command::Invalid)
+}
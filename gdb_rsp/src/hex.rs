@@ -0,0 +1,43 @@
+//! Helpers for the hexadecimal encodings used throughout the protocol.
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// The value of a single hexadecimal digit.
+pub fn digit_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// The two hexadecimal digits of a byte, most significant first.
+pub fn byte_digits(byte: u8) -> [u8; 2] {
+    [
+        HEX_DIGITS[usize::from(byte >> 4)],
+        HEX_DIGITS[usize::from(byte & 0xf)],
+    ]
+}
+
+/// Parse a big endian hexadecimal number, like an address or a length.
+pub fn parse_u64(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, digit| {
+        Some((value << 4) | u64::from(digit_value(*digit)?))
+    })
+}
+
+/// Decode pairs of hexadecimal digits into bytes. Returns the number of bytes
+/// decoded, or None if the digits are malformed or don't fit.
+pub fn decode_bytes(digits: &[u8], bytes: &mut [u8]) -> Option<usize> {
+    if digits.len() & 1 != 0 || digits.len() / 2 > bytes.len() {
+        return None;
+    }
+    for (pair, byte) in digits.chunks(2).zip(bytes.iter_mut()) {
+        *byte = (digit_value(pair[0])? << 4) | digit_value(pair[1])?;
+    }
+    Some(digits.len() / 2)
+}
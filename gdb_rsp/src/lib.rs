@@ -0,0 +1,24 @@
+#![cfg_attr(not(test), no_std)]
+#![warn(missing_docs)]
+
+//! An implementation of the server side of the GDB Remote Serial Protocol.
+//! The hypervisor uses it to let gdb debug the guest over a serial port.
+//!
+//! This crate only speaks the protocol. The debugged machine is reached
+//! through the [Target](trait.Target.html) trait, and the bytes are moved by
+//! the [Connection](trait.Connection.html) trait, so the protocol can be
+//! tested on the host against recorded gdb sessions.
+//!
+//! For more information see the gdb manual, Appendix E
+//! "GDB Remote Serial Protocol".
+
+mod command;
+mod hex;
+mod packet;
+mod registers;
+mod stub;
+
+pub use command::{parse, BreakpointKind, Command, ThreadId};
+pub use packet::{Event, PacketReader, PacketWriter, MAX_PACKET_SIZE};
+pub use registers::{Registers, REGISTER_COUNT};
+pub use stub::{Connection, ResumeAction, StopReason, Stub, Target, TargetError};
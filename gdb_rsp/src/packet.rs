@@ -0,0 +1,241 @@
+//! Splits the byte stream from gdb into packets, and frames the replies.
+//!
+//! A packet looks like `$payload#cc`, where cc is the modulo 256 sum of the
+//! payload bytes in hexadecimal. The receiver acknowledges each packet with a
+//! `+`, or asks for it again with a `-`. A lone 0x03 byte outside of a packet
+//! asks the stub to stop the target.
+//! See the gdb manual, Section E.1 "Overview".
+use crate::hex;
+
+/// The largest packet payload the stub will send or receive.
+pub const MAX_PACKET_SIZE: usize = 0x1000;
+
+const PACKET_START: u8 = b'$';
+const PACKET_END: u8 = b'#';
+const ESCAPE: u8 = b'}';
+const ESCAPE_XOR: u8 = 0x20;
+const RUN_LENGTH: u8 = b'*';
+const INTERRUPT: u8 = 0x03;
+const ACK: u8 = b'+';
+const NACK: u8 = b'-';
+
+/// Something received from gdb.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// A packet with a valid checksum. The payload has been unescaped. It
+    /// should be acknowledged.
+    Packet(&'a [u8]),
+    /// A packet with a bad checksum or which was too large. It should be
+    /// rejected so gdb sends it again.
+    BadPacket,
+    /// gdb asked for the target to be stopped.
+    Interrupt,
+    /// gdb received the last packet sent.
+    Ack,
+    /// gdb wants the last packet sent again.
+    Nack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReaderState {
+    Idle,
+    Payload,
+    Escape,
+    ChecksumHigh,
+    ChecksumLow(u8),
+}
+
+/// Assembles packets from the bytes sent by gdb, one byte at a time.
+pub struct PacketReader {
+    buffer: [u8; MAX_PACKET_SIZE],
+    length: usize,
+    checksum: u8,
+    overflowed: bool,
+    state: ReaderState,
+}
+
+impl Default for PacketReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketReader {
+    /// Creates a reader which is waiting for the start of a packet.
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_PACKET_SIZE],
+            length: 0,
+            checksum: 0,
+            overflowed: false,
+            state: ReaderState::Idle,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.length == self.buffer.len() {
+            self.overflowed = true;
+        } else {
+            self.buffer[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    /// Process the next byte from gdb. Returns an event once a packet or a
+    /// control character has been received.
+    pub fn feed(&mut self, byte: u8) -> Option<Event<'_>> {
+        match self.state {
+            ReaderState::Idle => match byte {
+                PACKET_START => {
+                    self.length = 0;
+                    self.checksum = 0;
+                    self.overflowed = false;
+                    self.state = ReaderState::Payload;
+                    None
+                }
+                INTERRUPT => Some(Event::Interrupt),
+                ACK => Some(Event::Ack),
+                NACK => Some(Event::Nack),
+                // Anything else between packets is noise.
+                _ => None,
+            },
+            ReaderState::Payload => {
+                match byte {
+                    PACKET_END => self.state = ReaderState::ChecksumHigh,
+                    ESCAPE => {
+                        self.checksum = self.checksum.wrapping_add(byte);
+                        self.state = ReaderState::Escape;
+                    }
+                    _ => {
+                        self.checksum = self.checksum.wrapping_add(byte);
+                        self.push(byte);
+                    }
+                }
+                None
+            }
+            ReaderState::Escape => {
+                self.checksum = self.checksum.wrapping_add(byte);
+                self.push(byte ^ ESCAPE_XOR);
+                self.state = ReaderState::Payload;
+                None
+            }
+            ReaderState::ChecksumHigh => {
+                self.state = ReaderState::ChecksumLow(byte);
+                None
+            }
+            ReaderState::ChecksumLow(high) => {
+                self.state = ReaderState::Idle;
+                let checksum = match (hex::digit_value(high), hex::digit_value(byte)) {
+                    (Some(high), Some(low)) => Some((high << 4) | low),
+                    _ => None,
+                };
+                if self.overflowed || checksum != Some(self.checksum) {
+                    Some(Event::BadPacket)
+                } else {
+                    Some(Event::Packet(&self.buffer[..self.length]))
+                }
+            }
+        }
+    }
+}
+
+/// Builds the payload of a packet to send to gdb.
+/// Payloads which don't fit in [MAX_PACKET_SIZE](constant.MAX_PACKET_SIZE.html)
+/// are truncated, so callers should size variable length replies to fit.
+pub struct PacketWriter {
+    buffer: [u8; MAX_PACKET_SIZE],
+    length: usize,
+}
+
+impl Default for PacketWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketWriter {
+    /// Creates an empty payload.
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; MAX_PACKET_SIZE],
+            length: 0,
+        }
+    }
+
+    /// Discard the payload.
+    pub fn clear(&mut self) {
+        self.length = 0;
+    }
+
+    /// The number of bytes which can still be added to the payload.
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.length
+    }
+
+    /// Append bytes to the payload, escaping any which have a special meaning.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            match *byte {
+                PACKET_START | PACKET_END | ESCAPE | RUN_LENGTH => {
+                    self.write_raw(ESCAPE);
+                    self.write_raw(byte ^ ESCAPE_XOR);
+                }
+                byte => self.write_raw(byte),
+            }
+        }
+    }
+
+    /// Append a string to the payload.
+    pub fn write_str(&mut self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    /// Append each byte as two hexadecimal digits.
+    pub fn write_hex_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let digits = hex::byte_digits(*byte);
+            self.write_raw(digits[0]);
+            self.write_raw(digits[1]);
+        }
+    }
+
+    /// Append a number in big endian hexadecimal without leading zeroes, the
+    /// way thread ids and addresses are written.
+    pub fn write_hex_u64(&mut self, value: u64) {
+        let bytes = value.to_be_bytes();
+        let mut started = false;
+        for byte in bytes.iter() {
+            let digits = hex::byte_digits(*byte);
+            for digit in digits.iter() {
+                if started || *digit != b'0' {
+                    started = true;
+                    self.write_raw(*digit);
+                }
+            }
+        }
+        if !started {
+            self.write_raw(b'0');
+        }
+    }
+
+    fn write_raw(&mut self, byte: u8) {
+        if self.length < self.buffer.len() {
+            self.buffer[self.length] = byte;
+            self.length += 1;
+        }
+    }
+
+    /// The escaped payload.
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+
+    /// The checksum which follows the payload, as two hexadecimal digits.
+    pub fn checksum(&self) -> [u8; 2] {
+        let checksum = self
+            .payload()
+            .iter()
+            .fold(0u8, |checksum, byte| checksum.wrapping_add(*byte));
+        hex::byte_digits(checksum)
+    }
+}
@@ -0,0 +1,105 @@
+//! The register file gdb expects for the i386:x86-64 architecture.
+//! gdb numbers the registers in the order of its amd64 register description,
+//! features/i386/64bit-core.xml: the general purpose registers, rip, eflags
+//! and the segment selectors. The registers after those, like the x87 and SSE
+//! registers, are not reported, which gdb shows as unavailable.
+use crate::hex;
+use crate::packet::PacketWriter;
+
+/// The number of registers in [Registers](struct.Registers.html).
+pub const REGISTER_COUNT: usize = 24;
+
+/// The register number of rip.
+const RIP: usize = 16;
+/// The register number of eflags, the first 32 bit register.
+const EFLAGS: usize = 17;
+
+/// The registers of a thread, in gdb's order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, then r8 through r15.
+    pub gprs: [u64; 16],
+    /// The instruction pointer.
+    pub rip: u64,
+    /// The flags register.
+    pub eflags: u32,
+    /// cs, ss, ds, es, fs and gs.
+    pub segments: [u32; 6],
+}
+
+impl Registers {
+    /// The size in bytes of a register.
+    fn size(register: usize) -> usize {
+        if register < EFLAGS {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// The value of a register, or None if there is no such register.
+    pub fn get(&self, register: usize) -> Option<u64> {
+        match register {
+            0..=15 => Some(self.gprs[register]),
+            RIP => Some(self.rip),
+            EFLAGS => Some(u64::from(self.eflags)),
+            18..=23 => Some(u64::from(self.segments[register - 18])),
+            _ => None,
+        }
+    }
+
+    /// Set a register. Returns None if there is no such register.
+    pub fn set(&mut self, register: usize, value: u64) -> Option<()> {
+        match register {
+            0..=15 => self.gprs[register] = value,
+            RIP => self.rip = value,
+            EFLAGS => self.eflags = value as u32,
+            18..=23 => self.segments[register - 18] = value as u32,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Write a register in target byte order, as in a `p` reply.
+    pub fn write_register(&self, register: usize, writer: &mut PacketWriter) -> Option<()> {
+        let bytes = self.get(register)?.to_le_bytes();
+        writer.write_hex_bytes(&bytes[..Self::size(register)]);
+        Some(())
+    }
+
+    /// Write every register, as in a `g` reply.
+    pub fn write_all(&self, writer: &mut PacketWriter) {
+        for register in 0..REGISTER_COUNT {
+            self.write_register(register, writer);
+        }
+    }
+
+    /// Decode the value of a register from a `P` packet.
+    pub fn decode_register(register: usize, digits: &[u8]) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        let size = Self::size(register);
+        if hex::decode_bytes(digits, &mut bytes[..size])? != size {
+            return None;
+        }
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    /// Update the registers from a `G` packet. gdb may send fewer registers
+    /// than there are, in which case the rest are unchanged, or more, in which
+    /// case the extra registers are ignored.
+    pub fn decode_all(&mut self, mut digits: &[u8]) -> Option<()> {
+        for register in 0..REGISTER_COUNT {
+            let length = Self::size(register) * 2;
+            if digits.is_empty() {
+                break;
+            }
+            if digits.len() < length {
+                return None;
+            }
+            let value = Self::decode_register(register, &digits[..length])?;
+            self.set(register, value)?;
+            digits = &digits[length..];
+        }
+        Some(())
+    }
+}
@@ -0,0 +1,709 @@
+//! Answers gdb's commands while the target is stopped.
+use crate::command::{self, BreakpointKind, Command, ThreadId};
+use crate::hex;
+use crate::packet::{Event, PacketReader, PacketWriter, MAX_PACKET_SIZE};
+use crate::registers::Registers;
+
+/// The signal reported when gdb interrupted the target.
+const SIGINT: u8 = 2;
+/// The signal reported for breakpoints and steps.
+const SIGTRAP: u8 = 5;
+
+/// Memory is read and written through a buffer of this size.
+const MEMORY_CHUNK_SIZE: usize = 64;
+
+/// Moves bytes between the stub and gdb, e.g. over a serial port.
+pub trait Connection {
+    /// Wait for the next byte from gdb.
+    fn read_byte(&mut self) -> u8;
+    /// Send bytes to gdb.
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+/// The ways the target can fail a request from gdb.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetError {
+    /// The memory is not mapped or can't be accessed.
+    Fault,
+    /// The request doesn't make sense, e.g. it names a thread which doesn't
+    /// exist or all the hardware breakpoints are used.
+    Invalid,
+    /// The target doesn't implement the request.
+    Unsupported,
+}
+
+/// The machine being debugged. Threads are numbered from 1.
+pub trait Target {
+    /// The thread which stopped.
+    fn current_thread(&mut self) -> u64;
+    /// Call f with each thread.
+    fn for_each_thread(&mut self, f: &mut dyn FnMut(u64));
+    /// True if the thread exists.
+    fn is_thread_alive(&mut self, thread: u64) -> bool;
+    /// Read a thread's registers.
+    fn read_registers(&mut self, thread: u64) -> Result<Registers, TargetError>;
+    /// Write a thread's registers.
+    fn write_registers(&mut self, thread: u64, registers: &Registers) -> Result<(), TargetError>;
+    /// Read memory through a thread's address space.
+    fn read_memory(
+        &mut self,
+        thread: u64,
+        address: u64,
+        data: &mut [u8],
+    ) -> Result<(), TargetError>;
+    /// Write memory through a thread's address space.
+    fn write_memory(&mut self, thread: u64, address: u64, data: &[u8]) -> Result<(), TargetError>;
+    /// Add a breakpoint or watchpoint.
+    fn insert_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        address: u64,
+        length: u64,
+    ) -> Result<(), TargetError>;
+    /// Remove a breakpoint or watchpoint.
+    fn remove_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        address: u64,
+        length: u64,
+    ) -> Result<(), TargetError>;
+}
+
+/// What gdb asked the target to do once the stub returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeAction {
+    /// Run until the next breakpoint or interrupt.
+    Continue,
+    /// Execute one instruction.
+    Step,
+    /// gdb went away. Run freely.
+    Detach,
+}
+
+/// Why the target stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// gdb asked the target to stop, or just connected.
+    Interrupted,
+    /// A step finished.
+    Step,
+    /// The target executed a software breakpoint.
+    SoftwareBreakpoint,
+    /// The target hit a hardware breakpoint.
+    HardwareBreakpoint,
+    /// The target stopped itself, e.g. by executing a breakpoint instruction
+    /// gdb didn't insert.
+    Trap,
+    /// The target accessed memory watched by a watchpoint.
+    Watchpoint {
+        /// The kind of watchpoint which triggered.
+        kind: BreakpointKind,
+        /// The address of the watchpoint.
+        address: u64,
+    },
+}
+
+/// The state of the stub which outlives a stop of the target.
+struct StubState {
+    reply: PacketWriter,
+    stop_reason: StopReason,
+    /// The thread used for register and memory accesses.
+    general_thread: ThreadId,
+    /// True if gdb resumed the target and is waiting for it to stop.
+    waiting_for_stop: bool,
+    /// True if gdb understands the swbreak stop reason.
+    swbreak: bool,
+    /// True if gdb understands the hwbreak stop reason.
+    hwbreak: bool,
+}
+
+/// A GDB Remote Serial Protocol server.
+pub struct Stub {
+    reader: PacketReader,
+    state: StubState,
+}
+
+impl Default for Stub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stub {
+    /// Creates a stub which hasn't talked to gdb yet.
+    pub const fn new() -> Self {
+        Self {
+            reader: PacketReader::new(),
+            state: StubState {
+                reply: PacketWriter::new(),
+                stop_reason: StopReason::Interrupted,
+                general_thread: ThreadId::Any,
+                waiting_for_stop: false,
+                swbreak: false,
+                hwbreak: false,
+            },
+        }
+    }
+
+    /// Serve gdb while the target is stopped. Returns once gdb resumes the
+    /// target. If gdb is waiting for the target to stop, it is told why the
+    /// target stopped first.
+    pub fn run(
+        &mut self,
+        connection: &mut dyn Connection,
+        target: &mut dyn Target,
+        reason: StopReason,
+    ) -> ResumeAction {
+        let state = &mut self.state;
+        state.stop_reason = reason;
+        if state.waiting_for_stop {
+            state.waiting_for_stop = false;
+            state.general_thread = ThreadId::Any;
+            state.reply.clear();
+            state.write_stop_reply(target);
+            state.send_reply(connection);
+        }
+
+        loop {
+            let byte = connection.read_byte();
+            match self.reader.feed(byte) {
+                Some(Event::Packet(payload)) => {
+                    connection.write_bytes(b"+");
+                    let command = command::parse(payload);
+                    // The kill packet has no reply, and the reply to
+                    // continue or step is sent once the target stops.
+                    let is_kill = command == Command::Kill;
+                    state.reply.clear();
+                    let action = state.handle(command, target);
+                    let resumed = matches!(
+                        action,
+                        Some(ResumeAction::Continue) | Some(ResumeAction::Step)
+                    );
+                    if !is_kill && !resumed {
+                        state.send_reply(connection);
+                    }
+                    if let Some(action) = action {
+                        return action;
+                    }
+                }
+                Some(Event::BadPacket) => connection.write_bytes(b"-"),
+                Some(Event::Nack) => state.send_reply(connection),
+                // The target is already stopped.
+                Some(Event::Interrupt) | Some(Event::Ack) | None => {}
+            }
+        }
+    }
+}
+
+impl StubState {
+    fn send_reply(&self, connection: &mut dyn Connection) {
+        connection.write_bytes(b"$");
+        connection.write_bytes(self.reply.payload());
+        connection.write_bytes(b"#");
+        connection.write_bytes(&self.reply.checksum());
+    }
+
+    fn write_error(&mut self, error: TargetError) {
+        match error {
+            // EFAULT
+            TargetError::Fault => self.reply.write_str("E0e"),
+            // EINVAL
+            TargetError::Invalid => self.reply.write_str("E16"),
+            TargetError::Unsupported => {}
+        }
+    }
+
+    fn write_result(&mut self, result: Result<(), TargetError>) {
+        match result {
+            Ok(()) => self.reply.write_str("OK"),
+            Err(error) => self.write_error(error),
+        }
+    }
+
+    /// The thread a command applies to.
+    fn resolve(thread: ThreadId, target: &mut dyn Target) -> u64 {
+        match thread {
+            ThreadId::Id(thread) => thread,
+            ThreadId::Any | ThreadId::All => target.current_thread(),
+        }
+    }
+
+    fn write_stop_reply(&mut self, target: &mut dyn Target) {
+        let signal = match self.stop_reason {
+            StopReason::Interrupted => SIGINT,
+            _ => SIGTRAP,
+        };
+        self.reply.write_str("T");
+        self.reply.write_hex_bytes(&[signal]);
+        self.reply.write_str("thread:");
+        self.reply.write_hex_u64(target.current_thread());
+        self.reply.write_str(";");
+        match self.stop_reason {
+            StopReason::SoftwareBreakpoint if self.swbreak => self.reply.write_str("swbreak:;"),
+            StopReason::HardwareBreakpoint if self.hwbreak => self.reply.write_str("hwbreak:;"),
+            StopReason::Watchpoint { kind, address } => {
+                let name = match kind {
+                    BreakpointKind::ReadWatchpoint => "rwatch:",
+                    BreakpointKind::AccessWatchpoint => "awatch:",
+                    _ => "watch:",
+                };
+                self.reply.write_str(name);
+                self.reply.write_hex_u64(address);
+                self.reply.write_str(";");
+            }
+            _ => {}
+        }
+    }
+
+    fn query_supported(&mut self, features: &[u8]) {
+        self.swbreak = features
+            .split(|byte| *byte == b';')
+            .any(|f| f == b"swbreak+");
+        self.hwbreak = features
+            .split(|byte| *byte == b';')
+            .any(|f| f == b"hwbreak+");
+        self.reply.write_str("PacketSize=");
+        self.reply.write_hex_u64(MAX_PACKET_SIZE as u64);
+        self.reply.write_str(";swbreak+;hwbreak+");
+    }
+
+    fn read_memory(&mut self, target: &mut dyn Target, address: u64, length: u64) {
+        let thread = Self::resolve(self.general_thread, target);
+        // Each byte takes two hexadecimal digits. gdb asks for the rest if
+        // the reply is short.
+        let mut remaining = core::cmp::min(length, (MAX_PACKET_SIZE / 2) as u64) as usize;
+        let mut address = address;
+        let mut chunk = [0u8; MEMORY_CHUNK_SIZE];
+        let mut read_any = false;
+        while remaining > 0 {
+            let size = core::cmp::min(remaining, MEMORY_CHUNK_SIZE);
+            match target.read_memory(thread, address, &mut chunk[..size]) {
+                Ok(()) => self.reply.write_hex_bytes(&chunk[..size]),
+                Err(error) => {
+                    if !read_any {
+                        self.write_error(error);
+                    }
+                    return;
+                }
+            }
+            read_any = true;
+            remaining -= size;
+            address = address.wrapping_add(size as u64);
+        }
+    }
+
+    fn write_memory(&mut self, target: &mut dyn Target, address: u64, data: &[u8]) {
+        let thread = Self::resolve(self.general_thread, target);
+        let mut chunk = [0u8; MEMORY_CHUNK_SIZE];
+        let mut address = address;
+        for digits in data.chunks(MEMORY_CHUNK_SIZE * 2) {
+            let size = match hex::decode_bytes(digits, &mut chunk) {
+                Some(size) => size,
+                None => return self.write_error(TargetError::Invalid),
+            };
+            if let Err(error) = target.write_memory(thread, address, &chunk[..size]) {
+                return self.write_error(error);
+            }
+            address = address.wrapping_add(size as u64);
+        }
+        self.reply.write_str("OK");
+    }
+
+    fn set_rip(&mut self, target: &mut dyn Target, rip: u64) -> Result<(), TargetError> {
+        let thread = target.current_thread();
+        let mut registers = target.read_registers(thread)?;
+        registers.rip = rip;
+        target.write_registers(thread, &registers)
+    }
+
+    fn resume(
+        &mut self,
+        target: &mut dyn Target,
+        address: Option<u64>,
+        action: ResumeAction,
+    ) -> Option<ResumeAction> {
+        if let Some(address) = address {
+            if let Err(error) = self.set_rip(target, address) {
+                self.write_error(error);
+                return None;
+            }
+        }
+        self.waiting_for_stop = true;
+        Some(action)
+    }
+
+    /// Carry out a command and build the reply. Returns an action if gdb
+    /// resumed the target.
+    fn handle(&mut self, command: Command, target: &mut dyn Target) -> Option<ResumeAction> {
+        match command {
+            Command::QuerySupported(features) => self.query_supported(features),
+            Command::HaltReason => self.write_stop_reply(target),
+            Command::ReadRegisters => {
+                let thread = Self::resolve(self.general_thread, target);
+                match target.read_registers(thread) {
+                    Ok(registers) => registers.write_all(&mut self.reply),
+                    Err(error) => self.write_error(error),
+                }
+            }
+            Command::WriteRegisters(digits) => {
+                let thread = Self::resolve(self.general_thread, target);
+                let result = target.read_registers(thread).and_then(|mut registers| {
+                    registers.decode_all(digits).ok_or(TargetError::Invalid)?;
+                    target.write_registers(thread, &registers)
+                });
+                self.write_result(result);
+            }
+            Command::ReadRegister(register) => {
+                let thread = Self::resolve(self.general_thread, target);
+                match target.read_registers(thread) {
+                    // Registers the stub doesn't know get an empty reply, so
+                    // gdb treats them as unavailable.
+                    Ok(registers) => {
+                        registers.write_register(register, &mut self.reply);
+                    }
+                    Err(error) => self.write_error(error),
+                }
+            }
+            Command::WriteRegister(register, digits) => {
+                let thread = Self::resolve(self.general_thread, target);
+                let result = target.read_registers(thread).and_then(|mut registers| {
+                    let value =
+                        Registers::decode_register(register, digits).ok_or(TargetError::Invalid)?;
+                    registers.set(register, value).ok_or(TargetError::Invalid)?;
+                    target.write_registers(thread, &registers)
+                });
+                self.write_result(result);
+            }
+            Command::ReadMemory { address, length } => self.read_memory(target, address, length),
+            Command::WriteMemory { address, data } => self.write_memory(target, address, data),
+            Command::Continue(address) => {
+                return self.resume(target, address, ResumeAction::Continue)
+            }
+            Command::Step(address) => return self.resume(target, address, ResumeAction::Step),
+            Command::InsertBreakpoint {
+                kind,
+                address,
+                length,
+            } => {
+                let result = target.insert_breakpoint(kind, address, length);
+                self.write_result(result);
+            }
+            Command::RemoveBreakpoint {
+                kind,
+                address,
+                length,
+            } => {
+                let result = target.remove_breakpoint(kind, address, length);
+                self.write_result(result);
+            }
+            Command::SetThread { operation, thread } => {
+                if let ThreadId::Id(id) = thread {
+                    if !target.is_thread_alive(id) {
+                        self.write_error(TargetError::Invalid);
+                        return None;
+                    }
+                }
+                // Only the stopped thread can be resumed, so the thread for
+                // continue and step is accepted and ignored.
+                if operation == b'g' {
+                    self.general_thread = thread;
+                }
+                self.reply.write_str("OK");
+            }
+            Command::ThreadAlive(thread) => {
+                let alive = match thread {
+                    ThreadId::Id(id) => target.is_thread_alive(id),
+                    ThreadId::Any | ThreadId::All => false,
+                };
+                if alive {
+                    self.reply.write_str("OK");
+                } else {
+                    self.write_error(TargetError::Invalid);
+                }
+            }
+            Command::FirstThreadInfo => {
+                let reply = &mut self.reply;
+                let mut separator = "m";
+                target.for_each_thread(&mut |thread| {
+                    reply.write_str(separator);
+                    reply.write_hex_u64(thread);
+                    separator = ",";
+                });
+            }
+            Command::SubsequentThreadInfo => self.reply.write_str("l"),
+            Command::CurrentThread => {
+                self.reply.write_str("QC");
+                self.reply.write_hex_u64(target.current_thread());
+            }
+            Command::Attached => self.reply.write_str("1"),
+            Command::Detach => {
+                self.reply.write_str("OK");
+                return Some(ResumeAction::Detach);
+            }
+            Command::Kill => return Some(ResumeAction::Detach),
+            Command::Invalid => self.write_error(TargetError::Invalid),
+            Command::Unsupported => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Replays the bytes gdb sent and records the bytes the stub sent.
+    struct ReplayConnection {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Connection for ReplayConnection {
+        fn read_byte(&mut self) -> u8 {
+            self.input
+                .pop_front()
+                .expect("The stub read past the end of the transcript")
+        }
+
+        fn write_bytes(&mut self, bytes: &[u8]) {
+            self.output.extend_from_slice(bytes);
+        }
+    }
+
+    const MEMORY_BASE: u64 = 0x1000;
+
+    /// Two threads sharing 256 bytes of memory at 0x1000.
+    struct MockTarget {
+        registers: [Registers; 2],
+        memory: [u8; 0x100],
+        breakpoints: Vec<(BreakpointKind, u64, u64)>,
+    }
+
+    impl MockTarget {
+        fn new() -> Self {
+            let mut first = Registers::default();
+            for (index, gpr) in first.gprs.iter_mut().enumerate() {
+                *gpr = index as u64 * 0x1111;
+            }
+            first.rip = 0x1010;
+            first.eflags = 0x246;
+            first.segments = [0x38, 0x30, 0x30, 0x30, 0x30, 0x30];
+            let second = Registers {
+                gprs: [0xaa; 16],
+                rip: 0x2020,
+                eflags: 0x2,
+                segments: first.segments,
+            };
+            let mut memory = [0; 0x100];
+            for (index, byte) in memory.iter_mut().enumerate() {
+                *byte = index as u8;
+            }
+            MockTarget {
+                registers: [first, second],
+                memory,
+                breakpoints: Vec::new(),
+            }
+        }
+
+        fn range(&self, address: u64, length: usize) -> Result<usize, TargetError> {
+            let offset = address.checked_sub(MEMORY_BASE).ok_or(TargetError::Fault)? as usize;
+            if offset + length > self.memory.len() {
+                return Err(TargetError::Fault);
+            }
+            Ok(offset)
+        }
+    }
+
+    impl Target for MockTarget {
+        fn current_thread(&mut self) -> u64 {
+            1
+        }
+
+        fn for_each_thread(&mut self, f: &mut dyn FnMut(u64)) {
+            f(1);
+            f(2);
+        }
+
+        fn is_thread_alive(&mut self, thread: u64) -> bool {
+            thread == 1 || thread == 2
+        }
+
+        fn read_registers(&mut self, thread: u64) -> Result<Registers, TargetError> {
+            Ok(self.registers[thread as usize - 1])
+        }
+
+        fn write_registers(
+            &mut self,
+            thread: u64,
+            registers: &Registers,
+        ) -> Result<(), TargetError> {
+            self.registers[thread as usize - 1] = *registers;
+            Ok(())
+        }
+
+        fn read_memory(
+            &mut self,
+            _thread: u64,
+            address: u64,
+            data: &mut [u8],
+        ) -> Result<(), TargetError> {
+            let offset = self.range(address, data.len())?;
+            data.copy_from_slice(&self.memory[offset..offset + data.len()]);
+            Ok(())
+        }
+
+        fn write_memory(
+            &mut self,
+            _thread: u64,
+            address: u64,
+            data: &[u8],
+        ) -> Result<(), TargetError> {
+            let offset = self.range(address, data.len())?;
+            self.memory[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn insert_breakpoint(
+            &mut self,
+            kind: BreakpointKind,
+            address: u64,
+            length: u64,
+        ) -> Result<(), TargetError> {
+            if kind == BreakpointKind::ReadWatchpoint {
+                return Err(TargetError::Unsupported);
+            }
+            self.breakpoints.push((kind, address, length));
+            Ok(())
+        }
+
+        fn remove_breakpoint(
+            &mut self,
+            kind: BreakpointKind,
+            address: u64,
+            length: u64,
+        ) -> Result<(), TargetError> {
+            let index = self
+                .breakpoints
+                .iter()
+                .position(|breakpoint| *breakpoint == (kind, address, length))
+                .ok_or(TargetError::Invalid)?;
+            self.breakpoints.remove(index);
+            Ok(())
+        }
+    }
+
+    fn parse_stop_reason(reason: &str) -> StopReason {
+        let mut words = reason.split_whitespace();
+        match words.next() {
+            Some("interrupt") => StopReason::Interrupted,
+            Some("step") => StopReason::Step,
+            Some("swbreak") => StopReason::SoftwareBreakpoint,
+            Some("hwbreak") => StopReason::HardwareBreakpoint,
+            Some("trap") => StopReason::Trap,
+            Some("watch") => StopReason::Watchpoint {
+                kind: BreakpointKind::WriteWatchpoint,
+                address: u64::from_str_radix(words.next().unwrap(), 16).unwrap(),
+            },
+            _ => panic!("Unknown stop reason {}", reason),
+        }
+    }
+
+    fn parse_resume_action(action: &str) -> ResumeAction {
+        match action {
+            "continue" => ResumeAction::Continue,
+            "step" => ResumeAction::Step,
+            "detach" => ResumeAction::Detach,
+            _ => panic!("Unknown resume action {}", action),
+        }
+    }
+
+    /// Run the stub through every stop of the target in a transcript, and
+    /// check that it replies exactly like it did when the transcript was
+    /// recorded.
+    fn replay(transcript: &str) -> MockTarget {
+        let mut stub = Stub::new();
+        let mut target = MockTarget::new();
+        let mut lines = transcript
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .peekable();
+        while let Some(line) = lines.next() {
+            let reason = parse_stop_reason(line.strip_prefix("== stop ").unwrap());
+            let mut connection = ReplayConnection {
+                input: VecDeque::new(),
+                output: Vec::new(),
+            };
+            let mut expected = Vec::new();
+            let mut expected_action = None;
+            while let Some(line) = lines.next_if(|line| !line.starts_with("== stop ")) {
+                if let Some(bytes) = line.strip_prefix("-> ") {
+                    connection.input.extend(bytes.bytes());
+                } else if let Some(bytes) = line.strip_prefix("<- ") {
+                    expected.extend(bytes.bytes());
+                } else if let Some(action) = line.strip_prefix("== resumed ") {
+                    expected_action = Some(parse_resume_action(action));
+                } else {
+                    panic!("Malformed transcript line {}", line);
+                }
+            }
+
+            let action = stub.run(&mut connection, &mut target, reason);
+            assert_eq!(
+                String::from_utf8_lossy(&connection.output),
+                String::from_utf8_lossy(&expected)
+            );
+            assert_eq!(Some(action), expected_action);
+            assert!(connection.input.is_empty());
+        }
+        target
+    }
+
+    #[test]
+    fn attach_and_detach() {
+        replay(include_str!("../transcripts/attach.txt"));
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let target = replay(include_str!("../transcripts/breakpoints.txt"));
+        assert!(target.breakpoints.is_empty());
+        assert_eq!(target.registers[0].rip, 0x1040);
+        assert_eq!(&target.memory[8..12], &[0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            command::parse(b"m1000,40"),
+            Command::ReadMemory {
+                address: 0x1000,
+                length: 0x40
+            }
+        );
+        assert_eq!(command::parse(b"C05;1234"), Command::Continue(Some(0x1234)));
+        assert_eq!(
+            command::parse(b"Hgp1.2"),
+            Command::SetThread {
+                operation: b'g',
+                thread: ThreadId::Id(2)
+            }
+        );
+        assert_eq!(command::parse(b"M1000,2:abc"), Command::Invalid);
+        assert_eq!(command::parse(b"vCont?"), Command::Unsupported);
+    }
+
+    #[test]
+    fn escaped_packets() {
+        let mut reader = PacketReader::new();
+        let mut event = None;
+        for byte in b"$X}]#32".iter() {
+            event = reader.feed(*byte).map(|event| format!("{:?}", event));
+        }
+        assert_eq!(event.unwrap(), format!("{:?}", Event::Packet(b"X}")));
+
+        let mut writer = PacketWriter::new();
+        writer.write_bytes(b"a#b");
+        assert_eq!(writer.payload(), b"a}\x03b");
+    }
+}
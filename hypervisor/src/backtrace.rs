@@ -0,0 +1,68 @@
+//! Walks the host stack through frame pointers, so that a panic can say how
+//! the hypervisor got there. The hypervisor is built with
+//! `-Cforce-frame-pointers=yes`, so every function saves the caller's rbp at
+//! the bottom of its frame, with its return address right above it.
+//!
+//! The stack may be corrupt when this runs, so each frame is checked before
+//! it is read: the walk stops at a frame pointer which is null, misaligned,
+//! doesn't move up the stack, or jumps further than a frame could be.
+#![cfg(not(test))]
+
+/// The most frames printed.
+pub const MAX_FRAMES: usize = 32;
+
+/// The furthest apart two consecutive frame pointers can be. Anything further
+/// is assumed to be garbage rather than a frame.
+const MAX_FRAME_SIZE: u64 = 0x10000;
+
+/// The current frame pointer.
+#[inline(always)]
+pub fn current_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// The current stack pointer.
+#[inline(always)]
+pub fn current_stack_pointer() -> u64 {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    rsp
+}
+
+/// Call f with the return address of each frame, innermost first, starting
+/// with the frame rbp points to. rsp is the stack pointer of the code which
+/// owns rbp, and bounds where the first frame may be.
+pub fn walk(rsp: u64, rbp: u64, mut f: impl FnMut(usize, u64)) {
+    let mut lowest = rsp;
+    let mut rbp = rbp;
+    for depth in 0..MAX_FRAMES {
+        if rbp == 0
+            || rbp & 7 != 0
+            || rbp < lowest
+            || rbp - lowest > MAX_FRAME_SIZE
+            || rbp.checked_add(16).is_none()
+        {
+            return;
+        }
+        let (caller_rbp, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (
+                core::ptr::read_volatile(frame),
+                core::ptr::read_volatile(frame.add(1)),
+            )
+        };
+        if return_address == 0 {
+            return;
+        }
+        f(depth, return_address);
+        // The caller's frame must be above this one.
+        lowest = rbp + 16;
+        rbp = caller_rbp;
+    }
+}
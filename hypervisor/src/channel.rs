@@ -0,0 +1,255 @@
+//! Channels carry messages between guest agents and the hypervisor which are
+//! too large for a hypercall's registers, like logs, traces or policies.
+//!
+//! A guest agent initializes two [rings](../../hypervisor_abi/ring/index.html)
+//! in its memory, one for messages to the hypervisor and one for replies, and
+//! registers them with the
+//! [HYPERCALL_REASON_CHANNEL](../../hypervisor_abi/constant.HYPERCALL_REASON_CHANNEL.html)
+//! hypercall. The hypervisor checks the rings and finds their host addresses
+//! once, when they are registered, and refuses rings on any page the host
+//! doesn't map writable, since the rings are then accessed directly rather
+//! than through the [exception table](../exception_table/index.html). The
+//! agent then sends messages and rings the channel's doorbell with the same
+//! hypercall, and the hypervisor handles every message waiting in the ring
+//! before returning. Subsystems handle messages of their own kinds by
+//! registering a [MessageHandler](type.MessageHandler.html).
+//!
+//! The hypervisor doesn't use EPT, so it can't protect the rings from the
+//! rest of the guest or stop the agent from freeing them while they are
+//! registered. Everything in them is treated as untrusted.
+//!
+//! A channel belongs to the address space which registered it, named by its
+//! CR3, and only that address space may ring its doorbell or unregister it.
+//!
+//! The hypervisor reaches the rings at their guest physical addresses, which
+//! only works under UEFI, where memory is identity mapped, or inside the
+//! ranges the loader registered with
+//! [rustyvisor_crash_dump_add_range](../fn.rustyvisor_crash_dump_add_range.html).
+//! The Linux loader registers none, so the channel hypercall is only offered
+//! under UEFI.
+use crate::guest_memory;
+use crate::hypercall_handler::{self, HypercallArguments, HypercallResults};
+use crate::hypercall_policy::HypercallCaller;
+use hypervisor_abi::ring::{RingConsumer, RingError, RingProducer};
+use hypervisor_abi::{HypercallError, CHANNEL_MAX_MESSAGE_SIZE, CHANNEL_MAX_RING_SIZE};
+use log::{trace, warn};
+use spin::Mutex;
+
+/// The number of channels which can be registered at once.
+const MAX_CHANNELS: usize = 4;
+
+/// The number of message kinds which can have a handler.
+const MAX_MESSAGE_HANDLERS: usize = 16;
+
+/// The most messages handled for each doorbell, so that a guest which keeps
+/// sending messages on another core can't keep this one in the hypervisor.
+const MAX_MESSAGES_PER_DOORBELL: u64 = 256;
+
+/// Handles a message of one kind, and may reply on the same channel.
+pub type MessageHandler = fn(payload: &[u8], replies: &mut RingProducer) -> Result<(), RingError>;
+
+/// The reasons a message handler can't be registered.
+#[derive(Debug)]
+pub enum RegisterMessageHandlerError {
+    /// A handler for the same kind is already registered.
+    AlreadyRegistered,
+    /// There are already MAX_MESSAGE_HANDLERS handlers.
+    TableFull,
+}
+
+struct Channel {
+    /// The CR3 of the address space which registered the channel.
+    owner: u64,
+    to_hypervisor: RingConsumer,
+    to_guest: RingProducer,
+}
+
+struct Channels {
+    channels: [Option<Channel>; MAX_CHANNELS],
+    /// Holds each message's payload while it is handled.
+    buffer: [u8; CHANNEL_MAX_MESSAGE_SIZE],
+}
+
+static CHANNELS: Mutex<Channels> = Mutex::new(Channels {
+    channels: [None, None, None, None],
+    buffer: [0; CHANNEL_MAX_MESSAGE_SIZE],
+});
+
+static MESSAGE_HANDLERS: Mutex<[Option<(u32, MessageHandler)>; MAX_MESSAGE_HANDLERS]> =
+    Mutex::new([None; MAX_MESSAGE_HANDLERS]);
+
+/// Handle messages of a kind on every channel. Handlers run with the channels
+/// locked, so must not register channels or handlers themselves.
+pub fn register_message_handler(
+    kind: u32,
+    handler: MessageHandler,
+) -> Result<(), RegisterMessageHandlerError> {
+    let mut handlers = MESSAGE_HANDLERS.lock();
+    if handlers
+        .iter()
+        .flatten()
+        .any(|(registered_kind, _)| *registered_kind == kind)
+    {
+        return Err(RegisterMessageHandlerError::AlreadyRegistered);
+    }
+    let slot = handlers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(RegisterMessageHandlerError::TableFull)?;
+    *slot = Some((kind, handler));
+    Ok(())
+}
+
+fn echo(payload: &[u8], replies: &mut RingProducer) -> Result<(), RingError> {
+    replies.send(hypervisor_abi::CHANNEL_MESSAGE_ECHO, payload)
+}
+
+/// Check a ring's guest physical address and size, and find its host address,
+/// which the host must map writable.
+fn map_ring(address: u64, len: u64) -> Result<*mut u8, HypercallError> {
+    if len == 0
+        || len > CHANNEL_MAX_RING_SIZE
+        || address & (hypervisor_abi::ring::RING_ALIGN as u64 - 1) != 0
+    {
+        return Err(HypercallError::InvalidArgument);
+    }
+    let host_address = guest_memory::host_address(address, len as usize)
+        .map_err(|_| HypercallError::InvalidBuffer)?;
+    guest_memory::check_host_mapping(host_address, len as usize, true)
+        .map_err(|_| HypercallError::InvalidBuffer)?;
+    Ok(host_address)
+}
+
+fn register_channel(arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    let registers = &arguments.registers;
+    let (to_hypervisor_address, to_hypervisor_len) = (registers[1], registers[2]);
+    let (to_guest_address, to_guest_len) = (registers[3], registers[4]);
+    let to_hypervisor = map_ring(to_hypervisor_address, to_hypervisor_len)?;
+    let to_guest = map_ring(to_guest_address, to_guest_len)?;
+    if to_hypervisor_address < to_guest_address.saturating_add(to_guest_len)
+        && to_guest_address < to_hypervisor_address.saturating_add(to_hypervisor_len)
+    {
+        return Err(HypercallError::InvalidArgument);
+    }
+    let channel = unsafe {
+        Channel {
+            owner: arguments.caller.cr3,
+            to_hypervisor: RingConsumer::attach(to_hypervisor, to_hypervisor_len as usize)
+                .map_err(|_| HypercallError::InvalidArgument)?,
+            to_guest: RingProducer::attach(to_guest, to_guest_len as usize)
+                .map_err(|_| HypercallError::InvalidArgument)?,
+        }
+    };
+    let mut channels = CHANNELS.lock();
+    let (index, slot) = channels
+        .channels
+        .iter_mut()
+        .enumerate()
+        .find(|(_, slot)| slot.is_none())
+        .ok_or(HypercallError::InvalidArgument)?;
+    *slot = Some(channel);
+    trace!(
+        "Registered channel {} rings {:x} {:x} for cr3 {:x}",
+        index,
+        to_hypervisor_address,
+        to_guest_address,
+        arguments.caller.cr3
+    );
+    Ok([index as u64, 0, 0, 0])
+}
+
+fn handle_message(kind: u32, payload: &[u8], replies: &mut RingProducer) {
+    let handler = MESSAGE_HANDLERS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|(registered_kind, _)| *registered_kind == kind)
+        .map(|(_, handler)| *handler);
+    match handler {
+        Some(handler) => {
+            if let Err(e) = handler(payload, replies) {
+                trace!("Channel message {} failed {:?}", kind, e);
+            }
+        }
+        None => trace!("Dropping channel message of unknown kind {}", kind),
+    }
+}
+
+/// The slot of a channel the caller registered. Channels registered by other
+/// address spaces look the same as unregistered ones.
+fn owned_slot<'a>(
+    channels: &'a mut [Option<Channel>],
+    index: u64,
+    caller: &HypercallCaller,
+) -> Result<&'a mut Option<Channel>, HypercallError> {
+    channels
+        .get_mut(index as usize)
+        .filter(|slot| matches!(slot, Some(channel) if channel.owner == caller.cr3))
+        .ok_or(HypercallError::InvalidArgument)
+}
+
+fn ring_doorbell(arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    let registers = &arguments.registers;
+    let mut channels = CHANNELS.lock();
+    let Channels { channels, buffer } = &mut *channels;
+    let channel = owned_slot(channels, registers[1], &arguments.caller)?
+        .as_mut()
+        .ok_or(HypercallError::InvalidArgument)?;
+    let mut handled = 0;
+    while handled < MAX_MESSAGES_PER_DOORBELL {
+        match channel.to_hypervisor.receive(buffer) {
+            Ok(Some(message)) => {
+                handle_message(message.kind, &buffer[..message.len], &mut channel.to_guest)
+            }
+            Ok(None) => break,
+            Err(RingError::MessageTooLarge) => {
+                trace!(
+                    "Dropping channel message larger than {} bytes",
+                    buffer.len()
+                );
+                let _ = channel.to_hypervisor.discard();
+            }
+            Err(e) => {
+                warn!("Channel {} is unusable {:?}", registers[1], e);
+                return Err(HypercallError::InvalidBuffer);
+            }
+        }
+        handled += 1;
+    }
+    let pending = channel
+        .to_guest
+        .pending()
+        .map_err(|_| HypercallError::InvalidBuffer)?;
+    Ok([handled, pending as u64, 0, 0])
+}
+
+fn unregister_channel(arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    let mut channels = CHANNELS.lock();
+    *owned_slot(
+        &mut channels.channels,
+        arguments.registers[1],
+        &arguments.caller,
+    )? = None;
+    Ok([0; 4])
+}
+
+fn channel_hypercall(arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    match arguments.registers[0] {
+        hypervisor_abi::CHANNEL_OPERATION_REGISTER => register_channel(arguments),
+        hypervisor_abi::CHANNEL_OPERATION_DOORBELL => ring_doorbell(arguments),
+        hypervisor_abi::CHANNEL_OPERATION_UNREGISTER => unregister_channel(arguments),
+        _ => Err(HypercallError::InvalidArgument),
+    }
+}
+
+/// Register the channel hypercall and the echo message handler. Does nothing
+/// outside of UEFI, where the rings can't be reached.
+pub fn register_hypercalls() -> Result<(), hypercall_handler::RegisterHypercallError> {
+    if !cfg!(target_os = "uefi") {
+        return Ok(());
+    }
+    hypercall_handler::register(hypervisor_abi::HYPERCALL_REASON_CHANNEL, channel_hypercall)?;
+    // The message handler table starts empty, so this can't fail.
+    let _ = register_message_handler(hypervisor_abi::CHANNEL_MESSAGE_ECHO, echo);
+    Ok(())
+}
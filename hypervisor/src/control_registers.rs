@@ -0,0 +1,326 @@
+//! Emulates guest accesses to the control registers cr0, cr3, cr4 and cr8, as
+//! reported by control register access VM exits.
+//!
+//! VMX operation requires some bits of cr0 and cr4 to hold fixed values, which
+//! are reported by the IA32_VMX_CR0_FIXED0/1 and IA32_VMX_CR4_FIXED0/1 MSRs.
+//! Most notably cr4.VMXE must be set. The hypervisor owns those bits through
+//! the cr0 and cr4 guest/host masks, so that the guest reads the values it
+//! expects from the read shadows, and any guest write which would change them
+//! causes a VM exit.
+//! For more information see the Intel manual, Volume 3, Section 24.6.6
+//! "Guest/Host Masks and Read Shadows for CR0 and CR4", and Appendix A.7
+//! "VMX-Fixed Bits in CR0" and A.8 "VMX-Fixed Bits in CR4".
+//!
+//! CLTS and LMSW are emulated as writes to cr0. Accesses to cr8, the task
+//! priority register, don't exit, so the guest's cr8 stays in the processor,
+//! which masks the interrupts delivered straight to the guest. The
+//! [virtual interrupt controller](../interrupt_controller/index.html)
+//! consults it as well, and a cr8 access which is reported anyway is checked
+//! and passed through to the processor.
+use crate::interrupt_controller;
+use crate::msr::{rdmsrl, Msr};
+use crate::vmcs_fields::{SecondaryCpuBasedControlsVpidEnable, VmEntryIa32eMode, VmcsField};
+use crate::vmx::{self, vmread, vmwrite};
+use log::trace;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_TS: u64 = 1 << 3;
+/// The bits of cr0 loaded by LMSW: PE, MP, EM and TS.
+const CR0_LMSW_BITS: u64 = 0xf;
+const CR0_NW: u64 = 1 << 29;
+const CR0_CD: u64 = 1 << 30;
+const CR0_PG: u64 = 1 << 31;
+
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const CR4_VMXE: u64 = 1 << 13;
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// When cr4.PCIDE is set, setting bit 63 of the value moved to cr3 asks the
+/// processor not to invalidate the TLB entries of the new PCID. The bit is not
+/// stored in cr3.
+const CR3_PCID_NO_FLUSH: u64 = 1 << 63;
+
+/// Only the low 4 bits of cr8 may be set.
+const CR8_RESERVED: u64 = !0xf;
+
+/// The L bit of the segment access rights, set for 64 bit code segments.
+const SEGMENT_ACCESS_RIGHTS_LONG_MODE: u64 = 1 << 13;
+
+/// The values of cr0 and cr4 before they were adjusted for VMX operation.
+/// These are the values the guest expects to read.
+#[derive(Debug, Clone, Copy)]
+pub struct OriginalControlRegisters {
+    /// The original value of cr0.
+    pub cr0: u64,
+    /// The original value of cr4.
+    pub cr4: u64,
+}
+
+/// The reasons emulating a control register access can fail.
+#[derive(Debug)]
+pub enum ControlRegisterAccessError {
+    /// Accessing the vmcs failed.
+    VmFail(x86::vmx::VmFail),
+    /// The access is architecturally illegal. A #GP should be injected into
+    /// the guest instead of completing the access.
+    GeneralProtectionFault,
+}
+
+impl From<x86::vmx::VmFail> for ControlRegisterAccessError {
+    fn from(e: x86::vmx::VmFail) -> Self {
+        ControlRegisterAccessError::VmFail(e)
+    }
+}
+
+/// The bits of a control register which must be 0 or 1 in VMX operation.
+struct FixedBits {
+    /// Bits which are set here must be set in the control register.
+    fixed0: u64,
+    /// Bits which are clear here must be clear in the control register.
+    fixed1: u64,
+}
+
+impl FixedBits {
+    fn cr0() -> Self {
+        FixedBits {
+            fixed0: rdmsrl(Msr::Ia32VmxCr0Fixed0),
+            fixed1: rdmsrl(Msr::Ia32VmxCr0Fixed1),
+        }
+    }
+
+    fn cr4() -> Self {
+        FixedBits {
+            fixed0: rdmsrl(Msr::Ia32VmxCr4Fixed0),
+            fixed1: rdmsrl(Msr::Ia32VmxCr4Fixed1),
+        }
+    }
+
+    /// The bits the hypervisor must own because they are fixed.
+    fn host_owned(&self) -> u64 {
+        self.fixed0 | !self.fixed1
+    }
+
+    /// Adjust a value the guest would like to use to one which is allowed in
+    /// VMX operation.
+    fn apply(&self, value: u64) -> u64 {
+        (value | self.fixed0) & self.fixed1
+    }
+}
+
+/// True if the guest is in IA-32e mode, i.e. if EFER.LMA is set.
+/// The guest's EFER is not loaded on VM entry, so use the IA-32e mode guest
+/// VM entry control, which the processor keeps in sync with EFER.LMA.
+fn guest_is_in_ia32e_mode() -> Result<bool, x86::vmx::VmFail> {
+    Ok(vmread(VmcsField::VmEntryControls)? & VmEntryIa32eMode != 0)
+}
+
+/// True if the guest is running 64 bit code, as opposed to compatibility
+/// mode or legacy protected mode.
+pub fn guest_is_in_64_bit_mode() -> Result<bool, x86::vmx::VmFail> {
+    Ok(guest_is_in_ia32e_mode()?
+        && vmread(VmcsField::GuestCsArBytes)? & SEGMENT_ACCESS_RIGHTS_LONG_MODE != 0)
+}
+
+/// Set up the guest/host masks, the read shadows, and the guest's cr0 and cr4
+/// for the currently loaded vmcs.
+pub fn initialize_guest_control_registers(
+    original: &OriginalControlRegisters,
+) -> Result<(), x86::vmx::VmFail> {
+    let cr0_fixed = FixedBits::cr0();
+    vmwrite(VmcsField::Cr0GuestHostMask, cr0_fixed.host_owned())?;
+    vmwrite(VmcsField::Cr0ReadShadow, original.cr0)?;
+    vmwrite(VmcsField::GuestCr0, cr0_fixed.apply(original.cr0))?;
+
+    let cr4_fixed = FixedBits::cr4();
+    vmwrite(VmcsField::Cr4GuestHostMask, cr4_fixed.host_owned())?;
+    vmwrite(VmcsField::Cr4ReadShadow, original.cr4)?;
+    vmwrite(VmcsField::GuestCr4, cr4_fixed.apply(original.cr4))?;
+    Ok(())
+}
+
+/// Emulate a guest write to cr0.
+/// The guest's value is kept in the read shadow, and the real cr0 holds the
+/// guest's value adjusted for VMX operation.
+/// See the Intel manual, Volume 2, "MOV—Move to/from Control Registers" for
+/// the conditions which cause a #GP.
+pub fn write_cr0(value: u64) -> Result<(), ControlRegisterAccessError> {
+    trace!("Guest cr0 write {:x}", value);
+    if value >> 32 != 0 {
+        return Err(ControlRegisterAccessError::GeneralProtectionFault);
+    }
+    if value & CR0_PG != 0 && value & CR0_PE == 0 {
+        return Err(ControlRegisterAccessError::GeneralProtectionFault);
+    }
+    if value & CR0_NW != 0 && value & CR0_CD == 0 {
+        return Err(ControlRegisterAccessError::GeneralProtectionFault);
+    }
+
+    let fixed = FixedBits::cr0();
+    if value & CR0_PG == 0 && fixed.fixed0 & CR0_PG != 0 {
+        // Clearing paging is illegal in 64 bit mode, but is how the guest
+        // leaves IA-32e mode from compatibility mode. We can't run the guest
+        // with paging disabled without the unrestricted guest control, so
+        // the guest gets a #GP either way rather than bringing down the host.
+        return Err(ControlRegisterAccessError::GeneralProtectionFault);
+    }
+
+    vmwrite(VmcsField::Cr0ReadShadow, value)?;
+    vmwrite(VmcsField::GuestCr0, fixed.apply(value))?;
+    Ok(())
+}
+
+/// Emulate a guest write to cr4.
+/// The guest's value is kept in the read shadow, and the real cr4 holds the
+/// guest's value adjusted for VMX operation.
+/// cr4.VMXE is reserved from the guest's point of view, since VMX is hidden
+/// from the guest.
+/// See the Intel manual, Volume 2, "MOV—Move to/from Control Registers" for
+/// the conditions which cause a #GP.
+pub fn write_cr4(value: u64) -> Result<(), ControlRegisterAccessError> {
+    trace!("Guest cr4 write {:x}", value);
+    let fixed = FixedBits::cr4();
+    let reserved = !fixed.fixed1 | CR4_VMXE;
+    if value & reserved != 0 {
+        return Err(ControlRegisterAccessError::GeneralProtectionFault);
+    }
+
+    let old_value = guest_cr4()?;
+    if guest_is_in_ia32e_mode()? {
+        if value & CR4_PAE == 0 {
+            return Err(ControlRegisterAccessError::GeneralProtectionFault);
+        }
+        if (value ^ old_value) & CR4_LA57 != 0 {
+            return Err(ControlRegisterAccessError::GeneralProtectionFault);
+        }
+    }
+    if value & CR4_PCIDE != 0 && old_value & CR4_PCIDE == 0 {
+        let cr3 = vmread(VmcsField::GuestCr3)?;
+        if !guest_is_in_ia32e_mode()? || cr3 & 0xfff != 0 {
+            return Err(ControlRegisterAccessError::GeneralProtectionFault);
+        }
+    }
+
+    vmwrite(VmcsField::Cr4ReadShadow, value)?;
+    vmwrite(VmcsField::GuestCr4, fixed.apply(value))?;
+    Ok(())
+}
+
+/// The value of cr0 as the guest sees it, with the bits owned by the hypervisor
+/// taken from the read shadow.
+pub fn guest_cr0() -> Result<u64, x86::vmx::VmFail> {
+    let mask = vmread(VmcsField::Cr0GuestHostMask)?;
+    Ok((vmread(VmcsField::GuestCr0)? & !mask) | (vmread(VmcsField::Cr0ReadShadow)? & mask))
+}
+
+/// The value of cr4 as the guest sees it, with the bits owned by the hypervisor
+/// taken from the read shadow.
+pub fn guest_cr4() -> Result<u64, x86::vmx::VmFail> {
+    let mask = vmread(VmcsField::Cr4GuestHostMask)?;
+    Ok((vmread(VmcsField::GuestCr4)? & !mask) | (vmread(VmcsField::Cr4ReadShadow)? & mask))
+}
+
+/// The number of bits in a physical address on this processor.
+fn physical_address_width() -> u32 {
+    let result = unsafe { core::arch::x86_64::__cpuid(0x8000_0008) };
+    result.eax & 0xff
+}
+
+/// Emulate a guest write to cr3.
+/// If the guest uses PCIDs and sets the no flush bit, the bit is dropped
+/// before the value is loaded. Otherwise the guest expects its non-global TLB
+/// entries to be invalidated. Without VPIDs every VM entry and exit does this
+/// already, but with VPIDs the guest's entries must be invalidated by hand.
+/// See the Intel manual, Volume 3, Section 4.10.4.1 "Operations that
+/// Invalidate TLBs and Paging-Structure Caches".
+pub fn write_cr3(value: u64) -> Result<(), ControlRegisterAccessError> {
+    trace!("Guest cr3 write {:x}", value);
+    let pcid_enabled = guest_cr4()? & CR4_PCIDE != 0;
+    let no_flush = pcid_enabled && value & CR3_PCID_NO_FLUSH != 0;
+    let value = if pcid_enabled {
+        value & !CR3_PCID_NO_FLUSH
+    } else {
+        value
+    };
+
+    if value >> physical_address_width() != 0 {
+        return Err(ControlRegisterAccessError::GeneralProtectionFault);
+    }
+
+    vmwrite(VmcsField::GuestCr3, value)?;
+
+    if !no_flush
+        && vmread(VmcsField::SecondaryVmExecControl)? & SecondaryCpuBasedControlsVpidEnable != 0
+    {
+        // INVVPID can't select a single PCID, so invalidate every non-global
+        // entry of the guest's VPID, which includes the entries for the new
+        // PCID.
+        let vpid = vmread(VmcsField::VirtualProcessorID)? as u16;
+        vmx::invvpid_single_context_retaining_globals(vpid)?;
+    }
+    Ok(())
+}
+
+/// Emulate a guest write to cr8, the task priority register.
+pub fn write_cr8(value: u64) -> Result<(), ControlRegisterAccessError> {
+    trace!("Guest cr8 write {:x}", value);
+    if value & CR8_RESERVED != 0 {
+        return Err(ControlRegisterAccessError::GeneralProtectionFault);
+    }
+    interrupt_controller::set_task_priority(value);
+    Ok(())
+}
+
+/// Emulate CLTS, which clears cr0.TS.
+pub fn clts() -> Result<(), ControlRegisterAccessError> {
+    trace!("Guest clts");
+    write_cr0(guest_cr0()? & !CR0_TS)
+}
+
+/// Emulate LMSW, which loads the low 4 bits of cr0 from its source operand.
+/// The processor reports the source operand in the exit qualification
+/// whether it was a register or memory, so there is no need to decode the
+/// instruction. LMSW can set cr0.PE but can't clear it.
+pub fn lmsw(source: u64) -> Result<(), ControlRegisterAccessError> {
+    trace!("Guest lmsw {:x}", source);
+    let cr0 = guest_cr0()?;
+    write_cr0((cr0 & !CR0_LMSW_BITS) | (source & CR0_LMSW_BITS) | (cr0 & CR0_PE))
+}
+
+/// Emulate a MOV to a control register.
+pub fn write(crnum: u64, value: u64) -> Result<(), ControlRegisterAccessError> {
+    match crnum {
+        0 => write_cr0(value),
+        3 => write_cr3(value),
+        4 => write_cr4(value),
+        8 => write_cr8(value),
+        _ => panic!("Illegal control register write cr{}", crnum),
+    }
+}
+
+/// Emulate a MOV from a control register.
+pub fn read(crnum: u64) -> Result<u64, x86::vmx::VmFail> {
+    match crnum {
+        0 => guest_cr0(),
+        3 => vmread(VmcsField::GuestCr3),
+        4 => guest_cr4(),
+        8 => Ok(interrupt_controller::task_priority()),
+        _ => panic!("Illegal control register read cr{}", crnum),
+    }
+}
+
+/// Finish emulating a control register access which failed with an error.
+/// If the access was illegal a #GP is injected into the guest, and the guest's
+/// instruction pointer must not be advanced.
+pub fn handle_access_error(error: ControlRegisterAccessError) -> Result<(), x86::vmx::VmFail> {
+    match error {
+        ControlRegisterAccessError::VmFail(e) => Err(e),
+        ControlRegisterAccessError::GeneralProtectionFault => {
+            trace!("Illegal control register access, injecting #GP");
+            interrupt_controller::inject_exception(
+                interrupt_controller::EXCEPTION_VECTOR_GENERAL_PROTECTION,
+                Some(0),
+            )
+        }
+    }
+}
@@ -0,0 +1,475 @@
+//! Decides what the guest sees when it executes CPUID.
+//!
+//! The hypervisor starts from the hardware's values for the requested leaf and
+//! subleaf, and always hides VMX support so the guest doesn't try to load a
+//! hypervisor of its own. The loader may then supply a policy: a table of
+//! entries which each match a leaf, optionally a subleaf and optionally a
+//! single vCPU, and change the registers with AND and OR masks. An entry with
+//! an AND mask of zero overrides a register completely. Every matching entry
+//! is applied in the order it was added.
+//!
+//! The policy is written as text, one entry per line or separated by
+//! semicolons. Each entry is a leaf, an optional subleaf after a period, an
+//! optional vCPU index after an at sign, and then any number of register
+//! operations:
+//!
+//! ```text
+//! 0x1 ecx&=0xfffffffe
+//! 0x7.0 ebx&=0xfffff7ff; 0xb@1 edx=1
+//! ```
+//!
+//! The loader may also opt in to the hypervisor CPUID leaves starting at
+//! [CPUID_LEAF_HYPERVISOR_BASE](../../hypervisor_abi/constant.CPUID_LEAF_HYPERVISOR_BASE.html),
+//! which report the hypervisor's vendor signature and interface version, and
+//! set the hypervisor present bit in leaf 1. They aren't reported by default
+//! so that the hypervisor stays hidden.
+//!
+//! The policy is applied to the hypervisor leaves too, so they can be changed
+//! or hidden from particular vCPUs like any other leaf.
+//!
+//! The loader builds the policy before the hypervisor is loaded on any core.
+//! It is then published once, and CPUID exits read the published copy
+//! without taking a lock, since CPUID is one of the most frequent exits and
+//! the panic handler must not find the policy locked.
+use core::convert::TryFrom;
+
+use crate::vmx::CPUIDLeafProcessorInfoAndFeaturesECXBits;
+use spin::{Mutex, Once};
+
+/// The number of entries the policy can hold.
+const MAX_CPUID_POLICY_ENTRIES: usize = 64;
+
+/// The processor info and features leaf, whose ecx reports VMX support and
+/// the hypervisor present bit.
+const CPUID_LEAF_PROCESSOR_INFO_AND_FEATURES: u32 = 1;
+
+/// The last leaf the hypervisor answers for when the hypervisor leaves are
+/// enabled. Leaves between the highest leaf it reports and this one are zero.
+const CPUID_LEAF_HYPERVISOR_LAST: u32 = 0x4000_00ff;
+
+/// The policy while the loader builds it.
+static POLICY: Mutex<CpuidPolicy> = Mutex::new(CpuidPolicy::new());
+
+/// The policy CPUID exits use, which never changes once published.
+static PUBLISHED: Once<CpuidPolicy> = Once::new();
+
+/// The four registers set by CPUID.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidRegisters {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+impl CpuidRegisters {
+    const ALL_ONES: CpuidRegisters = CpuidRegisters {
+        eax: u32::MAX,
+        ebx: u32::MAX,
+        ecx: u32::MAX,
+        edx: u32::MAX,
+    };
+
+    fn by_name(&mut self, name: &str) -> Option<&mut u32> {
+        match name {
+            "eax" => Some(&mut self.eax),
+            "ebx" => Some(&mut self.ebx),
+            "ecx" => Some(&mut self.ecx),
+            "edx" => Some(&mut self.edx),
+            _ => None,
+        }
+    }
+}
+
+/// Changes the registers returned for a leaf to (register & and_mask) |
+/// or_mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidPolicyEntry {
+    pub leaf: u32,
+    /// The subleaf, in ecx, the entry applies to, or None for every subleaf.
+    pub subleaf: Option<u32>,
+    /// The index of the vCPU the entry applies to, or None for every vCPU.
+    pub vcpu: Option<usize>,
+    pub and_mask: CpuidRegisters,
+    pub or_mask: CpuidRegisters,
+}
+
+impl CpuidPolicyEntry {
+    fn matches(&self, vcpu: usize, leaf: u32, subleaf: u32) -> bool {
+        self.leaf == leaf
+            && self.subleaf.unwrap_or(subleaf) == subleaf
+            && self.vcpu.unwrap_or(vcpu) == vcpu
+    }
+
+    fn apply(&self, registers: &mut CpuidRegisters) {
+        registers.eax = (registers.eax & self.and_mask.eax) | self.or_mask.eax;
+        registers.ebx = (registers.ebx & self.and_mask.ebx) | self.or_mask.ebx;
+        registers.ecx = (registers.ecx & self.and_mask.ecx) | self.or_mask.ecx;
+        registers.edx = (registers.edx & self.and_mask.edx) | self.or_mask.edx;
+    }
+}
+
+/// The reasons a policy can't be parsed or added.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CpuidPolicyError {
+    /// The policy has more than MAX_CPUID_POLICY_ENTRIES entries.
+    TooManyEntries,
+    /// The leaf, subleaf or vCPU index isn't a number.
+    InvalidSelector,
+    /// A register operation doesn't name eax, ebx, ecx or edx.
+    InvalidRegister,
+    /// A register operation isn't &=, |= or =.
+    InvalidOperator,
+    /// A register operation's value isn't a 32 bit number.
+    InvalidValue,
+}
+
+/// A table of policy entries, along with whether the hypervisor leaves are
+/// reported.
+#[derive(Clone)]
+pub struct CpuidPolicy {
+    entries: [Option<CpuidPolicyEntry>; MAX_CPUID_POLICY_ENTRIES],
+    hypervisor_leaves: bool,
+}
+
+impl CpuidPolicy {
+    const fn new() -> Self {
+        CpuidPolicy {
+            entries: [None; MAX_CPUID_POLICY_ENTRIES],
+            hypervisor_leaves: false,
+        }
+    }
+
+    fn add(&mut self, entry: CpuidPolicyEntry) -> Result<(), CpuidPolicyError> {
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(CpuidPolicyError::TooManyEntries)?;
+        *slot = Some(entry);
+        Ok(())
+    }
+
+    /// The registers the hypervisor reports for one of its own leaves.
+    fn hypervisor_leaf(leaf: u32) -> CpuidRegisters {
+        match leaf {
+            hypervisor_abi::CPUID_LEAF_HYPERVISOR_BASE => {
+                let signature = hypervisor_abi::CPUID_HYPERVISOR_SIGNATURE;
+                let word = |i: usize| {
+                    u32::from_le_bytes([
+                        signature[i],
+                        signature[i + 1],
+                        signature[i + 2],
+                        signature[i + 3],
+                    ])
+                };
+                CpuidRegisters {
+                    eax: hypervisor_abi::CPUID_LEAF_HYPERVISOR_INTERFACE,
+                    ebx: word(0),
+                    ecx: word(4),
+                    edx: word(8),
+                }
+            }
+            hypervisor_abi::CPUID_LEAF_HYPERVISOR_INTERFACE => CpuidRegisters {
+                eax: hypervisor_abi::HYPERVISOR_INTERFACE_VERSION,
+                ..Default::default()
+            },
+            _ => CpuidRegisters::default(),
+        }
+    }
+
+    /// The registers the guest sees when vCPU vcpu executes CPUID with leaf
+    /// in eax and subleaf in ecx, given the hardware's values.
+    fn emulate(
+        &self,
+        vcpu: usize,
+        leaf: u32,
+        subleaf: u32,
+        hardware: CpuidRegisters,
+    ) -> CpuidRegisters {
+        let mut registers = hardware;
+        if leaf == CPUID_LEAF_PROCESSOR_INFO_AND_FEATURES {
+            registers.ecx &= !(CPUIDLeafProcessorInfoAndFeaturesECXBits::VMXAvailable as u32);
+            if self.hypervisor_leaves {
+                registers.ecx |= CPUIDLeafProcessorInfoAndFeaturesECXBits::HypervisorPresent as u32;
+            }
+        }
+        if self.hypervisor_leaves
+            && (hypervisor_abi::CPUID_LEAF_HYPERVISOR_BASE..=CPUID_LEAF_HYPERVISOR_LAST)
+                .contains(&leaf)
+        {
+            registers = Self::hypervisor_leaf(leaf);
+        }
+        for entry in self.entries.iter().flatten() {
+            if entry.matches(vcpu, leaf, subleaf) {
+                entry.apply(&mut registers);
+            }
+        }
+        registers
+    }
+}
+
+/// Parse a decimal number, or a hexadecimal one starting with 0x.
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_u32(text: &str) -> Option<u32> {
+    parse_number(text).and_then(|n| u32::try_from(n).ok())
+}
+
+/// Parse the leaf, subleaf and vCPU index at the start of an entry.
+fn parse_selector(text: &str) -> Result<(u32, Option<u32>, Option<usize>), CpuidPolicyError> {
+    let (text, vcpu) = match text.split_once('@') {
+        Some((text, vcpu)) => (
+            text,
+            Some(parse_number(vcpu).ok_or(CpuidPolicyError::InvalidSelector)? as usize),
+        ),
+        None => (text, None),
+    };
+    let (leaf, subleaf) = match text.split_once('.') {
+        Some((leaf, subleaf)) => (
+            leaf,
+            Some(parse_u32(subleaf).ok_or(CpuidPolicyError::InvalidSelector)?),
+        ),
+        None => (text, None),
+    };
+    let leaf = parse_u32(leaf).ok_or(CpuidPolicyError::InvalidSelector)?;
+    Ok((leaf, subleaf, vcpu))
+}
+
+/// Parse one entry. See the [module documentation](index.html) for the
+/// format.
+fn parse_entry(text: &str) -> Result<CpuidPolicyEntry, CpuidPolicyError> {
+    let mut words = text.split_whitespace();
+    let selector = words.next().ok_or(CpuidPolicyError::InvalidSelector)?;
+    let (leaf, subleaf, vcpu) = parse_selector(selector)?;
+    let mut entry = CpuidPolicyEntry {
+        leaf,
+        subleaf,
+        vcpu,
+        and_mask: CpuidRegisters::ALL_ONES,
+        or_mask: CpuidRegisters::default(),
+    };
+    for operation in words {
+        let split = operation
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .ok_or(CpuidPolicyError::InvalidOperator)?;
+        let (register, rest) = operation.split_at(split);
+        let (operator, value) = match rest.find('=') {
+            Some(end) => rest.split_at(end + 1),
+            None => return Err(CpuidPolicyError::InvalidOperator),
+        };
+        let value = parse_u32(value).ok_or(CpuidPolicyError::InvalidValue)?;
+        let and_mask = entry
+            .and_mask
+            .by_name(register)
+            .ok_or(CpuidPolicyError::InvalidRegister)?;
+        let or_mask = entry.or_mask.by_name(register).unwrap();
+        match operator {
+            // Masks compose, so earlier operations on the same register
+            // still apply.
+            "&=" => {
+                *and_mask &= value;
+                *or_mask &= value;
+            }
+            "|=" => *or_mask |= value,
+            "=" => {
+                *and_mask = 0;
+                *or_mask = value;
+            }
+            _ => return Err(CpuidPolicyError::InvalidOperator),
+        }
+    }
+    Ok(entry)
+}
+
+/// Parse a policy and call add with each entry. Blank entries are ignored.
+fn parse_policy(
+    text: &str,
+    mut add: impl FnMut(CpuidPolicyEntry) -> Result<(), CpuidPolicyError>,
+) -> Result<(), CpuidPolicyError> {
+    for entry in text.split(&['\n', ';'][..]) {
+        if entry.trim().is_empty() {
+            continue;
+        }
+        add(parse_entry(entry)?)?;
+    }
+    Ok(())
+}
+
+/// Add the entries in a policy to the table. On failure none of the entries
+/// are added. Must be called before the hypervisor is loaded on any core, as
+/// entries added after the policy is published are ignored.
+pub fn add_policy(text: &str) -> Result<(), CpuidPolicyError> {
+    // Check the whole policy first so that a bad entry doesn't leave the
+    // table half updated.
+    let mut count = 0;
+    parse_policy(text, |_| {
+        count += 1;
+        Ok(())
+    })?;
+    let mut policy = POLICY.lock();
+    if policy.entries.iter().filter(|slot| slot.is_none()).count() < count {
+        return Err(CpuidPolicyError::TooManyEntries);
+    }
+    parse_policy(text, |entry| policy.add(entry))
+}
+
+/// Report the hypervisor CPUID leaves or not. Must be called before the
+/// hypervisor is loaded on any core, as changes after the policy is published
+/// are ignored.
+pub fn set_hypervisor_leaves(enabled: bool) {
+    POLICY.lock().hypervisor_leaves = enabled;
+}
+
+/// The registers the guest sees when vCPU vcpu executes CPUID with leaf in eax
+/// and subleaf in ecx.
+pub fn emulate(vcpu: usize, leaf: u32, subleaf: u32) -> CpuidRegisters {
+    let hardware = unsafe { core::arch::x86_64::__cpuid_count(leaf, subleaf) };
+    let hardware = CpuidRegisters {
+        eax: hardware.eax,
+        ebx: hardware.ebx,
+        ecx: hardware.ecx,
+        edx: hardware.edx,
+    };
+    publish().emulate(vcpu, leaf, subleaf, hardware)
+}
+
+/// Fix the policy the guest sees, the first time it is called, and return it.
+/// Called while loading the hypervisor on each core, before the guest can
+/// execute CPUID.
+pub fn publish() -> &'static CpuidPolicy {
+    PUBLISHED.call_once(|| POLICY.lock().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HARDWARE: CpuidRegisters = CpuidRegisters {
+        eax: 0x1111_1111,
+        ebx: 0x2222_2222,
+        ecx: 0x7fff_ffff,
+        edx: 0x4444_4444,
+    };
+
+    fn policy(text: &str) -> CpuidPolicy {
+        let mut policy = CpuidPolicy::new();
+        parse_policy(text, |entry| policy.add(entry)).unwrap();
+        policy
+    }
+
+    #[test]
+    fn hides_vmx_and_nothing_else() {
+        let registers = CpuidPolicy::new().emulate(0, 1, 0, HARDWARE);
+        assert_eq!(registers.ecx, 0x7fff_ffdf);
+        assert_eq!(registers.eax, HARDWARE.eax);
+        assert_eq!(registers.edx, HARDWARE.edx);
+        assert_eq!(CpuidPolicy::new().emulate(0, 2, 0, HARDWARE), HARDWARE);
+    }
+
+    #[test]
+    fn applies_masks_and_overrides() {
+        let policy = policy("0x2 eax&=0xff00ff00 ebx|=0x1 edx=7");
+        let registers = policy.emulate(0, 2, 0, HARDWARE);
+        assert_eq!(registers.eax, 0x1100_1100);
+        assert_eq!(registers.ebx, 0x2222_2223);
+        assert_eq!(registers.ecx, HARDWARE.ecx);
+        assert_eq!(registers.edx, 7);
+    }
+
+    #[test]
+    fn operations_on_one_register_compose() {
+        let policy = policy("0x2 eax=0xf0 eax|=0x1 eax&=0x31");
+        assert_eq!(policy.emulate(0, 2, 0, HARDWARE).eax, 0x31);
+    }
+
+    #[test]
+    fn entries_apply_in_order() {
+        let policy = policy("2 eax=1; 2 eax|=2\n2 eax&=6");
+        assert_eq!(policy.emulate(0, 2, 0, HARDWARE).eax, 2);
+    }
+
+    #[test]
+    fn matches_subleaf_and_vcpu() {
+        let policy = policy("7.1 ebx=1; 7@2 ecx=2");
+        assert_eq!(policy.emulate(0, 7, 1, HARDWARE).ebx, 1);
+        assert_eq!(policy.emulate(0, 7, 0, HARDWARE).ebx, HARDWARE.ebx);
+        assert_eq!(policy.emulate(2, 7, 5, HARDWARE).ecx, 2);
+        assert_eq!(policy.emulate(1, 7, 5, HARDWARE).ecx, HARDWARE.ecx);
+    }
+
+    #[test]
+    fn policy_can_restore_vmx() {
+        let policy = policy("1 ecx|=0x20");
+        assert_eq!(policy.emulate(0, 1, 0, HARDWARE).ecx, HARDWARE.ecx);
+    }
+
+    #[test]
+    fn hypervisor_leaves_are_opt_in() {
+        let mut policy = CpuidPolicy::new();
+        let base = hypervisor_abi::CPUID_LEAF_HYPERVISOR_BASE;
+        assert_eq!(policy.emulate(0, base, 0, HARDWARE), HARDWARE);
+        assert_eq!(policy.emulate(0, 1, 0, HARDWARE).ecx & (1 << 31), 0);
+
+        policy.hypervisor_leaves = true;
+        let registers = policy.emulate(0, base, 0, HARDWARE);
+        assert_eq!(
+            registers.eax,
+            hypervisor_abi::CPUID_LEAF_HYPERVISOR_INTERFACE
+        );
+        let mut signature = [0; 12];
+        signature[0..4].copy_from_slice(&registers.ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&registers.ecx.to_le_bytes());
+        signature[8..12].copy_from_slice(&registers.edx.to_le_bytes());
+        assert_eq!(&signature, hypervisor_abi::CPUID_HYPERVISOR_SIGNATURE);
+        assert_eq!(
+            policy
+                .emulate(
+                    0,
+                    hypervisor_abi::CPUID_LEAF_HYPERVISOR_INTERFACE,
+                    0,
+                    HARDWARE
+                )
+                .eax,
+            hypervisor_abi::HYPERVISOR_INTERFACE_VERSION
+        );
+        assert_eq!(
+            policy.emulate(0, base + 0x10, 0, HARDWARE),
+            CpuidRegisters::default()
+        );
+        assert_ne!(policy.emulate(0, 1, 0, HARDWARE).ecx & (1 << 31), 0);
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        let mut policy = CpuidPolicy::new();
+        let mut parse = |text| parse_policy(text, |entry| policy.add(entry));
+        assert_eq!(parse("leaf eax=1"), Err(CpuidPolicyError::InvalidSelector));
+        assert_eq!(parse("1.x eax=1"), Err(CpuidPolicyError::InvalidSelector));
+        assert_eq!(parse("1 esi=1"), Err(CpuidPolicyError::InvalidRegister));
+        assert_eq!(parse("1 eax^=1"), Err(CpuidPolicyError::InvalidOperator));
+        assert_eq!(parse("1 eax"), Err(CpuidPolicyError::InvalidOperator));
+        assert_eq!(
+            parse("1 eax=0x100000000"),
+            Err(CpuidPolicyError::InvalidValue)
+        );
+        assert_eq!(parse(" ; \n"), Ok(()));
+    }
+
+    #[test]
+    fn table_fills_up() {
+        let mut policy = CpuidPolicy::new();
+        for _ in 0..MAX_CPUID_POLICY_ENTRIES {
+            policy.add(parse_entry("1 eax=1").unwrap()).unwrap();
+        }
+        assert_eq!(
+            policy.add(parse_entry("1 eax=1").unwrap()),
+            Err(CpuidPolicyError::TooManyEntries)
+        );
+    }
+}
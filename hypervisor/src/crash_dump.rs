@@ -0,0 +1,474 @@
+//! Writes the state of the guest out as an ELF64 core file when the guest dies
+//! or the hypervisor panics, so the guest kernel can be examined post-mortem
+//! with gdb.
+//!
+//! The core file has a single PT_NOTE segment holding one NT_PRSTATUS note per
+//! vCPU with a [snapshot](../snapshot/struct.GuestSnapshot.html), followed by
+//! one PT_LOAD segment for each guest physical memory range the loader
+//! registered with
+//! [rustyvisor_crash_dump_add_range](../fn.rustyvisor_crash_dump_add_range.html).
+//! For the layout of the file see the System V ABI, Chapter 4 ("Object Files")
+//! and Chapter 5 ("Program Loading"), and the Linux kernel's
+//! include/uapi/linux/elfcore.h for the layout of NT_PRSTATUS.
+//!
+//! The panicking core stops every other core before writing the core file,
+//! and each core captures its guest's state as it stops, so every core which
+//! stopped gets a note. A core which is handling a VM exit captures the
+//! registers of that exit, and a core running the guest is made to exit by
+//! the panic's NMI. See the [panic](../panic/index.html) module. A core which
+//! doesn't stop in time has no note.
+//!
+//! The core file is written to a [CoreDumpSink](trait.CoreDumpSink.html). By
+//! default it is streamed over a serial port, framed by a line of text
+//! announcing its size so it can be cut out of a capture of the port.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::register_state::GeneralPurposeRegisterState;
+use crate::snapshot::GuestSnapshot;
+use crate::vcpu;
+use spin::RwLock;
+
+/// The serial port the core file is streamed over when the guest dies or the
+/// hypervisor panics.
+const CRASH_DUMP_PORT: pcuart::UartComPort = pcuart::UartComPort::Com1;
+
+/// The maximum number of guest physical memory ranges which can be included
+/// in a core file.
+const MAX_MEMORY_RANGES: usize = 16;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const NOTE_HEADER_SIZE: usize = 12;
+/// "CORE" plus a nul terminator, padded to 4 bytes.
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+const NOTE_NAME_SIZE: u32 = 5;
+/// The size of struct elf_prstatus on x86_64.
+const PRSTATUS_SIZE: usize = 336;
+/// The offset of pr_reg, a struct user_regs_struct, in struct elf_prstatus.
+const PRSTATUS_REGISTERS_OFFSET: usize = 112;
+const PRSTATUS_PID_OFFSET: usize = 32;
+const NOTE_SIZE: usize = NOTE_HEADER_SIZE + NOTE_NAME.len() + PRSTATUS_SIZE;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+const NT_PRSTATUS: u32 = 1;
+
+/// Somewhere a core file can be written to.
+pub trait CoreDumpSink {
+    /// Append bytes to the core file.
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+impl CoreDumpSink for pcuart::Uart {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        pcuart::Uart::write_bytes(self, bytes);
+    }
+}
+
+/// A range of guest physical memory to be included in the core file.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemoryRange {
+    /// The guest physical address of the start of the range.
+    pub guest_phys: u64,
+    /// A host virtual address through which the hypervisor can read the
+    /// range.
+    pub host_virt: u64,
+    /// The size of the range in bytes.
+    pub size: u64,
+}
+
+struct MemoryRanges {
+    ranges: [MemoryRange; MAX_MEMORY_RANGES],
+    count: usize,
+}
+
+/// A reader lock, so that a core stopped by a panic while looking up a range
+/// doesn't keep the panicking core from doing the same.
+static MEMORY_RANGES: RwLock<MemoryRanges> = RwLock::new(MemoryRanges {
+    ranges: [MemoryRange {
+        guest_phys: 0,
+        host_virt: 0,
+        size: 0,
+    }; MAX_MEMORY_RANGES],
+    count: 0,
+});
+
+/// Only write one core file, even if the guest dies and then the hypervisor
+/// panics.
+static CORE_DUMP_WRITTEN: AtomicBool = AtomicBool::new(false);
+
+/// Include a range of guest physical memory in future core files.
+pub fn add_memory_range(range: MemoryRange) -> Result<(), ()> {
+    let mut memory_ranges = MEMORY_RANGES.write();
+    if memory_ranges.count == MAX_MEMORY_RANGES || range.size == 0 || range.host_virt == 0 {
+        return Err(());
+    }
+    let count = memory_ranges.count;
+    memory_ranges.ranges[count] = range;
+    memory_ranges.count += 1;
+    Ok(())
+}
+
+/// The host virtual address of size bytes of guest physical memory, if they
+/// lie within one of the ranges added by the loader.
+#[cfg_attr(
+    not(any(feature = "gdb_stub", feature = "host_gdb_stub")),
+    allow(dead_code)
+)]
+pub fn host_address(guest_phys: u64, size: u64) -> Option<u64> {
+    let memory_ranges = MEMORY_RANGES.read();
+    memory_ranges.ranges[..memory_ranges.count]
+        .iter()
+        .find(|range| {
+            guest_phys >= range.guest_phys
+                && guest_phys - range.guest_phys <= range.size
+                && size <= range.size - (guest_phys - range.guest_phys)
+        })
+        .map(|range| range.host_virt + (guest_phys - range.guest_phys))
+}
+
+fn put_u16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut [u8], offset: usize, value: u64) {
+    buffer[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn elf_header(program_header_count: u16) -> [u8; ELF_HEADER_SIZE] {
+    let mut header = [0; ELF_HEADER_SIZE];
+    header[0..4].copy_from_slice(b"\x7fELF");
+    header[4] = ELFCLASS64;
+    header[5] = ELFDATA2LSB;
+    header[6] = EV_CURRENT;
+    put_u16(&mut header, 16, ET_CORE);
+    put_u16(&mut header, 18, EM_X86_64);
+    put_u32(&mut header, 20, u32::from(EV_CURRENT));
+    // e_entry is 0.
+    put_u64(&mut header, 32, ELF_HEADER_SIZE as u64);
+    // e_shoff and e_flags are 0.
+    put_u16(&mut header, 52, ELF_HEADER_SIZE as u16);
+    put_u16(&mut header, 54, PROGRAM_HEADER_SIZE as u16);
+    put_u16(&mut header, 56, program_header_count);
+    // There are no section headers.
+    header
+}
+
+fn program_header(kind: u32, offset: u64, address: u64, size: u64) -> [u8; PROGRAM_HEADER_SIZE] {
+    let mut header = [0; PROGRAM_HEADER_SIZE];
+    put_u32(&mut header, 0, kind);
+    put_u32(&mut header, 4, PF_R | PF_W | PF_X);
+    put_u64(&mut header, 8, offset);
+    // Guest memory is described by physical address. For guests running
+    // identity mapped, like UEFI, this is also the virtual address.
+    put_u64(&mut header, 16, address);
+    put_u64(&mut header, 24, address);
+    put_u64(&mut header, 32, size);
+    put_u64(&mut header, 40, size);
+    // p_align is 0, the segments are not aligned in the file.
+    header
+}
+
+/// Build an NT_PRSTATUS note for a vCPU. gdb treats each one as a thread
+/// whose id is pr_pid, so use the vCPU index plus one.
+fn prstatus_note(index: usize, snapshot: &GuestSnapshot) -> [u8; NOTE_SIZE] {
+    let mut note = [0; NOTE_SIZE];
+    put_u32(&mut note, 0, NOTE_NAME_SIZE);
+    put_u32(&mut note, 4, PRSTATUS_SIZE as u32);
+    put_u32(&mut note, 8, NT_PRSTATUS);
+    note[NOTE_HEADER_SIZE..NOTE_HEADER_SIZE + NOTE_NAME.len()].copy_from_slice(NOTE_NAME);
+
+    let prstatus = &mut note[NOTE_HEADER_SIZE + NOTE_NAME.len()..];
+    put_u32(prstatus, PRSTATUS_PID_OFFSET, index as u32 + 1);
+
+    // The order of struct user_regs_struct.
+    let registers = [
+        snapshot.r15,
+        snapshot.r14,
+        snapshot.r13,
+        snapshot.r12,
+        snapshot.rbp,
+        snapshot.rbx,
+        snapshot.r11,
+        snapshot.r10,
+        snapshot.r9,
+        snapshot.r8,
+        snapshot.rax,
+        snapshot.rcx,
+        snapshot.rdx,
+        snapshot.rsi,
+        snapshot.rdi,
+        !0, // orig_rax, there is no system call in progress.
+        snapshot.rip,
+        snapshot.cs,
+        snapshot.rflags,
+        snapshot.rsp,
+        snapshot.ss,
+        snapshot.fs_base,
+        snapshot.gs_base,
+        snapshot.ds,
+        snapshot.es,
+        snapshot.fs,
+        snapshot.gs,
+    ];
+    for (i, register) in registers.iter().enumerate() {
+        put_u64(
+            prstatus,
+            PRSTATUS_REGISTERS_OFFSET + i * core::mem::size_of::<u64>(),
+            *register,
+        );
+    }
+    note
+}
+
+/// Write a core file describing the given vCPUs and memory ranges to a sink.
+/// Each vCPU is given as its index and a snapshot of its state.
+pub fn write_core_dump<I>(sink: &mut dyn CoreDumpSink, snapshots: I, ranges: &[MemoryRange])
+where
+    I: Iterator<Item = (usize, GuestSnapshot)> + Clone,
+{
+    let snapshot_count = snapshots.clone().count();
+    let program_header_count = 1 + ranges.len();
+    let notes_offset = ELF_HEADER_SIZE + program_header_count * PROGRAM_HEADER_SIZE;
+    let notes_size = snapshot_count * NOTE_SIZE;
+
+    sink.write_bytes(&elf_header(program_header_count as u16));
+    sink.write_bytes(&program_header(
+        PT_NOTE,
+        notes_offset as u64,
+        0,
+        notes_size as u64,
+    ));
+    let mut offset = (notes_offset + notes_size) as u64;
+    for range in ranges {
+        sink.write_bytes(&program_header(
+            PT_LOAD,
+            offset,
+            range.guest_phys,
+            range.size,
+        ));
+        offset += range.size;
+    }
+
+    for (index, snapshot) in snapshots {
+        sink.write_bytes(&prstatus_note(index, &snapshot));
+    }
+
+    for range in ranges {
+        let memory = unsafe {
+            core::slice::from_raw_parts(range.host_virt as *const u8, range.size as usize)
+        };
+        sink.write_bytes(memory);
+    }
+}
+
+/// The size in bytes of a core file with the given number of vCPUs and memory
+/// ranges.
+pub fn core_dump_size(snapshot_count: usize, ranges: &[MemoryRange]) -> u64 {
+    let headers = ELF_HEADER_SIZE + (1 + ranges.len()) * PROGRAM_HEADER_SIZE;
+    let notes = snapshot_count * NOTE_SIZE;
+    ranges
+        .iter()
+        .fold((headers + notes) as u64, |size, range| size + range.size)
+}
+
+/// Record the state of the guest on the current core so that it is included
+/// in the core file.
+pub fn capture_current_guest(gprs: &GeneralPurposeRegisterState) {
+    if let Some(vcpu) = vcpu::try_get_current_vcpu() {
+        vcpu.guest_snapshot = GuestSnapshot::capture(gprs);
+        vcpu.guest_snapshot_valid = true;
+    }
+}
+
+/// Record the state of the guest on the current core if the core is handling
+/// a VM exit, replacing any older snapshot.
+pub fn capture_current_guest_in_vm_exit() {
+    if let Some(vcpu) = vcpu::try_get_current_vcpu() {
+        if !vcpu.guest_gprs.is_null() {
+            capture_current_guest(unsafe { &*vcpu.guest_gprs });
+        }
+    }
+}
+
+/// Stream a core file over the crash dump serial port.
+/// The guest state of the current core is captured if it is handling a VM
+/// exit. Called while panicking, after the other cores have stopped and
+/// captured their own state, so progress is reported directly on the serial
+/// port instead of through the logger, which may be locked.
+/// Only the first call writes a core file.
+#[cfg_attr(test, allow(dead_code))]
+pub fn dump_guest() {
+    use core::fmt::Write;
+
+    capture_current_guest_in_vm_exit();
+
+    let snapshots = vcpu::vcpus()
+        .filter(|(_, vcpu)| vcpu.guest_snapshot_valid)
+        .map(|(index, vcpu)| (index, vcpu.guest_snapshot));
+    let snapshot_count = snapshots.clone().count();
+    let mut uart = pcuart::Uart::new(CRASH_DUMP_PORT);
+    if snapshot_count == 0 {
+        let _ = write!(
+            uart,
+            "No guest state was captured, not writing a core dump\r\n"
+        );
+        return;
+    }
+
+    // Don't deadlock if we panicked while adding a memory range. A later
+    // attempt may still find the ranges unlocked and write the core file.
+    let memory_ranges = match MEMORY_RANGES.try_read() {
+        Some(memory_ranges) => memory_ranges,
+        None => {
+            let _ = write!(
+                uart,
+                "Memory ranges are locked, not writing a core dump\r\n"
+            );
+            return;
+        }
+    };
+
+    if CORE_DUMP_WRITTEN
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return;
+    }
+    let ranges = &memory_ranges.ranges[..memory_ranges.count];
+
+    let _ = write!(
+        uart,
+        "\r\nrustyvisor core dump begin {:x} bytes {} vCPUs\r\n",
+        core_dump_size(snapshot_count, ranges),
+        snapshot_count
+    );
+    write_core_dump(&mut uart, snapshots, ranges);
+    let _ = write!(uart, "\r\nrustyvisor core dump end\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct BufferSink {
+        bytes: [u8; 4096],
+        len: usize,
+    }
+
+    impl CoreDumpSink for BufferSink {
+        fn write_bytes(&mut self, bytes: &[u8]) {
+            self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+    }
+
+    fn get_u16(buffer: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([buffer[offset], buffer[offset + 1]])
+    }
+
+    fn get_u32(buffer: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&buffer[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn get_u64(buffer: &[u8], offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&buffer[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn elf_header_layout() {
+        let header = elf_header(3);
+        assert_eq!(&header[0..4], b"\x7fELF");
+        assert_eq!(header[4], ELFCLASS64);
+        assert_eq!(header[5], ELFDATA2LSB);
+        assert_eq!(header[6], EV_CURRENT);
+        assert_eq!(get_u16(&header, 16), ET_CORE);
+        assert_eq!(get_u16(&header, 18), EM_X86_64);
+        assert_eq!(get_u64(&header, 32), 64);
+        assert_eq!(get_u64(&header, 40), 0);
+        assert_eq!(get_u16(&header, 52), 64);
+        assert_eq!(get_u16(&header, 54), 56);
+        assert_eq!(get_u16(&header, 56), 3);
+        assert_eq!(get_u16(&header, 58), 0);
+        assert_eq!(get_u16(&header, 60), 0);
+    }
+
+    #[test]
+    fn prstatus_note_layout() {
+        let snapshot = GuestSnapshot {
+            r15: 0x15,
+            rax: 0xa,
+            rip: 0x1234,
+            rsp: 0x5678,
+            gs: 0x9,
+            ..Default::default()
+        };
+        let note = prstatus_note(2, &snapshot);
+        assert_eq!(get_u32(&note, 0), 5);
+        assert_eq!(get_u32(&note, 4), 336);
+        assert_eq!(get_u32(&note, 8), NT_PRSTATUS);
+        assert_eq!(&note[12..20], b"CORE\0\0\0\0");
+        let prstatus = &note[20..];
+        assert_eq!(prstatus.len(), 336);
+        assert_eq!(get_u32(prstatus, 32), 3);
+        let register = |index: usize| get_u64(prstatus, 112 + index * 8);
+        assert_eq!(register(0), 0x15);
+        assert_eq!(register(10), 0xa);
+        assert_eq!(register(15), !0);
+        assert_eq!(register(16), 0x1234);
+        assert_eq!(register(19), 0x5678);
+        assert_eq!(register(26), 0x9);
+    }
+
+    #[test]
+    fn core_dump_layout() {
+        let memory = [0xabu8; 32];
+        let ranges = [MemoryRange {
+            guest_phys: 0x1000,
+            host_virt: memory.as_ptr() as u64,
+            size: memory.len() as u64,
+        }];
+        let snapshots = [(0, GuestSnapshot::default()), (3, GuestSnapshot::default())];
+        let mut sink = BufferSink {
+            bytes: [0; 4096],
+            len: 0,
+        };
+        write_core_dump(&mut sink, snapshots.iter().copied(), &ranges);
+        let file = &sink.bytes[..sink.len];
+        assert_eq!(file.len() as u64, core_dump_size(2, &ranges));
+
+        let notes_offset = 64 + 2 * 56;
+        let note = &file[64..64 + 56];
+        assert_eq!(get_u32(note, 0), PT_NOTE);
+        assert_eq!(get_u64(note, 8), notes_offset as u64);
+        assert_eq!(get_u64(note, 32), 2 * NOTE_SIZE as u64);
+
+        let load = &file[64 + 56..64 + 2 * 56];
+        let load_offset = notes_offset + 2 * NOTE_SIZE;
+        assert_eq!(get_u32(load, 0), PT_LOAD);
+        assert_eq!(get_u64(load, 8), load_offset as u64);
+        assert_eq!(get_u64(load, 16), 0x1000);
+        assert_eq!(get_u64(load, 32), 32);
+        assert_eq!(get_u64(load, 40), 32);
+
+        let second_note = &file[notes_offset + NOTE_SIZE..];
+        assert_eq!(get_u32(second_note, 20 + 32), 4);
+        assert_eq!(&file[load_offset..], &memory[..]);
+    }
+}
@@ -0,0 +1,524 @@
+//! Virtualizes the debug registers dr0 through dr7.
+//!
+//! Usually the guest owns the debug registers. MOV-DR exiting is disabled, the
+//! guest's dr0-dr3 and dr6 are left in the hardware registers by VM exits and
+//! entries, and the guest's dr7 and IA32_DEBUGCTL are saved and loaded through
+//! the vmcs by the debug controls.
+//!
+//! The processor doesn't switch dr0-dr3 and dr6, so the hypervisor does it
+//! itself. They are saved at the start of every VM exit and loaded back before
+//! the guest resumes, and while the exit is handled everything in this module
+//! works on the saved copy. The host, e.g. the host gdb stub single stepping
+//! through the exit handler, may then use the hardware registers without
+//! clobbering the guest's. The guest's breakpoints can't trigger in the host
+//! in the meantime, since VM exits load dr7 with every breakpoint disabled.
+//!
+//! When the hypervisor reserves a hardware breakpoint for itself, MOV-DR
+//! exiting is enabled and every guest access to a debug register is emulated.
+//! The guest's values for reserved breakpoints and its dr7 are kept in a
+//! shadow copy. The guest's dr7 is merged with the hypervisor's breakpoints
+//! into the real dr7, so the guest's unreserved breakpoints keep working.
+//! After the last reservation is released the guest takes the debug registers
+//! back lazily, on its next debug register access, and that access is executed
+//! by the hardware again instead of being emulated.
+//!
+//! For more information see the Intel manual, Volume 3, Chapter 17
+//! "Debug, Branch Profile, TSC, and Intel Resource Director Technology
+//! Features", and Table 27-4 "Exit Qualification for MOV DR".
+use crate::control_registers;
+use crate::interrupt_controller;
+use crate::vcpu::get_current_vcpu;
+use crate::vmcs_fields::{CpuBasedControlsMovDrExiting, VmcsField};
+use crate::vmx::{vmread, vmwrite};
+use log::trace;
+
+/// The number of breakpoint address registers, dr0-dr3.
+pub const BREAKPOINT_COUNT: usize = 4;
+
+/// The debug registers which are saved at the start of each VM exit, dr0-dr3
+/// and dr6, in the order they are kept in.
+const SWITCHED_REGISTERS: [usize; BREAKPOINT_COUNT + 1] = [0, 1, 2, 3, 6];
+
+const CR4_DE: u64 = 1 << 3;
+
+/// The bits of dr6 which report which breakpoint conditions were met.
+const DR6_BREAKPOINT_CONDITIONS: u64 = 0xf;
+/// Set in dr6 when a #DB was caused by the general detect condition.
+const DR6_BD: u64 = 1 << 13;
+/// Set in dr6 when a #DB was caused by single stepping with rflags.TF.
+const DR6_BS: u64 = 1 << 14;
+
+/// Bits of dr7 which always read as 1.
+const DR7_FIXED_ONES: u64 = 1 << 10;
+/// Bits of dr7 which always read as 0.
+const DR7_FIXED_ZEROES: u64 = (1 << 12) | (1 << 14) | (1 << 15);
+/// The local and global exact breakpoint enables.
+const DR7_LE_GE: u64 = (1 << 8) | (1 << 9);
+/// The general detect enable. Debug register accesses cause a #DB when set.
+const DR7_GD: u64 = 1 << 13;
+
+/// The condition which triggers a breakpoint, as encoded in the R/W bits of
+/// dr7.
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum BreakpointCondition {
+    /// Break on instruction execution.
+    Execute = 0,
+    /// Break on data writes.
+    Write = 1,
+    /// Break on I/O reads or writes. Requires cr4.DE.
+    Io = 2,
+    /// Break on data reads or writes.
+    ReadWrite = 3,
+}
+
+/// The size of the memory watched by a breakpoint, as encoded in the LEN bits
+/// of dr7.
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum BreakpointLength {
+    /// One byte. Must be used for execution breakpoints.
+    One = 0,
+    /// Two bytes.
+    Two = 1,
+    /// Eight bytes.
+    Eight = 2,
+    /// Four bytes.
+    Four = 3,
+}
+
+/// The guest's view of the debug registers while the hypervisor has
+/// breakpoints reserved, along with the hypervisor's breakpoints.
+/// Should be initialized as all zeroes, which means the guest owns the debug
+/// registers.
+/// Each core should have their own VirtualDebugRegisters.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct VirtualDebugRegisters {
+    /// The guest's values of dr0-dr3. Only valid for reserved breakpoints,
+    /// the others are in the hardware registers.
+    guest_breakpoints: [u64; BREAKPOINT_COUNT],
+    /// The guest's value of dr7. Only valid while shadowed is true.
+    guest_dr7: u64,
+    /// The dr7 enable, condition and length bits of the hypervisor's
+    /// breakpoints.
+    host_dr7: u64,
+    /// Bit n is set if the hypervisor has reserved breakpoint n.
+    reserved: u8,
+    /// True if MOV-DR exiting is enabled and guest_dr7 holds the guest's dr7.
+    shadowed: bool,
+    /// The values of the SWITCHED_REGISTERS the guest runs with. Saved from
+    /// the hardware at the start of each VM exit, and loaded back into it
+    /// before the guest resumes.
+    switched: [u64; SWITCHED_REGISTERS.len()],
+}
+
+/// The reasons emulating a debug register access can fail.
+#[derive(Debug)]
+pub enum DebugRegisterAccessError {
+    /// Accessing the vmcs failed.
+    VmFail(x86::vmx::VmFail),
+    /// The access is architecturally illegal. A #GP should be injected into
+    /// the guest instead of completing the access.
+    GeneralProtectionFault,
+    /// The guest accessed dr4 or dr5 with cr4.DE set. A #UD should be injected
+    /// into the guest.
+    InvalidOpcode,
+    /// The guest set dr7.GD. A #DB should be injected into the guest.
+    GeneralDetect,
+}
+
+impl From<x86::vmx::VmFail> for DebugRegisterAccessError {
+    fn from(e: x86::vmx::VmFail) -> Self {
+        DebugRegisterAccessError::VmFail(e)
+    }
+}
+
+fn get_virtual_debug_registers() -> &'static mut VirtualDebugRegisters {
+    &mut get_current_vcpu().debug_registers
+}
+
+/// The enable, condition and length bits of dr7 for a breakpoint.
+fn dr7_breakpoint_bits(index: usize) -> u64 {
+    (0b11 << (2 * index)) | (0b1111 << (16 + 4 * index))
+}
+
+/// The dr7 bits of every breakpoint in a mask of breakpoints.
+fn dr7_breakpoint_mask(breakpoints: u8) -> u64 {
+    (0..BREAKPOINT_COUNT)
+        .filter(|index| breakpoints & (1 << index) != 0)
+        .map(dr7_breakpoint_bits)
+        .fold(0, |mask, bits| mask | bits)
+}
+
+/// Where a debug register is kept in the saved copy.
+fn switched_slot(index: usize) -> usize {
+    SWITCHED_REGISTERS
+        .iter()
+        .position(|register| *register == index)
+        .unwrap_or_else(|| panic!("Debug register dr{} isn't switched", index))
+}
+
+impl VirtualDebugRegisters {
+    /// The value of dr0-dr3 or dr6 the guest will run with.
+    fn switched(&self, index: usize) -> u64 {
+        self.switched[switched_slot(index)]
+    }
+
+    /// Set the value of dr0-dr3 or dr6 the guest will run with.
+    fn set_switched(&mut self, index: usize, value: u64) {
+        self.switched[switched_slot(index)] = value;
+    }
+
+    /// Save the guest's values from the hardware.
+    fn save(&mut self, read: impl Fn(usize) -> u64) {
+        for (value, index) in self.switched.iter_mut().zip(SWITCHED_REGISTERS) {
+            *value = read(index);
+        }
+    }
+
+    /// Load the guest's values into the hardware. Only registers which changed
+    /// are written, as writes to the debug registers are slow.
+    fn load(&self, read: impl Fn(usize) -> u64, mut write: impl FnMut(usize, u64)) {
+        for (value, index) in self.switched.iter().zip(SWITCHED_REGISTERS) {
+            if read(index) != *value {
+                write(index, *value);
+            }
+        }
+    }
+}
+
+/// Save the guest's dr0-dr3 and dr6. Must be called at the start of every VM
+/// exit, before anything else touches the debug registers.
+pub fn save_guest_registers() {
+    get_virtual_debug_registers().save(read_hardware);
+}
+
+/// Load the guest's dr0-dr3 and dr6 back into the hardware, with any changes
+/// made while handling the exit. Must be called at the end of every VM exit.
+pub fn load_guest_registers() {
+    get_virtual_debug_registers().load(read_hardware, write_hardware);
+}
+
+fn read_hardware(index: usize) -> u64 {
+    let value: u64;
+    unsafe {
+        match index {
+            0 => asm!("mov {}, dr0", out(reg)(value)),
+            1 => asm!("mov {}, dr1", out(reg)(value)),
+            2 => asm!("mov {}, dr2", out(reg)(value)),
+            3 => asm!("mov {}, dr3", out(reg)(value)),
+            6 => asm!("mov {}, dr6", out(reg)(value)),
+            _ => panic!("Illegal hardware debug register read dr{}", index),
+        }
+    }
+    value
+}
+
+fn write_hardware(index: usize, value: u64) {
+    unsafe {
+        match index {
+            0 => asm!("mov dr0, {}", in(reg)(value)),
+            1 => asm!("mov dr1, {}", in(reg)(value)),
+            2 => asm!("mov dr2, {}", in(reg)(value)),
+            3 => asm!("mov dr3, {}", in(reg)(value)),
+            6 => asm!("mov dr6, {}", in(reg)(value)),
+            _ => panic!("Illegal hardware debug register write dr{}", index),
+        }
+    }
+}
+
+fn set_mov_dr_exiting(enabled: bool) -> Result<(), x86::vmx::VmFail> {
+    let mut cpu_based_controls = vmread(VmcsField::CpuBasedVmExecControl)?;
+    if enabled {
+        cpu_based_controls |= CpuBasedControlsMovDrExiting;
+    } else {
+        cpu_based_controls &= !CpuBasedControlsMovDrExiting;
+    }
+    vmwrite(VmcsField::CpuBasedVmExecControl, cpu_based_controls)
+}
+
+/// Write the guest's dr7 merged with the hypervisor's breakpoints to the vmcs.
+/// General detect is emulated, so it is never enabled in the hardware.
+fn update_dr7(debug_registers: &VirtualDebugRegisters) -> Result<(), x86::vmx::VmFail> {
+    let host_mask = dr7_breakpoint_mask(debug_registers.reserved);
+    let mut dr7 =
+        (debug_registers.guest_dr7 & !host_mask & !DR7_GD) | (debug_registers.host_dr7 & host_mask);
+    if debug_registers.reserved != 0 {
+        dr7 |= DR7_LE_GE;
+    }
+    vmwrite(VmcsField::GuestDr7, dr7)
+}
+
+/// Take the debug registers away from the guest, if it owns them.
+fn shadow_guest(debug_registers: &mut VirtualDebugRegisters) -> Result<(), x86::vmx::VmFail> {
+    if debug_registers.shadowed {
+        return Ok(());
+    }
+    trace!("Shadowing guest debug registers");
+    debug_registers.guest_dr7 = vmread(VmcsField::GuestDr7)?;
+    debug_registers.shadowed = true;
+    set_mov_dr_exiting(true)
+}
+
+/// Reserve a hardware breakpoint for the hypervisor. The breakpoint only
+/// triggers while the guest is running. The guest may keep using the
+/// breakpoint register, but its accesses are emulated and its breakpoint won't
+/// trigger until the hypervisor's reservation is
+/// [released](fn.release_breakpoint.html).
+/// Must be called in host context on the core which runs the guest.
+#[allow(dead_code)]
+pub fn reserve_breakpoint(
+    index: usize,
+    address: u64,
+    condition: BreakpointCondition,
+    length: BreakpointLength,
+) -> Result<(), x86::vmx::VmFail> {
+    assert!(index < BREAKPOINT_COUNT);
+    trace!(
+        "Reserving breakpoint {} at {:x} {:?} {:?}",
+        index,
+        address,
+        condition,
+        length
+    );
+    let debug_registers = get_virtual_debug_registers();
+    shadow_guest(debug_registers)?;
+
+    if debug_registers.reserved & (1 << index) == 0 {
+        debug_registers.guest_breakpoints[index] = debug_registers.switched(index);
+        debug_registers.reserved |= 1 << index;
+    }
+    debug_registers.set_switched(index, address);
+
+    let enable = 1 << (2 * index + 1);
+    let condition_and_length = ((condition as u64) | ((length as u64) << 2)) << (16 + 4 * index);
+    debug_registers.host_dr7 &= !dr7_breakpoint_bits(index);
+    debug_registers.host_dr7 |= enable | condition_and_length;
+    update_dr7(debug_registers)
+}
+
+/// Give a breakpoint reserved with
+/// [reserve_breakpoint](fn.reserve_breakpoint.html) back to the guest.
+/// Must be called in host context on the core which runs the guest.
+#[allow(dead_code)]
+pub fn release_breakpoint(index: usize) -> Result<(), x86::vmx::VmFail> {
+    assert!(index < BREAKPOINT_COUNT);
+    let debug_registers = get_virtual_debug_registers();
+    if debug_registers.reserved & (1 << index) == 0 {
+        return Ok(());
+    }
+    trace!("Releasing breakpoint {}", index);
+    debug_registers.set_switched(index, debug_registers.guest_breakpoints[index]);
+    debug_registers.reserved &= !(1 << index);
+    debug_registers.host_dr7 &= !dr7_breakpoint_bits(index);
+    update_dr7(debug_registers)
+}
+
+/// A mask of the breakpoints reserved by the hypervisor, where bit n is set if
+/// breakpoint n is reserved. Useful for telling the hypervisor's breakpoints
+/// from the guest's in dr6.
+#[allow(dead_code)]
+pub fn reserved_breakpoints() -> u8 {
+    get_virtual_debug_registers().reserved
+}
+
+/// Update dr6 for a #DB which caused a VM exit, so the guest sees it as the
+/// processor would have reported it. The processor doesn't write dr6 when a
+/// #DB causes a VM exit, instead the exit qualification holds the bits it
+/// would have set. The conditions of the hypervisor's breakpoints are left
+/// alone.
+/// See the Intel manual, Volume 3, Table 27-1 "Exit Qualification for Debug
+/// Exceptions".
+#[cfg_attr(not(feature = "gdb_stub"), allow(dead_code))]
+pub fn record_debug_exception(qualification: u64) {
+    let debug_registers = get_virtual_debug_registers();
+    let guest_conditions = DR6_BREAKPOINT_CONDITIONS & !u64::from(debug_registers.reserved);
+    let reported = qualification & (guest_conditions | DR6_BD | DR6_BS);
+    let dr6 = debug_registers.switched(6) & !guest_conditions;
+    debug_registers.set_switched(6, dr6 | reported);
+}
+
+/// Give the debug registers back to the guest if the hypervisor no longer
+/// needs any of them. Returns true if the guest owns the debug registers
+/// again, in which case its access should be executed again by the hardware
+/// instead of being emulated.
+pub fn return_to_guest_if_unreserved() -> Result<bool, x86::vmx::VmFail> {
+    let debug_registers = get_virtual_debug_registers();
+    if !debug_registers.shadowed || debug_registers.reserved != 0 {
+        return Ok(false);
+    }
+    trace!("Returning debug registers to the guest");
+    vmwrite(VmcsField::GuestDr7, debug_registers.guest_dr7)?;
+    debug_registers.shadowed = false;
+    set_mov_dr_exiting(false)?;
+    Ok(true)
+}
+
+/// The guest's dr0-dr3, dr6 and dr7, wherever they are kept, for when the
+/// hypervisor stops virtualizing the debug registers and they have to be put
+/// back into the hardware.
+#[cfg_attr(test, allow(dead_code))]
+pub fn guest_debug_registers() -> Result<([u64; BREAKPOINT_COUNT], u64, u64), x86::vmx::VmFail> {
+    let debug_registers = get_virtual_debug_registers();
+    let mut breakpoints = [0; BREAKPOINT_COUNT];
+    for (index, breakpoint) in breakpoints.iter_mut().enumerate() {
+        *breakpoint = if debug_registers.reserved & (1 << index) != 0 {
+            debug_registers.guest_breakpoints[index]
+        } else {
+            debug_registers.switched(index)
+        };
+    }
+    let dr6 = debug_registers.switched(6) & !u64::from(debug_registers.reserved);
+    let dr7 = if debug_registers.shadowed {
+        debug_registers.guest_dr7
+    } else {
+        vmread(VmcsField::GuestDr7)?
+    };
+    Ok((breakpoints, dr6, dr7))
+}
+
+/// Check the conditions common to reads and writes, and translate dr4 and dr5
+/// to the registers they alias.
+fn check_access(index: u64) -> Result<usize, DebugRegisterAccessError> {
+    let debug_registers = get_virtual_debug_registers();
+    if debug_registers.guest_dr7 & DR7_GD != 0 {
+        return Err(DebugRegisterAccessError::GeneralDetect);
+    }
+    match index {
+        4 | 5 if control_registers::guest_cr4()? & CR4_DE != 0 => {
+            Err(DebugRegisterAccessError::InvalidOpcode)
+        }
+        4 => Ok(6),
+        5 => Ok(7),
+        _ => Ok(index as usize),
+    }
+}
+
+/// Emulate a MOV to a debug register while the hypervisor has breakpoints
+/// reserved.
+/// See the Intel manual, Volume 2, "MOV—Move to/from Debug Registers" for the
+/// conditions which cause an exception.
+pub fn write(index: u64, value: u64) -> Result<(), DebugRegisterAccessError> {
+    trace!("Guest dr{} write {:x}", index, value);
+    let index = check_access(index)?;
+    let value = if control_registers::guest_is_in_64_bit_mode()? {
+        value
+    } else {
+        value & 0xffff_ffff
+    };
+
+    let debug_registers = get_virtual_debug_registers();
+    match index {
+        0..=3 if debug_registers.reserved & (1 << index) != 0 => {
+            debug_registers.guest_breakpoints[index] = value;
+        }
+        0..=3 => debug_registers.set_switched(index, value),
+        6 | 7 if value >> 32 != 0 => {
+            return Err(DebugRegisterAccessError::GeneralProtectionFault);
+        }
+        6 => {
+            // The conditions of the hypervisor's breakpoints belong to the
+            // hypervisor.
+            let host_conditions = u64::from(debug_registers.reserved);
+            let value =
+                (value & !host_conditions) | (debug_registers.switched(6) & host_conditions);
+            debug_registers.set_switched(6, value);
+        }
+        7 => {
+            debug_registers.guest_dr7 = (value | DR7_FIXED_ONES) & !DR7_FIXED_ZEROES;
+            update_dr7(debug_registers)?;
+        }
+        _ => panic!("Illegal debug register write dr{}", index),
+    }
+    Ok(())
+}
+
+/// Emulate a MOV from a debug register while the hypervisor has breakpoints
+/// reserved.
+pub fn read(index: u64) -> Result<u64, DebugRegisterAccessError> {
+    let index = check_access(index)?;
+    let debug_registers = get_virtual_debug_registers();
+    let value = match index {
+        0..=3 if debug_registers.reserved & (1 << index) != 0 => {
+            debug_registers.guest_breakpoints[index]
+        }
+        0..=3 => debug_registers.switched(index),
+        6 => debug_registers.switched(6) & !u64::from(debug_registers.reserved),
+        7 => debug_registers.guest_dr7,
+        _ => panic!("Illegal debug register read dr{}", index),
+    };
+    trace!("Guest dr{} read {:x}", index, value);
+    Ok(value)
+}
+
+/// Finish emulating a debug register access which failed with an error by
+/// injecting the appropriate exception into the guest. The guest's
+/// instruction pointer must not be advanced.
+pub fn handle_access_error(error: DebugRegisterAccessError) -> Result<(), x86::vmx::VmFail> {
+    match error {
+        DebugRegisterAccessError::VmFail(e) => Err(e),
+        DebugRegisterAccessError::GeneralProtectionFault => {
+            trace!("Illegal debug register access, injecting #GP");
+            interrupt_controller::inject_exception(
+                interrupt_controller::EXCEPTION_VECTOR_GENERAL_PROTECTION,
+                Some(0),
+            )
+        }
+        DebugRegisterAccessError::InvalidOpcode => {
+            trace!("Debug register access to dr4 or dr5 with cr4.DE set, injecting #UD");
+            interrupt_controller::inject_exception(
+                interrupt_controller::EXCEPTION_VECTOR_INVALID_OPCODE,
+                None,
+            )
+        }
+        DebugRegisterAccessError::GeneralDetect => {
+            trace!("Debug register access with dr7.GD set, injecting #DB");
+            // The processor clears GD when it delivers the #DB so the
+            // handler can access the debug registers.
+            let debug_registers = get_virtual_debug_registers();
+            debug_registers.guest_dr7 &= !DR7_GD;
+            update_dr7(debug_registers)?;
+            let guest_conditions = DR6_BREAKPOINT_CONDITIONS & !u64::from(debug_registers.reserved);
+            let dr6 = debug_registers.switched(6) & !guest_conditions;
+            debug_registers.set_switched(6, dr6 | DR6_BD);
+            interrupt_controller::inject_exception(
+                interrupt_controller::EXCEPTION_VECTOR_DEBUG,
+                None,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+
+    #[test]
+    fn exits_keep_the_guests_registers() {
+        let mut guest = [0; 8];
+        guest[0] = 0x1000;
+        guest[1] = 0x2000;
+        guest[6] = 0xffff_0ff1;
+        let hardware = RefCell::new(guest);
+        let read = |index: usize| hardware.borrow()[index];
+
+        let mut debug_registers = VirtualDebugRegisters::default();
+        debug_registers.save(read);
+        // The host uses the hardware registers while handling the exit, and
+        // the exit changes one of the guest's.
+        hardware.borrow_mut()[0] = 0xdead_beef;
+        hardware.borrow_mut()[6] = 0xffff_4ff0;
+        debug_registers.set_switched(1, 0x3000);
+
+        let mut writes = 0;
+        debug_registers.load(read, |index, value| {
+            hardware.borrow_mut()[index] = value;
+            writes += 1;
+        });
+        guest[1] = 0x3000;
+        assert_eq!(*hardware.borrow(), guest);
+        assert_eq!(writes, 3);
+    }
+}
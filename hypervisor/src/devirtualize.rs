@@ -0,0 +1,445 @@
+//! Hands the processor back to the guest when the hypervisor panics, so that
+//! the machine keeps running without the hypervisor instead of freezing.
+//!
+//! Devirtualization is off unless the loader enables it with
+//! [rustyvisor_set_devirtualize_on_panic](../fn.rustyvisor_set_devirtualize_on_panic.html).
+//! When it is on, a panic on a core which is handling a VM exit ends with the
+//! core loading the guest's state from the vmcs into the processor, leaving
+//! VMX operation with VMXOFF, and returning to the guest at the instruction
+//! which caused the exit, as if the guest had never been virtualized. The
+//! instruction is then executed by the hardware, so the exit's work is done
+//! natively instead of by the hypervisor.
+//!
+//! The other cores devirtualize as well when the panic handler's NMI stops
+//! them, if they were handling a VM exit, or if they were running the guest
+//! and the processor supports virtual NMIs, so that the guest exits for the
+//! NMI (see the [nmi](../nmi/index.html) module). Any other core, including
+//! one running a guest which takes the NMI itself, does so at the start of
+//! its next VM exit, before it touches anything the panicking core may have
+//! left locked.
+//!
+//! A core can only be handed back if the guest can be resumed where it
+//! exited. A core stays halted if the exit was caused by a triple fault or a
+//! failed VM entry, interrupted the delivery of an event, or came from a guest
+//! which isn't in IA-32e mode. The last instructions before the guest resumes
+//! run after the guest's cr3 is loaded, so the guest's page tables must map
+//! them, along with the guest's stack and the guest's TSS descriptor, at the
+//! same physical addresses as the host's page tables. Under Linux that is the
+//! case for exits from the kernel, but not from user space. Under UEFI it is
+//! only the case while the guest still uses the firmware's page tables.
+//!
+//! The guest's TSC jumps if it is scaled. State the hypervisor keeps for
+//! itself, like the exit traces and the log, is left behind.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::control_registers;
+use crate::debug_registers::{self, BREAKPOINT_COUNT};
+use crate::guest_memory::AddressSpace;
+use crate::msr::{wrmsr, Msr, MsrValuePair};
+use crate::register_state::GeneralPurposeRegisterState;
+use crate::timekeeping;
+use crate::vcpu;
+use crate::vmcs_fields::{VmEntryIa32eMode, VmcsField};
+use crate::vmexit_reasons::VMEXIT_REASON_TRIPLE_FAULT;
+use crate::vmx::vmread;
+use crate::VCpu;
+use x86::dtables::{self, DescriptorTablePointer};
+
+/// True if the loader asked for the guest to be resumed natively when the
+/// hypervisor panics.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// True once a panic has decided that every core should devirtualize.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+const PAGE_SIZE: u64 = 0x1000;
+/// Bits 51:12 of cr3 hold the physical address of the top level page table.
+const CR3_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const CR0_WP: u64 = 1 << 16;
+
+/// Set in the exit reason if the VM entry failed.
+const EXIT_REASON_ENTRY_FAILURE: u64 = 1 << 31;
+/// Set in the IDT-vectoring information if the exit interrupted the delivery
+/// of an event.
+const IDT_VECTORING_INFO_VALID: u64 = 1 << 31;
+
+/// The busy bit of a TSS descriptor's type, in the descriptor's sixth byte.
+const TSS_DESCRIPTOR_BUSY: u8 = 1 << 1;
+const TSS_DESCRIPTOR_TYPE_OFFSET: u64 = 5;
+
+/// The guest's stack below rsp may be in use by leaf functions in user space,
+/// which the System V ABI lets use 128 bytes below rsp without moving it.
+const RED_ZONE_SIZE: u64 = 128;
+/// The guest's general purpose registers followed by an interrupt stack
+/// frame, which is built on the guest's stack and popped by hand_over.
+const FRAME_WORDS: usize = 20;
+const FRAME_SIZE: u64 = FRAME_WORDS as u64 * 8;
+/// An upper bound on the size of hand_over's code.
+const HAND_OVER_SIZE: u64 = 0x80;
+
+/// The reasons a core can't be handed back to the guest.
+#[derive(Debug)]
+pub enum DevirtualizeError {
+    /// Reading the vmcs failed. Only read through Debug.
+    #[allow(dead_code)]
+    VmFail(x86::vmx::VmFail),
+    /// The core isn't handling a VM exit, so there's no guest to resume.
+    NotInVmExit,
+    /// The guest triple faulted or the VM entry failed, so it can't run.
+    GuestNotRunnable,
+    /// The exit interrupted the delivery of an event, which would be lost.
+    EventInterrupted,
+    /// The guest isn't in IA-32e mode.
+    UnsupportedMode,
+    /// The guest's page tables don't map the code, stack or TSS descriptor
+    /// used to hand over the same way the host's do.
+    NotMapped,
+    /// VMCLEAR or VMXOFF failed.
+    VmxOffFailed,
+}
+
+impl From<x86::vmx::VmFail> for DevirtualizeError {
+    fn from(e: x86::vmx::VmFail) -> Self {
+        DevirtualizeError::VmFail(e)
+    }
+}
+
+/// Set whether the guest is resumed natively when the hypervisor panics. See
+/// [rustyvisor_set_devirtualize_on_panic](../fn.rustyvisor_set_devirtualize_on_panic.html).
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Called by the panic handler before it stops the other cores. Returns true
+/// if every core should devirtualize.
+pub fn request() -> bool {
+    if ENABLED.load(Ordering::SeqCst) {
+        REQUESTED.store(true, Ordering::SeqCst);
+    }
+    requested()
+}
+
+/// True if a panic has asked every core to devirtualize.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// The guest's state which the processor doesn't hold in VMX root operation,
+/// read from the vmcs and the VCpu before leaving VMX operation.
+struct NativeState {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    breakpoints: [u64; BREAKPOINT_COUNT],
+    dr6: u64,
+    dr7: u64,
+    debugctl: u64,
+    tsc_adjust: Option<u64>,
+    tsc_deadline: Option<u64>,
+    sysenter_cs: u64,
+    sysenter_esp: u64,
+    sysenter_eip: u64,
+    gdtr_base: u64,
+    gdtr_limit: u16,
+    idtr_base: u64,
+    idtr_limit: u16,
+    ds: u16,
+    es: u16,
+    fs: u16,
+    gs: u16,
+    ldtr: u16,
+    tr: u16,
+    fs_base: u64,
+    gs_base: u64,
+    vmcs_phys: u64,
+    frame: [u64; FRAME_WORDS],
+}
+
+fn read_selector(field: VmcsField) -> Result<u16, x86::vmx::VmFail> {
+    Ok(vmread(field)? as u16)
+}
+
+impl NativeState {
+    /// Read the state of the guest whose VM exit the current core is
+    /// handling, and check that the guest can be resumed natively.
+    fn capture(vcpu: &VCpu, gprs: &GeneralPurposeRegisterState) -> Result<Self, DevirtualizeError> {
+        let exit_reason = vmread(VmcsField::VmExitReason)?;
+        if exit_reason & EXIT_REASON_ENTRY_FAILURE != 0
+            || exit_reason & 0xffff == VMEXIT_REASON_TRIPLE_FAULT
+        {
+            return Err(DevirtualizeError::GuestNotRunnable);
+        }
+        if vmread(VmcsField::IdtVectoringInfoField)? & IDT_VECTORING_INFO_VALID != 0 {
+            return Err(DevirtualizeError::EventInterrupted);
+        }
+        if vmread(VmcsField::VmEntryControls)? & VmEntryIa32eMode == 0 {
+            return Err(DevirtualizeError::UnsupportedMode);
+        }
+
+        let (breakpoints, dr6, dr7) = debug_registers::guest_debug_registers()?;
+        Ok(NativeState {
+            cr0: control_registers::guest_cr0()?,
+            cr3: vmread(VmcsField::GuestCr3)?,
+            cr4: control_registers::guest_cr4()?,
+            breakpoints,
+            dr6,
+            dr7,
+            debugctl: vmread(VmcsField::GuestIA32Debugctl)?,
+            tsc_adjust: timekeeping::native_tsc_adjust()?,
+            tsc_deadline: timekeeping::native_tsc_deadline()?,
+            sysenter_cs: vmread(VmcsField::GuestSysenterCs)?,
+            sysenter_esp: vmread(VmcsField::GuestSysenterEsp)?,
+            sysenter_eip: vmread(VmcsField::GuestSysenterEip)?,
+            gdtr_base: vmread(VmcsField::GuestGdtrBase)?,
+            gdtr_limit: vmread(VmcsField::GuestGdtrLimit)? as u16,
+            idtr_base: vmread(VmcsField::GuestIdtrBase)?,
+            idtr_limit: vmread(VmcsField::GuestIdtrLimit)? as u16,
+            ds: read_selector(VmcsField::GuestDsSelector)?,
+            es: read_selector(VmcsField::GuestEsSelector)?,
+            fs: read_selector(VmcsField::GuestFsSelector)?,
+            gs: read_selector(VmcsField::GuestGsSelector)?,
+            ldtr: read_selector(VmcsField::GuestLdtrSelector)?,
+            tr: read_selector(VmcsField::GuestTrSelector)?,
+            fs_base: vmread(VmcsField::GuestFsBase)?,
+            gs_base: vmread(VmcsField::GuestGsBase)?,
+            vmcs_phys: vcpu.vmcs_phys,
+            // In the order hand_over pops them, which is the order of
+            // GeneralPurposeRegisterState, followed by the frame IRETQ pops.
+            frame: [
+                gprs.r15,
+                gprs.r14,
+                gprs.r13,
+                gprs.r12,
+                gprs.r11,
+                gprs.r10,
+                gprs.r9,
+                gprs.r8,
+                gprs.rdi,
+                gprs.rsi,
+                gprs.rbp,
+                gprs.rdx,
+                gprs.rcx,
+                gprs.rbx,
+                gprs.rax,
+                vmread(VmcsField::GuestRip)?,
+                vmread(VmcsField::GuestCsSelector)?,
+                vmread(VmcsField::GuestRFlags)?,
+                vmread(VmcsField::GuestRsp)?,
+                vmread(VmcsField::GuestSsSelector)?,
+            ],
+        })
+    }
+
+    /// Where the frame goes on the guest's stack.
+    fn frame_address(&self) -> u64 {
+        let rsp = self.frame[FRAME_WORDS - 2];
+        rsp.wrapping_sub(RED_ZONE_SIZE + FRAME_SIZE) & !0xf
+    }
+
+    /// The address of the guest's TSS descriptor.
+    fn tss_descriptor_address(&self) -> u64 {
+        self.gdtr_base + u64::from(self.tr & !0x7)
+    }
+
+    /// Check that everything touched after the guest's cr3 is loaded, or
+    /// written through the host's page tables on the guest's behalf, is the
+    /// same memory in both.
+    fn check_mappings(&self) -> Result<(), DevirtualizeError> {
+        let host_cr3 = unsafe { x86::controlregs::cr3() };
+        if self.cr3 & CR3_ADDRESS_MASK == host_cr3 & CR3_ADDRESS_MASK {
+            return Ok(());
+        }
+        let guest = AddressSpace::current()?;
+        let host = AddressSpace::host();
+        let ranges = [
+            (hand_over as *const () as u64, HAND_OVER_SIZE),
+            (self.frame_address(), FRAME_SIZE),
+            (self.tss_descriptor_address(), 16),
+        ];
+        for (address, size) in ranges.iter() {
+            if !mapped_alike(&guest, &host, *address, *size) {
+                return Err(DevirtualizeError::NotMapped);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load everything but the guest's cr3, general purpose registers, rip,
+    /// rflags, cs, ss and rsp into the processor. Must be called after
+    /// leaving VMX operation.
+    ///
+    /// # Safety
+    /// Replaces the host's descriptor tables, segments and FS base with the
+    /// guest's, so nothing which uses them, like
+    /// [vcpu::get_current_vcpu](../vcpu/fn.get_current_vcpu.html), may be
+    /// called afterwards.
+    unsafe fn load(&self) {
+        if let Some(tsc_adjust) = self.tsc_adjust {
+            wrmsr(Msr::Ia32TscAdjust, split(tsc_adjust));
+        }
+        // The deadline is compared with the TSC, so it goes after the TSC.
+        if let Some(tsc_deadline) = self.tsc_deadline {
+            wrmsr(Msr::Ia32TscDeadline, split(tsc_deadline));
+        }
+        wrmsr(Msr::Ia32SysenterCs, split(self.sysenter_cs));
+        wrmsr(Msr::Ia32SysenterEsp, split(self.sysenter_esp));
+        wrmsr(Msr::Ia32SysenterEip, split(self.sysenter_eip));
+        wrmsr(Msr::Ia32DebugControl, split(self.debugctl));
+        let [dr0, dr1, dr2, dr3] = self.breakpoints;
+        asm!(
+            "mov dr0, {}",
+            "mov dr1, {}",
+            "mov dr2, {}",
+            "mov dr3, {}",
+            "mov dr6, {}",
+            in(reg) dr0,
+            in(reg) dr1,
+            in(reg) dr2,
+            in(reg) dr3,
+            in(reg) self.dr6,
+        );
+
+        dtables::lgdt(&DescriptorTablePointer {
+            limit: self.gdtr_limit,
+            base: self.gdtr_base as *const u64,
+        });
+        // LTR faults on a busy TSS, and the guest's has been busy since the
+        // guest loaded it. The guest's GDT may be mapped read only.
+        if self.tr != 0 {
+            let cr0 = read_cr0();
+            write_cr0(cr0 & !CR0_WP);
+            let descriptor =
+                (self.tss_descriptor_address() + TSS_DESCRIPTOR_TYPE_OFFSET) as *mut u8;
+            core::ptr::write_volatile(
+                descriptor,
+                core::ptr::read_volatile(descriptor) & !TSS_DESCRIPTOR_BUSY,
+            );
+            write_cr0(cr0);
+            asm!("ltr {:x}", in(reg) self.tr);
+        }
+        asm!("lldt {:x}", in(reg) self.ldtr);
+        dtables::lidt(&DescriptorTablePointer {
+            limit: self.idtr_limit,
+            base: self.idtr_base as *const u64,
+        });
+
+        // Loading fs and gs clears their bases, so they are written after.
+        asm!(
+            "mov ds, {:x}",
+            "mov es, {:x}",
+            "mov fs, {:x}",
+            "mov gs, {:x}",
+            in(reg) self.ds,
+            in(reg) self.es,
+            in(reg) self.fs,
+            in(reg) self.gs,
+        );
+        wrmsr(Msr::Ia32FsBase, split(self.fs_base));
+        wrmsr(Msr::Ia32GsBase, split(self.gs_base));
+
+        // cr4 goes before cr3, as cr4.PCIDE can't be set while cr3 holds a
+        // PCID, and after anything written through the host's page tables, as
+        // cr4.SMAP may be set.
+        write_cr0(self.cr0);
+        asm!("mov cr4, {}", in(reg) self.cr4);
+        asm!("mov dr7, {}", in(reg) self.dr7);
+    }
+}
+
+fn split(value: u64) -> MsrValuePair {
+    MsrValuePair {
+        edx: (value >> 32) as u32,
+        eax: value as u32,
+    }
+}
+
+fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0);
+    }
+    cr0
+}
+
+unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, {}", in(reg) cr0);
+}
+
+/// True if size bytes at address translate to the same physical memory in
+/// both address spaces.
+fn mapped_alike(guest: &AddressSpace, host: &AddressSpace, address: u64, size: u64) -> bool {
+    let mut page = address & !(PAGE_SIZE - 1);
+    while page < address + size {
+        match (guest.translate(page), host.translate(page)) {
+            (Ok(guest_phys), Ok(host_phys)) if guest_phys == host_phys => {}
+            _ => return false,
+        }
+        page += PAGE_SIZE;
+    }
+    true
+}
+
+/// Switch to the guest's page tables, pop the guest's general purpose
+/// registers off the frame and return to the guest with IRETQ, which loads
+/// its rip, cs, rflags, rsp and ss.
+#[inline(never)]
+unsafe extern "C" fn hand_over(cr3: u64, frame: u64) -> ! {
+    asm!(
+        "mov cr3, rdi",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rdi",
+        "pop rsi",
+        "pop rbp",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+        in("rdi") cr3,
+        in("rsi") frame,
+        options(noreturn),
+    );
+}
+
+/// Read the state of the guest whose VM exit the current core is handling,
+/// and write its frame onto its stack.
+fn prepare() -> Result<(NativeState, u64), DevirtualizeError> {
+    let vcpu = vcpu::try_get_current_vcpu().ok_or(DevirtualizeError::NotInVmExit)?;
+    if vcpu.guest_gprs.is_null() {
+        return Err(DevirtualizeError::NotInVmExit);
+    }
+    let state = NativeState::capture(vcpu, unsafe { &*vcpu.guest_gprs })?;
+    state.check_mappings()?;
+    let frame = state.frame_address();
+    unsafe {
+        core::ptr::copy_nonoverlapping(state.frame.as_ptr(), frame as *mut u64, FRAME_WORDS);
+    }
+    Ok((state, frame))
+}
+
+/// Hand the current core back to the guest whose VM exit it is handling.
+/// Only returns if that isn't possible, with the reason why.
+pub fn current_core() -> DevirtualizeError {
+    let (state, frame) = match prepare() {
+        Ok(prepared) => prepared,
+        Err(e) => return e,
+    };
+    unsafe {
+        if x86::bits64::vmx::vmclear(state.vmcs_phys)
+            .and_then(|_| x86::bits64::vmx::vmxoff())
+            .is_err()
+        {
+            return DevirtualizeError::VmxOffFailed;
+        }
+        state.load();
+        hand_over(state.cr3, frame);
+    }
+}
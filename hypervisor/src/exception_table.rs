@@ -0,0 +1,182 @@
+//! Lets the host recover from faults in code which touches memory it can't
+//! trust, like Linux's exception tables.
+//!
+//! An access routine which may fault registers the address of the
+//! instruction which may fault, along with a fixup address to resume at
+//! instead. When the host takes a page fault or a general protection fault
+//! on a registered instruction, the interrupt handler resumes the routine at
+//! its fixup with the exception's vector in rax, and the routine returns an
+//! error. Faults anywhere else are still fatal.
+//!
+//! The table is filled in while the hypervisor loads rather than by the
+//! linker, since the Linux kernel would treat an `__ex_table` section in the
+//! module as its own, and PE images have no way to find the bounds of a
+//! section.
+//!
+//! [copy](fn.copy.html) is the only access routine. The
+//! [guest_memory](../guest_memory/index.html) module copies all guest memory
+//! through it, so that a hypercall given a pointer to memory the host can't
+//! reach fails instead of bringing down the host.
+use spin::RwLock;
+
+use crate::interrupts::{self, RegisterInterruptHandlerError};
+use crate::register_state::InterruptRegisterState;
+
+/// The vector of the general protection fault exception, #GP.
+const GENERAL_PROTECTION_VECTOR: u64 = 13;
+/// The vector of the page fault exception, #PF.
+const PAGE_FAULT_VECTOR: u64 = 14;
+
+/// An instruction which may fault, and where to resume if it does.
+#[derive(Clone, Copy, Debug)]
+struct ExceptionTableEntry {
+    instruction: u64,
+    fixup: u64,
+}
+
+/// The number of instructions which can be registered.
+const MAX_ENTRIES: usize = 8;
+
+/// Every registered instruction. Faults only ever read this, with try_read,
+/// so that a fault taken while an entry is being registered on the same core
+/// can't deadlock.
+static TABLE: RwLock<[Option<ExceptionTableEntry>; MAX_ENTRIES]> = RwLock::new([None; MAX_ENTRIES]);
+
+/// The reasons the exception table can't be set up.
+#[derive(Debug)]
+pub enum ExceptionTableError {
+    /// The #PF or #GP handler couldn't be registered. Only read through
+    /// Debug.
+    #[allow(dead_code)]
+    RegisterInterruptHandler(RegisterInterruptHandlerError),
+    /// There are already MAX_ENTRIES instructions.
+    TableFull,
+}
+
+impl From<RegisterInterruptHandlerError> for ExceptionTableError {
+    fn from(e: RegisterInterruptHandlerError) -> Self {
+        ExceptionTableError::RegisterInterruptHandler(e)
+    }
+}
+
+/// Resume at fixup when the instruction at instruction faults.
+fn register(instruction: u64, fixup: u64) -> Result<(), ExceptionTableError> {
+    let mut table = TABLE.write();
+    if table
+        .iter()
+        .flatten()
+        .any(|entry| entry.instruction == instruction)
+    {
+        return Ok(());
+    }
+    let slot = table
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(ExceptionTableError::TableFull)?;
+    *slot = Some(ExceptionTableEntry { instruction, fixup });
+    Ok(())
+}
+
+/// The fixup for a faulting instruction, if it has one.
+fn search(instruction: u64) -> Option<u64> {
+    let table = TABLE.try_read()?;
+    table
+        .iter()
+        .flatten()
+        .find(|entry| entry.instruction == instruction)
+        .map(|entry| entry.fixup)
+}
+
+/// The host's #PF and #GP handler.
+fn handle_fault(state: &mut InterruptRegisterState) -> bool {
+    match search(state.rip) {
+        Some(fixup) => {
+            state.registers.rax = state.interrupt_number;
+            state.rip = fixup;
+            true
+        }
+        None => false,
+    }
+}
+
+/// A copy which faulted. Only read through Debug.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct CopyFault {
+    /// The vector of the exception the host took.
+    pub vector: u64,
+    /// The number of bytes which weren't copied.
+    pub remaining: usize,
+}
+
+/// The result of a copy, and the addresses of the instruction which may
+/// fault and of its fixup.
+struct RawCopy {
+    remaining: usize,
+    vector: u64,
+    instruction: u64,
+    fixup: u64,
+}
+
+/// Copy len bytes with a single `rep movsb`. If it faults the fixup is the
+/// next instruction: rcx holds the number of bytes left, and the handler puts
+/// the vector in rax. Never inlined, so that there is only one copy of the
+/// instruction to register.
+#[inline(never)]
+unsafe fn copy_raw(destination: *mut u8, source: *const u8, len: usize) -> RawCopy {
+    let remaining: usize;
+    let vector: u64;
+    let instruction: u64;
+    let fixup: u64;
+    asm!(
+        "lea {instruction}, [rip + 2f]",
+        "lea {fixup}, [rip + 3f]",
+        "xor eax, eax",
+        "2:",
+        "rep movsb",
+        "3:",
+        instruction = out(reg) instruction,
+        fixup = out(reg) fixup,
+        inout("rcx") len => remaining,
+        inout("rdi") destination => _,
+        inout("rsi") source => _,
+        out("rax") vector,
+        options(nostack),
+    );
+    RawCopy {
+        remaining,
+        vector,
+        instruction,
+        fixup,
+    }
+}
+
+/// Copy len bytes from source to destination, either of which may not be
+/// mapped. Returns an error if the host faulted, in which case only part of
+/// the bytes were copied.
+///
+/// # Safety
+/// Neither range may overlap anything Rust code holds a reference to, and
+/// source and destination must not overlap each other.
+pub unsafe fn copy(destination: *mut u8, source: *const u8, len: usize) -> Result<(), CopyFault> {
+    let result = copy_raw(destination, source, len);
+    if result.remaining == 0 {
+        Ok(())
+    } else {
+        Err(CopyFault {
+            vector: result.vector,
+            remaining: result.remaining,
+        })
+    }
+}
+
+/// Register the access routines and take over the host's #PF and #GP. Called
+/// once while loading the hypervisor.
+pub fn init() -> Result<(), ExceptionTableError> {
+    // An empty copy touches no memory, and only gives the addresses.
+    let empty = unsafe { copy_raw(core::ptr::null_mut(), core::ptr::null(), 0) };
+    register(empty.instruction, empty.fixup)?;
+    interrupts::register(PAGE_FAULT_VECTOR, handle_fault)?;
+    interrupts::register(GENERAL_PROTECTION_VECTOR, handle_fault)?;
+    Ok(())
+}
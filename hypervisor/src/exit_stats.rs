@@ -0,0 +1,150 @@
+//! Counts VM exits by reason on each core, and measures how long the
+//! hypervisor takes to handle them.
+//!
+//! The time is measured with RDTSC from the start of
+//! [hypervisor_handle_vmexit](../vmexit_handlers/fn.hypervisor_handle_vmexit.html)
+//! to its end, so it doesn't include the VM exit and VM entry themselves. It
+//! is kept in a histogram with a bucket for each power of two, see
+//! [EXIT_STATS_HISTOGRAM_BUCKETS](../../hypervisor_abi/constant.EXIT_STATS_HISTOGRAM_BUCKETS.html).
+//!
+//! Each core only updates its own statistics, so they are kept in atomics
+//! with relaxed ordering rather than behind a lock. Other cores may read them
+//! at any time with the
+//! [HYPERCALL_REASON_EXIT_STATS](../../hypervisor_abi/constant.HYPERCALL_REASON_EXIT_STATS.html)
+//! hypercall, and may see an exit counted but not yet added to the histogram.
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::hypercall_handler::{self, HypercallArguments, HypercallResults};
+use crate::vcpu::{self, get_current_vcpu};
+use hypervisor_abi::{HypercallError, EXIT_STATS_HISTOGRAM_BUCKETS, EXIT_STATS_MAX_REASONS};
+
+const MAX_REASONS: usize = EXIT_STATS_MAX_REASONS as usize;
+
+/// The basic exit reason is in bits 15:0 of the exit reason field. The rest
+/// are flags, e.g. for VM entry failures.
+const EXIT_REASON_BASIC_MASK: u64 = 0xffff;
+
+/// The statistics for one exit reason.
+struct ReasonStats {
+    count: AtomicU64,
+    total_ticks: AtomicU64,
+    histogram: [AtomicU32; EXIT_STATS_HISTOGRAM_BUCKETS],
+}
+
+/// A core's VM exit statistics.
+/// Each core should have their own ExitStats, initialized as all zeroes,
+/// which counts no exits. It is too large to build on the stack.
+pub struct ExitStats {
+    reasons: [ReasonStats; MAX_REASONS],
+}
+
+impl core::fmt::Debug for ExitStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ExitStats").finish_non_exhaustive()
+    }
+}
+
+/// A snapshot of the statistics for one exit reason on one core.
+pub struct ReasonStatsSnapshot {
+    /// The number of exits.
+    pub count: u64,
+    /// The total number of TSC ticks spent handling the exits.
+    pub total_ticks: u64,
+    /// The number of exits in each bucket of the histogram.
+    pub histogram: [u32; EXIT_STATS_HISTOGRAM_BUCKETS],
+}
+
+/// Read the time stamp counter. Called at the start of every VM exit.
+pub fn start() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// The histogram bucket for an exit which took ticks to handle.
+fn bucket(ticks: u64) -> usize {
+    let log2 = 63 - (ticks | 1).leading_zeros() as usize;
+    core::cmp::min(log2, EXIT_STATS_HISTOGRAM_BUCKETS - 1)
+}
+
+/// Count an exit on the current core which started at start, as returned by
+/// [start](fn.start.html). Called at the end of every VM exit.
+pub fn record(exit_reason: u64, start: u64) {
+    let ticks = unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start);
+    let reason = (exit_reason & EXIT_REASON_BASIC_MASK) as usize;
+    let stats = match get_current_vcpu().exit_stats.reasons.get(reason) {
+        Some(stats) => stats,
+        None => return,
+    };
+    // Only this core writes its statistics, so there is no need for atomic
+    // read-modify-write instructions.
+    let increment = |counter: &AtomicU64, value: u64| {
+        counter.store(
+            counter.load(Ordering::Relaxed).wrapping_add(value),
+            Ordering::Relaxed,
+        );
+    };
+    increment(&stats.count, 1);
+    increment(&stats.total_ticks, ticks);
+    let bucket = &stats.histogram[bucket(ticks)];
+    bucket.store(
+        bucket.load(Ordering::Relaxed).saturating_add(1),
+        Ordering::Relaxed,
+    );
+}
+
+/// Read the statistics for an exit reason on a core. Returns None if there is
+/// no such core, or the exit reason isn't counted.
+pub fn read(vcpu_index: usize, reason: usize) -> Option<ReasonStatsSnapshot> {
+    let (_, vcpu) = vcpu::vcpus().find(|(index, _)| *index == vcpu_index)?;
+    let stats = vcpu.exit_stats.reasons.get(reason)?;
+    let mut snapshot = ReasonStatsSnapshot {
+        count: stats.count.load(Ordering::Relaxed),
+        total_ticks: stats.total_ticks.load(Ordering::Relaxed),
+        histogram: [0; EXIT_STATS_HISTOGRAM_BUCKETS],
+    };
+    for (bucket, count) in stats.histogram.iter().zip(snapshot.histogram.iter_mut()) {
+        *count = bucket.load(Ordering::Relaxed);
+    }
+    Some(snapshot)
+}
+
+/// The results of an exit stats hypercall query, or None if the query is out
+/// of range.
+fn query_results(stats: &ReasonStatsSnapshot, query: u32) -> Option<HypercallResults> {
+    if query == hypervisor_abi::EXIT_STATS_QUERY_TOTALS {
+        return Some([
+            stats.count & 0xffff_ffff,
+            stats.count >> 32,
+            stats.total_ticks & 0xffff_ffff,
+            stats.total_ticks >> 32,
+        ]);
+    }
+    let first_bucket =
+        (query.checked_sub(hypervisor_abi::EXIT_STATS_QUERY_HISTOGRAM)? * 4) as usize;
+    let buckets = stats.histogram.get(first_bucket..first_bucket + 4)?;
+    Some([
+        u64::from(buckets[0]),
+        u64::from(buckets[1]),
+        u64::from(buckets[2]),
+        u64::from(buckets[3]),
+    ])
+}
+
+fn exit_stats_hypercall(
+    arguments: &HypercallArguments,
+) -> Result<HypercallResults, HypercallError> {
+    let argument = arguments.registers[0];
+    let vcpu_index = (argument >> hypervisor_abi::EXIT_STATS_VCPU_SHIFT) & 0xffff;
+    let exit_reason = (argument >> hypervisor_abi::EXIT_STATS_REASON_SHIFT) & 0xffff;
+    let query = (argument & 0xff) as u32;
+    read(vcpu_index as usize, exit_reason as usize)
+        .and_then(|stats| query_results(&stats, query))
+        .ok_or(HypercallError::InvalidArgument)
+}
+
+/// Register the exit statistics' hypercalls.
+pub fn register_hypercalls() -> Result<(), hypercall_handler::RegisterHypercallError> {
+    hypercall_handler::register(
+        hypervisor_abi::HYPERCALL_REASON_EXIT_STATS,
+        exit_stats_hypercall,
+    )
+}
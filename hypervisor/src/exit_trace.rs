@@ -0,0 +1,293 @@
+//! Keeps a trace of the most recent VM exits on each core, so that when the
+//! guest hangs or the hypervisor panics we can see what led up to it.
+//!
+//! Each core records every exit in a ring of
+//! [ExitTraceRecords](../../hypervisor_abi/struct.ExitTraceRecord.html) as
+//! soon as it starts handling it, so the exit being handled when the
+//! hypervisor panics is the last one in the trace. Only the core itself
+//! writes its ring, without taking a lock, so that a panic or an NMI can't
+//! find the ring locked. Each slot has a stamp which works like a seqlock, as
+//! in the [in-memory log](../log_ring/index.html), so readers on other cores
+//! can tell a complete record from one which is being written or has been
+//! overwritten. The records can be read at
+//! any time with the
+//! [HYPERCALL_REASON_EXIT_TRACE](../../hypervisor_abi/constant.HYPERCALL_REASON_EXIT_TRACE.html)
+//! hypercall, and are written over the serial port when the hypervisor
+//! panics. scripts/exit_trace_decode.py renders them as a timeline.
+use core::fmt::Write;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use crate::hypercall_handler::{self, HypercallArguments, HypercallResults};
+use crate::register_state::GeneralPurposeRegisterState;
+use crate::vcpu::{self, get_current_vcpu};
+use crate::vmcs_fields::VmcsField;
+use crate::vmx::vmread;
+use hypervisor_abi::{
+    ExitTraceBounds, ExitTraceRecord, HypercallError, VmcallBuffer, EXIT_TRACE_RECORD_SIZE,
+};
+
+/// The number of exits each core remembers.
+const MAX_EXIT_TRACE_RECORDS: usize = 1024;
+
+/// The serial port the traces are written to when the hypervisor panics.
+#[cfg_attr(test, allow(dead_code))]
+const EXIT_TRACE_PORT: pcuart::UartComPort = pcuart::UartComPort::Com1;
+
+const RECORD_WORDS: usize = EXIT_TRACE_RECORD_SIZE / 8;
+
+struct Slot {
+    /// 0 if the slot is empty, 2n+1 while the record with sequence number n
+    /// is being written, and 2n+2 once it is complete.
+    stamp: AtomicU64,
+    /// The record in its binary layout. Atomic so that readers racing with
+    /// the writer read stale data rather than undefined behavior.
+    words: [AtomicU64; RECORD_WORDS],
+}
+
+/// A core's trace of recent VM exits.
+/// Each core should have their own ExitTrace, initialized as all zeroes,
+/// which is an empty trace. It is too large to build on the stack.
+pub struct ExitTrace {
+    slots: [Slot; MAX_EXIT_TRACE_RECORDS],
+    /// The sequence number of the next record. The record with sequence
+    /// number n is in slot n % MAX_EXIT_TRACE_RECORDS.
+    next: AtomicU64,
+}
+
+impl ExitTrace {
+    /// The sequence number of the oldest record.
+    fn oldest(next: u64) -> u64 {
+        next.saturating_sub(MAX_EXIT_TRACE_RECORDS as u64)
+    }
+
+    fn slot(&self, sequence: u64) -> &Slot {
+        &self.slots[(sequence % MAX_EXIT_TRACE_RECORDS as u64) as usize]
+    }
+
+    /// Append a record. Must only be called by the core which owns the trace.
+    fn push(&self, record: &ExitTraceRecord) {
+        let sequence = self.next.load(Ordering::Relaxed);
+        let slot = self.slot(sequence);
+        slot.stamp.store(2 * sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (word, bytes) in slot.words.iter().zip(record.to_bytes().chunks_exact(8)) {
+            let mut value = [0; 8];
+            value.copy_from_slice(bytes);
+            word.store(u64::from_le_bytes(value), Ordering::Relaxed);
+        }
+        slot.stamp.store(2 * sequence + 2, Ordering::Release);
+        self.next.store(sequence + 1, Ordering::Release);
+    }
+
+    /// The sequence numbers of the records in the trace.
+    fn bounds(&self) -> ExitTraceBounds {
+        let next = self.next.load(Ordering::Acquire);
+        ExitTraceBounds {
+            oldest: Self::oldest(next) as u32,
+            next: next as u32,
+        }
+    }
+
+    /// Read the record with a full sequence number, if it is complete and
+    /// hasn't been overwritten.
+    fn read(&self, sequence: u64) -> Option<[u8; EXIT_TRACE_RECORD_SIZE]> {
+        let slot = self.slot(sequence);
+        let complete = 2 * sequence + 2;
+        if slot.stamp.load(Ordering::Acquire) != complete {
+            return None;
+        }
+        let mut bytes = [0; EXIT_TRACE_RECORD_SIZE];
+        for (word, bytes) in slot.words.iter().zip(bytes.chunks_exact_mut(8)) {
+            bytes.copy_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+        }
+        fence(Ordering::Acquire);
+        if slot.stamp.load(Ordering::Relaxed) != complete {
+            return None;
+        }
+        Some(bytes)
+    }
+
+    /// Find the record whose sequence number has the given low 32 bits. The
+    /// ring is much smaller than 2^32 records, so there is at most one.
+    fn get(&self, sequence: u32) -> Option<[u8; EXIT_TRACE_RECORD_SIZE]> {
+        let next = self.next.load(Ordering::Acquire);
+        let age = (next as u32).wrapping_sub(sequence);
+        if age == 0 || u64::from(age) > next - Self::oldest(next) {
+            return None;
+        }
+        self.read(next - u64::from(age))
+    }
+}
+
+impl core::fmt::Debug for ExitTrace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ExitTrace").finish_non_exhaustive()
+    }
+}
+
+/// Record an exit on the current core. tsc is the time stamp counter when the
+/// exit handler started. Called at the start of every VM exit.
+pub fn record(tsc: u64, exit_reason: u64, qualification: u64, gprs: &GeneralPurposeRegisterState) {
+    let vcpu = get_current_vcpu();
+    let record = ExitTraceRecord {
+        tsc,
+        rip: vmread(VmcsField::GuestRip).unwrap_or(0xbadc0de),
+        qualification,
+        rax: gprs.rax,
+        rbx: gprs.rbx,
+        rcx: gprs.rcx,
+        rdx: gprs.rdx,
+        exit_reason: exit_reason as u32,
+        vcpu: vcpu::index_of(vcpu).unwrap_or(0) as u16,
+    };
+    vcpu.exit_trace.push(&record);
+}
+
+fn find_vcpu(vcpu_index: usize) -> Option<&'static crate::VCpu> {
+    vcpu::vcpus()
+        .find(|(index, _)| *index == vcpu_index)
+        .map(|(_, vcpu)| vcpu)
+}
+
+/// The sequence numbers of the records in a core's trace, or None if there is
+/// no such core.
+pub fn bounds(vcpu_index: usize) -> Option<ExitTraceBounds> {
+    Some(find_vcpu(vcpu_index)?.exit_trace.bounds())
+}
+
+/// A record from a core's trace, encoded in its binary layout. Returns None if
+/// there is no such core, or the record isn't in the trace.
+pub fn read(vcpu_index: usize, sequence: u32) -> Option<[u8; EXIT_TRACE_RECORD_SIZE]> {
+    find_vcpu(vcpu_index)?.exit_trace.get(sequence)
+}
+
+/// Write every core's trace over the serial port, oldest record first. Each
+/// trace is framed by lines of text announcing its size, like core dumps.
+/// Called while panicking, so progress is reported directly on the serial
+/// port instead of through the logger, which may be locked.
+#[cfg_attr(test, allow(dead_code))]
+pub fn dump() {
+    let mut uart = pcuart::Uart::new(EXIT_TRACE_PORT);
+    for (index, vcpu) in vcpu::vcpus() {
+        let next = vcpu.exit_trace.next.load(Ordering::Acquire);
+        let oldest = ExitTrace::oldest(next);
+        let count = next - oldest;
+        let _ = write!(
+            uart,
+            "\r\nrustyvisor exit trace begin {:x} bytes vCPU {}\r\n",
+            count * EXIT_TRACE_RECORD_SIZE as u64,
+            index
+        );
+        for sequence in oldest..next {
+            // A core which was stopped while recording an exit leaves that
+            // record incomplete, and it is written as zeroes.
+            let record = vcpu.exit_trace.read(sequence);
+            uart.write_bytes(&record.unwrap_or([0; EXIT_TRACE_RECORD_SIZE]));
+        }
+        let _ = write!(uart, "\r\nrustyvisor exit trace end\r\n");
+    }
+}
+
+/// The results of an exit trace hypercall query, or None if the query is out
+/// of range.
+fn query_results(vcpu_index: usize, query: u32, sequence: u32) -> Option<HypercallResults> {
+    if query == hypervisor_abi::EXIT_TRACE_QUERY_BOUNDS {
+        let bounds = bounds(vcpu_index)?;
+        return Some([
+            u64::from(bounds.oldest),
+            u64::from(bounds.next),
+            EXIT_TRACE_RECORD_SIZE as u64,
+            0,
+        ]);
+    }
+    let offset = (query.checked_sub(hypervisor_abi::EXIT_TRACE_QUERY_RECORD)? * 16) as usize;
+    let record = read(vcpu_index, sequence)?;
+    let chunk = record.get(offset..offset + 16)?;
+    let mut results = [0; 4];
+    for (result, word) in results.iter_mut().zip(chunk.chunks_exact(4)) {
+        *result = u64::from(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+    }
+    Some(results)
+}
+
+/// Write consecutive records from a core's trace into a hypercall's buffer,
+/// starting with sequence, and return how many were written.
+fn records_to_buffer(
+    vcpu_index: usize,
+    sequence: u32,
+    buffer: VmcallBuffer,
+) -> Result<HypercallResults, HypercallError> {
+    let capacity = buffer.len / EXIT_TRACE_RECORD_SIZE as u64;
+    let mut count = 0;
+    while count < capacity {
+        let record = match read(vcpu_index, sequence.wrapping_add(count as u32)) {
+            Some(record) => record,
+            None if count == 0 => return Err(HypercallError::InvalidArgument),
+            None => break,
+        };
+        hypercall_handler::write_buffer(&buffer, count * EXIT_TRACE_RECORD_SIZE as u64, &record)?;
+        count += 1;
+    }
+    Ok([count, 0, 0, 0])
+}
+
+fn exit_trace_hypercall(
+    arguments: &HypercallArguments,
+) -> Result<HypercallResults, HypercallError> {
+    let argument = arguments.registers[0];
+    let query = ((argument >> hypervisor_abi::EXIT_TRACE_QUERY_SHIFT) & 0xff) as u32;
+    let vcpu_index = ((argument >> hypervisor_abi::EXIT_TRACE_VCPU_SHIFT) & 0xffff) as usize;
+    let sequence = argument as u32;
+    if let Some(buffer) = arguments.buffer {
+        if query == hypervisor_abi::EXIT_TRACE_QUERY_RECORD {
+            return records_to_buffer(vcpu_index, sequence, buffer);
+        }
+    }
+    query_results(vcpu_index, query, sequence).ok_or(HypercallError::InvalidArgument)
+}
+
+/// Register the exit trace's hypercalls.
+pub fn register_hypercalls() -> Result<(), hypercall_handler::RegisterHypercallError> {
+    hypercall_handler::register(
+        hypervisor_abi::HYPERCALL_REASON_EXIT_TRACE,
+        exit_trace_hypercall,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tsc: u64) -> ExitTraceRecord {
+        ExitTraceRecord {
+            tsc,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn zeroed_trace_is_empty() {
+        let trace: ExitTrace = unsafe { core::mem::zeroed() };
+        let bounds = trace.bounds();
+        assert_eq!((bounds.oldest, bounds.next), (0, 0));
+        assert_eq!(trace.get(0), None);
+    }
+
+    #[test]
+    fn oldest_records_are_overwritten() {
+        let trace: ExitTrace = unsafe { core::mem::zeroed() };
+        let total = MAX_EXIT_TRACE_RECORDS as u64 + 3;
+        for tsc in 0..total {
+            trace.push(&record(tsc));
+        }
+        let bounds = trace.bounds();
+        assert_eq!(bounds.oldest, 3);
+        assert_eq!(bounds.next, total as u32);
+        assert_eq!(trace.get(2), None);
+        assert_eq!(trace.get(total as u32), None);
+        for sequence in [3, total as u32 - 1] {
+            let bytes = trace.get(sequence).unwrap();
+            assert_eq!(ExitTraceRecord::from_bytes(&bytes).tsc, u64::from(sequence));
+        }
+    }
+}
@@ -0,0 +1,679 @@
+//! Lets gdb debug the guest over a serial port, using the GDB Remote Serial
+//! Protocol server in the gdb_rsp crate.
+//!
+//! Each vCPU is shown to gdb as a thread, numbered from 1 in the order the
+//! cores were loaded. The guest stops and waits for gdb:
+//! - When it executes an int3 placed by a software breakpoint. #BP is
+//!   intercepted with the exception bitmap, and #BPs which aren't gdb's are
+//!   reinjected.
+//! - When it triggers a hardware breakpoint or watchpoint. These are reserved
+//!   from the [debug_registers](../debug_registers/index.html) module, and #DB
+//!   is intercepted too.
+//! - After a step, which uses the monitor trap flag through the
+//!   [single_step](../single_step/index.html) module.
+//! - When gdb sends anything, e.g. a ^C. This is noticed at the end of the
+//!   next VM exit on any core.
+//!
+//! Only the vCPU which stopped waits for gdb. The others keep running, and
+//! wait for the stub if they stop too. While gdb is attached every core takes
+//! a snapshot of its guest state on each VM exit, and gdb is shown the other
+//! vCPUs' registers as of their last VM exit. Those registers can't be
+//! changed. Hardware breakpoints only apply to the vCPU which was stopped
+//! when they were inserted.
+use crate::crash_dump;
+use crate::debug_registers::{self, BreakpointCondition, BreakpointLength, BREAKPOINT_COUNT};
+use crate::guest_memory::{AddressSpace, GuestMemoryError};
+use crate::interrupt_controller::{EXCEPTION_VECTOR_BREAKPOINT, EXCEPTION_VECTOR_DEBUG};
+use crate::register_state::GeneralPurposeRegisterState;
+use crate::single_step::{self, SingleStepError, SingleStepOutcome};
+use crate::snapshot::GuestSnapshot;
+use crate::vcpu::{self, get_current_vcpu};
+use crate::vmcs_fields::VmcsField;
+use crate::vmx::{vmread, vmwrite};
+use crate::VCpu;
+use core::sync::atomic::{AtomicBool, Ordering};
+use gdb_rsp::{
+    BreakpointKind, Connection, Registers, ResumeAction, StopReason, Stub, Target, TargetError,
+};
+use log::{error, trace};
+use spin::Mutex;
+
+const GDB_STUB_PORT: pcuart::UartComPort = pcuart::UartComPort::Com2;
+
+/// The exceptions which cause VM exits so the stub can tell whether they were
+/// caused by gdb's breakpoints.
+pub const EXCEPTION_BITMAP: u64 =
+    (1 << EXCEPTION_VECTOR_DEBUG) | (1 << EXCEPTION_VECTOR_BREAKPOINT);
+
+const MAX_SOFTWARE_BREAKPOINTS: usize = 64;
+const INT3: u8 = 0xcc;
+
+const RFLAGS_FIXED_ONES: u64 = 1 << 1;
+const RFLAGS_RF: u64 = 1 << 16;
+
+/// The fields of the VM exit and VM entry interruption information.
+/// See the Intel manual, Volume 3, Section 24.9.2 "Information for VM Exits
+/// Due to Vectored Events".
+const INTERRUPT_INFO_VECTOR_MASK: u64 = 0xff;
+const INTERRUPT_INFO_TYPE_MASK: u64 = 7 << 8;
+const INTERRUPT_INFO_TYPE_PRIVILEGED_SOFTWARE_EXCEPTION: u64 = 5 << 8;
+const INTERRUPT_INFO_TYPE_SOFTWARE_EXCEPTION: u64 = 6 << 8;
+const INTERRUPT_INFO_DELIVER_ERROR_CODE: u64 = 1 << 11;
+const INTERRUPT_INFO_VALID: u64 = 1 << 31;
+
+/// An int3 written over guest code.
+#[derive(Clone, Copy)]
+struct SoftwareBreakpoint {
+    address: u64,
+    original: u8,
+}
+
+/// A debug register reserved for gdb. The slot in the table is the number of
+/// the debug register.
+#[derive(Clone, Copy)]
+struct HardwareBreakpoint {
+    /// The index of the vCPU whose debug register it is.
+    vcpu: usize,
+    kind: BreakpointKind,
+    address: u64,
+    length: u64,
+    /// True if gdb removed it while another vCPU was stopped. The owning core
+    /// releases it at the end of its next VM exit.
+    removed: bool,
+}
+
+struct Breakpoints {
+    software: [Option<SoftwareBreakpoint>; MAX_SOFTWARE_BREAKPOINTS],
+    hardware: [Option<HardwareBreakpoint>; BREAKPOINT_COUNT],
+}
+
+struct UartConnection {
+    uart: pcuart::Uart,
+    /// A byte read while checking whether gdb wants the guest stopped.
+    pending: Option<u8>,
+}
+
+impl Connection for UartConnection {
+    fn read_byte(&mut self) -> u8 {
+        match self.pending.take() {
+            Some(byte) => byte,
+            None => self.uart.read_byte(),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.uart.write_bytes(bytes);
+    }
+}
+
+struct Debugger {
+    stub: Stub,
+    connection: UartConnection,
+    breakpoints: Breakpoints,
+}
+
+/// Held by the core which is stopped and talking to gdb.
+static DEBUGGER: Mutex<Debugger> = Mutex::new(Debugger {
+    stub: Stub::new(),
+    connection: UartConnection {
+        uart: pcuart::Uart::new(GDB_STUB_PORT),
+        pending: None,
+    },
+    breakpoints: Breakpoints {
+        software: [None; MAX_SOFTWARE_BREAKPOINTS],
+        hardware: [None; BREAKPOINT_COUNT],
+    },
+});
+
+/// True from the first time gdb stops the guest until it detaches.
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+/// Set up the serial port gdb connects to. Called once while loading the
+/// hypervisor.
+pub fn init() {
+    DEBUGGER
+        .lock()
+        .connection
+        .uart
+        .init(false, pcuart::UartBaudRate::Baud115200);
+}
+
+fn current_vcpu_index() -> usize {
+    vcpu::index_of(get_current_vcpu() as *const VCpu).expect("Current VCpu isn't registered")
+}
+
+fn thread_of_vcpu(index: usize) -> u64 {
+    index as u64 + 1
+}
+
+fn vcpu_of_thread(thread: u64) -> Option<&'static VCpu> {
+    let index = thread.checked_sub(1)? as usize;
+    vcpu::vcpus()
+        .find(|(vcpu_index, _)| *vcpu_index == index)
+        .map(|(_, vcpu)| vcpu)
+}
+
+/// The snapshot of a vCPU which isn't stopped, if it has had a VM exit since
+/// gdb attached.
+fn other_vcpu_snapshot(thread: u64) -> Result<&'static GuestSnapshot, TargetError> {
+    let vcpu = vcpu_of_thread(thread).ok_or(TargetError::Invalid)?;
+    if vcpu.guest_snapshot_valid {
+        Ok(&vcpu.guest_snapshot)
+    } else {
+        Err(TargetError::Fault)
+    }
+}
+
+fn snapshot_registers(snapshot: &GuestSnapshot) -> Registers {
+    Registers {
+        gprs: [
+            snapshot.rax,
+            snapshot.rbx,
+            snapshot.rcx,
+            snapshot.rdx,
+            snapshot.rsi,
+            snapshot.rdi,
+            snapshot.rbp,
+            snapshot.rsp,
+            snapshot.r8,
+            snapshot.r9,
+            snapshot.r10,
+            snapshot.r11,
+            snapshot.r12,
+            snapshot.r13,
+            snapshot.r14,
+            snapshot.r15,
+        ],
+        rip: snapshot.rip,
+        eflags: snapshot.rflags as u32,
+        segments: [
+            snapshot.cs as u32,
+            snapshot.ss as u32,
+            snapshot.ds as u32,
+            snapshot.es as u32,
+            snapshot.fs as u32,
+            snapshot.gs as u32,
+        ],
+    }
+}
+
+fn vmcs_error(e: x86::vmx::VmFail) -> TargetError {
+    error!("vmcs access failed while serving gdb {:?}", e);
+    TargetError::Fault
+}
+
+fn memory_error(e: GuestMemoryError) -> TargetError {
+    trace!("Guest memory access failed {:?}", e);
+    match e {
+        GuestMemoryError::VmFail(e) => vmcs_error(e),
+        _ => TargetError::Fault,
+    }
+}
+
+/// The guest as seen by gdb, while the current core is stopped.
+struct GuestTarget<'a> {
+    gprs: &'a mut GeneralPurposeRegisterState,
+    breakpoints: &'a mut Breakpoints,
+}
+
+impl GuestTarget<'_> {
+    fn is_current(&self, thread: u64) -> bool {
+        thread == thread_of_vcpu(current_vcpu_index())
+    }
+
+    fn address_space(&self, thread: u64) -> Result<AddressSpace, TargetError> {
+        if self.is_current(thread) {
+            AddressSpace::current().map_err(vmcs_error)
+        } else {
+            Ok(AddressSpace::from_snapshot(other_vcpu_snapshot(thread)?))
+        }
+    }
+
+    fn write_current_registers(&mut self, registers: &Registers) -> Result<(), TargetError> {
+        // Loading a segment register also loads its descriptor, which gdb has
+        // no way to ask for.
+        let current = snapshot_registers(&GuestSnapshot::capture(self.gprs));
+        if registers.segments != current.segments {
+            return Err(TargetError::Invalid);
+        }
+        let gprs = &registers.gprs;
+        self.gprs.rax = gprs[0];
+        self.gprs.rbx = gprs[1];
+        self.gprs.rcx = gprs[2];
+        self.gprs.rdx = gprs[3];
+        self.gprs.rsi = gprs[4];
+        self.gprs.rdi = gprs[5];
+        self.gprs.rbp = gprs[6];
+        self.gprs.r8 = gprs[8];
+        self.gprs.r9 = gprs[9];
+        self.gprs.r10 = gprs[10];
+        self.gprs.r11 = gprs[11];
+        self.gprs.r12 = gprs[12];
+        self.gprs.r13 = gprs[13];
+        self.gprs.r14 = gprs[14];
+        self.gprs.r15 = gprs[15];
+        vmwrite(VmcsField::GuestRsp, gprs[7]).map_err(vmcs_error)?;
+        vmwrite(VmcsField::GuestRip, registers.rip).map_err(vmcs_error)?;
+        vmwrite(
+            VmcsField::GuestRFlags,
+            u64::from(registers.eflags) | RFLAGS_FIXED_ONES,
+        )
+        .map_err(vmcs_error)
+    }
+
+    fn insert_software_breakpoint(&mut self, address: u64) -> Result<(), TargetError> {
+        let software = &mut self.breakpoints.software;
+        if software
+            .iter()
+            .flatten()
+            .any(|breakpoint| breakpoint.address == address)
+        {
+            return Ok(());
+        }
+        let slot = software
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(TargetError::Invalid)?;
+        let address_space = AddressSpace::current().map_err(vmcs_error)?;
+        let mut original = [0u8];
+        address_space
+            .read(address, &mut original)
+            .map_err(memory_error)?;
+        address_space
+            .write(address, &[INT3])
+            .map_err(memory_error)?;
+        *slot = Some(SoftwareBreakpoint {
+            address,
+            original: original[0],
+        });
+        Ok(())
+    }
+
+    fn remove_software_breakpoint(&mut self, address: u64) -> Result<(), TargetError> {
+        let slot = self
+            .breakpoints
+            .software
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(breakpoint) if breakpoint.address == address))
+            .ok_or(TargetError::Invalid)?;
+        let original = slot.map_or(0, |breakpoint| breakpoint.original);
+        AddressSpace::current()
+            .map_err(vmcs_error)?
+            .write(address, &[original])
+            .map_err(memory_error)?;
+        *slot = None;
+        Ok(())
+    }
+
+    /// The debug register settings for a hardware breakpoint or watchpoint.
+    fn hardware_condition(
+        kind: BreakpointKind,
+        address: u64,
+        length: u64,
+    ) -> Result<(BreakpointCondition, BreakpointLength), TargetError> {
+        let condition = match kind {
+            BreakpointKind::Hardware => {
+                return Ok((BreakpointCondition::Execute, BreakpointLength::One))
+            }
+            BreakpointKind::WriteWatchpoint => BreakpointCondition::Write,
+            BreakpointKind::AccessWatchpoint => BreakpointCondition::ReadWrite,
+            // The debug registers can't watch for only reads.
+            BreakpointKind::ReadWatchpoint | BreakpointKind::Software => {
+                return Err(TargetError::Unsupported)
+            }
+        };
+        let breakpoint_length = match length {
+            1 => BreakpointLength::One,
+            2 => BreakpointLength::Two,
+            4 => BreakpointLength::Four,
+            8 => BreakpointLength::Eight,
+            _ => return Err(TargetError::Invalid),
+        };
+        // The watched memory must be aligned to its length.
+        if address & (length - 1) != 0 {
+            return Err(TargetError::Invalid);
+        }
+        Ok((condition, breakpoint_length))
+    }
+
+    fn insert_hardware_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        address: u64,
+        length: u64,
+    ) -> Result<(), TargetError> {
+        let (condition, breakpoint_length) = Self::hardware_condition(kind, address, length)?;
+        let slot = self
+            .breakpoints
+            .hardware
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(TargetError::Invalid)?;
+        debug_registers::reserve_breakpoint(slot, address, condition, breakpoint_length)
+            .map_err(vmcs_error)?;
+        self.breakpoints.hardware[slot] = Some(HardwareBreakpoint {
+            vcpu: current_vcpu_index(),
+            kind,
+            address,
+            length,
+            removed: false,
+        });
+        Ok(())
+    }
+
+    fn remove_hardware_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        address: u64,
+        length: u64,
+    ) -> Result<(), TargetError> {
+        let slot = self
+            .breakpoints
+            .hardware
+            .iter()
+            .position(|slot| {
+                matches!(slot, Some(breakpoint) if !breakpoint.removed
+                    && breakpoint.kind == kind
+                    && breakpoint.address == address
+                    && breakpoint.length == length)
+            })
+            .ok_or(TargetError::Invalid)?;
+        let breakpoint = self.breakpoints.hardware[slot]
+            .as_mut()
+            .expect("Breakpoint slot was just found");
+        if breakpoint.vcpu == current_vcpu_index() {
+            debug_registers::release_breakpoint(slot).map_err(vmcs_error)?;
+            self.breakpoints.hardware[slot] = None;
+        } else {
+            breakpoint.removed = true;
+        }
+        Ok(())
+    }
+}
+
+impl Target for GuestTarget<'_> {
+    fn current_thread(&mut self) -> u64 {
+        thread_of_vcpu(current_vcpu_index())
+    }
+
+    fn for_each_thread(&mut self, f: &mut dyn FnMut(u64)) {
+        for (index, _) in vcpu::vcpus() {
+            f(thread_of_vcpu(index));
+        }
+    }
+
+    fn is_thread_alive(&mut self, thread: u64) -> bool {
+        vcpu_of_thread(thread).is_some()
+    }
+
+    fn read_registers(&mut self, thread: u64) -> Result<Registers, TargetError> {
+        if self.is_current(thread) {
+            Ok(snapshot_registers(&GuestSnapshot::capture(self.gprs)))
+        } else {
+            Ok(snapshot_registers(other_vcpu_snapshot(thread)?))
+        }
+    }
+
+    fn write_registers(&mut self, thread: u64, registers: &Registers) -> Result<(), TargetError> {
+        if self.is_current(thread) {
+            self.write_current_registers(registers)
+        } else {
+            // The other vCPUs are running.
+            Err(TargetError::Fault)
+        }
+    }
+
+    fn read_memory(
+        &mut self,
+        thread: u64,
+        address: u64,
+        data: &mut [u8],
+    ) -> Result<(), TargetError> {
+        self.address_space(thread)?
+            .read(address, data)
+            .map_err(memory_error)
+    }
+
+    fn write_memory(&mut self, thread: u64, address: u64, data: &[u8]) -> Result<(), TargetError> {
+        self.address_space(thread)?
+            .write(address, data)
+            .map_err(memory_error)
+    }
+
+    fn insert_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        address: u64,
+        length: u64,
+    ) -> Result<(), TargetError> {
+        trace!(
+            "gdb inserting {:?} at {:x} length {}",
+            kind,
+            address,
+            length
+        );
+        match kind {
+            BreakpointKind::Software => self.insert_software_breakpoint(address),
+            _ => self.insert_hardware_breakpoint(kind, address, length),
+        }
+    }
+
+    fn remove_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        address: u64,
+        length: u64,
+    ) -> Result<(), TargetError> {
+        trace!("gdb removing {:?} at {:x} length {}", kind, address, length);
+        match kind {
+            BreakpointKind::Software => self.remove_software_breakpoint(address),
+            _ => self.remove_hardware_breakpoint(kind, address, length),
+        }
+    }
+}
+
+/// Wait for gdb to resume the guest on the current core.
+fn serve(
+    debugger: &mut Debugger,
+    gprs: &mut GeneralPurposeRegisterState,
+    mut reason: StopReason,
+) -> Result<(), x86::vmx::VmFail> {
+    ATTACHED.store(true, Ordering::SeqCst);
+    trace!("Guest stopped for gdb {:?}", reason);
+    let Debugger {
+        stub,
+        connection,
+        breakpoints,
+    } = debugger;
+    loop {
+        let mut target = GuestTarget {
+            gprs: &mut *gprs,
+            breakpoints: &mut *breakpoints,
+        };
+        let action = stub.run(connection, &mut target, reason);
+
+        // Instruction breakpoints are faults, so the guest would hit the
+        // breakpoint again as soon as it resumed. RF suppresses it for one
+        // instruction.
+        if reason == StopReason::HardwareBreakpoint {
+            let rflags = vmread(VmcsField::GuestRFlags)?;
+            vmwrite(VmcsField::GuestRFlags, rflags | RFLAGS_RF)?;
+        }
+
+        match action {
+            ResumeAction::Continue => return Ok(()),
+            ResumeAction::Detach => {
+                trace!("gdb detached");
+                ATTACHED.store(false, Ordering::SeqCst);
+                return Ok(());
+            }
+            ResumeAction::Step => match single_step::step(step_finished) {
+                Ok(()) => return Ok(()),
+                Err(SingleStepError::VmFail(e)) => return Err(e),
+                Err(e) => {
+                    // Tell gdb the guest stopped without moving.
+                    error!("Failed to single step the guest {:?}", e);
+                    reason = StopReason::Interrupted;
+                }
+            },
+        }
+    }
+}
+
+/// Stop the guest on the current core and wait for gdb.
+fn enter(
+    gprs: &mut GeneralPurposeRegisterState,
+    reason: StopReason,
+) -> Result<(), x86::vmx::VmFail> {
+    serve(&mut DEBUGGER.lock(), gprs, reason)
+}
+
+fn step_finished(
+    gprs: &mut GeneralPurposeRegisterState,
+    _outcome: SingleStepOutcome,
+) -> Result<(), x86::vmx::VmFail> {
+    enter(gprs, StopReason::Step)
+}
+
+/// Deliver an exception which caused a VM exit to the guest, as if it hadn't
+/// been intercepted.
+/// See the Intel manual, Volume 3, Section 26.6 "Event Injection".
+fn reinject_exception(interrupt_info: u64) -> Result<(), x86::vmx::VmFail> {
+    let interrupt_type = interrupt_info & INTERRUPT_INFO_TYPE_MASK;
+    if interrupt_info & INTERRUPT_INFO_DELIVER_ERROR_CODE != 0 {
+        vmwrite(
+            VmcsField::VmEntryExceptIonErrorCode,
+            vmread(VmcsField::VmExitIntrErrorCode)?,
+        )?;
+    }
+    // int3 and int1 are injected along with their length, so that the
+    // guest's handler returns after them.
+    if interrupt_type == INTERRUPT_INFO_TYPE_SOFTWARE_EXCEPTION
+        || interrupt_type == INTERRUPT_INFO_TYPE_PRIVILEGED_SOFTWARE_EXCEPTION
+    {
+        vmwrite(
+            VmcsField::VmEntryInstructionLen,
+            vmread(VmcsField::VmExitInstructionLen)?,
+        )?;
+    }
+    vmwrite(
+        VmcsField::VmEntryIntrInfoField,
+        interrupt_info
+            & (INTERRUPT_INFO_VALID
+                | INTERRUPT_INFO_DELIVER_ERROR_CODE
+                | INTERRUPT_INFO_TYPE_MASK
+                | INTERRUPT_INFO_VECTOR_MASK),
+    )
+}
+
+fn handle_breakpoint(
+    gprs: &mut GeneralPurposeRegisterState,
+    interrupt_info: u64,
+) -> Result<(), x86::vmx::VmFail> {
+    // The exit happens before the int3 is executed, so rip points at it.
+    let rip = vmread(VmcsField::GuestRip)?;
+    let mut debugger = DEBUGGER.lock();
+    if debugger
+        .breakpoints
+        .software
+        .iter()
+        .flatten()
+        .any(|breakpoint| breakpoint.address == rip)
+    {
+        return serve(&mut debugger, gprs, StopReason::SoftwareBreakpoint);
+    }
+    drop(debugger);
+
+    // gdb may have removed the breakpoint while this core waited for another
+    // to resume. If so, execute the original instruction.
+    let mut instruction = [0u8];
+    if AddressSpace::current()?.read(rip, &mut instruction).is_ok() && instruction[0] != INT3 {
+        trace!("Breakpoint at {:x} was removed", rip);
+        return Ok(());
+    }
+    reinject_exception(interrupt_info)
+}
+
+fn handle_debug_exception(
+    gprs: &mut GeneralPurposeRegisterState,
+    interrupt_info: u64,
+) -> Result<(), x86::vmx::VmFail> {
+    let qualification = vmread(VmcsField::ExitQualificatIon)?;
+    let index = current_vcpu_index();
+    let mut debugger = DEBUGGER.lock();
+    let hit = debugger
+        .breakpoints
+        .hardware
+        .iter()
+        .enumerate()
+        .find_map(|(slot, breakpoint)| match breakpoint {
+            Some(breakpoint) if breakpoint.vcpu == index && qualification & (1 << slot) != 0 => {
+                Some(*breakpoint)
+            }
+            _ => None,
+        });
+    match hit {
+        // It is released at the end of this VM exit.
+        Some(breakpoint) if breakpoint.removed => Ok(()),
+        Some(breakpoint) => {
+            let reason = match breakpoint.kind {
+                BreakpointKind::Hardware => StopReason::HardwareBreakpoint,
+                kind => StopReason::Watchpoint {
+                    kind,
+                    address: breakpoint.address,
+                },
+            };
+            serve(&mut debugger, gprs, reason)
+        }
+        None => {
+            drop(debugger);
+            debug_registers::record_debug_exception(qualification);
+            reinject_exception(interrupt_info)
+        }
+    }
+}
+
+/// Handle a #DB or #BP which caused a VM exit. If it was caused by one of
+/// gdb's breakpoints the guest stops, otherwise the exception is delivered to
+/// the guest.
+pub fn handle_exception(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    let interrupt_info = vmread(VmcsField::VmExitIntrInfo)?;
+    match interrupt_info & INTERRUPT_INFO_VECTOR_MASK {
+        EXCEPTION_VECTOR_BREAKPOINT => handle_breakpoint(gprs, interrupt_info),
+        EXCEPTION_VECTOR_DEBUG => handle_debug_exception(gprs, interrupt_info),
+        vector => panic!("Unexpected exception {} in the guest", vector),
+    }
+}
+
+/// Release the hardware breakpoints gdb removed from this core while another
+/// core was stopped.
+fn release_removed_breakpoints(breakpoints: &mut Breakpoints) -> Result<(), x86::vmx::VmFail> {
+    let index = current_vcpu_index();
+    for (slot, breakpoint) in breakpoints.hardware.iter_mut().enumerate() {
+        if matches!(breakpoint, Some(breakpoint) if breakpoint.removed && breakpoint.vcpu == index)
+        {
+            debug_registers::release_breakpoint(slot)?;
+            *breakpoint = None;
+        }
+    }
+    Ok(())
+}
+
+/// Called at the end of every VM exit. Stops the guest if gdb sent anything,
+/// and keeps the state gdb is shown for this core up to date.
+pub fn poll(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    if ATTACHED.load(Ordering::SeqCst) {
+        crash_dump::capture_current_guest(gprs);
+    }
+    // Only the core holding the stub may read from the serial port.
+    let mut debugger = match DEBUGGER.try_lock() {
+        Some(debugger) => debugger,
+        None => return Ok(()),
+    };
+    release_removed_breakpoints(&mut debugger.breakpoints)?;
+    if let Some(byte) = debugger.connection.uart.try_read_byte() {
+        debugger.connection.pending = Some(byte);
+        return serve(&mut debugger, gprs, StopReason::Interrupted);
+    }
+    Ok(())
+}
@@ -0,0 +1,262 @@
+//! Reads and writes guest memory by guest virtual address.
+//!
+//! The hypervisor doesn't use EPT, so guest physical addresses are host
+//! physical addresses. Guest virtual addresses are translated by walking the
+//! guest's page tables in software, the same way the processor does for
+//! 4-level and 5-level paging.
+//!
+//! The host reaches physical memory through the ranges registered by the
+//! loader with
+//! [rustyvisor_crash_dump_add_range](../fn.rustyvisor_crash_dump_add_range.html).
+//! UEFI identity maps all of memory, so under UEFI any physical address
+//! outside of those ranges is used directly.
+//!
+//! The host's own page tables are walked the same way, so that the host gdb
+//! stub doesn't fault on unmapped addresses, and so that memory the host
+//! accesses directly, like channel rings, can be checked up front.
+//!
+//! For more information see the Intel manual, Volume 3, Section 4.5 "4-Level
+//! Paging and 5-Level Paging".
+//!
+//! Memory is copied through the [exception table](../exception_table/index.html),
+//! so an address the host has no page for fails the access instead of
+//! faulting fatally.
+//!
+//! Without the gdb stubs, only hypercall buffers are accessed, through the
+//! caller's own page tables and with the caller's access rights.
+#![cfg_attr(
+    not(any(feature = "gdb_stub", feature = "host_gdb_stub")),
+    allow(dead_code)
+)]
+use crate::crash_dump;
+use crate::exception_table;
+use crate::snapshot::GuestSnapshot;
+use crate::vmcs_fields::{VmEntryIa32eMode, VmcsField};
+use crate::vmx::vmread;
+
+const PAGE_SIZE: u64 = 0x1000;
+const PAGE_OFFSET_MASK: u64 = PAGE_SIZE - 1;
+/// Bits 51:12 of a paging structure entry or cr3 hold a physical address.
+const PHYSICAL_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+
+const PAGE_PRESENT: u64 = 1 << 0;
+const PAGE_WRITABLE: u64 = 1 << 1;
+/// Set in a PDPTE or PDE which maps a 1GB or 2MB page.
+const PAGE_SIZE_BIT: u64 = 1 << 7;
+
+/// The reasons a guest memory access can fail.
+#[derive(Debug)]
+pub enum GuestMemoryError {
+    /// Accessing the vmcs failed.
+    #[cfg_attr(not(feature = "gdb_stub"), allow(dead_code))]
+    VmFail(x86::vmx::VmFail),
+    /// The guest virtual address isn't mapped.
+    PageNotPresent,
+    /// The guest maps the guest virtual address read only.
+    ReadOnly,
+    /// The guest uses 32 bit or PAE paging, which aren't supported.
+    UnsupportedPagingMode,
+    /// The host has no mapping for the guest physical address.
+    NotAccessible,
+    /// The host faulted accessing the guest physical address, e.g. because
+    /// the loader registered a range which isn't all mapped.
+    Fault(exception_table::CopyFault),
+}
+
+impl From<x86::vmx::VmFail> for GuestMemoryError {
+    fn from(e: x86::vmx::VmFail) -> Self {
+        GuestMemoryError::VmFail(e)
+    }
+}
+
+/// The control register state which determines how a guest translates
+/// virtual addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct AddressSpace {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    ia32e_mode: bool,
+}
+
+impl AddressSpace {
+    /// The address space of the guest on the current core.
+    /// Must be called in host context during a VM exit.
+    pub fn current() -> Result<Self, x86::vmx::VmFail> {
+        Ok(AddressSpace {
+            cr0: vmread(VmcsField::GuestCr0)?,
+            cr3: vmread(VmcsField::GuestCr3)?,
+            cr4: vmread(VmcsField::GuestCr4)?,
+            ia32e_mode: vmread(VmcsField::VmEntryControls)? & VmEntryIa32eMode != 0,
+        })
+    }
+
+    /// The address space of a guest at the time a snapshot was taken.
+    /// The guest's EFER isn't saved on VM exit, so a guest with PAE paging
+    /// enabled is assumed to be in IA-32e mode. The loaders only run in long
+    /// mode, so the guest starts out in IA-32e mode.
+    #[cfg_attr(not(feature = "gdb_stub"), allow(dead_code))]
+    pub fn from_snapshot(snapshot: &GuestSnapshot) -> Self {
+        AddressSpace {
+            cr0: snapshot.cr0,
+            cr3: snapshot.cr3,
+            cr4: snapshot.cr4,
+            ia32e_mode: snapshot.cr0 & CR0_PG != 0 && snapshot.cr4 & CR4_PAE != 0,
+        }
+    }
+
+    /// The address space of the host on the current core. The host runs in
+    /// IA-32e mode.
+    pub fn host() -> Self {
+        AddressSpace {
+            cr0: unsafe { x86::controlregs::cr0() }.bits() as u64,
+            cr3: unsafe { x86::controlregs::cr3() },
+            cr4: unsafe { x86::controlregs::cr4() }.bits() as u64,
+            ia32e_mode: true,
+        }
+    }
+
+    /// Translate a guest virtual address to a guest physical address.
+    pub fn translate(&self, address: u64) -> Result<u64, GuestMemoryError> {
+        self.translate_access(address, false)
+    }
+
+    /// Translate a guest virtual address to a guest physical address, failing
+    /// if write is set and supervisor code in the guest couldn't write to it.
+    /// Supervisor code may write to any page while CR0.WP is clear.
+    fn translate_access(&self, address: u64, write: bool) -> Result<u64, GuestMemoryError> {
+        if self.cr0 & CR0_PG == 0 {
+            return Ok(address);
+        }
+        let check_writable = write && self.cr0 & CR0_WP != 0;
+        if !self.ia32e_mode {
+            return Err(GuestMemoryError::UnsupportedPagingMode);
+        }
+
+        let levels = if self.cr4 & CR4_LA57 != 0 { 5 } else { 4 };
+        let mut table = self.cr3 & PHYSICAL_ADDRESS_MASK;
+        for level in (1..=levels).rev() {
+            let shift = 12 + 9 * (level - 1);
+            let index = (address >> shift) & 0x1ff;
+            let mut entry = [0u8; 8];
+            read_physical(table + index * 8, &mut entry)?;
+            let entry = u64::from_le_bytes(entry);
+            if entry & PAGE_PRESENT == 0 {
+                return Err(GuestMemoryError::PageNotPresent);
+            }
+            // A page is only writable if every entry mapping it is.
+            if check_writable && entry & PAGE_WRITABLE == 0 {
+                return Err(GuestMemoryError::ReadOnly);
+            }
+            // PDPTEs and PDEs may map 1GB and 2MB pages.
+            if level == 1 || ((level == 2 || level == 3) && entry & PAGE_SIZE_BIT != 0) {
+                let offset_mask = (1 << shift) - 1;
+                return Ok((entry & PHYSICAL_ADDRESS_MASK & !offset_mask) | (address & offset_mask));
+            }
+            table = entry & PHYSICAL_ADDRESS_MASK;
+        }
+        unreachable!();
+    }
+
+    /// Read guest memory starting at a guest virtual address.
+    pub fn read(&self, address: u64, data: &mut [u8]) -> Result<(), GuestMemoryError> {
+        let mut done = 0;
+        while done < data.len() {
+            let address = address.wrapping_add(done as u64);
+            let size = chunk_size(address, data.len() - done);
+            read_physical(self.translate(address)?, &mut data[done..done + size])?;
+            done += size;
+        }
+        Ok(())
+    }
+
+    /// Write guest memory starting at a guest virtual address.
+    pub fn write(&self, address: u64, data: &[u8]) -> Result<(), GuestMemoryError> {
+        let mut done = 0;
+        while done < data.len() {
+            let address = address.wrapping_add(done as u64);
+            let size = chunk_size(address, data.len() - done);
+            write_physical(self.translate(address)?, &data[done..done + size])?;
+            done += size;
+        }
+        Ok(())
+    }
+
+    /// Write guest memory starting at a guest virtual address, with the access
+    /// rights of supervisor code in the guest, so that pages it maps read only
+    /// aren't written. The debugger plants breakpoints in read only code with
+    /// write instead, which ignores the access rights.
+    pub fn write_as_supervisor(&self, address: u64, data: &[u8]) -> Result<(), GuestMemoryError> {
+        let mut done = 0;
+        while done < data.len() {
+            let address = address.wrapping_add(done as u64);
+            let size = chunk_size(address, data.len() - done);
+            write_physical(
+                self.translate_access(address, true)?,
+                &data[done..done + size],
+            )?;
+            done += size;
+        }
+        Ok(())
+    }
+}
+
+/// The number of bytes which can be accessed from address without crossing
+/// into the next page.
+fn chunk_size(address: u64, remaining: usize) -> usize {
+    core::cmp::min(remaining as u64, PAGE_SIZE - (address & PAGE_OFFSET_MASK)) as usize
+}
+
+/// The host virtual address of size bytes of guest memory starting at a guest
+/// physical address, if the host can reach all of them.
+pub fn host_address(guest_phys: u64, size: usize) -> Result<*mut u8, GuestMemoryError> {
+    if let Some(host_virt) = crash_dump::host_address(guest_phys, size as u64) {
+        return Ok(host_virt as *mut u8);
+    }
+    if cfg!(target_os = "uefi") {
+        Ok(guest_phys as *mut u8)
+    } else {
+        Err(GuestMemoryError::NotAccessible)
+    }
+}
+
+/// Check that the host maps every page of size bytes at a host virtual
+/// address, for writing as well if write is set, so that the host can access
+/// them directly without faulting. The host's page tables don't change once
+/// the hypervisor is loaded, so the check stays valid.
+pub fn check_host_mapping(
+    host_virt: *mut u8,
+    size: usize,
+    write: bool,
+) -> Result<(), GuestMemoryError> {
+    let host = AddressSpace::host();
+    let start = host_virt as u64 & !PAGE_OFFSET_MASK;
+    let end = (host_virt as u64)
+        .checked_add(size as u64)
+        .ok_or(GuestMemoryError::NotAccessible)?;
+    let mut page = start;
+    while page < end {
+        host.translate_access(page, write)?;
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Read guest memory starting at a guest physical address.
+pub fn read_physical(guest_phys: u64, data: &mut [u8]) -> Result<(), GuestMemoryError> {
+    let source = host_address(guest_phys, data.len())?;
+    unsafe { exception_table::copy(data.as_mut_ptr(), source, data.len()) }
+        .map_err(GuestMemoryError::Fault)
+}
+
+/// Write guest memory starting at a guest physical address.
+pub fn write_physical(guest_phys: u64, data: &[u8]) -> Result<(), GuestMemoryError> {
+    let destination = host_address(guest_phys, data.len())?;
+    unsafe { exception_table::copy(destination, data.as_ptr(), data.len()) }
+        .map_err(GuestMemoryError::Fault)
+}
@@ -0,0 +1,355 @@
+//! Lets gdb debug the hypervisor itself over a serial port, using the GDB
+//! Remote Serial Protocol server in the gdb_rsp crate.
+//!
+//! The host's #BP and #DB handlers enter the stub, which shows gdb the
+//! registers saved in the [InterruptRegisterState](../register_state/struct.InterruptRegisterState.html)
+//! and lets it read and write host memory, insert software breakpoints in
+//! hypervisor code, step and continue. The host IDT is only loaded in VMX
+//! root operation, so the stub can only be entered during a VM exit. gdb
+//! breaks in by sending anything, which is noticed at the start of the next
+//! VM exit.
+//!
+//! The hypervisor is shown as a single thread, the core which stopped. Other
+//! cores keep running until they stop too, and then wait for the stub.
+//! Nothing is logged while the stub is running, since the stub may have
+//! stopped the hypervisor while it was holding the logger's lock.
+use crate::guest_memory::AddressSpace;
+use crate::interrupt_controller::{EXCEPTION_VECTOR_BREAKPOINT, EXCEPTION_VECTOR_DEBUG};
+use crate::interrupts;
+use crate::register_state::InterruptRegisterState;
+use core::sync::atomic::{AtomicBool, Ordering};
+use gdb_rsp::{
+    BreakpointKind, Connection, Registers, ResumeAction, StopReason, Stub, Target, TargetError,
+};
+use log::error;
+use spin::Mutex;
+
+/// A different port than the guest gdb stub, so both can be used at once.
+const HOST_GDB_STUB_PORT: pcuart::UartComPort = pcuart::UartComPort::Com3;
+
+const HOST_THREAD: u64 = 1;
+
+const MAX_SOFTWARE_BREAKPOINTS: usize = 64;
+const INT3: u8 = 0xcc;
+
+const RFLAGS_FIXED_ONES: u64 = 1 << 1;
+const RFLAGS_TF: u64 = 1 << 8;
+
+/// Set in dr6 when a #DB was caused by single stepping with rflags.TF.
+const DR6_BS: u64 = 1 << 14;
+
+/// An int3 written over hypervisor code.
+#[derive(Clone, Copy)]
+struct SoftwareBreakpoint {
+    address: u64,
+    original: u8,
+}
+
+struct UartConnection {
+    uart: pcuart::Uart,
+    /// A byte read while checking whether gdb wants the hypervisor stopped.
+    pending: Option<u8>,
+}
+
+impl Connection for UartConnection {
+    fn read_byte(&mut self) -> u8 {
+        match self.pending.take() {
+            Some(byte) => byte,
+            None => self.uart.read_byte(),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.uart.write_bytes(bytes);
+    }
+}
+
+struct HostDebugger {
+    stub: Stub,
+    connection: UartConnection,
+    breakpoints: [Option<SoftwareBreakpoint>; MAX_SOFTWARE_BREAKPOINTS],
+}
+
+/// Held by the core which is stopped and talking to gdb.
+static HOST_DEBUGGER: Mutex<HostDebugger> = Mutex::new(HostDebugger {
+    stub: Stub::new(),
+    connection: UartConnection {
+        uart: pcuart::Uart::new(HOST_GDB_STUB_PORT),
+        pending: None,
+    },
+    breakpoints: [None; MAX_SOFTWARE_BREAKPOINTS],
+});
+
+/// True if the next #BP was raised by [poll](fn.poll.html) because gdb sent
+/// something.
+static INTERRUPT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set up the serial port gdb connects to and take over the host's debug
+/// and breakpoint exceptions. Called once while loading the hypervisor.
+pub fn init() {
+    HOST_DEBUGGER
+        .lock()
+        .connection
+        .uart
+        .init(false, pcuart::UartBaudRate::Baud115200);
+    for vector in [EXCEPTION_VECTOR_DEBUG, EXCEPTION_VECTOR_BREAKPOINT].iter() {
+        if let Err(e) = interrupts::register(*vector, handle_exception) {
+            error!("Can't handle host exception {}: {:?}", vector, e);
+        }
+    }
+}
+
+fn read_dr6() -> u64 {
+    let dr6: u64;
+    unsafe {
+        asm!("mov {}, dr6", out(reg)(dr6));
+    }
+    dr6
+}
+
+fn write_dr6(dr6: u64) {
+    unsafe {
+        asm!("mov dr6, {}", in(reg)(dr6));
+    }
+}
+
+fn frame_registers(state: &InterruptRegisterState) -> Registers {
+    let gprs = &state.registers;
+    Registers {
+        gprs: [
+            gprs.rax, gprs.rbx, gprs.rcx, gprs.rdx, gprs.rsi, gprs.rdi, gprs.rbp, state.rsp,
+            gprs.r8, gprs.r9, gprs.r10, gprs.r11, gprs.r12, gprs.r13, gprs.r14, gprs.r15,
+        ],
+        rip: state.rip,
+        eflags: state.rflags as u32,
+        segments: [
+            state.cs as u32,
+            state.ss as u32,
+            state.ds as u32,
+            state.es as u32,
+            state.fs as u32,
+            state.gs as u32,
+        ],
+    }
+}
+
+/// The hypervisor as seen by gdb, while the current core is stopped in an
+/// exception handler.
+struct HostTarget<'a> {
+    state: &'a mut InterruptRegisterState,
+    breakpoints: &'a mut [Option<SoftwareBreakpoint>; MAX_SOFTWARE_BREAKPOINTS],
+}
+
+impl HostTarget<'_> {
+    fn check_thread(thread: u64) -> Result<(), TargetError> {
+        if thread == HOST_THREAD {
+            Ok(())
+        } else {
+            Err(TargetError::Invalid)
+        }
+    }
+}
+
+impl Target for HostTarget<'_> {
+    fn current_thread(&mut self) -> u64 {
+        HOST_THREAD
+    }
+
+    fn for_each_thread(&mut self, f: &mut dyn FnMut(u64)) {
+        f(HOST_THREAD);
+    }
+
+    fn is_thread_alive(&mut self, thread: u64) -> bool {
+        thread == HOST_THREAD
+    }
+
+    fn read_registers(&mut self, thread: u64) -> Result<Registers, TargetError> {
+        Self::check_thread(thread)?;
+        Ok(frame_registers(self.state))
+    }
+
+    fn write_registers(&mut self, thread: u64, registers: &Registers) -> Result<(), TargetError> {
+        Self::check_thread(thread)?;
+        // The segment registers would need to be reloaded, which iretq only
+        // does for cs and ss.
+        if registers.segments != frame_registers(self.state).segments {
+            return Err(TargetError::Invalid);
+        }
+        let gprs = &registers.gprs;
+        let state = &mut *self.state;
+        state.registers.rax = gprs[0];
+        state.registers.rbx = gprs[1];
+        state.registers.rcx = gprs[2];
+        state.registers.rdx = gprs[3];
+        state.registers.rsi = gprs[4];
+        state.registers.rdi = gprs[5];
+        state.registers.rbp = gprs[6];
+        state.rsp = gprs[7];
+        state.registers.r8 = gprs[8];
+        state.registers.r9 = gprs[9];
+        state.registers.r10 = gprs[10];
+        state.registers.r11 = gprs[11];
+        state.registers.r12 = gprs[12];
+        state.registers.r13 = gprs[13];
+        state.registers.r14 = gprs[14];
+        state.registers.r15 = gprs[15];
+        state.rip = registers.rip;
+        state.rflags = u64::from(registers.eflags) | RFLAGS_FIXED_ONES;
+        Ok(())
+    }
+
+    fn read_memory(
+        &mut self,
+        thread: u64,
+        address: u64,
+        data: &mut [u8],
+    ) -> Result<(), TargetError> {
+        Self::check_thread(thread)?;
+        AddressSpace::host()
+            .read(address, data)
+            .map_err(|_| TargetError::Fault)
+    }
+
+    fn write_memory(&mut self, thread: u64, address: u64, data: &[u8]) -> Result<(), TargetError> {
+        Self::check_thread(thread)?;
+        AddressSpace::host()
+            .write(address, data)
+            .map_err(|_| TargetError::Fault)
+    }
+
+    fn insert_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        address: u64,
+        _length: u64,
+    ) -> Result<(), TargetError> {
+        // The processor loads dr7 on every VM exit, so the hypervisor can't
+        // keep hardware breakpoints for itself.
+        if kind != BreakpointKind::Software {
+            return Err(TargetError::Unsupported);
+        }
+        if self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|breakpoint| breakpoint.address == address)
+        {
+            return Ok(());
+        }
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(TargetError::Invalid)?;
+        let address_space = AddressSpace::host();
+        let mut original = [0u8];
+        address_space
+            .read(address, &mut original)
+            .map_err(|_| TargetError::Fault)?;
+        address_space
+            .write(address, &[INT3])
+            .map_err(|_| TargetError::Fault)?;
+        *slot = Some(SoftwareBreakpoint {
+            address,
+            original: original[0],
+        });
+        Ok(())
+    }
+
+    fn remove_breakpoint(
+        &mut self,
+        kind: BreakpointKind,
+        address: u64,
+        _length: u64,
+    ) -> Result<(), TargetError> {
+        if kind != BreakpointKind::Software {
+            return Err(TargetError::Unsupported);
+        }
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(breakpoint) if breakpoint.address == address))
+            .ok_or(TargetError::Invalid)?;
+        let original = slot.map_or(0, |breakpoint| breakpoint.original);
+        AddressSpace::host()
+            .write(address, &[original])
+            .map_err(|_| TargetError::Fault)?;
+        *slot = None;
+        Ok(())
+    }
+}
+
+/// Enter the stub if an exception taken by the host was caused by a
+/// breakpoint or a step. Registered for the host's #DB and #BP. Returns
+/// true if the stub handled the exception, in which case the interrupted code
+/// should be resumed with the register state gdb left.
+pub fn handle_exception(state: &mut InterruptRegisterState) -> bool {
+    if state.interrupt_number != EXCEPTION_VECTOR_BREAKPOINT
+        && state.interrupt_number != EXCEPTION_VECTOR_DEBUG
+    {
+        return false;
+    }
+
+    let mut debugger = HOST_DEBUGGER.lock();
+    let reason = if state.interrupt_number == EXCEPTION_VECTOR_BREAKPOINT {
+        // int3 is a trap, so rip points after it. Rewind rip to gdb's
+        // breakpoint so that the original instruction is executed once gdb
+        // removes it.
+        let address = state.rip.wrapping_sub(1);
+        if debugger
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|breakpoint| breakpoint.address == address)
+        {
+            state.rip = address;
+            StopReason::SoftwareBreakpoint
+        } else if INTERRUPT_REQUESTED.swap(false, Ordering::SeqCst) {
+            StopReason::Interrupted
+        } else {
+            StopReason::Trap
+        }
+    } else {
+        // The processor never clears dr6.
+        let dr6 = read_dr6();
+        write_dr6(dr6 & !DR6_BS);
+        if dr6 & DR6_BS != 0 && state.rflags & RFLAGS_TF != 0 {
+            StopReason::Step
+        } else {
+            StopReason::Trap
+        }
+    };
+    state.rflags &= !RFLAGS_TF;
+
+    let HostDebugger {
+        stub,
+        connection,
+        breakpoints,
+    } = &mut *debugger;
+    let mut target = HostTarget {
+        state: &mut *state,
+        breakpoints,
+    };
+    match stub.run(connection, &mut target, reason) {
+        ResumeAction::Step => state.rflags |= RFLAGS_TF,
+        ResumeAction::Continue | ResumeAction::Detach => {}
+    }
+    true
+}
+
+/// Stop the hypervisor with a breakpoint if gdb sent anything. Called at the
+/// start of every VM exit, when the host IDT is loaded.
+pub fn poll() {
+    let mut debugger = match HOST_DEBUGGER.try_lock() {
+        Some(debugger) => debugger,
+        None => return,
+    };
+    if let Some(byte) = debugger.connection.uart.try_read_byte() {
+        debugger.connection.pending = Some(byte);
+        INTERRUPT_REQUESTED.store(true, Ordering::SeqCst);
+        drop(debugger);
+        unsafe {
+            asm!("int3");
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Changes the host's page tables, so that loaders can put an unmapped guard
+//! page below each host stack.
+//!
+//! Only loaders whose page tables identity map all of memory can use this,
+//! since every paging structure is reached at its physical address. That is
+//! the case under UEFI, where the host keeps running on the firmware's page
+//! tables. The Linux loader gets its guard pages from vmalloc instead.
+//!
+//! Only the core which owns a stack ever touches it, and that core
+//! invalidates its own translation of the guard page, so other cores don't
+//! need a TLB shootdown.
+//!
+//! For more information see the Intel manual, Volume 3, Section 4.5 "4-Level
+//! Paging and 5-Level Paging".
+use spin::Mutex;
+
+const PAGE_SIZE: u64 = 0x1000;
+/// Bits 51:12 of a paging structure entry or cr3 hold a physical address.
+const PHYSICAL_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const ENTRIES_PER_TABLE: u64 = 512;
+
+const CR0_WP: u64 = 1 << 16;
+const CR4_LA57: u64 = 1 << 12;
+
+const PAGE_PRESENT: u64 = 1 << 0;
+/// Set in a PDPTE or PDE which maps a 1GB or 2MB page.
+const PAGE_SIZE_BIT: u64 = 1 << 7;
+/// The PAT bit of a PTE. It is in the same place as PAGE_SIZE_BIT.
+const PAGE_PAT: u64 = 1 << 7;
+/// The PAT bit of a PDPTE or PDE which maps a 1GB or 2MB page.
+const PAGE_PAT_LARGE: u64 = 1 << 12;
+
+/// Serializes changes to the page tables, which the cores share.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// The reasons a page can't be unmapped.
+#[derive(Debug)]
+pub enum HostPagingError {
+    /// The page isn't mapped in the first place.
+    PageNotPresent,
+    /// A large page had to be split, and no page table could be allocated.
+    OutOfMemory,
+}
+
+/// Make the 4KB page containing address not present in the current core's
+/// page tables. A 1GB or 2MB page containing it is split into smaller pages
+/// first, with page tables from allocate_table, which must return the
+/// physical address of a zeroed page, or None.
+///
+/// # Safety
+/// The page tables must identity map all of memory, and nothing may use the
+/// page after it is unmapped.
+pub unsafe fn unmap_page(
+    address: u64,
+    mut allocate_table: impl FnMut() -> Option<u64>,
+) -> Result<(), HostPagingError> {
+    let _lock = LOCK.lock();
+    let levels = if read_cr4() & CR4_LA57 != 0 { 5 } else { 4 };
+    let mut table = read_cr3() & PHYSICAL_ADDRESS_MASK;
+    for level in (1..=levels).rev() {
+        let index = (address / page_size(level)) % ENTRIES_PER_TABLE;
+        let entry = (table + index * 8) as *mut u64;
+        let value = core::ptr::read_volatile(entry);
+        if value & PAGE_PRESENT == 0 {
+            return Err(HostPagingError::PageNotPresent);
+        }
+        if level == 1 {
+            write_entry(entry, value & !PAGE_PRESENT);
+            break;
+        }
+        if (level == 2 || level == 3) && value & PAGE_SIZE_BIT != 0 {
+            let split = allocate_table().ok_or(HostPagingError::OutOfMemory)?;
+            fill_split_table(split, value, level);
+            // The new table inherits the access rights of the large page, the
+            // smaller pages its memory type.
+            let flags = value & !PHYSICAL_ADDRESS_MASK & !PAGE_SIZE_BIT;
+            write_entry(entry, split | flags);
+            table = split;
+        } else {
+            table = value & PHYSICAL_ADDRESS_MASK;
+        }
+    }
+    asm!("invlpg [{}]", in(reg) address, options(nostack));
+    Ok(())
+}
+
+/// Fill a page table with the pages making up the large page mapped by a
+/// level 3 or level 2 entry.
+unsafe fn fill_split_table(table: u64, large_page: u64, level: u64) {
+    let size = page_size(level);
+    let child_size = page_size(level - 1);
+    let base = large_page & PHYSICAL_ADDRESS_MASK & !(size - 1);
+    let mut flags = large_page & !PHYSICAL_ADDRESS_MASK;
+    if level == 2 {
+        // 4KB pages have no size bit, and keep the PAT bit where it was.
+        flags &= !PAGE_SIZE_BIT;
+        if large_page & PAGE_PAT_LARGE != 0 {
+            flags |= PAGE_PAT;
+        }
+    } else {
+        flags |= large_page & PAGE_PAT_LARGE;
+    }
+    let entries = table as *mut u64;
+    for index in 0..ENTRIES_PER_TABLE {
+        core::ptr::write_volatile(
+            entries.add(index as usize),
+            (base + index * child_size) | flags,
+        );
+    }
+}
+
+/// The size in bytes of the pages a paging structure entry at a level maps.
+const fn page_size(level: u64) -> u64 {
+    PAGE_SIZE << (9 * (level - 1))
+}
+
+/// Write a paging structure entry, which the firmware may have mapped read
+/// only.
+unsafe fn write_entry(entry: *mut u64, value: u64) {
+    let cr0 = read_cr0();
+    write_cr0(cr0 & !CR0_WP);
+    core::ptr::write_volatile(entry, value);
+    write_cr0(cr0);
+}
+
+fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0);
+    }
+    cr0
+}
+
+unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, {}", in(reg) cr0);
+}
+
+fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3);
+    }
+    cr3
+}
+
+fn read_cr4() -> u64 {
+    let cr4: u64;
+    unsafe {
+        asm!("mov {}, cr4", out(reg) cr4);
+    }
+    cr4
+}
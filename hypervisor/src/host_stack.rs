@@ -0,0 +1,74 @@
+//! Detects overflows of the host stacks.
+//!
+//! Each vCPU's host stack is allocated by the loader, with a size the loader
+//! chooses. The loaders leave the page below each stack unmapped when they
+//! can, so an overflow page faults right away. The page fault can't be
+//! delivered on the overflowed stack, so the processor raises a double fault
+//! instead, which runs on its own stack and reports the vCPU whose stack
+//! overflowed.
+//!
+//! An overflow which skips the guard page, or happens where the loader
+//! couldn't map one, is caught by a canary at the bottom of the stack. The
+//! canary is checked at the end of every VM exit, so it catches the overflow
+//! after the fact, before the guest resumes on top of corrupted memory.
+use crate::interrupts::{self, RegisterInterruptHandlerError, DOUBLE_FAULT_VECTOR};
+use crate::register_state::InterruptRegisterState;
+use crate::vcpu;
+use crate::VCpu;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Written to the lowest words of each host stack.
+const CANARY: u64 = 0x5275_7374_7953_7461;
+/// The number of canary words. More than one, so that a write which skips the
+/// first word is still likely to hit another.
+const CANARY_WORDS: usize = 4;
+
+/// Take double faults, which an overflow into a guard page causes. Called
+/// once while loading the hypervisor.
+pub fn register_interrupt_handler() -> Result<(), RegisterInterruptHandlerError> {
+    interrupts::register(DOUBLE_FAULT_VECTOR, handle_double_fault)
+}
+
+/// Write the canary to the bottom of the vCPU's host stack. Must be called
+/// before the vCPU first takes a VM exit.
+pub fn init(vcpu: &VCpu) {
+    let canary = vcpu.stack_base as *mut u64;
+    for word in 0..CANARY_WORDS {
+        unsafe { core::ptr::write_volatile(canary.add(word), CANARY) };
+    }
+}
+
+/// Panic if the current vCPU's host stack overflowed. Called at the end of
+/// every VM exit.
+pub fn check(vcpu: &VCpu) {
+    let canary = vcpu.stack_base as *const u64;
+    for word in 0..CANARY_WORDS {
+        if unsafe { core::ptr::read_volatile(canary.add(word)) } != CANARY {
+            panic!(
+                "The host stack of vCPU {:?} overflowed, its canary at {:x?} was overwritten",
+                vcpu::index_of(vcpu),
+                vcpu.stack_base
+            );
+        }
+    }
+}
+
+/// The host's double fault handler. Runs on its own stack. Panics naming the
+/// vCPU if the fault happened with rsp near the bottom of the vCPU's host
+/// stack, otherwise leaves the fault to the default report.
+fn handle_double_fault(state: &mut InterruptRegisterState) -> bool {
+    if let Some(vcpu) = vcpu::try_get_current_vcpu() {
+        let base = vcpu.stack_base as u64;
+        if state.rsp >= base.wrapping_sub(PAGE_SIZE) && state.rsp < base + PAGE_SIZE {
+            panic!(
+                "Double fault, the host stack of vCPU {:?} overflowed, stack {:x?}-{:x?} {:x?}",
+                vcpu::index_of(vcpu),
+                vcpu.stack_base,
+                vcpu.stack_top,
+                state
+            );
+        }
+    }
+    false
+}
@@ -1,4 +1,10 @@
+use core::convert::TryFrom;
+
+use crate::guest_memory::AddressSpace;
+use crate::hypercall_policy::{self, HypercallCaller};
 use crate::register_state::GeneralPurposeRegisterState;
+use hypervisor_abi::{HypercallError, VmcallBuffer};
+use spin::Mutex;
 
 const HYPERVISOR_VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -22,27 +28,235 @@ fn parse_version(version_string: &str) -> [u32; 3] {
     version
 }
 
-/// Handle a hypercall.
+/// The arguments to a hypercall, made with either CPUID or VMCALL.
+pub struct HypercallArguments {
+    /// The argument registers. Hypercalls made with CPUID only have the first,
+    /// passed in rdx.
+    pub registers: [u64; hypervisor_abi::VMCALL_MAX_ARGUMENTS],
+    /// A buffer for results which don't fit in registers. Only hypercalls made
+    /// with VMCALL from CPL 0 may pass one. Access it with
+    /// [read_buffer](fn.read_buffer.html) and
+    /// [write_buffer](fn.write_buffer.html).
+    pub buffer: Option<VmcallBuffer>,
+    /// Where the hypercall was made from.
+    pub caller: HypercallCaller,
+}
+
+/// The values a hypercall returns in rax, rbx, rcx and rdx when made with
+/// CPUID, or in rdi, rsi, rdx and r10 when made with VMCALL.
+pub type HypercallResults = [u64; 4];
+
+/// A hypercall, shared by the CPUID and VMCALL transports.
+pub type HypercallHandler = fn(&HypercallArguments) -> Result<HypercallResults, HypercallError>;
+
+/// The number of hypercalls that can be registered.
+const MAX_HYPERCALLS: usize = 32;
+
+/// Every registered hypercall and its reason.
+static HYPERCALLS: Mutex<[Option<(u32, HypercallHandler)>; MAX_HYPERCALLS]> =
+    Mutex::new([None; MAX_HYPERCALLS]);
+
+/// The reasons a hypercall can't be registered.
+#[derive(Debug)]
+pub enum RegisterHypercallError {
+    /// A hypercall with the same reason is already registered.
+    AlreadyRegistered,
+    /// There are already MAX_HYPERCALLS hypercalls.
+    TableFull,
+}
+
+/// Make a hypercall available to the guest with the given reason.
+pub fn register(reason: u32, handler: HypercallHandler) -> Result<(), RegisterHypercallError> {
+    let mut hypercalls = HYPERCALLS.lock();
+    if hypercalls
+        .iter()
+        .flatten()
+        .any(|(registered_reason, _)| *registered_reason == reason)
+    {
+        return Err(RegisterHypercallError::AlreadyRegistered);
+    }
+    let slot = hypercalls
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(RegisterHypercallError::TableFull)?;
+    *slot = Some((reason, handler));
+    Ok(())
+}
+
+/// Register the hypercalls which describe the hypervisor itself.
+pub fn register_hypercalls() -> Result<(), RegisterHypercallError> {
+    register(hypervisor_abi::HYPERCALL_REASON_VERSION, version)?;
+    register(hypervisor_abi::HYPERCALL_REASON_CAPABILITIES, capabilities)
+}
+
+fn version(_arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    let version = parse_version(&HYPERVISOR_VERSION);
+    Ok([
+        u64::from(version[0]),
+        u64::from(version[1]),
+        u64::from(version[2]),
+        0, // Reserved 0
+    ])
+}
+
+fn capabilities(arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    let first_reason = arguments.registers[0]
+        .checked_mul(64)
+        .filter(|first_reason| *first_reason <= u64::from(u32::MAX))
+        .ok_or(HypercallError::InvalidArgument)?;
+    let hypercalls = HYPERCALLS.lock();
+    let mut count = 0;
+    let mut bitmap: u64 = 0;
+    // Only list the hypercalls the caller may make.
+    for (reason, _) in hypercalls
+        .iter()
+        .flatten()
+        .filter(|(reason, _)| hypercall_policy::visible(*reason, &arguments.caller))
+    {
+        count += 1;
+        if let Some(bit) = u64::from(*reason).checked_sub(first_reason) {
+            if bit < 64 {
+                bitmap |= 1 << bit;
+            }
+        }
+    }
+    Ok([
+        u64::from(hypervisor_abi::HYPERVISOR_INTERFACE_VERSION),
+        count,
+        bitmap & 0xffff_ffff,
+        bitmap >> 32,
+    ])
+}
+
+/// Read from a hypercall's buffer, starting offset bytes into it. The buffer
+/// is at a guest virtual address in the caller's address space, so the caller
+/// can only pass memory its own page tables map.
+pub fn read_buffer(
+    buffer: &VmcallBuffer,
+    offset: u64,
+    data: &mut [u8],
+) -> Result<(), HypercallError> {
+    let address = buffer_address(buffer, offset, data.len())?;
+    AddressSpace::current()
+        .map_err(|_| HypercallError::InvalidBuffer)?
+        .read(address, data)
+        .map_err(|_| HypercallError::InvalidBuffer)
+}
+
+/// Write to a hypercall's buffer, starting offset bytes into it. Like
+/// [read_buffer](fn.read_buffer.html), and the caller's page tables must also
+/// let it write to the buffer.
+pub fn write_buffer(buffer: &VmcallBuffer, offset: u64, data: &[u8]) -> Result<(), HypercallError> {
+    let address = buffer_address(buffer, offset, data.len())?;
+    AddressSpace::current()
+        .map_err(|_| HypercallError::InvalidBuffer)?
+        .write_as_supervisor(address, data)
+        .map_err(|_| HypercallError::InvalidBuffer)
+}
+
+/// The guest virtual address of size bytes at offset in a buffer, if they lie
+/// within it.
+fn buffer_address(buffer: &VmcallBuffer, offset: u64, size: usize) -> Result<u64, HypercallError> {
+    offset
+        .checked_add(size as u64)
+        .filter(|end| *end <= buffer.len)
+        .and_then(|_| buffer.address.checked_add(offset))
+        .ok_or(HypercallError::InvalidBuffer)
+}
+
+/// Run the hypercall for a reason, or return None if the caller isn't allowed
+/// to make it. A reason of None can't be valid, e.g. because it is wider than
+/// 32 bits.
+/// Only CPL 0 may pass a buffer, whatever the policy, since the hypervisor
+/// writes to it with supervisor access rights.
+fn dispatch(
+    reason: Option<u32>,
+    arguments: &HypercallArguments,
+) -> Option<Result<HypercallResults, HypercallError>> {
+    if !hypercall_policy::allowed(reason, arguments) {
+        return None;
+    }
+    if arguments.buffer.is_some() && arguments.caller.cpl != 0 {
+        return Some(Err(HypercallError::InvalidBuffer));
+    }
+    // Don't hold the lock while the handler runs, so that it can use the
+    // table too.
+    let handler = reason.and_then(|reason| {
+        HYPERCALLS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|(registered_reason, _)| *registered_reason == reason)
+            .map(|(_, handler)| *handler)
+    });
+    Some(match handler {
+        Some(handler) => handler(arguments),
+        None => Err(HypercallError::UnknownReason),
+    })
+}
+
+/// Handle a hypercall made with CPUID.
 /// Expects gprs.rax to hold hypercall::HYPERCALL_MAGIC and gprs.rcx to hold a
 /// valid hypercall reason.
-pub fn handle_hypercall(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+/// Hypercalls which fail return HYPERCALL_ERROR in rax, rcx and rdx, and the
+/// error's status in rbx.
+/// Must be called before the guest's RIP is advanced past the CPUID. Returns
+/// false without changing gprs if the caller isn't allowed to make the
+/// hypercall, in which case the CPUID should be emulated as usual.
+pub fn handle_hypercall(gprs: &mut GeneralPurposeRegisterState) -> Result<bool, x86::vmx::VmFail> {
     assert_eq!(hypervisor_abi::HYPERCALL_MAGIC, gprs.rax as u32);
 
-    let reason = gprs.rcx as u32;
-    match reason {
-        hypervisor_abi::HYPERCALL_REASON_VERSION => {
-            let version = parse_version(&HYPERVISOR_VERSION);
-            gprs.rax = u64::from(version[0]);
-            gprs.rbx = u64::from(version[1]);
-            gprs.rcx = u64::from(version[2]);
-            gprs.rdx = 0; // Reserved 0
-        }
-        _ => {
-            gprs.rax = 0;
-            gprs.rbx = 0;
-            gprs.rcx = 0;
-            gprs.rdx = 0;
+    let arguments = HypercallArguments {
+        registers: [gprs.rdx, 0, 0, 0, 0, 0],
+        buffer: None,
+        caller: HypercallCaller::current()?,
+    };
+    let error = u64::from(hypervisor_abi::HYPERCALL_ERROR);
+    let results = match dispatch(Some(gprs.rcx as u32), &arguments) {
+        Some(Ok(results)) => results,
+        Some(Err(e)) => [error, e.status(), error, error],
+        None => return Ok(false),
+    };
+    // CPUID only sets the low 32 bits of each register.
+    gprs.rax = results[0] & 0xffff_ffff;
+    gprs.rbx = results[1] & 0xffff_ffff;
+    gprs.rcx = results[2] & 0xffff_ffff;
+    gprs.rdx = results[3] & 0xffff_ffff;
+    Ok(true)
+}
+
+/// Handle a hypercall made with VMCALL, with the reason in rax. See the
+/// [hypervisor_abi](../../hypervisor_abi/index.html) crate for the calling
+/// convention.
+/// Must be called before the guest's RIP is advanced past the VMCALL. Returns
+/// false without changing gprs if the caller isn't allowed to make the
+/// hypercall, in which case the VMCALL should raise #UD as if the hypervisor
+/// wasn't loaded.
+pub fn handle_vmcall(gprs: &mut GeneralPurposeRegisterState) -> Result<bool, x86::vmx::VmFail> {
+    let arguments = HypercallArguments {
+        registers: [gprs.rdi, gprs.rsi, gprs.rdx, gprs.r10, gprs.r8, gprs.r9],
+        buffer: match gprs.rcx {
+            0 => None,
+            len => Some(VmcallBuffer {
+                address: gprs.rbx,
+                len,
+            }),
+        },
+        caller: HypercallCaller::current()?,
+    };
+    let result = match dispatch(u32::try_from(gprs.rax).ok(), &arguments) {
+        Some(result) => result,
+        None => return Ok(false),
+    };
+    gprs.rax = match result {
+        Ok(results) => {
+            gprs.rdi = results[0];
+            gprs.rsi = results[1];
+            gprs.rdx = results[2];
+            gprs.r10 = results[3];
+            hypervisor_abi::HYPERCALL_STATUS_SUCCESS
         }
-    }
-    Ok(())
+        Err(e) => e.status(),
+    };
+    Ok(true)
 }
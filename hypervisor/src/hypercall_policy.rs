@@ -0,0 +1,267 @@
+//! Decides who may make each hypercall.
+//!
+//! By default only guest code running at CPL 0 may make hypercalls, so user
+//! space can't reach them unless the loader opts in. The loader can give each
+//! hypercall reason a policy, and reasons without one use the default policy,
+//! which the loader may also replace, e.g. with one requiring nothing. A
+//! policy may require the caller to be running at CPL 0, in a particular
+//! address space named by its CR3, with its RIP inside one of the code ranges
+//! the loader registered, or in an address space which has made the
+//! [handshake](../../hypervisor_abi/constant.HYPERCALL_REASON_HANDSHAKE.html)
+//! hypercall with the secret the loader set.
+//!
+//! A caller which isn't allowed to make a hypercall sees the same thing as if
+//! the hypervisor wasn't loaded, so user space can't probe for privileged
+//! hypercalls: CPUID returns the processor's values and VMCALL raises #UD.
+//! Unknown hypercall reasons use the default policy too, so it should be at
+//! least as strict as any other, or a caller could tell a forbidden reason
+//! from an unknown one.
+use crate::hypercall_handler::{self, HypercallArguments, HypercallResults};
+use crate::vmcs_fields::VmcsField;
+use crate::vmx::vmread;
+use hypervisor_abi::HypercallError;
+use spin::Mutex;
+
+/// The DPL is in bits 6:5 of a segment's access rights.
+/// See the Intel manual, Volume 3, Section 24.4.1 "Guest Register State".
+const ACCESS_RIGHTS_DPL_SHIFT: u64 = 5;
+const ACCESS_RIGHTS_DPL_MASK: u64 = 3;
+/// Bits 3:2 of a code segment's type are both set if it is conforming, in
+/// which case its DPL may be lower than the CPL.
+const ACCESS_RIGHTS_TYPE_CONFORMING_CODE: u64 = 0b1100;
+
+/// Bits 51:12 of CR3 name the address space. The rest hold the PCID and
+/// flags.
+const CR3_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The number of hypercall reasons which can have their own policy.
+const MAX_POLICIES: usize = 32;
+
+/// The number of code ranges which can be registered.
+const MAX_CODE_RANGES: usize = 16;
+
+/// The number of address spaces which can complete a handshake. Once full,
+/// each handshake replaces the oldest.
+const MAX_AUTHENTICATED_ADDRESS_SPACES: usize = 16;
+
+/// Where a hypercall was made from, read from the guest state in the vmcs.
+#[derive(Debug, Clone, Copy)]
+pub struct HypercallCaller {
+    /// The guest's current privilege level.
+    pub cpl: u8,
+    /// The guest's CR3, without the PCID and flags.
+    pub cr3: u64,
+    /// The address of the hypercall instruction.
+    pub rip: u64,
+}
+
+impl HypercallCaller {
+    /// Read the caller of the hypercall which caused the current VM exit. Must
+    /// be called before the guest's RIP is advanced past the hypercall.
+    /// The CPL is the DPL of SS, or of CS if it is a higher non-conforming code
+    /// segment, which may only differ from the CPL if the guest's segments are
+    /// inconsistent.
+    pub fn current() -> Result<Self, x86::vmx::VmFail> {
+        let ss_access_rights = vmread(VmcsField::GuestSsArBytes)?;
+        let cs_access_rights = vmread(VmcsField::GuestCsArBytes)?;
+        let mut cpl = (ss_access_rights >> ACCESS_RIGHTS_DPL_SHIFT) & ACCESS_RIGHTS_DPL_MASK;
+        if cs_access_rights & ACCESS_RIGHTS_TYPE_CONFORMING_CODE
+            != ACCESS_RIGHTS_TYPE_CONFORMING_CODE
+        {
+            cpl = cpl.max((cs_access_rights >> ACCESS_RIGHTS_DPL_SHIFT) & ACCESS_RIGHTS_DPL_MASK);
+        }
+        Ok(HypercallCaller {
+            cpl: cpl as u8,
+            cr3: vmread(VmcsField::GuestCr3)? & CR3_ADDRESS_MASK,
+            rip: vmread(VmcsField::GuestRip)?,
+        })
+    }
+}
+
+/// What a caller needs to make a hypercall. HypercallPolicy::default()
+/// requires nothing, unlike the default policy, which requires CPL 0 until the
+/// loader changes it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HypercallPolicy {
+    /// The caller must be running at CPL 0.
+    pub require_cpl0: bool,
+    /// The caller must be running in this address space.
+    pub cr3: Option<u64>,
+    /// The caller's RIP must be inside a registered code range.
+    pub require_code_range: bool,
+    /// The caller's address space must have completed a handshake.
+    pub require_handshake: bool,
+}
+
+/// The reasons a policy can't be changed.
+#[derive(Debug)]
+pub enum HypercallPolicyError {
+    /// MAX_POLICIES hypercall reasons already have their own policy.
+    TooManyPolicies,
+    /// MAX_CODE_RANGES code ranges have already been registered.
+    TooManyCodeRanges,
+    /// The code range ends before it starts.
+    InvalidCodeRange,
+}
+
+struct AccessControl {
+    default: HypercallPolicy,
+    policies: [Option<(u32, HypercallPolicy)>; MAX_POLICIES],
+    /// The start and end of each code range. The end is exclusive.
+    code_ranges: [Option<(u64, u64)>; MAX_CODE_RANGES],
+    /// The handshake secret, or 0 if handshakes aren't accepted.
+    secret: u64,
+    /// The CR3s of the address spaces which completed a handshake.
+    authenticated: [Option<u64>; MAX_AUTHENTICATED_ADDRESS_SPACES],
+    /// The index in authenticated of the next handshake.
+    next_authenticated: usize,
+}
+
+impl AccessControl {
+    /// The policy for a reason. None is a reason which can't be valid, like a
+    /// VMCALL reason wider than 32 bits, and gets the default policy.
+    fn policy(&self, reason: Option<u32>) -> &HypercallPolicy {
+        self.policies
+            .iter()
+            .flatten()
+            .find(|(policy_reason, _)| Some(*policy_reason) == reason)
+            .map_or(&self.default, |(_, policy)| policy)
+    }
+
+    fn is_authenticated(&self, cr3: u64) -> bool {
+        self.authenticated.contains(&Some(cr3))
+    }
+
+    /// True if the caller meets the policy for reason. The handshake
+    /// hypercall never requires a handshake.
+    fn policy_allows(&self, reason: Option<u32>, caller: &HypercallCaller) -> bool {
+        let policy = self.policy(reason);
+        if policy.require_cpl0 && caller.cpl != 0 {
+            return false;
+        }
+        if let Some(cr3) = policy.cr3 {
+            if cr3 & CR3_ADDRESS_MASK != caller.cr3 {
+                return false;
+            }
+        }
+        if policy.require_code_range
+            && !self
+                .code_ranges
+                .iter()
+                .flatten()
+                .any(|(start, end)| (*start..*end).contains(&caller.rip))
+        {
+            return false;
+        }
+        if policy.require_handshake
+            && reason != Some(hypervisor_abi::HYPERCALL_REASON_HANDSHAKE)
+            && !self.is_authenticated(caller.cr3)
+        {
+            return false;
+        }
+        true
+    }
+}
+
+static ACCESS_CONTROL: Mutex<AccessControl> = Mutex::new(AccessControl {
+    default: HypercallPolicy {
+        require_cpl0: true,
+        cr3: None,
+        require_code_range: false,
+        require_handshake: false,
+    },
+    policies: [None; MAX_POLICIES],
+    code_ranges: [None; MAX_CODE_RANGES],
+    secret: 0,
+    authenticated: [None; MAX_AUTHENTICATED_ADDRESS_SPACES],
+    next_authenticated: 0,
+});
+
+/// Set the policy for a hypercall reason, or the default policy if reason is
+/// None.
+pub fn set_policy(
+    reason: Option<u32>,
+    policy: HypercallPolicy,
+) -> Result<(), HypercallPolicyError> {
+    let mut access_control = ACCESS_CONTROL.lock();
+    let reason = match reason {
+        Some(reason) => reason,
+        None => {
+            access_control.default = policy;
+            return Ok(());
+        }
+    };
+    let slot = match access_control
+        .policies
+        .iter()
+        .position(|slot| matches!(slot, Some((policy_reason, _)) if *policy_reason == reason))
+    {
+        Some(index) => &mut access_control.policies[index],
+        None => access_control
+            .policies
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(HypercallPolicyError::TooManyPolicies)?,
+    };
+    *slot = Some((reason, policy));
+    Ok(())
+}
+
+/// Register a range of guest virtual addresses, from start up to but not
+/// including end, which policies may require hypercalls to be made from.
+pub fn add_code_range(start: u64, end: u64) -> Result<(), HypercallPolicyError> {
+    if end < start {
+        return Err(HypercallPolicyError::InvalidCodeRange);
+    }
+    let mut access_control = ACCESS_CONTROL.lock();
+    let slot = access_control
+        .code_ranges
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(HypercallPolicyError::TooManyCodeRanges)?;
+    *slot = Some((start, end));
+    Ok(())
+}
+
+/// Set the secret the handshake hypercall expects. 0 disables handshakes.
+pub fn set_secret(secret: u64) {
+    ACCESS_CONTROL.lock().secret = secret;
+}
+
+/// True if the caller may make a hypercall. The handshake hypercall is only
+/// allowed with the right secret. A reason of None can't be valid, and is
+/// allowed if the default policy allows the caller, so that they can be told
+/// the reason is unknown.
+pub fn allowed(reason: Option<u32>, arguments: &HypercallArguments) -> bool {
+    let access_control = ACCESS_CONTROL.lock();
+    if !access_control.policy_allows(reason, &arguments.caller) {
+        return false;
+    }
+    reason != Some(hypervisor_abi::HYPERCALL_REASON_HANDSHAKE)
+        || (access_control.secret != 0 && arguments.registers[0] == access_control.secret)
+}
+
+/// True if the caller may see that a hypercall exists, i.e. may make it given
+/// the right arguments.
+pub fn visible(reason: u32, caller: &HypercallCaller) -> bool {
+    let access_control = ACCESS_CONTROL.lock();
+    access_control.policy_allows(Some(reason), caller)
+        && (reason != hypervisor_abi::HYPERCALL_REASON_HANDSHAKE || access_control.secret != 0)
+}
+
+/// Only reached with the right secret, see [allowed](fn.allowed.html).
+fn handshake(arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    let mut access_control = ACCESS_CONTROL.lock();
+    let cr3 = arguments.caller.cr3;
+    if !access_control.is_authenticated(cr3) {
+        let index = access_control.next_authenticated;
+        access_control.authenticated[index] = Some(cr3);
+        access_control.next_authenticated = (index + 1) % MAX_AUTHENTICATED_ADDRESS_SPACES;
+    }
+    Ok([u64::from(hypervisor_abi::HYPERCALL_MAGIC), 0, 0, 0])
+}
+
+/// Register the handshake hypercall.
+pub fn register_hypercalls() -> Result<(), hypercall_handler::RegisterHypercallError> {
+    hypercall_handler::register(hypervisor_abi::HYPERCALL_REASON_HANDSHAKE, handshake)
+}
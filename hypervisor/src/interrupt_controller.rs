@@ -42,12 +42,20 @@ fn check_for_highest_priority_interrupt() -> Option<u64> {
     if interrupt_controller.total_interrupts_count == 0 {
         return None;
     }
+    let task_priority = task_priority();
     for (interrupt_number, count) in &mut interrupt_controller
         .delayed_delivery_interrupts
         .iter_mut()
         .enumerate()
+        .rev()
     {
         if *count > 0 {
+            // Interrupts whose priority class is not above the task priority
+            // are masked. See the Intel manual, Volume 3, Section 10.8.3.1
+            // "Task and Processor Priorities".
+            if (interrupt_number as u64 >> 4) <= task_priority {
+                return None;
+            }
             *count -= 1;
             interrupt_controller.total_interrupts_count -= 1;
             return Some(interrupt_number as u64);
@@ -56,6 +64,26 @@ fn check_for_highest_priority_interrupt() -> Option<u64> {
     unreachable!();
 }
 
+/// The guest's task priority, i.e. the value of its cr8.
+/// The guest's accesses to cr8 don't exit, and VM exits don't change cr8, so
+/// during a VM exit the processor's cr8 is the guest's.
+pub fn task_priority() -> u64 {
+    let cr8: u64;
+    unsafe {
+        asm!("mov {}, cr8", out(reg)(cr8));
+    }
+    cr8
+}
+
+/// Set the guest's task priority in the processor's cr8, which masks
+/// interrupts delivered to the guest by the hardware, as well as those
+/// delivered by the virtual interrupt controller.
+pub fn set_task_priority(task_priority: u64) {
+    unsafe {
+        asm!("mov cr8, {}", in(reg)(task_priority));
+    }
+}
+
 // See 33.3.3.4 Generation of Virtual Interrupt Events by VMM
 fn vmx_is_guest_interruptable() -> bool {
     let guest_rflags = vmread(VmcsField::GuestRFlags).unwrap();
@@ -73,6 +101,38 @@ fn vmx_is_guest_interruptable() -> bool {
 
 const VM_ENTRY_INTERRUPT_INFO_VALID: u64 = 1 << 31;
 const VM_ENTRY_INTERRUPT_INFO_TYPE_EXTERNAL_INTERRUPT: u64 = 0 << 8;
+const VM_ENTRY_INTERRUPT_INFO_TYPE_HARDWARE_EXCEPTION: u64 = 3 << 8;
+const VM_ENTRY_INTERRUPT_INFO_DELIVER_ERROR_CODE: u64 = 1 << 11;
+
+/// The vector of the debug exception, #DB.
+pub const EXCEPTION_VECTOR_DEBUG: u64 = 1;
+
+/// The vector of the breakpoint exception, #BP.
+#[cfg_attr(
+    not(any(feature = "gdb_stub", feature = "host_gdb_stub")),
+    allow(dead_code)
+)]
+pub const EXCEPTION_VECTOR_BREAKPOINT: u64 = 3;
+
+/// The vector of the invalid opcode exception, #UD.
+pub const EXCEPTION_VECTOR_INVALID_OPCODE: u64 = 6;
+
+/// The vector of the general protection fault exception, #GP.
+pub const EXCEPTION_VECTOR_GENERAL_PROTECTION: u64 = 13;
+
+/// Inject a hardware exception into the guest on the next VM entry.
+/// Exceptions which push an error code, like #GP, must be given one.
+/// See the Intel manual, Volume 3, Section 26.6 "Event Injection".
+pub fn inject_exception(vector: u64, error_code: Option<u32>) -> Result<(), x86::vmx::VmFail> {
+    assert!(vector < 32);
+    let mut interrupt_info =
+        vector | VM_ENTRY_INTERRUPT_INFO_VALID | VM_ENTRY_INTERRUPT_INFO_TYPE_HARDWARE_EXCEPTION;
+    if let Some(error_code) = error_code {
+        interrupt_info |= VM_ENTRY_INTERRUPT_INFO_DELIVER_ERROR_CODE;
+        vmwrite(VmcsField::VmEntryExceptIonErrorCode, u64::from(error_code))?;
+    }
+    vmwrite(VmcsField::VmEntryIntrInfoField, interrupt_info)
+}
 fn vmx_inject_interrupt_into_guest(vector: u64) -> Result<(), x86::vmx::VmFail> {
     assert!(vector < INTERRUPT_COUNT as u64);
     let interrupt_info =
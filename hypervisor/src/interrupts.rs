@@ -1,10 +1,24 @@
 //! This module defines various structurs used by the hypervisor to set up the
 //! host Interrupt Descriptor Table and handle interrupts and exceptions received
 //! by the host.
+//!
+//! Every one of the 256 vectors has an entry, and the dispatcher hands each
+//! interrupt to the handler registered for its vector. Interrupts nobody
+//! handles panic.
+//!
+//! NMIs, double faults and machine checks can arrive when the stack can't be
+//! trusted, e.g. after the host stack overflowed, so they switch to a stack
+//! of their own through the Interrupt Stack Table in each core's TSS. The
+//! loaders allocate these stacks; see
+//! [InterruptStack](../segmentation/enum.InterruptStack.html).
+//! An interrupt on an IST stack which is interrupted by another using the same
+//! stack overwrites it, which is why only these three use one.
 use crate::isr;
-use spin::Mutex;
+use crate::nmi::NMI_VECTOR;
+use spin::{Mutex, RwLock};
 
 use crate::register_state::InterruptRegisterState;
+use crate::segmentation::InterruptStack;
 
 #[allow(unused)]
 #[derive(Copy, Clone, Default)]
@@ -12,15 +26,18 @@ use crate::register_state::InterruptRegisterState;
 struct IdtEntry {
     base_low: u16,
     selector: u16,
-    always0: u8,
+    /// The Interrupt Stack Table index, or 0 to stay on the current stack.
+    ist: u8,
     flags: u8,
     base_high: u16,
     base_highest: u32,
     _reserved: u32,
 }
 
-#[derive(Default)]
-pub struct Idt([IdtEntry; 20]);
+/// The number of vectors, and so entries in the IDT.
+pub const VECTOR_COUNT: usize = 256;
+
+pub struct Idt([IdtEntry; VECTOR_COUNT]);
 
 impl IdtEntry {
     const fn new() -> Self {
@@ -28,7 +45,7 @@ impl IdtEntry {
             base_high: 0,
             base_highest: 0,
             base_low: 0,
-            always0: 0,
+            ist: 0,
             flags: 0,
             selector: 0,
             _reserved: 0,
@@ -42,18 +59,71 @@ const IDT_ENTRY_FLAGS_INTERRUPT_GATE: u8 = 0x0e;
 
 impl Idt {
     const fn new() -> Self {
-        Idt([IdtEntry::new(); 20])
+        Idt([IdtEntry::new(); VECTOR_COUNT])
     }
-    fn set_entry(&mut self, num: usize, base: u64, selector: u16, flags: u8) {
+    fn set_entry(&mut self, num: usize, base: u64, selector: u16, flags: u8, ist: u8) {
         self.0[num].base_low = (base & 0xffff) as u16;
         self.0[num].base_high = ((base >> 16) & 0xffff) as u16;
         self.0[num].base_highest = (base >> 32) as u32;
-        self.0[num].always0 = 0;
+        self.0[num].ist = ist;
         self.0[num].selector = selector;
         self.0[num].flags = flags;
     }
 }
 
+/// The vector of the double fault exception, #DF.
+pub const DOUBLE_FAULT_VECTOR: u64 = 8;
+/// The vector of the machine check exception, #MC.
+const MACHINE_CHECK_VECTOR: u64 = 18;
+
+/// The Interrupt Stack Table index of the stack a vector switches to, or 0 to
+/// stay on the current stack.
+fn interrupt_stack_index(vector: u64) -> u8 {
+    let stack = match vector {
+        NMI_VECTOR => InterruptStack::NonMaskableInterrupt,
+        DOUBLE_FAULT_VECTOR => InterruptStack::DoubleFault,
+        MACHINE_CHECK_VECTOR => InterruptStack::MachineCheck,
+        _ => return 0,
+    };
+    stack as u8
+}
+
+/// The names of the architectural exceptions, by vector, for panics.
+const EXCEPTION_NAMES: [&str; 32] = [
+    "divide error",
+    "debug exception",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating point error",
+    "alignment check",
+    "machine check",
+    "SIMD floating point exception",
+    "virtualization exception",
+    "control protection exception",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection exception",
+    "VMM communication exception",
+    "security exception",
+    "reserved",
+];
+
 static IDT: Mutex<Idt> = Mutex::new(Idt::new());
 
 pub fn host_idt_base() -> u64 {
@@ -61,19 +131,70 @@ pub fn host_idt_base() -> u64 {
     idt as u64
 }
 
+/// Handles an interrupt taken by the host. Returns true if it was handled,
+/// in which case the interrupted code is resumed with the register state the
+/// handler left, or false to leave it unhandled.
+pub type InterruptHandler = fn(&mut InterruptRegisterState) -> bool;
+
+/// The handler registered for each vector. Interrupts only ever read this,
+/// with try_read, so that an interrupt taken while a handler is being
+/// registered on the same core can't deadlock.
+static HANDLERS: RwLock<[Option<InterruptHandler>; VECTOR_COUNT]> =
+    RwLock::new([None; VECTOR_COUNT]);
+
+/// The reasons an interrupt handler can't be registered.
+#[derive(Debug)]
+pub enum RegisterInterruptHandlerError {
+    /// The vector is above 255.
+    InvalidVector,
+    /// A handler is already registered for the vector.
+    AlreadyRegistered,
+}
+
+/// Handle interrupts with the given vector on every core.
+pub fn register(
+    vector: u64,
+    handler: InterruptHandler,
+) -> Result<(), RegisterInterruptHandlerError> {
+    let mut handlers = HANDLERS.write();
+    let slot = handlers
+        .get_mut(vector as usize)
+        .ok_or(RegisterInterruptHandlerError::InvalidVector)?;
+    if slot.is_some() {
+        return Err(RegisterInterruptHandlerError::AlreadyRegistered);
+    }
+    *slot = Some(handler);
+    Ok(())
+}
+
 #[no_mangle]
 pub extern "C" fn interrupt_dispatcher(state: &mut InterruptRegisterState) {
-    panic!("Unhandled interrupt {:x?}", state);
+    let handler = HANDLERS
+        .try_read()
+        .and_then(|handlers| handlers.get(state.interrupt_number as usize).copied())
+        .flatten();
+    if let Some(handler) = handler {
+        if handler(state) {
+            return;
+        }
+    }
+    match EXCEPTION_NAMES.get(state.interrupt_number as usize) {
+        Some(name) => panic!("Unhandled {} {:x?}", name, state),
+        None => panic!("Unhandled interrupt {:x?}", state),
+    }
 }
 
+/// Fill in every entry of the host IDT. Called once while loading the
+/// hypervisor.
 pub fn init_interrupt_handlers(cs: u16) {
     let mut idt = IDT.lock();
-    for i in 0..20 {
+    for (i, isr) in isr::ISR.iter().enumerate() {
         idt.set_entry(
             i,
-            isr::ISR[i as usize] as usize as u64,
+            *isr as usize as u64,
             cs,
             IDT_ENTRY_FLAGS_RING_0 | IDT_ENTRY_FLAGS_PRESENT | IDT_ENTRY_FLAGS_INTERRUPT_GATE,
+            interrupt_stack_index(i as u64),
         );
     }
 }
@@ -25,18 +25,45 @@
 //! 1. On each logical core, call [rustyvisor_core_unload](fn.rustyvisor_core_unload.html)
 //! 2. Once globally, call [rustyvisor_unload](fn.rustyvisor_unload.html)
 
-use ::log::{error, info, trace, LevelFilter};
+use ::log::{error, info, trace};
 extern crate hypervisor_abi;
 
+mod backtrace;
+mod channel;
+mod control_registers;
+mod cpuid_policy;
+mod crash_dump;
 mod debug;
+mod debug_registers;
+// Only the panic handler devirtualizes, and it isn't built for tests.
+#[cfg_attr(test, allow(dead_code))]
+mod devirtualize;
+mod exception_table;
+mod exit_stats;
+mod exit_trace;
+#[cfg(feature = "gdb_stub")]
+mod gdb_stub;
+mod guest_memory;
+#[cfg(feature = "host_gdb_stub")]
+mod host_gdb_stub;
+pub mod host_paging;
+mod host_stack;
 mod hypercall_handler;
+mod hypercall_policy;
 pub mod interrupt_controller;
 mod interrupts;
 mod isr;
+mod log_ring;
+mod log_sinks;
 mod msr;
+mod nmi;
 mod panic;
+mod profiler;
 mod register_state;
 pub mod segmentation;
+mod single_step;
+mod snapshot;
+mod timekeeping;
 mod vcpu;
 mod vmcs;
 mod vmcs_dump;
@@ -106,9 +133,12 @@ pub struct VCpu {
     /// True if the hypervisor loaded successfully on this core, false
     /// otherwise.
     pub loaded_successfully: bool,
-    /// The virtual address of the base of the hypervisor host's stack.
+    /// The virtual address of the base of the hypervisor host's stack. The
+    /// hypervisor keeps a canary in the lowest bytes. The page below should
+    /// be unmapped, so that an overflow faults. See
+    /// [host_paging](host_paging/index.html).
     pub stack_base: *mut u8,
-    /// The size of the hypervisor host's stack.
+    /// The size of the hypervisor host's stack. Must be at least a page.
     pub stack_size: usize,
     /// The virtual address of the top of the hypervisor host's stack.
     pub stack_top: *mut u8,
@@ -126,11 +156,45 @@ pub struct VCpu {
     /// support MSR bitmaps.
     /// The backing memory must be zeroed.
     pub msr_bitmap: u64,
+    /// The virtual address of the MSR bitmap. Must back the msr_bitmap
+    /// physical address above.
+    pub msr_bitmap_virt: *mut u8,
     /// The virtual address of the base of the TSS, a mostly vestigal structure
     /// required by the CPU for hardware task switching.
     pub tr_base: u64,
     /// The selector of the TSS segment.
     pub tr_selector: u16,
+    /// The guest's general purpose registers saved by _host_entrypoint during
+    /// the current VM exit, or null outside of a VM exit. Set by the
+    /// hypervisor. Must be initialized to null.
+    pub guest_gprs: *mut register_state::GeneralPurposeRegisterState,
+    /// A copy of the guest's state to be included in crash dumps. Set by the
+    /// hypervisor.
+    pub guest_snapshot: snapshot::GuestSnapshot,
+    /// True if guest_snapshot holds the guest's state, false otherwise.
+    /// Cleared at the start of every VM exit. Must be initialized to false.
+    pub guest_snapshot_valid: bool,
+    /// The guest's debug registers while the hypervisor uses some of the
+    /// hardware breakpoints. Must be initialized as zeroes.
+    pub debug_registers: debug_registers::VirtualDebugRegisters,
+    /// The state of the single step in progress, if any. Must be initialized
+    /// as zeroes.
+    pub single_step: single_step::SingleStep,
+    /// The guest RIP samples taken on this core. Must be initialized with
+    /// Default::default().
+    pub profiler: profiler::Profiler,
+    /// The number of VM exits on this core and how long they took to handle.
+    /// Must be initialized as zeroes, in place.
+    pub exit_stats: exit_stats::ExitStats,
+    /// The most recent VM exits on this core. Must be initialized as zeroes,
+    /// in place.
+    pub exit_trace: exit_trace::ExitTrace,
+    /// The guest's view of the time stamp counter on this core. Must be
+    /// initialized with Default::default().
+    pub timekeeping: timekeeping::VirtualTsc,
+    /// The NMI waiting to be injected into the guest on this core, if any.
+    /// Must be initialized with Default::default().
+    pub nmi: nmi::VirtualNmi,
 }
 
 /// Set up hypervisor global state. Must be one called only once by the loader
@@ -146,15 +210,35 @@ pub struct VCpu {
 /// processor.
 #[no_mangle]
 pub extern "C" fn rustyvisor_load() -> i32 {
-    let logger_result = log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Trace));
-    match logger_result {
-        Ok(()) => {}
-        Err(_) => return -1,
+    if log_sinks::init().is_err() {
+        return -1;
     }
 
     info!("{}", "rustyvisor_load");
 
+    if let Err(e) = register_hypercalls() {
+        error!("Failed to register hypercalls {:?}", e);
+        return -1;
+    }
+
     interrupts::init_interrupt_handlers(x86::segmentation::cs().bits());
+    if let Err(e) = nmi::register_interrupt_handler() {
+        error!("Failed to register the NMI handler {:?}", e);
+        return -1;
+    }
+    if let Err(e) = exception_table::init() {
+        error!("Failed to set up the exception table {:?}", e);
+        return -1;
+    }
+    if let Err(e) = host_stack::register_interrupt_handler() {
+        error!("Failed to register the double fault handler {:?}", e);
+        return -1;
+    }
+
+    #[cfg(feature = "gdb_stub")]
+    gdb_stub::init();
+    #[cfg(feature = "host_gdb_stub")]
+    host_gdb_stub::init();
 
     #[cfg(feature = "runtime_tests")]
     runtime_tests();
@@ -162,6 +246,19 @@ pub extern "C" fn rustyvisor_load() -> i32 {
     0
 }
 
+/// Register every subsystem's hypercalls. Subsystems which add hypercalls
+/// register them here rather than in a central dispatch table.
+fn register_hypercalls() -> Result<(), hypercall_handler::RegisterHypercallError> {
+    hypercall_handler::register_hypercalls()?;
+    hypercall_policy::register_hypercalls()?;
+    profiler::register_hypercalls()?;
+    exit_stats::register_hypercalls()?;
+    exit_trace::register_hypercalls()?;
+    channel::register_hypercalls()?;
+    log_ring::register_hypercalls()?;
+    log_sinks::register_hypercalls()
+}
+
 /// Load the hypervisor on the current logical core.
 /// Enables VMX on the current core and enters vmx guest operation.
 /// After returning, the caller will be running as a VM guest.
@@ -178,17 +275,25 @@ pub extern "C" fn rustyvisor_core_load(data: &VCpu) -> i32 {
         data,
         data as *const VCpu
     );
+    if vcpu::register(data as *const VCpu as *mut VCpu).is_none() {
+        error!("Too many VCpus");
+        return -1;
+    }
+    host_stack::init(data);
+    cpuid_policy::publish();
+
     trace!("Enabling vmx");
-    if vmx::enable(
+    let original_control_registers = match vmx::enable(
         data.vmxon_region,
         data.vmxon_region_phys,
         data.vmxon_region_size,
-    )
-    .is_err()
-    {
-        error!("Failed to enable VMX");
-        return -1;
-    }
+    ) {
+        Ok(original_control_registers) => original_control_registers,
+        Err(_) => {
+            error!("Failed to enable VMX");
+            return -1;
+        }
+    };
     trace!(
         "VCPU in rustyvisor_core_load enable {:x?} {:x?}\r\n",
         data,
@@ -197,7 +302,7 @@ pub extern "C" fn rustyvisor_core_load(data: &VCpu) -> i32 {
 
     trace!("Vmx enabled");
     trace!("Loading vmm {:x?}", data);
-    if vmx::load_vm(data).is_err() {
+    if vmx::load_vm(data, &original_control_registers).is_err() {
         error!("Failed to load VMX");
         return 1;
     }
@@ -220,6 +325,240 @@ pub extern "C" fn rustyvisor_core_unload() {
     vmx::disable();
 }
 
+/// Include a range of guest physical memory in the ELF core file written when
+/// the guest dies or the hypervisor panics. The loader chooses which ranges
+/// are worth the time it takes to stream them over the serial port.
+/// guest_phys is the guest physical address of the range, and host_virt is a
+/// virtual address through which the hypervisor can read it.
+/// Returns 0 on success, or -1 if the range is invalid or too many ranges
+/// have been added.
+#[no_mangle]
+pub extern "C" fn rustyvisor_crash_dump_add_range(
+    guest_phys: u64,
+    host_virt: *const u8,
+    size: u64,
+) -> i32 {
+    let range = crash_dump::MemoryRange {
+        guest_phys,
+        host_virt: host_virt as u64,
+        size,
+    };
+    match crash_dump::add_memory_range(range) {
+        Ok(()) => 0,
+        Err(()) => -1,
+    }
+}
+
+/// Sample where the guest is running every period TSC ticks on every core the
+/// hypervisor is loaded on afterwards. Must be called before
+/// rustyvisor_core_load. A period of 0, the default, disables profiling.
+/// The samples are drained with the
+/// [HYPERCALL_REASON_PROFILE_SAMPLE](../hypervisor_abi/constant.HYPERCALL_REASON_PROFILE_SAMPLE.html)
+/// hypercall.
+#[no_mangle]
+pub extern "C" fn rustyvisor_profiler_set_period(period: u64) {
+    profiler::set_period(period);
+}
+
+/// Configure how the guest sees the time stamp counter on every core the
+/// hypervisor is loaded on afterwards. Must be called before
+/// rustyvisor_core_load.
+/// If compensated is true, RDTSC and RDTSCP exit and the time the hypervisor
+/// spends handling VM exits is hidden from the guest. If tsc_multiplier is
+/// not 0, the guest's TSC runs at the host's rate times tsc_multiplier / 2^48
+/// on processors which support TSC scaling. By default neither is enabled.
+#[no_mangle]
+pub extern "C" fn rustyvisor_timekeeping_configure(compensated: bool, tsc_multiplier: u64) {
+    timekeeping::configure(compensated, tsc_multiplier);
+}
+
+/// Add entries to the CPUID policy applied on every core, see the
+/// [cpuid_policy](cpuid_policy/index.html) module for the format. policy
+/// points to len bytes of UTF-8 text. Must be called before
+/// rustyvisor_core_load. Returns 0 on success, or -1 if the policy is invalid,
+/// in which case none of it is applied.
+///
+/// # Safety
+/// policy must point to len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rustyvisor_cpuid_add_policy(policy: *const u8, len: usize) -> i32 {
+    let policy = match core::str::from_utf8(core::slice::from_raw_parts(policy, len)) {
+        Ok(policy) => policy,
+        Err(_) => {
+            error!("CPUID policy isn't valid UTF-8");
+            return -1;
+        }
+    };
+    match cpuid_policy::add_policy(policy) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("Invalid CPUID policy: {:?}", e);
+            -1
+        }
+    }
+}
+
+/// Report the hypervisor's vendor signature and interface version in the
+/// CPUID leaves starting at
+/// [CPUID_LEAF_HYPERVISOR_BASE](../hypervisor_abi/constant.CPUID_LEAF_HYPERVISOR_BASE.html),
+/// and set the hypervisor present bit. Must be called before
+/// rustyvisor_core_load. By default the hypervisor hides itself.
+#[no_mangle]
+pub extern "C" fn rustyvisor_cpuid_set_hypervisor_leaves(enabled: bool) {
+    cpuid_policy::set_hypervisor_leaves(enabled);
+}
+
+/// Require the caller of a hypercall to be running at CPL 0. See
+/// [rustyvisor_hypercall_set_policy](fn.rustyvisor_hypercall_set_policy.html).
+pub const HYPERCALL_POLICY_REQUIRE_CPL0: u32 = 1 << 0;
+/// Require the caller of a hypercall to be running with the given CR3.
+pub const HYPERCALL_POLICY_REQUIRE_CR3: u32 = 1 << 1;
+/// Require the caller of a hypercall to be running code inside a range added
+/// with [rustyvisor_hypercall_add_code_range](fn.rustyvisor_hypercall_add_code_range.html).
+pub const HYPERCALL_POLICY_REQUIRE_CODE_RANGE: u32 = 1 << 2;
+/// Require the caller of a hypercall to be running in an address space which
+/// has made the handshake hypercall with the secret given to
+/// [rustyvisor_hypercall_set_secret](fn.rustyvisor_hypercall_set_secret.html).
+pub const HYPERCALL_POLICY_REQUIRE_HANDSHAKE: u32 = 1 << 3;
+/// The reason passed to
+/// [rustyvisor_hypercall_set_policy](fn.rustyvisor_hypercall_set_policy.html)
+/// to set the default policy, which applies to every hypercall without its
+/// own.
+pub const HYPERCALL_POLICY_DEFAULT: u32 = u32::MAX;
+
+/// Restrict who may make a hypercall, or every hypercall without its own
+/// policy if reason is HYPERCALL_POLICY_DEFAULT. flags is a combination of the
+/// HYPERCALL_POLICY_REQUIRE flags, and cr3 is only used with
+/// HYPERCALL_POLICY_REQUIRE_CR3. By default only callers running at CPL 0 may
+/// make hypercalls. A default policy of 0 lets anyone make any hypercall.
+/// Callers who aren't allowed to make a hypercall see the processor's CPUID
+/// values, or a #UD for VMCALL, as if the hypervisor wasn't loaded. See the
+/// [hypercall_policy](hypercall_policy/index.html) module.
+/// Returns 0 on success, or -1 if flags has unknown bits or too many
+/// hypercalls have their own policy.
+#[no_mangle]
+pub extern "C" fn rustyvisor_hypercall_set_policy(reason: u32, flags: u32, cr3: u64) -> i32 {
+    let known_flags = HYPERCALL_POLICY_REQUIRE_CPL0
+        | HYPERCALL_POLICY_REQUIRE_CR3
+        | HYPERCALL_POLICY_REQUIRE_CODE_RANGE
+        | HYPERCALL_POLICY_REQUIRE_HANDSHAKE;
+    if flags & !known_flags != 0 {
+        error!("Unknown hypercall policy flags {:x}", flags);
+        return -1;
+    }
+    let policy = hypercall_policy::HypercallPolicy {
+        require_cpl0: flags & HYPERCALL_POLICY_REQUIRE_CPL0 != 0,
+        cr3: if flags & HYPERCALL_POLICY_REQUIRE_CR3 != 0 {
+            Some(cr3)
+        } else {
+            None
+        },
+        require_code_range: flags & HYPERCALL_POLICY_REQUIRE_CODE_RANGE != 0,
+        require_handshake: flags & HYPERCALL_POLICY_REQUIRE_HANDSHAKE != 0,
+    };
+    let reason = if reason == HYPERCALL_POLICY_DEFAULT {
+        None
+    } else {
+        Some(reason)
+    };
+    match hypercall_policy::set_policy(reason, policy) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("Failed to set hypercall policy: {:?}", e);
+            -1
+        }
+    }
+}
+
+/// Add a range of guest virtual addresses, from start up to but not including
+/// end, which hypercalls with HYPERCALL_POLICY_REQUIRE_CODE_RANGE may be made
+/// from. Returns 0 on success, or -1 if the range is invalid or too many
+/// ranges have been added.
+#[no_mangle]
+pub extern "C" fn rustyvisor_hypercall_add_code_range(start: u64, end: u64) -> i32 {
+    match hypercall_policy::add_code_range(start, end) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("Failed to add hypercall code range: {:?}", e);
+            -1
+        }
+    }
+}
+
+/// Set the secret the
+/// [HYPERCALL_REASON_HANDSHAKE](../hypervisor_abi/constant.HYPERCALL_REASON_HANDSHAKE.html)
+/// hypercall expects. 0, the default, rejects every handshake.
+#[no_mangle]
+pub extern "C" fn rustyvisor_hypercall_set_secret(secret: u64) {
+    hypercall_policy::set_secret(secret);
+}
+
+/// The loader's console, COM1 under UEFI or the kernel log under Linux. See
+/// [rustyvisor_log_add_filters](fn.rustyvisor_log_add_filters.html).
+pub const LOG_SINK_CONSOLE: u32 = hypervisor_abi::LOG_SINK_CONSOLE;
+/// The in-memory log the guest can read with a hypercall.
+pub const LOG_SINK_MEMORY: u32 = hypervisor_abi::LOG_SINK_MEMORY;
+/// I/O port 0xe9, which Bochs and QEMU can print.
+pub const LOG_SINK_DEBUG_PORT: u32 = hypervisor_abi::LOG_SINK_DEBUG_PORT;
+
+/// Set which log records a sink keeps, see the
+/// [log_sinks](log_sinks/index.html) module for the format of directives.
+/// directives points to len bytes of UTF-8 text. By default the console and
+/// in-memory log keep every record and the debug port keeps none. Returns 0
+/// on success, or -1 if the sink or directives are invalid, in which case
+/// none of them are applied.
+///
+/// # Safety
+/// directives must point to len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rustyvisor_log_add_filters(
+    sink: u32,
+    directives: *const u8,
+    len: usize,
+) -> i32 {
+    let directives = match core::str::from_utf8(core::slice::from_raw_parts(directives, len)) {
+        Ok(directives) => directives,
+        Err(_) => {
+            error!("Log directives aren't valid UTF-8");
+            return -1;
+        }
+    };
+    match log_sinks::FAN_OUT_LOGGER.add_filters(sink as usize, directives) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("Invalid log directives for sink {}: {:?}", sink, e);
+            -1
+        }
+    }
+}
+
+/// Make a log sink write records in binary form, to be decoded with
+/// scripts/log_decode.py, or as text, the default. Only the debug port, and
+/// the console under UEFI, can write binary. Returns 0 on success, or -1 if
+/// the sink can't.
+#[no_mangle]
+pub extern "C" fn rustyvisor_log_set_binary(sink: u32, binary: bool) -> i32 {
+    match log_sinks::FAN_OUT_LOGGER.set_binary(sink as usize, binary) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!(
+                "Can't change log sink {} to binary {}: {:?}",
+                sink, binary, e
+            );
+            -1
+        }
+    }
+}
+
+/// Hand every core back to the guest instead of halting when the hypervisor
+/// panics while handling a VM exit, so the machine keeps running without the
+/// hypervisor. See the [devirtualize](devirtualize/index.html) module for
+/// when a core can't be handed back. Off by default.
+#[no_mangle]
+pub extern "C" fn rustyvisor_set_devirtualize_on_panic(enabled: bool) {
+    devirtualize::set_enabled(enabled);
+}
+
 /// Tear down hypervisor global state.
 /// This function must be called at most once per load of the hypervisor and in
 /// the following sequence of calls:
@@ -0,0 +1,170 @@
+//! Keeps the hypervisor's most recent log messages in memory, so they can be
+//! read on machines without a serial port.
+//!
+//! Every core logs into one ring of LOG_RING_RECORDS
+//! [LogRecords](../../hypervisor_abi/struct.LogRecord.html) without taking a
+//! lock, so logging can't deadlock even in NMI or panic context. A writer
+//! claims the next sequence number with an atomic increment, which picks its
+//! slot, and the slot's stamp works like a seqlock: it is odd while the
+//! record is being written and even once it is complete, and encodes the
+//! sequence number so that readers can tell a record they want from one which
+//! has overwritten it. The oldest records are overwritten once the ring is
+//! full.
+//!
+//! The guest pages the log out with the
+//! [HYPERCALL_REASON_LOG](../../hypervisor_abi/constant.HYPERCALL_REASON_LOG.html)
+//! hypercall, passing back the sequence number each call returns so that
+//! nothing is read twice.
+use core::fmt::Write;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use crate::hypercall_handler::{self, HypercallArguments, HypercallResults};
+use crate::log_sinks::{LogOrigin, LogSink, Message, TruncatingWriter};
+use hypervisor_abi::{
+    HypercallError, LogRecord, LOG_RECORD_SIZE, LOG_RECORD_TEXT_SIZE, LOG_RING_RECORDS,
+};
+
+const RECORD_WORDS: usize = LOG_RECORD_SIZE / 8;
+
+struct Slot {
+    /// 0 if the slot is empty, 2n+1 while the record with sequence number n
+    /// is being written, and 2n+2 once it is complete.
+    stamp: AtomicU64,
+    /// The record in its binary layout. Atomic so that readers racing with a
+    /// writer read stale data rather than undefined behavior.
+    words: [AtomicU64; RECORD_WORDS],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_WORD: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    stamp: AtomicU64::new(0),
+    words: [EMPTY_WORD; RECORD_WORDS],
+};
+
+/// A lock-free, multiple producer ring of log records.
+pub struct LogRing {
+    slots: [Slot; LOG_RING_RECORDS],
+    /// The sequence number the next record will have.
+    next: AtomicU64,
+}
+
+/// The hypervisor's in-memory log.
+pub static LOG_RING: LogRing = LogRing {
+    slots: [EMPTY_SLOT; LOG_RING_RECORDS],
+    next: AtomicU64::new(0),
+};
+
+impl LogRing {
+    /// The sequence number of the oldest record which may still be in the
+    /// ring.
+    fn oldest(&self, next: u64) -> u64 {
+        next.saturating_sub(LOG_RING_RECORDS as u64)
+    }
+
+    fn push(&self, origin: &LogOrigin, level: log::Level, args: &core::fmt::Arguments) {
+        let mut text = [0; LOG_RECORD_TEXT_SIZE];
+        let mut writer = TruncatingWriter::new(&mut text);
+        // A message which doesn't fit is truncated.
+        let _ = writer.write_fmt(*args);
+        let len = writer.len();
+
+        let sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        let record = LogRecord {
+            sequence,
+            level: level as u8,
+            len: len as u8,
+            cpu: origin.cpu,
+            tsc: origin.tsc,
+            text,
+        };
+        let slot = &self.slots[(sequence % LOG_RING_RECORDS as u64) as usize];
+        // If a writer with a later sequence number already claimed the slot,
+        // this record has been overwritten before it was written.
+        if slot.stamp.fetch_max(2 * sequence + 1, Ordering::Relaxed) > 2 * sequence + 1 {
+            return;
+        }
+        fence(Ordering::Release);
+        for (word, bytes) in slot.words.iter().zip(record.to_bytes().chunks_exact(8)) {
+            let mut value = [0; 8];
+            value.copy_from_slice(bytes);
+            word.store(u64::from_le_bytes(value), Ordering::Relaxed);
+        }
+        let _ = slot.stamp.compare_exchange(
+            2 * sequence + 1,
+            2 * sequence + 2,
+            Ordering::Release,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Read the record with a sequence number. Returns Err(true) if it hasn't
+    /// been completely written yet, or Err(false) if it has been overwritten.
+    fn read(&self, sequence: u64) -> Result<[u8; LOG_RECORD_SIZE], bool> {
+        let slot = &self.slots[(sequence % LOG_RING_RECORDS as u64) as usize];
+        let complete = 2 * sequence + 2;
+        let stamp = slot.stamp.load(Ordering::Acquire);
+        if stamp != complete {
+            return Err(stamp < complete);
+        }
+        let mut bytes = [0; LOG_RECORD_SIZE];
+        for (word, bytes) in slot.words.iter().zip(bytes.chunks_exact_mut(8)) {
+            bytes.copy_from_slice(&word.load(Ordering::Relaxed).to_le_bytes());
+        }
+        fence(Ordering::Acquire);
+        if slot.stamp.load(Ordering::Relaxed) != complete {
+            return Err(false);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Records are always kept in their binary layout.
+impl LogSink for LogRing {
+    fn log(&self, origin: &LogOrigin, record: &log::Record, _binary: bool) {
+        self.push(origin, record.level(), &format_args!("{}", Message(record)));
+    }
+}
+
+/// Write as many records as fit in the hypercall's buffer, starting with the
+/// oldest whose sequence number is at least first.
+fn records_to_buffer(
+    first: u64,
+    buffer: hypervisor_abi::VmcallBuffer,
+) -> Result<HypercallResults, HypercallError> {
+    let capacity = buffer.len / LOG_RECORD_SIZE as u64;
+    let next = LOG_RING.next.load(Ordering::Relaxed);
+    let mut sequence = first.max(LOG_RING.oldest(next));
+    let mut count = 0;
+    while count < capacity && sequence < next {
+        match LOG_RING.read(sequence) {
+            Ok(record) => {
+                let offset = count * LOG_RECORD_SIZE as u64;
+                hypercall_handler::write_buffer(&buffer, offset, &record)?;
+                count += 1;
+            }
+            // Stop at a record which is still being written, so that it is
+            // read by the next call.
+            Err(true) => break,
+            Err(false) => {}
+        }
+        sequence += 1;
+    }
+    Ok([count, sequence, 0, 0])
+}
+
+fn log_hypercall(arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    match arguments.buffer {
+        Some(buffer) => records_to_buffer(arguments.registers[0], buffer),
+        None => {
+            let next = LOG_RING.next.load(Ordering::Relaxed);
+            Ok([LOG_RING.oldest(next), next, LOG_RECORD_SIZE as u64, 0])
+        }
+    }
+}
+
+/// Register the log hypercall.
+pub fn register_hypercalls() -> Result<(), hypercall_handler::RegisterHypercallError> {
+    hypercall_handler::register(hypervisor_abi::HYPERCALL_REASON_LOG, log_hypercall)
+}
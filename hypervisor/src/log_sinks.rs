@@ -0,0 +1,847 @@
+//! Fans every log record out to the hypervisor's log sinks: the loader's
+//! console, the [in-memory log](../log_ring/index.html), and the debug port
+//! Bochs and QEMU print.
+//!
+//! Each sink keeps records up to its own level. A filter gives one log
+//! target, i.e. a module like `hypervisor::interrupt_controller`, and the
+//! modules under it a different level on one sink, and the most specific
+//! filter wins. The loader sets the initial filters with directives separated
+//! by commas, each either a level, which sets the sink's level, or
+//! `target=level`, e.g. `info,hypervisor::interrupt_controller=trace`. The
+//! levels are off, error, warn, info, debug and trace. Both can be changed
+//! while the hypervisor runs with the
+//! [HYPERCALL_REASON_LOG_LEVEL](../../hypervisor_abi/constant.HYPERCALL_REASON_LOG_LEVEL.html)
+//! hypercall, so that one module can be traced without rebuilding.
+//!
+//! Logging never waits for the filters: a record logged while another core
+//! is changing them is filtered with the sinks' levels alone.
+//!
+//! Every record is stamped with the host's TSC and the index of the vCPU
+//! which logged it, and each sink numbers the records it keeps, so that lines
+//! from different cores can be put back in order and lost records noticed.
+//! The console and debug port write records as text, or as a
+//! [LogFrameHeader](../../hypervisor_abi/struct.LogFrameHeader.html) followed
+//! by the record's target, message and arguments if the loader asks for
+//! binary, which leaves formatting to scripts/log_decode.py. In binary, a
+//! message is written as its format string and the log crate's key-value
+//! arguments, e.g. `trace!(key = value; "message")`, are encoded
+//! rather than formatted, so that logging on hot paths stays cheap. Only
+//! messages which interpolate values inline, and values with no primitive
+//! form, are formatted by the hypervisor. As text, key-value arguments follow
+//! the message as `key=value`, with unsigned integers in hex.
+use core::fmt::Write;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::hypercall_handler::{self, HypercallArguments, HypercallResults};
+use crate::log_ring;
+use crate::msr::{rdmsrl, Msr};
+use crate::vcpu;
+use hypervisor_abi::{
+    HypercallError, LogFrameHeader, LOG_ARGUMENT_BOOL, LOG_ARGUMENT_CHAR, LOG_ARGUMENT_F64,
+    LOG_ARGUMENT_I128, LOG_ARGUMENT_I64, LOG_ARGUMENT_NONE, LOG_ARGUMENT_TEXT, LOG_ARGUMENT_U128,
+    LOG_ARGUMENT_U64, LOG_CPU_UNKNOWN, LOG_LEVEL_INHERIT, LOG_TARGET_MAX_SIZE,
+};
+use log::kv::{self, Key, Value, VisitSource, VisitValue};
+use log::{info, LevelFilter, Log};
+use spin::{Mutex, RwLock};
+
+const SINKS: usize = hypervisor_abi::LOG_SINKS as usize;
+
+/// The number of target filters which can be set across every sink.
+const MAX_TARGET_FILTERS: usize = 16;
+
+/// Bochs and QEMU print bytes written to this port.
+const DEBUG_PORT: u16 = 0xe9;
+
+/// The longest message a binary record holds. Longer messages are truncated.
+const FRAME_MESSAGE_SIZE: usize = 256;
+/// The most bytes of encoded arguments a binary record holds. Arguments which
+/// don't fit are dropped.
+const FRAME_ARGUMENTS_SIZE: usize = 256;
+
+/// The reasons a sink's level or filters can't be changed.
+#[derive(Debug)]
+pub enum LogFilterError {
+    /// There is no sink with this number.
+    InvalidSink,
+    /// The level isn't one of the log crate's levels.
+    InvalidLevel,
+    /// The target is empty or longer than LOG_TARGET_MAX_SIZE bytes.
+    InvalidTarget,
+    /// MAX_TARGET_FILTERS filters are already set.
+    TooManyFilters,
+    /// The sink can't write records in binary form.
+    BinaryUnsupported,
+}
+
+/// Where and when a record was logged, found once for every sink.
+#[derive(Debug, Clone, Copy)]
+pub struct LogOrigin {
+    /// The host's TSC.
+    pub tsc: u64,
+    /// The index of the current vCPU, or LOG_CPU_UNKNOWN outside hypervisor
+    /// host context.
+    pub cpu: u16,
+}
+
+impl LogOrigin {
+    fn current() -> Self {
+        // Reading the fs base never exits, unlike CPUID, so this can't recurse
+        // into the VM exit handlers when the guest logs.
+        let fs_base = rdmsrl(Msr::Ia32FsBase) as *const crate::VCpu;
+        LogOrigin {
+            tsc: unsafe { core::arch::x86_64::_rdtsc() },
+            cpu: vcpu::index_of(fs_base).map_or(LOG_CPU_UNKNOWN, |index| index as u16),
+        }
+    }
+}
+
+impl core::fmt::Display for LogOrigin {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if self.cpu == LOG_CPU_UNKNOWN {
+            write!(f, "{:x} cpu-", self.tsc)
+        } else {
+            write!(f, "{:x} cpu{}", self.tsc, self.cpu)
+        }
+    }
+}
+
+/// Formats into a fixed buffer, truncating at a character boundary once it
+/// is full.
+pub struct TruncatingWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> TruncatingWriter<'a> {
+    /// Format into buffer.
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        TruncatingWriter { buffer, len: 0 }
+    }
+
+    /// The number of bytes written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl<'a> Write for TruncatingWriter<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            let mut encoded = [0; 4];
+            let encoded = c.encode_utf8(&mut encoded).as_bytes();
+            let end = self.len + encoded.len();
+            if end > self.buffer.len() {
+                return Err(core::fmt::Error);
+            }
+            self.buffer[self.len..end].copy_from_slice(encoded);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+/// One of the places log records go.
+pub trait LogSink: Sync {
+    /// Write a record the sink's filters keep, in binary form if binary is
+    /// true.
+    fn log(&self, origin: &LogOrigin, record: &log::Record, binary: bool);
+
+    /// True if the sink can write records in binary form.
+    fn supports_binary(&self) -> bool {
+        false
+    }
+
+    /// Write out any records the sink buffers.
+    fn flush(&self) {}
+}
+
+/// Formats a record's message followed by its key-value arguments, see the
+/// [module](index.html) documentation.
+pub struct Message<'a>(pub &'a log::Record<'a>);
+
+impl<'a> core::fmt::Display for Message<'a> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.0.args())?;
+        self.0
+            .key_values()
+            .visit(&mut TextArguments(f))
+            .map_err(|_| core::fmt::Error)
+    }
+}
+
+struct TextArguments<'a, 'b>(&'a mut core::fmt::Formatter<'b>);
+
+impl<'a, 'b, 'kvs> VisitSource<'kvs> for TextArguments<'a, 'b> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        write!(self.0, " {}=", key)?;
+        value.visit(self)
+    }
+}
+
+impl<'a, 'b, 'v> VisitValue<'v> for TextArguments<'a, 'b> {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        Ok(write!(self.0, "{}", value)?)
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        Ok(write!(self.0, "{:#x}", value)?)
+    }
+
+    fn visit_u128(&mut self, value: u128) -> Result<(), kv::Error> {
+        Ok(write!(self.0, "{:#x}", value)?)
+    }
+}
+
+/// Encodes a record's key-value arguments for a binary record, see
+/// [LogFrameHeader](../../hypervisor_abi/struct.LogFrameHeader.html).
+struct BinaryArguments {
+    bytes: [u8; FRAME_ARGUMENTS_SIZE],
+    len: usize,
+}
+
+impl BinaryArguments {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), kv::Error> {
+        let end = self.len + bytes.len();
+        if end > self.bytes.len() {
+            return Err(kv::Error::msg("Log arguments don't fit in the record"));
+        }
+        self.bytes[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn push_value(&mut self, kind: u8, value: &[u8]) -> Result<(), kv::Error> {
+        self.push(&[kind])?;
+        self.push(value)
+    }
+
+    fn push_pair(&mut self, key: Key, value: Value) -> Result<(), kv::Error> {
+        let key = key.as_str().as_bytes();
+        let key = &key[..key.len().min(u8::MAX as usize)];
+        self.push(&[key.len() as u8])?;
+        self.push(key)?;
+        value.visit(self)
+    }
+}
+
+impl<'kvs> VisitSource<'kvs> for BinaryArguments {
+    /// An argument which doesn't fit is dropped, along with the rest.
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let start = self.len;
+        let result = self.push_pair(key, value);
+        if result.is_err() {
+            self.len = start;
+        }
+        result
+    }
+}
+
+impl<'v> VisitValue<'v> for BinaryArguments {
+    /// Values with no primitive form are formatted, truncated to fit.
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        self.push(&[LOG_ARGUMENT_TEXT, 0, 0])?;
+        let start = self.len;
+        let mut writer = TruncatingWriter::new(&mut self.bytes[start..]);
+        let _ = write!(writer, "{}", value);
+        let len = writer.len();
+        self.bytes[start - 2..start].copy_from_slice(&(len as u16).to_le_bytes());
+        self.len += len;
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.push(&[LOG_ARGUMENT_NONE])
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.push_value(LOG_ARGUMENT_U64, &value.to_le_bytes())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.push_value(LOG_ARGUMENT_I64, &value.to_le_bytes())
+    }
+
+    fn visit_u128(&mut self, value: u128) -> Result<(), kv::Error> {
+        self.push_value(LOG_ARGUMENT_U128, &value.to_le_bytes())
+    }
+
+    fn visit_i128(&mut self, value: i128) -> Result<(), kv::Error> {
+        self.push_value(LOG_ARGUMENT_I128, &value.to_le_bytes())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.push_value(LOG_ARGUMENT_F64, &value.to_le_bytes())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.push_value(LOG_ARGUMENT_BOOL, &[value as u8])
+    }
+
+    fn visit_char(&mut self, value: char) -> Result<(), kv::Error> {
+        self.push_value(LOG_ARGUMENT_CHAR, &(value as u32).to_le_bytes())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        let len = value.len().min(u16::MAX as usize);
+        self.push_value(LOG_ARGUMENT_TEXT, &(len as u16).to_le_bytes())?;
+        self.push(&value.as_bytes()[..len])
+    }
+}
+
+/// Encode a record in binary form, and pass the header, target, message and
+/// arguments to write.
+fn write_frame(
+    sequence: u64,
+    origin: &LogOrigin,
+    record: &log::Record,
+    write: impl FnOnce(&[&[u8]]),
+) {
+    let mut formatted = [0; FRAME_MESSAGE_SIZE];
+    // Only a format string which interpolates values inline is formatted.
+    let message = match record.args().as_str() {
+        Some(message) if message.len() <= FRAME_MESSAGE_SIZE => message.as_bytes(),
+        _ => {
+            let mut writer = TruncatingWriter::new(&mut formatted);
+            // A message which doesn't fit is truncated.
+            let _ = write!(writer, "{}", record.args());
+            let len = writer.len();
+            &formatted[..len]
+        }
+    };
+    let mut arguments = BinaryArguments {
+        bytes: [0; FRAME_ARGUMENTS_SIZE],
+        len: 0,
+    };
+    // Arguments which don't fit are dropped.
+    let _ = record.key_values().visit(&mut arguments);
+    let target = record.target().as_bytes();
+    let target = &target[..target.len().min(u8::MAX as usize)];
+    let header = LogFrameHeader {
+        len: message.len() as u16,
+        level: record.level() as u8,
+        target_len: target.len() as u8,
+        sequence,
+        tsc: origin.tsc,
+        cpu: origin.cpu,
+        args_len: arguments.len as u16,
+    };
+    write(&[
+        &header.to_bytes(),
+        target,
+        message,
+        &arguments.bytes[..arguments.len],
+    ]);
+}
+
+#[cfg(target_os = "uefi")]
+fn write_console_chunks(chunks: &[&[u8]]) {
+    crate::LOGGER.write_chunks(chunks);
+}
+
+/// Never called, since set_binary doesn't allow binary on the kernel log.
+#[cfg(not(target_os = "uefi"))]
+fn write_console_chunks(_chunks: &[&[u8]]) {}
+
+/// The loader's console, i.e. LOGGER.
+struct ConsoleSink {
+    sequence: AtomicU64,
+}
+
+impl LogSink for ConsoleSink {
+    fn log(&self, origin: &LogOrigin, record: &log::Record, binary: bool) {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        if binary {
+            write_frame(sequence, origin, record, write_console_chunks);
+            return;
+        }
+        crate::LOGGER.log(
+            &log::Record::builder()
+                .level(record.level())
+                .target(record.target())
+                .args(format_args!(
+                    "[{} {}] {}",
+                    sequence,
+                    origin,
+                    Message(record)
+                ))
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        crate::LOGGER.flush();
+    }
+
+    /// The kernel log only takes text.
+    fn supports_binary(&self) -> bool {
+        cfg!(target_os = "uefi")
+    }
+}
+
+/// The debug port. Writes without waiting for other cores for long, like
+/// UnsynchronizedUartLogger, so records may be jumbled together.
+struct DebugPortSink {
+    sequence: AtomicU64,
+    lock: Mutex<()>,
+}
+
+struct DebugPort;
+
+impl Write for DebugPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        DebugPort::write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl DebugPort {
+    fn write_bytes(bytes: &[u8]) {
+        for byte in bytes {
+            unsafe { x86::io::outb(DEBUG_PORT, *byte) };
+        }
+    }
+}
+
+impl LogSink for DebugPortSink {
+    fn log(&self, origin: &LogOrigin, record: &log::Record, binary: bool) {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        // Keep records from being jumbled unless another core holds the lock
+        // for too long, e.g. because it panicked while holding it.
+        let _guard = (0..0x1000).find_map(|_| self.lock.try_lock());
+        if binary {
+            write_frame(sequence, origin, record, |chunks| {
+                chunks
+                    .iter()
+                    .for_each(|chunk| DebugPort::write_bytes(chunk))
+            });
+        } else {
+            let _ = write!(
+                DebugPort,
+                "{}: [{} {}] {}\r\n",
+                record.level(),
+                sequence,
+                origin,
+                Message(record)
+            );
+        }
+    }
+
+    fn supports_binary(&self) -> bool {
+        true
+    }
+}
+
+static CONSOLE_SINK: ConsoleSink = ConsoleSink {
+    sequence: AtomicU64::new(0),
+};
+
+static DEBUG_PORT_SINK: DebugPortSink = DebugPortSink {
+    sequence: AtomicU64::new(0),
+    lock: Mutex::new(()),
+};
+
+/// The sink for each LOG_SINK number.
+static SINKS_BY_NUMBER: [&dyn LogSink; SINKS] =
+    [&CONSOLE_SINK, &log_ring::LOG_RING, &DEBUG_PORT_SINK];
+
+#[derive(Debug, Clone, Copy)]
+struct TargetFilter {
+    sink: usize,
+    level: LevelFilter,
+    target: [u8; LOG_TARGET_MAX_SIZE],
+    len: usize,
+}
+
+impl TargetFilter {
+    fn target(&self) -> &[u8] {
+        &self.target[..self.len]
+    }
+
+    /// True for records from the filter's target and the modules under it.
+    fn matches(&self, target: &str) -> bool {
+        let target = target.as_bytes();
+        target.starts_with(self.target())
+            && (target.len() == self.len || target[self.len..].starts_with(b"::"))
+    }
+}
+
+/// Sends each record to every sink whose level, or filter for the record's
+/// target, keeps it.
+pub struct FanOutLogger {
+    /// Each sink's level, as a LevelFilter.
+    levels: [AtomicUsize; SINKS],
+    /// Whether each sink writes records in binary form.
+    binary: [AtomicBool; SINKS],
+    filters: RwLock<[Option<TargetFilter>; MAX_TARGET_FILTERS]>,
+}
+
+/// The logger the hypervisor installs.
+pub static FAN_OUT_LOGGER: FanOutLogger = FanOutLogger {
+    levels: [
+        AtomicUsize::new(LevelFilter::Trace as usize),
+        AtomicUsize::new(LevelFilter::Trace as usize),
+        AtomicUsize::new(LevelFilter::Off as usize),
+    ],
+    binary: [
+        AtomicBool::new(false),
+        AtomicBool::new(false),
+        AtomicBool::new(false),
+    ],
+    filters: RwLock::new([None; MAX_TARGET_FILTERS]),
+};
+
+fn level_filter(level: usize) -> Option<LevelFilter> {
+    match level {
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        5 => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// Split a directive into its target, if it has one, and level.
+fn parse_directive(directive: &str) -> Result<(Option<&str>, LevelFilter), LogFilterError> {
+    let (target, level) = match directive.rfind('=') {
+        Some(index) => (Some(directive[..index].trim()), &directive[index + 1..]),
+        None => (None, directive),
+    };
+    let level = LevelFilter::from_str(level.trim()).map_err(|_| LogFilterError::InvalidLevel)?;
+    match target {
+        Some(target) if target.is_empty() || target.len() > LOG_TARGET_MAX_SIZE => {
+            Err(LogFilterError::InvalidTarget)
+        }
+        _ => Ok((target, level)),
+    }
+}
+
+impl FanOutLogger {
+    fn sink_level(&self, sink: usize) -> LevelFilter {
+        level_filter(self.levels[sink].load(Ordering::Relaxed)).unwrap_or(LevelFilter::Off)
+    }
+
+    /// The level each sink keeps for records from a target.
+    fn levels(&self, target: &str) -> [LevelFilter; SINKS] {
+        let mut levels = [LevelFilter::Off; SINKS];
+        for (sink, level) in levels.iter_mut().enumerate() {
+            *level = self.sink_level(sink);
+        }
+        if let Some(filters) = self.filters.try_read() {
+            // The length of the longest target which matched on each sink.
+            // Targets are never empty, so 0 means none has.
+            let mut matched = [0; SINKS];
+            for filter in filters.iter().flatten().filter(|f| f.matches(target)) {
+                if filter.len > matched[filter.sink] {
+                    matched[filter.sink] = filter.len;
+                    levels[filter.sink] = filter.level;
+                }
+            }
+        }
+        levels
+    }
+
+    /// Let the log macros skip records no sink keeps.
+    fn update_max_level(&self, filters: &[Option<TargetFilter>]) {
+        let max_level = (0..SINKS)
+            .map(|sink| self.sink_level(sink))
+            .chain(filters.iter().flatten().map(|filter| filter.level))
+            .max()
+            .unwrap_or(LevelFilter::Off);
+        log::set_max_level(max_level);
+    }
+
+    /// Set a sink's level, returning the previous one.
+    pub fn set_level(
+        &self,
+        sink: usize,
+        level: LevelFilter,
+    ) -> Result<LevelFilter, LogFilterError> {
+        if sink >= SINKS {
+            return Err(LogFilterError::InvalidSink);
+        }
+        // Hold the filters so max level updates don't race.
+        let filters = self.filters.write();
+        let previous = level_filter(self.levels[sink].swap(level as usize, Ordering::Relaxed));
+        self.update_max_level(&*filters);
+        Ok(previous.unwrap_or(LevelFilter::Off))
+    }
+
+    /// Set a sink's level for a target and the modules under it, or remove
+    /// the target's filter if level is None. Returns the filter's previous
+    /// level, or None if it had none.
+    pub fn set_target_level(
+        &self,
+        sink: usize,
+        target: &str,
+        level: Option<LevelFilter>,
+    ) -> Result<Option<LevelFilter>, LogFilterError> {
+        if sink >= SINKS {
+            return Err(LogFilterError::InvalidSink);
+        }
+        if target.is_empty() || target.len() > LOG_TARGET_MAX_SIZE {
+            return Err(LogFilterError::InvalidTarget);
+        }
+        let mut filters = self.filters.write();
+        let previous = set_filter(&mut *filters, sink, target, level)?;
+        self.update_max_level(&*filters);
+        Ok(previous)
+    }
+
+    /// Make a sink write records in binary form, or as text.
+    pub fn set_binary(&self, sink: usize, binary: bool) -> Result<(), LogFilterError> {
+        let sink_logger = SINKS_BY_NUMBER
+            .get(sink)
+            .ok_or(LogFilterError::InvalidSink)?;
+        if binary && !sink_logger.supports_binary() {
+            return Err(LogFilterError::BinaryUnsupported);
+        }
+        self.binary[sink].store(binary, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Apply a sink's directives, see the [module](index.html) documentation.
+    /// None of them are applied if any is invalid, or if they need more
+    /// target filters than are free.
+    pub fn add_filters(&self, sink: usize, directives: &str) -> Result<(), LogFilterError> {
+        if sink >= SINKS {
+            return Err(LogFilterError::InvalidSink);
+        }
+        let directives = directives
+            .split(',')
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty());
+        // Hold the filters throughout, so that a full table is found before
+        // anything is changed and no other change can take the free slots.
+        let mut filters = self.filters.write();
+        let mut added = 0;
+        for (index, directive) in directives.clone().enumerate() {
+            let target = match parse_directive(directive)? {
+                (Some(target), _) => target,
+                (None, _) => continue,
+            };
+            let exists = find_filter(&*filters, sink, target).is_some();
+            let repeated = directives.clone().take(index).any(|earlier| {
+                matches!(parse_directive(earlier), Ok((Some(earlier), _)) if earlier == target)
+            });
+            if !exists && !repeated {
+                added += 1;
+            }
+        }
+        if added > filters.iter().filter(|slot| slot.is_none()).count() {
+            return Err(LogFilterError::TooManyFilters);
+        }
+        for directive in directives {
+            match parse_directive(directive)? {
+                (Some(target), level) => {
+                    set_filter(&mut *filters, sink, target, Some(level))?;
+                }
+                (None, level) => self.levels[sink].store(level as usize, Ordering::Relaxed),
+            }
+        }
+        self.update_max_level(&*filters);
+        Ok(())
+    }
+}
+
+/// The index of a sink's filter for a target.
+fn find_filter(filters: &[Option<TargetFilter>], sink: usize, target: &str) -> Option<usize> {
+    filters.iter().position(|filter| {
+        matches!(filter, Some(filter) if filter.sink == sink && filter.target() == target.as_bytes())
+    })
+}
+
+/// Set or remove a sink's filter for a target in the locked filters, see
+/// [FanOutLogger::set_target_level](struct.FanOutLogger.html#method.set_target_level).
+fn set_filter(
+    filters: &mut [Option<TargetFilter>],
+    sink: usize,
+    target: &str,
+    level: Option<LevelFilter>,
+) -> Result<Option<LevelFilter>, LogFilterError> {
+    let existing = find_filter(filters, sink, target);
+    let previous = existing
+        .and_then(|index| filters[index])
+        .map(|filter| filter.level);
+    let slot = match (existing, level) {
+        (Some(index), _) => &mut filters[index],
+        (None, Some(_)) => filters
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(LogFilterError::TooManyFilters)?,
+        (None, None) => return Ok(None),
+    };
+    *slot = level.map(|level| {
+        let mut filter = TargetFilter {
+            sink,
+            level,
+            target: [0; LOG_TARGET_MAX_SIZE],
+            len: target.len(),
+        };
+        filter.target[..target.len()].copy_from_slice(target.as_bytes());
+        filter
+    });
+    Ok(previous)
+}
+
+impl Log for FanOutLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.levels(metadata.target())
+            .iter()
+            .any(|level| metadata.level() <= *level)
+    }
+
+    fn log(&self, record: &log::Record) {
+        let levels = self.levels(record.target());
+        if levels.iter().all(|level| record.level() > *level) {
+            return;
+        }
+        let origin = LogOrigin::current();
+        // Log to the console last, since UartLogger panics if it can't lock
+        // the port, and the other sinks should still get the record.
+        for (sink, sink_logger) in SINKS_BY_NUMBER.iter().enumerate().rev() {
+            if record.level() <= levels[sink] {
+                sink_logger.log(&origin, record, self.binary[sink].load(Ordering::Relaxed));
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for sink_logger in SINKS_BY_NUMBER.iter() {
+            sink_logger.flush();
+        }
+    }
+}
+
+/// Install the logger, keeping records up to each sink's initial level.
+pub fn init() -> Result<(), log::SetLoggerError> {
+    log::set_logger(&FAN_OUT_LOGGER)?;
+    FAN_OUT_LOGGER.update_max_level(&*FAN_OUT_LOGGER.filters.read());
+    Ok(())
+}
+
+fn log_level_hypercall(arguments: &HypercallArguments) -> Result<HypercallResults, HypercallError> {
+    let argument = arguments.registers[0];
+    let sink = (argument >> hypervisor_abi::LOG_LEVEL_SINK_SHIFT) as usize;
+    let level = (argument & ((1 << hypervisor_abi::LOG_LEVEL_SINK_SHIFT) - 1)) as u32;
+    let buffer = match arguments.buffer {
+        Some(buffer) => buffer,
+        None => {
+            let level = level_filter(level as usize).ok_or(HypercallError::InvalidArgument)?;
+            let previous = FAN_OUT_LOGGER
+                .set_level(sink, level)
+                .map_err(|_| HypercallError::InvalidArgument)?;
+            info!("Log sink {} level {}", sink, level);
+            return Ok([previous as u64, 0, 0, 0]);
+        }
+    };
+    let level = match level {
+        LOG_LEVEL_INHERIT => None,
+        level => Some(level_filter(level as usize).ok_or(HypercallError::InvalidArgument)?),
+    };
+    if buffer.len > LOG_TARGET_MAX_SIZE as u64 {
+        return Err(HypercallError::InvalidArgument);
+    }
+    let mut target = [0; LOG_TARGET_MAX_SIZE];
+    let target = &mut target[..buffer.len as usize];
+    hypercall_handler::read_buffer(&buffer, 0, target)?;
+    let target = core::str::from_utf8(target).map_err(|_| HypercallError::InvalidArgument)?;
+    let previous = FAN_OUT_LOGGER
+        .set_target_level(sink, target, level)
+        .map_err(|_| HypercallError::InvalidArgument)?;
+    info!("Log sink {} target {} level {:?}", sink, target, level);
+    Ok([
+        previous.map_or(u64::from(LOG_LEVEL_INHERIT), |level| level as u64),
+        0,
+        0,
+        0,
+    ])
+}
+
+/// Register the log level hypercall.
+pub fn register_hypercalls() -> Result<(), hypercall_handler::RegisterHypercallError> {
+    hypercall_handler::register(
+        hypervisor_abi::HYPERCALL_REASON_LOG_LEVEL,
+        log_level_hypercall,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(record: &log::Record) -> [u8; 512] {
+        let origin = LogOrigin {
+            tsc: 0x1234,
+            cpu: 2,
+        };
+        let mut bytes = [0; 512];
+        write_frame(7, &origin, record, |chunks| {
+            let mut len = 0;
+            for chunk in chunks {
+                bytes[len..len + chunk.len()].copy_from_slice(chunk);
+                len += chunk.len();
+            }
+        });
+        bytes
+    }
+
+    #[test]
+    fn binary_arguments_are_encoded() {
+        let key_values: &[(&str, u64)] = &[("value", 0x8005_0033)];
+        let bytes = frame(
+            &log::Record::builder()
+                .level(log::Level::Trace)
+                .target("cr")
+                .args(format_args!("Guest cr0 write"))
+                .key_values(&key_values)
+                .build(),
+        );
+        let header = LogFrameHeader {
+            len: 15,
+            level: log::Level::Trace as u8,
+            target_len: 2,
+            sequence: 7,
+            tsc: 0x1234,
+            cpu: 2,
+            args_len: 15,
+        };
+        assert_eq!(bytes[..32], header.to_bytes());
+        assert_eq!(&bytes[32..49], b"crGuest cr0 write");
+        assert_eq!(&bytes[49..55], b"\x05value");
+        assert_eq!(bytes[55], LOG_ARGUMENT_U64);
+        assert_eq!(bytes[56..64], 0x8005_0033u64.to_le_bytes());
+        assert_eq!(bytes[64], 0);
+    }
+
+    #[test]
+    fn inline_values_are_formatted() {
+        let bytes = frame(
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .target("t")
+                .args(format_args!("vector {:x}", 0x20))
+                .build(),
+        );
+        assert_eq!(bytes[4..6], 9u16.to_le_bytes());
+        assert_eq!(bytes[26..28], 0u16.to_le_bytes());
+        assert_eq!(&bytes[32..42], b"tvector 20");
+    }
+
+    #[test]
+    fn text_shows_arguments_after_the_message() {
+        let key_values: &[(&str, u64)] = &[("msr", 0x1b)];
+        let record = log::Record::builder()
+            .args(format_args!("Unhandled rdmsr"))
+            .key_values(&key_values)
+            .build();
+        let mut text = [0; 64];
+        let mut writer = TruncatingWriter::new(&mut text);
+        write!(writer, "{}", Message(&record)).unwrap();
+        let len = writer.len();
+        assert_eq!(&text[..len], b"Unhandled rdmsr msr=0x1b");
+    }
+}
@@ -10,9 +10,19 @@
 #[derive(Debug, Copy, Clone)]
 #[repr(u32)]
 pub enum Msr {
+    Ia32TimeStampCounter = 0x0000_0010,
+    Ia32TscAdjust = 0x0000_003b,
+    Ia32TscDeadline = 0x0000_06e0,
+    Ia32ApicBase = 0x0000_001b,
+    Ia32TscAux = 0xc000_0103,
     EFER = 0xc000_0080,
+    Ia32FsBase = 0xc000_0100,
+    Ia32GsBase = 0xc000_0101,
     Ia32FeatureControl = 0x0000_003a,
     Ia32DebugControl = 0x0000_01d9,
+    Ia32SysenterCs = 0x0000_0174,
+    Ia32SysenterEsp = 0x0000_0175,
+    Ia32SysenterEip = 0x0000_0176,
     Ia32VmxBasic = 0x0000_0480,
     Ia32VmxPinBasedControls = 0x0000_0481,
     Ia32VmxProcBasedControls = 0x0000_0482,
@@ -31,6 +41,7 @@ pub enum Msr {
     Ia32VmxTrueExitControls = 0x0000_048f,
     Ia32VmxTrueEntryControls = 0x0000_0490,
     Ia32VmxVmFunc = 0x0000_0491,
+    Ia32X2ApicIcr = 0x0000_0830,
 }
 
 /// Represents the value of an Model specific register.
@@ -73,3 +84,48 @@ pub fn wrmsr(msr: Msr, pair: MsrValuePair) {
         );
     }
 }
+
+/// The offsets of the four 1k regions of the MSR bitmap, and the ranges of
+/// MSRs they cover. See the Intel manual, Volume 3, Section 24.6.9 "MSR-Bitmap
+/// Address".
+const MSR_BITMAP_READ_LOW: usize = 0x000;
+const MSR_BITMAP_READ_HIGH: usize = 0x400;
+const MSR_BITMAP_WRITE_LOW: usize = 0x800;
+const MSR_BITMAP_WRITE_HIGH: usize = 0xc00;
+const MSR_LOW_RANGE_BASE: u32 = 0x0000_0000;
+const MSR_HIGH_RANGE_BASE: u32 = 0xc000_0000;
+const MSR_RANGE_SIZE: u32 = 0x2000;
+
+/// Cause a VM exit when the guest reads or writes an MSR, by setting its bits
+/// in the MSR bitmap at the virtual address bitmap.
+/// Accesses to MSRs outside the two ranges covered by the bitmap always exit,
+/// so there is nothing to set for them.
+pub fn intercept_msr(bitmap: *mut u8, msr: Msr, read: bool, write: bool) {
+    let msr = msr as u32;
+    let (read_region, write_region, index) =
+        if msr.wrapping_sub(MSR_LOW_RANGE_BASE) < MSR_RANGE_SIZE {
+            (
+                MSR_BITMAP_READ_LOW,
+                MSR_BITMAP_WRITE_LOW,
+                msr - MSR_LOW_RANGE_BASE,
+            )
+        } else if msr.wrapping_sub(MSR_HIGH_RANGE_BASE) < MSR_RANGE_SIZE {
+            (
+                MSR_BITMAP_READ_HIGH,
+                MSR_BITMAP_WRITE_HIGH,
+                msr - MSR_HIGH_RANGE_BASE,
+            )
+        } else {
+            return;
+        };
+    let byte = (index / 8) as usize;
+    let bit = 1 << (index % 8);
+    unsafe {
+        if read {
+            *bitmap.add(read_region + byte) |= bit;
+        }
+        if write {
+            *bitmap.add(write_region + byte) |= bit;
+        }
+    }
+}
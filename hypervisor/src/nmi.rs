@@ -0,0 +1,213 @@
+//! Virtualizes non-maskable interrupts, or NMIs.
+//!
+//! Every NMI belongs to the guest: the guest's perf counters and watchdog
+//! raise them, and so do the guest's own NMI IPIs. The only exception is the
+//! NMI the [panic handler](../panic/index.html) sends to stop the other cores,
+//! which is recognized by a panic being in progress.
+//!
+//! An NMI can arrive while the guest runs or while the core handles a VM
+//! exit. With NMI exiting the first causes a VM exit, and the second is taken
+//! by the host's IDT. Either way the NMI is queued on the core, and the guest
+//! is made to exit as soon as it can take an NMI, with NMI-window exiting,
+//! which needs virtual NMIs. The NMI is then injected into the guest.
+//!
+//! Like the hardware, a core holds at most one NMI for the guest, so NMIs
+//! which arrive while one is queued are merged with it. Guests expect this,
+//! e.g. Linux checks every perf counter in each NMI.
+//!
+//! For more information see the Intel manual, Volume 3, Section 25.3 "Changes
+//! to Event Blocking" and Section 26.6 "Event Injection".
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::interrupts::{self, RegisterInterruptHandlerError};
+use crate::msr::{rdmsr, Msr};
+use crate::register_state::InterruptRegisterState;
+use crate::vcpu::{self, get_current_vcpu};
+use crate::vmcs_fields::{
+    CpuBasedControlsNmiWindowExiting, PinBasedControlsNmiExiting, PinBasedControlsVirtualNmi,
+    VmcsField,
+};
+use crate::vmx::{vmread, vmwrite};
+use crate::VCpu;
+use log::warn;
+
+/// The vector of the non-maskable interrupt.
+pub const NMI_VECTOR: u64 = 2;
+
+/// True if NMIs exit and are injected into the guest. The same on every
+/// core.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const INTERRUPT_INFO_VALID: u64 = 1 << 31;
+const INTERRUPT_INFO_TYPE_MASK: u64 = 7 << 8;
+const INTERRUPT_INFO_TYPE_NMI: u64 = 2 << 8;
+const INTERRUPT_INFO_TYPE_SOFTWARE_INTERRUPT: u64 = 4 << 8;
+const INTERRUPT_INFO_TYPE_PRIVILEGED_SOFTWARE_EXCEPTION: u64 = 5 << 8;
+const INTERRUPT_INFO_TYPE_SOFTWARE_EXCEPTION: u64 = 6 << 8;
+const INTERRUPT_INFO_DELIVER_ERROR_CODE: u64 = 1 << 11;
+/// Set in the exit interruption information if the exception interrupted an
+/// IRET which unblocked NMIs.
+const INTERRUPT_INFO_NMI_UNBLOCKED_BY_IRET: u64 = 1 << 12;
+/// The bits of the IDT-vectoring information which mean the same in the
+/// VM-entry interruption information. The rest are reserved.
+const INTERRUPT_INFO_ENTRY_MASK: u64 = INTERRUPT_INFO_VALID | 0xfff;
+
+const GUEST_INTERRUPTIBILITY_BLOCKING_BY_NMI: u64 = 1 << 3;
+
+/// The NMI state of a core.
+/// Each core should have their own VirtualNmi, initialized with
+/// Default::default().
+#[derive(Debug, Default)]
+pub struct VirtualNmi {
+    /// True if an NMI is waiting to be injected into the guest. Set by the
+    /// host's NMI handler, which may interrupt anything else on the core.
+    pending: AtomicBool,
+}
+
+/// Take NMIs which arrive while the host runs. Called once while loading the
+/// hypervisor.
+pub fn register_interrupt_handler() -> Result<(), RegisterInterruptHandlerError> {
+    interrupts::register(NMI_VECTOR, handle_host_nmi)
+}
+
+/// Enable NMI exiting and virtual NMIs. Must be called while setting up the
+/// current core's vmcs, after the VM execution controls have been
+/// initialized.
+/// Processors are assumed to be the same on every core, so if virtual NMIs
+/// aren't supported NMIs are left to the hardware on every core.
+pub fn init(vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
+    vcpu.nmi.pending.store(false, Ordering::SeqCst);
+    // The allowed 1-settings of the controls are in the high half of the
+    // capability MSRs. See the Intel manual, Volume 3, Appendix A.3.
+    let allowed_pin_controls = u64::from(rdmsr(Msr::Ia32VmxPinBasedControls).edx);
+    let allowed_controls = u64::from(rdmsr(Msr::Ia32VmxProcBasedControls).edx);
+    let pin_controls = PinBasedControlsNmiExiting | PinBasedControlsVirtualNmi;
+    if allowed_pin_controls & pin_controls != pin_controls
+        || allowed_controls & CpuBasedControlsNmiWindowExiting == 0
+    {
+        warn!("Virtual NMIs aren't supported, NMIs taken by the host are dropped");
+        ENABLED.store(false, Ordering::SeqCst);
+        return Ok(());
+    }
+    let controls = vmread(VmcsField::PinBasedVmExecControl)? | pin_controls;
+    vmwrite(VmcsField::PinBasedVmExecControl, controls)?;
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+fn set_nmi_window_exiting(enabled: bool) -> Result<(), x86::vmx::VmFail> {
+    let mut controls = vmread(VmcsField::CpuBasedVmExecControl)?;
+    if enabled {
+        controls |= CpuBasedControlsNmiWindowExiting;
+    } else {
+        controls &= !CpuBasedControlsNmiWindowExiting;
+    }
+    vmwrite(VmcsField::CpuBasedVmExecControl, controls)
+}
+
+/// Queue an NMI for the guest and have the guest exit once it can take it.
+fn queue(vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
+    vcpu.nmi.pending.store(true, Ordering::SeqCst);
+    set_nmi_window_exiting(true)
+}
+
+/// The host's NMI handler. Runs on its own stack, and may interrupt the
+/// hypervisor anywhere, so it takes no locks and doesn't log.
+#[cfg_attr(test, allow(unused_variables))]
+fn handle_host_nmi(state: &mut InterruptRegisterState) -> bool {
+    #[cfg(not(test))]
+    if crate::panic::in_progress() {
+        crate::panic::stop_this_core(state);
+    }
+    if !ENABLED.load(Ordering::SeqCst) {
+        // Without virtual NMIs there's no way to tell when the guest can
+        // take it.
+        return true;
+    }
+    if let Some(vcpu) = vcpu::try_get_current_vcpu() {
+        // The window is set again before the next VM entry if this
+        // interrupted code changing the same controls, or if no vmcs is
+        // current. See prepare_vm_entry.
+        let _ = queue(vcpu);
+    }
+    true
+}
+
+/// If the VM exit interrupted the delivery of an event through the guest's
+/// IDT, deliver it again on the next VM entry. See the Intel manual, Volume 3,
+/// Section 27.2.4 "Information for VM Exits During Event Delivery".
+fn reinject_interrupted_event() -> Result<(), x86::vmx::VmFail> {
+    let vectoring_info = vmread(VmcsField::IdtVectoringInfoField)?;
+    if vectoring_info & INTERRUPT_INFO_VALID == 0 {
+        return Ok(());
+    }
+    if vectoring_info & INTERRUPT_INFO_DELIVER_ERROR_CODE != 0 {
+        let error_code = vmread(VmcsField::IdtVectoringErrorCode)?;
+        vmwrite(VmcsField::VmEntryExceptIonErrorCode, error_code)?;
+    }
+    match vectoring_info & INTERRUPT_INFO_TYPE_MASK {
+        INTERRUPT_INFO_TYPE_SOFTWARE_INTERRUPT
+        | INTERRUPT_INFO_TYPE_PRIVILEGED_SOFTWARE_EXCEPTION
+        | INTERRUPT_INFO_TYPE_SOFTWARE_EXCEPTION => {
+            let len = vmread(VmcsField::VmExitInstructionLen)?;
+            vmwrite(VmcsField::VmEntryInstructionLen, len)?;
+        }
+        _ => {}
+    }
+    vmwrite(
+        VmcsField::VmEntryIntrInfoField,
+        vectoring_info & INTERRUPT_INFO_ENTRY_MASK,
+    )
+}
+
+/// Handle a VM exit caused by an NMI or an exception. If it was an NMI, queue
+/// it for the guest and return true. Otherwise return false and leave the
+/// exception to its handler, after restoring the guest's NMI blocking if the
+/// exception interrupted an IRET which lifted it. See the Intel manual,
+/// Volume 3, Section 27.2.3 "Information About NMI Unblocking Due to IRET".
+pub fn handle_nmi_or_exception_exit() -> Result<bool, x86::vmx::VmFail> {
+    let interrupt_info = vmread(VmcsField::VmExitIntrInfo)?;
+    if interrupt_info & INTERRUPT_INFO_TYPE_MASK != INTERRUPT_INFO_TYPE_NMI {
+        let vectoring_info = vmread(VmcsField::IdtVectoringInfoField)?;
+        if ENABLED.load(Ordering::SeqCst)
+            && interrupt_info & INTERRUPT_INFO_NMI_UNBLOCKED_BY_IRET != 0
+            && vectoring_info & INTERRUPT_INFO_VALID == 0
+        {
+            let interruptibility = vmread(VmcsField::GuestInterruptibilityInfo)?;
+            vmwrite(
+                VmcsField::GuestInterruptibilityInfo,
+                interruptibility | GUEST_INTERRUPTIBILITY_BLOCKING_BY_NMI,
+            )?;
+        }
+        return Ok(false);
+    }
+    reinject_interrupted_event()?;
+    queue(get_current_vcpu())?;
+    Ok(true)
+}
+
+/// Handle an NMI-window exit, which happens as soon as the guest can take an
+/// NMI, by injecting the queued NMI.
+pub fn handle_nmi_window() -> Result<(), x86::vmx::VmFail> {
+    // Turn the window off first, so that an NMI which arrives in between
+    // turns it back on.
+    set_nmi_window_exiting(false)?;
+    if get_current_vcpu().nmi.pending.swap(false, Ordering::SeqCst) {
+        vmwrite(
+            VmcsField::VmEntryIntrInfoField,
+            NMI_VECTOR | INTERRUPT_INFO_VALID | INTERRUPT_INFO_TYPE_NMI,
+        )?;
+    }
+    Ok(())
+}
+
+/// Called at the end of every VM exit, after anything else which changes the
+/// VM execution controls. Makes sure the guest exits for an NMI which is
+/// still queued, in case the host's NMI handler interrupted a change to the
+/// controls and its own change was lost.
+pub fn prepare_vm_entry() -> Result<(), x86::vmx::VmFail> {
+    if ENABLED.load(Ordering::SeqCst) && get_current_vcpu().nmi.pending.load(Ordering::SeqCst) {
+        set_nmi_window_exiting(true)?;
+    }
+    Ok(())
+}
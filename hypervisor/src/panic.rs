@@ -1,21 +1,248 @@
 //! This module handles hypervisor panics.
+//!
+//! Everything here may run while another core, or the panicking core itself,
+//! holds a lock, so output goes through UNSYNCHRONIZED_LOGGER and nothing
+//! takes a lock that the rest of the hypervisor uses.
+//!
+//! The panicking core first stops every other core with an NMI, so that they
+//! stop logging and stop changing the state about to be dumped. A core which
+//! receives the NMI records where it was and halts. A core running the guest
+//! takes a VM exit for the NMI and does the same, instead of passing the NMI
+//! on to the guest, but only if the processor supports virtual NMIs, without
+//! which [NMI exiting](../nmi/index.html) is left off. Otherwise the guest
+//! takes the NMI, and the core stops at the start of its next VM exit, which
+//! may come too late for the panicking core, which only waits so long.
+//!
+//! If the loader enabled it, the panicking core and the stopped cores then
+//! [devirtualize](../devirtualize/index.html) instead of halting.
 #![cfg(not(test))]
 
-use core::sync::atomic;
+use core::sync::atomic::{self, AtomicU64, AtomicUsize, Ordering};
 
 use core::panic::PanicInfo;
 
+use crate::backtrace;
+use crate::crash_dump;
+use crate::devirtualize;
+use crate::exit_trace;
+use crate::msr::{rdmsrl, wrmsr, Msr, MsrValuePair};
+use crate::register_state::InterruptRegisterState;
+use crate::vcpu;
+use crate::vmcs_dump;
 use crate::UNSYNCHRONIZED_LOGGER;
 
 /// Prevent recursive panicking.
 static HAVE_PANICKED: atomic::AtomicBool = atomic::AtomicBool::new(false);
 
+/// True once the panicking core has started stopping the others.
+static STOPPING: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// The number of other cores which have stopped for the panic.
+static STOPPED_CORES: AtomicUsize = AtomicUsize::new(0);
+
+/// Where a core was when it stopped for the panic. rip is 0 if the core
+/// hasn't stopped.
+struct StoppedCore {
+    rip: AtomicU64,
+    rsp: AtomicU64,
+    rbp: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const RUNNING_CORE: StoppedCore = StoppedCore {
+    rip: AtomicU64::new(0),
+    rsp: AtomicU64::new(0),
+    rbp: AtomicU64::new(0),
+};
+
+/// The stopped cores, by vCPU index.
+static STOPPED: [StoppedCore; vcpu::MAX_VCPUS] = [RUNNING_CORE; vcpu::MAX_VCPUS];
+
+/// How many TSC ticks to wait for the other cores to stop.
+const STOP_TIMEOUT_TICKS: u64 = 1 << 31;
+
+/// The local APIC's ICR value which sends an NMI to every core but this one.
+/// See the Intel manual, Volume 3, Section 10.6.1 "Interrupt Command Register
+/// (ICR)".
+const ICR_NMI_ALL_EXCLUDING_SELF: u32 = (0b11 << 18) | (1 << 14) | (0b100 << 8);
+/// The offset of the low half of the ICR in the xAPIC's MMIO page.
+#[cfg(target_os = "uefi")]
+const XAPIC_ICR_LOW_OFFSET: u64 = 0x300;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+#[cfg(target_os = "uefi")]
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// True once a panic has started stopping the other cores. An NMI after that
+/// is the panicking core's, and the core which takes it should stop.
+pub fn in_progress() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Called from the NMI handler on a core which is told to stop by a panic on
+/// another core.
+pub fn stop_this_core(state: &InterruptRegisterState) -> ! {
+    stop(state.rip, state.rsp, state.registers.rbp)
+}
+
+/// Called at the start of any VM exit on a core once another core has
+/// panicked, whether the exit was for the panic's NMI or not. The core is
+/// recorded as stopping here.
+pub fn stop_this_core_in_vm_exit() -> ! {
+    stop(
+        stop_this_core_in_vm_exit as *const () as u64,
+        backtrace::current_stack_pointer(),
+        backtrace::current_frame_pointer(),
+    )
+}
+
+/// Record where the core was, and the guest's state if it was handling a VM
+/// exit, then halt or devirtualize. The core says nothing itself, so that its
+/// output doesn't get mixed up with the panicking core's.
+fn stop(rip: u64, rsp: u64, rbp: u64) -> ! {
+    crash_dump::capture_current_guest_in_vm_exit();
+    if let Some(vcpu) = vcpu::try_get_current_vcpu() {
+        if let Some(index) = vcpu::index_of(vcpu) {
+            let stopped = &STOPPED[index];
+            stopped.rsp.store(rsp, Ordering::Relaxed);
+            stopped.rbp.store(rbp, Ordering::Relaxed);
+            stopped.rip.store(rip, Ordering::Relaxed);
+        }
+    }
+    STOPPED_CORES.fetch_add(1, Ordering::SeqCst);
+    if devirtualize::requested() {
+        devirtualize::current_core();
+    }
+    halt();
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli; hlt;");
+        }
+    }
+}
+
+/// Send an NMI to every other core through the local APIC. Returns false if
+/// the local APIC can't be reached.
+fn send_nmi_to_other_cores() -> bool {
+    let apic_base = rdmsrl(Msr::Ia32ApicBase);
+    if apic_base & APIC_BASE_ENABLE == 0 {
+        return false;
+    }
+    if apic_base & APIC_BASE_X2APIC_ENABLE != 0 {
+        wrmsr(
+            Msr::Ia32X2ApicIcr,
+            MsrValuePair {
+                edx: 0,
+                eax: ICR_NMI_ALL_EXCLUDING_SELF,
+            },
+        );
+        return true;
+    }
+    // The xAPIC's registers are memory mapped. Under UEFI memory is identity
+    // mapped, but elsewhere the page may not be mapped at all.
+    #[cfg(target_os = "uefi")]
+    {
+        let icr = ((apic_base & APIC_BASE_ADDRESS_MASK) + XAPIC_ICR_LOW_OFFSET) as *mut u32;
+        unsafe { core::ptr::write_volatile(icr, ICR_NMI_ALL_EXCLUDING_SELF) };
+        true
+    }
+    #[cfg(not(target_os = "uefi"))]
+    false
+}
+
+/// Stop every other vCPU, waiting a bounded time for them to do so.
+fn stop_other_cores() {
+    let this_core = vcpu::try_get_current_vcpu().and_then(|vcpu| vcpu::index_of(vcpu));
+    let other_cores = vcpu::vcpus()
+        .filter(|(index, _)| Some(*index) != this_core)
+        .count();
+    if other_cores == 0 {
+        return;
+    }
+    if !send_nmi_to_other_cores() {
+        write!(
+            UNSYNCHRONIZED_LOGGER,
+            "Can't reach the local APIC, not stopping {} other cores",
+            other_cores
+        );
+        return;
+    }
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    while STOPPED_CORES.load(Ordering::SeqCst) < other_cores {
+        if unsafe { core::arch::x86_64::_rdtsc() }.wrapping_sub(start) > STOP_TIMEOUT_TICKS {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    write!(
+        UNSYNCHRONIZED_LOGGER,
+        "Stopped {} of {} other cores",
+        STOPPED_CORES.load(Ordering::SeqCst),
+        other_cores
+    );
+}
+
+fn write_backtrace(rsp: u64, rbp: u64) {
+    backtrace::walk(rsp, rbp, |depth, return_address| {
+        write!(UNSYNCHRONIZED_LOGGER, "  #{} {:#x}", depth, return_address);
+    });
+}
+
+/// Write the guest's registers and the current vmcs, if this core is
+/// handling a VM exit.
+fn write_guest_state() {
+    let vcpu = match vcpu::try_get_current_vcpu() {
+        Some(vcpu) => vcpu,
+        None => {
+            write!(UNSYNCHRONIZED_LOGGER, "Not on a vCPU, no guest state");
+            return;
+        }
+    };
+    crash_dump::capture_current_guest_in_vm_exit();
+    if vcpu.guest_snapshot_valid {
+        write!(UNSYNCHRONIZED_LOGGER, "Guest: {:x?}", vcpu.guest_snapshot);
+    }
+    write!(UNSYNCHRONIZED_LOGGER, "VMCS:");
+    for (name, value) in vmcs_dump::fields() {
+        write!(UNSYNCHRONIZED_LOGGER, "  {}: {:x}", name, value);
+    }
+}
+
+fn write_stopped_cores() {
+    for (index, stopped) in STOPPED.iter().enumerate() {
+        let rip = stopped.rip.load(Ordering::Relaxed);
+        if rip == 0 {
+            continue;
+        }
+        write!(
+            UNSYNCHRONIZED_LOGGER,
+            "vCPU {} stopped at {:#x}, backtrace:",
+            index, rip
+        );
+        write_backtrace(
+            stopped.rsp.load(Ordering::Relaxed),
+            stopped.rbp.load(Ordering::Relaxed),
+        );
+    }
+}
+
 /// Called by the rust runtime when a panic occurs.
 /// Sets HAVE_PANICKED, and if this is the first time a panic has occurred,
-/// logs information about the panic.
+/// logs information about the panic, stops the other cores, and writes a
+/// backtrace of every core, the guest's registers and vmcs on this core, the
+/// trace of recent VM exits on every core and a crash dump of the guest.
+/// Then, if the loader enabled it, hands the core back to the guest.
+/// Return addresses in the backtraces are absolute; panic_fmt's own address
+/// is written so that they can be matched against the symbols of the
+/// hypervisor's binary wherever it was loaded.
 #[no_mangle]
 #[panic_handler]
 pub extern "C" fn panic_fmt(info: &PanicInfo) -> ! {
+    let rsp = backtrace::current_stack_pointer();
+    let rbp = backtrace::current_frame_pointer();
     if HAVE_PANICKED
         .compare_exchange(
             false,
@@ -26,11 +253,29 @@ pub extern "C" fn panic_fmt(info: &PanicInfo) -> ! {
         .is_ok()
     {
         write!(UNSYNCHRONIZED_LOGGER, "PANIC: {}", info);
-    }
-
-    loop {
-        unsafe {
-            asm!("cli; hlt;");
+        let devirtualizing = devirtualize::request();
+        STOPPING.store(true, Ordering::SeqCst);
+        stop_other_cores();
+        write!(
+            UNSYNCHRONIZED_LOGGER,
+            "Backtrace, panic_fmt is at {:#x}:",
+            panic_fmt as *const () as usize
+        );
+        write_backtrace(rsp, rbp);
+        write_guest_state();
+        write_stopped_cores();
+        exit_trace::dump();
+        crash_dump::dump_guest();
+        if devirtualizing {
+            write!(UNSYNCHRONIZED_LOGGER, "Devirtualizing");
+            let e = devirtualize::current_core();
+            write!(
+                UNSYNCHRONIZED_LOGGER,
+                "Can't devirtualize this core: {:?}",
+                e
+            );
         }
     }
+
+    halt();
 }
@@ -0,0 +1,211 @@
+//! Samples where the guest is running using the VMX preemption timer, so that
+//! code nothing else can profile, like firmware and early boot code, can be
+//! profiled.
+//!
+//! The loader sets a sampling period with
+//! [rustyvisor_profiler_set_period](../fn.rustyvisor_profiler_set_period.html)
+//! before loading the hypervisor on each core. Each core then arms its
+//! preemption timer, and every time it expires records the guest's RIP, CR3
+//! and CPL in the core's sample buffer. The buffers are drained one sample at
+//! a time with the
+//! [HYPERCALL_REASON_PROFILE_SAMPLE](../../hypervisor_abi/constant.HYPERCALL_REASON_PROFILE_SAMPLE.html)
+//! hypercall. Samples taken while a buffer is full are dropped.
+//!
+//! The preemption timer counts down at the rate of the TSC divided by a power
+//! of two given by IA32_VMX_MISC. Where supported, the timer's value is saved
+//! on VM exit so that the guest's time in other VM exits doesn't restart the
+//! period.
+//!
+//! The [interrupt_controller](../interrupt_controller/index.html) can also use
+//! the preemption timer to wait for an interrupt window, but that code isn't
+//! enabled.
+//!
+//! For more information see the Intel manual, Volume 3, Section 25.5.1 "VMX
+//! Preemption Timer".
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::hypercall_handler::{self, HypercallArguments, HypercallResults};
+use crate::msr::{rdmsr, rdmsrl, Msr};
+use crate::vcpu::{self, get_current_vcpu};
+use crate::vmcs_fields::{
+    PinBasedControlsVmxPreemption, VmExitSaveVmxPreemptionTimerValue, VmcsField,
+};
+use crate::vmx::{vmread, vmwrite};
+use hypervisor_abi::HypercallError;
+use log::{trace, warn};
+use spin::Mutex;
+
+/// The number of samples each core can hold before they are drained.
+const MAX_PROFILE_SAMPLES: usize = 256;
+
+/// Bits 4:0 of IA32_VMX_MISC are the number of TSC bits the preemption timer
+/// ignores.
+const VMX_MISC_PREEMPTION_TIMER_RATE_MASK: u64 = 0x1f;
+
+/// The descriptor privilege level in the guest's SS access rights, which is
+/// always the guest's CPL.
+const ACCESS_RIGHTS_DPL_SHIFT: u64 = 5;
+const ACCESS_RIGHTS_DPL_MASK: u64 = 3;
+
+/// The sampling period in TSC ticks, or 0 if profiling is disabled.
+static PROFILE_PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// The sampling period in preemption timer ticks, or 0 if the preemption
+/// timer isn't armed. The same on every core.
+static TIMER_PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// Where the guest was running when the preemption timer expired.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProfileSample {
+    /// The guest's instruction pointer.
+    pub rip: u64,
+    /// The guest's cr3, which identifies the address space rip is in.
+    pub cr3: u64,
+    /// The guest's current privilege level.
+    pub cpl: u8,
+}
+
+struct SampleBuffer {
+    samples: [ProfileSample; MAX_PROFILE_SAMPLES],
+    /// The index of the oldest sample.
+    head: usize,
+    count: usize,
+}
+
+/// A core's profiling samples.
+/// Each core should have their own Profiler, initialized with
+/// Default::default().
+pub struct Profiler {
+    /// Locked by the core taking samples, and by any core draining them.
+    buffer: Mutex<SampleBuffer>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            buffer: Mutex::new(SampleBuffer {
+                samples: [ProfileSample::default(); MAX_PROFILE_SAMPLES],
+                head: 0,
+                count: 0,
+            }),
+        }
+    }
+}
+
+impl core::fmt::Debug for Profiler {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Profiler").finish_non_exhaustive()
+    }
+}
+
+/// Set the sampling period in TSC ticks. A period of 0 disables profiling.
+/// Only affects cores the hypervisor is loaded on afterwards.
+pub fn set_period(period: u64) {
+    PROFILE_PERIOD.store(period, Ordering::SeqCst);
+}
+
+/// Arm the preemption timer if profiling is enabled. Must be called while
+/// setting up the current core's vmcs, after the VM execution controls have
+/// been initialized.
+pub fn init() -> Result<(), x86::vmx::VmFail> {
+    let period = PROFILE_PERIOD.load(Ordering::SeqCst);
+    if period == 0 {
+        return Ok(());
+    }
+    // The allowed 1-settings of the controls are in the high half of the
+    // capability MSRs. See the Intel manual, Volume 3, Appendix A.3.1
+    // "Pin-Based VM-Execution Controls".
+    if u64::from(rdmsr(Msr::Ia32VmxPinBasedControls).edx) & PinBasedControlsVmxPreemption == 0 {
+        warn!("The VMX preemption timer isn't supported, not profiling");
+        return Ok(());
+    }
+
+    let rate = rdmsrl(Msr::Ia32VmxMisc) & VMX_MISC_PREEMPTION_TIMER_RATE_MASK;
+    let timer_period = core::cmp::max(period >> rate, 1);
+    trace!(
+        "Profiling every {} TSC ticks, {} preemption timer ticks",
+        period,
+        timer_period
+    );
+
+    let pin_based_controls = vmread(VmcsField::PinBasedVmExecControl)?;
+    vmwrite(
+        VmcsField::PinBasedVmExecControl,
+        pin_based_controls | PinBasedControlsVmxPreemption,
+    )?;
+    if u64::from(rdmsr(Msr::Ia32VmxExitControls).edx) & VmExitSaveVmxPreemptionTimerValue != 0 {
+        let exit_controls = vmread(VmcsField::VmExitControls)?;
+        vmwrite(
+            VmcsField::VmExitControls,
+            exit_controls | VmExitSaveVmxPreemptionTimerValue,
+        )?;
+    }
+    TIMER_PERIOD.store(timer_period, Ordering::SeqCst);
+    vmwrite(VmcsField::VmxPreemptionTimerValue, timer_period)
+}
+
+/// Record where the guest was running and re-arm the preemption timer.
+/// Called when the preemption timer expires.
+pub fn handle_preemption_timer() -> Result<(), x86::vmx::VmFail> {
+    let timer_period = TIMER_PERIOD.load(Ordering::SeqCst);
+    if timer_period == 0 {
+        trace!("Spurious preemption timer VM exit");
+        return Ok(());
+    }
+
+    let sample = ProfileSample {
+        rip: vmread(VmcsField::GuestRip)?,
+        cr3: vmread(VmcsField::GuestCr3)?,
+        cpl: ((vmread(VmcsField::GuestSsArBytes)? >> ACCESS_RIGHTS_DPL_SHIFT)
+            & ACCESS_RIGHTS_DPL_MASK) as u8,
+    };
+    {
+        let mut buffer = get_current_vcpu().profiler.buffer.lock();
+        if buffer.count < MAX_PROFILE_SAMPLES {
+            let index = (buffer.head + buffer.count) % MAX_PROFILE_SAMPLES;
+            buffer.samples[index] = sample;
+            buffer.count += 1;
+        }
+    }
+
+    vmwrite(VmcsField::VmxPreemptionTimerValue, timer_period)
+}
+
+/// Remove the oldest sample taken by any core, along with the index of the
+/// core which took it.
+pub fn take_sample() -> Option<(usize, ProfileSample)> {
+    vcpu::vcpus().find_map(|(index, vcpu)| {
+        let mut buffer = vcpu.profiler.buffer.lock();
+        if buffer.count == 0 {
+            return None;
+        }
+        let sample = buffer.samples[buffer.head];
+        buffer.head = (buffer.head + 1) % MAX_PROFILE_SAMPLES;
+        buffer.count -= 1;
+        Some((index, sample))
+    })
+}
+
+fn profile_sample_hypercall(
+    _arguments: &HypercallArguments,
+) -> Result<HypercallResults, HypercallError> {
+    Ok(match take_sample() {
+        Some((vcpu_index, sample)) => [
+            sample.rip & 0xffff_ffff,
+            sample.rip >> 32,
+            (sample.cr3 >> 12) & 0xffff_ffff,
+            u64::from(hypervisor_abi::PROFILE_SAMPLE_VALID)
+                | ((vcpu_index as u64) << hypervisor_abi::PROFILE_SAMPLE_VCPU_SHIFT)
+                | u64::from(sample.cpl),
+        ],
+        None => [0; 4],
+    })
+}
+
+/// Register the profiler's hypercalls.
+pub fn register_hypercalls() -> Result<(), hypercall_handler::RegisterHypercallError> {
+    hypercall_handler::register(
+        hypervisor_abi::HYPERCALL_REASON_PROFILE_SAMPLE,
+        profile_sample_hypercall,
+    )
+}
@@ -60,16 +60,16 @@ impl GeneralPurposeRegisterState {
 #[derive(Debug)]
 #[repr(C)]
 pub struct InterruptRegisterState {
-    gs: u64,
-    fs: u64,
-    es: u64,
-    ds: u64,
-    registers: GeneralPurposeRegisterState,
-    interrupt_number: u64,
-    error_code: u64,
-    rip: u64,
-    cs: u64,
-    rflags: u64,
-    rsp: u64,
-    ss: u64,
+    pub gs: u64,
+    pub fs: u64,
+    pub es: u64,
+    pub ds: u64,
+    pub registers: GeneralPurposeRegisterState,
+    pub interrupt_number: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
@@ -155,6 +155,35 @@ pub struct GdtDescriptor {
     pub base: u64,
 }
 
+/// The interrupts which run on a stack of their own, numbered by their index
+/// in the Interrupt Stack Table. The loaders allocate one of each for every
+/// core and put them in its TSS with
+/// [set_interrupt_stack](struct.Tss.html#method.set_interrupt_stack).
+/// See the Intel manual, Volume 3, Section 6.14.5 "Interrupt Stack Table".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptStack {
+    /// The stack for NMIs.
+    NonMaskableInterrupt = 1,
+    /// The stack for double faults, #DF.
+    DoubleFault = 2,
+    /// The stack for machine checks, #MC.
+    MachineCheck = 3,
+}
+
+impl InterruptStack {
+    /// Every interrupt stack.
+    pub const ALL: [InterruptStack; 3] = [
+        InterruptStack::NonMaskableInterrupt,
+        InterruptStack::DoubleFault,
+        InterruptStack::MachineCheck,
+    ];
+}
+
+/// The size in pages of each interrupt stack. The panic handler runs on the
+/// double fault stack after a host stack overflow, so it needs more than a
+/// page.
+pub const INTERRUPT_STACK_PAGES: usize = 4;
+
 /// The Task Struct Segment.
 /// This is used for hardware task switching on 32 bit x86 and for holding
 /// interrupt stack bases on 64 bit x86.
@@ -175,3 +204,13 @@ pub struct Tss {
     reserved3: u16,
     iomap_base: u16,
 }
+
+impl Tss {
+    /// Set the top of the stack an interrupt switches to. The stack grows
+    /// down from top, which should be 16 byte aligned.
+    pub fn set_interrupt_stack(&mut self, stack: InterruptStack, top: u64) {
+        let mut ist = self.ist;
+        ist[stack as usize - 1] = top;
+        self.ist = ist;
+    }
+}
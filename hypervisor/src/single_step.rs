@@ -0,0 +1,230 @@
+//! Single steps the guest using the monitor trap flag.
+//!
+//! When the monitor trap flag VM execution control is set, the processor
+//! exits after the guest executes one instruction. Some care is needed to make
+//! that exactly one instruction:
+//! - If an event is injected on VM entry, the exit occurs after the event is
+//!   delivered, before the first instruction of its handler. The callback is
+//!   told so, since the guest's instruction was not executed.
+//! - If the stepped instruction caused a VM exit and was emulated by the
+//!   hypervisor, it counts as the step, so a pending MTF VM exit is injected
+//!   and the processor exits again immediately after VM entry.
+//! - Otherwise interrupts are blocked as if by STI for the stepped
+//!   instruction, so that an interrupt isn't delivered before it. NMIs are not
+//!   blocked.
+//!
+//! For more information see the Intel manual, Volume 3, Section 25.5.2
+//! "Monitor Trap Flag" and Section 26.7.2 "VM Exits Induced by the Monitor
+//! Trap Flag".
+use crate::msr::{rdmsr, Msr};
+use crate::register_state::GeneralPurposeRegisterState;
+use crate::vcpu::get_current_vcpu;
+use crate::vmcs_fields::{CpuBasedControlsMonitorTrapFlagEnable, VmcsField};
+use crate::vmx::{vmread, vmwrite};
+use log::trace;
+
+const VM_ENTRY_INTERRUPT_INFO_VALID: u64 = 1 << 31;
+const VM_ENTRY_INTERRUPT_INFO_TYPE_MASK: u64 = 7 << 8;
+/// Injecting an event of type other with vector 0 makes an MTF VM exit pending
+/// immediately after VM entry.
+const VM_ENTRY_INTERRUPT_INFO_TYPE_OTHER_EVENT: u64 = 7 << 8;
+
+const GUEST_INTERRUPTIBILITY_BLOCKING_BY_STI: u64 = 1 << 0;
+const GUEST_INTERRUPTIBILITY_BLOCKING_BY_MOV_SS: u64 = 1 << 1;
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// How a single step ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SingleStepOutcome {
+    /// The guest executed one instruction, or the hypervisor emulated one.
+    InstructionExecuted,
+    /// An event, e.g. an exception, was delivered instead. The guest is about
+    /// to execute the first instruction of the event's handler.
+    EventDelivered,
+}
+
+/// Called in host context on the monitor trap flag VM exit which ends a step.
+/// The callback may start another step.
+pub type SingleStepCallback = fn(
+    gprs: &mut GeneralPurposeRegisterState,
+    outcome: SingleStepOutcome,
+) -> Result<(), x86::vmx::VmFail>;
+
+/// The reasons a step can't be started.
+#[derive(Debug)]
+pub enum SingleStepError {
+    /// Accessing the vmcs failed.
+    VmFail(x86::vmx::VmFail),
+    /// The processor doesn't support the monitor trap flag.
+    Unsupported,
+    /// A step is already in progress on this core.
+    AlreadyStepping,
+}
+
+impl From<x86::vmx::VmFail> for SingleStepError {
+    fn from(e: x86::vmx::VmFail) -> Self {
+        SingleStepError::VmFail(e)
+    }
+}
+
+/// The state of a step in progress.
+/// Should be initialized as all zeroes.
+/// Each core should have their own SingleStep.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct SingleStep {
+    /// The function to call when the step ends. Some while a step is in
+    /// progress.
+    callback: Option<SingleStepCallback>,
+    /// True if the hypervisor emulated an instruction since the step started.
+    instruction_emulated: bool,
+    /// True if an event was injected since the step started.
+    event_delivered: bool,
+    /// True if the step set blocking by STI.
+    blocked_interrupts: bool,
+}
+
+fn get_single_step() -> &'static mut SingleStep {
+    &mut get_current_vcpu().single_step
+}
+
+fn set_monitor_trap_flag(enabled: bool) -> Result<(), x86::vmx::VmFail> {
+    let mut cpu_based_controls = vmread(VmcsField::CpuBasedVmExecControl)?;
+    if enabled {
+        cpu_based_controls |= CpuBasedControlsMonitorTrapFlagEnable;
+    } else {
+        cpu_based_controls &= !CpuBasedControlsMonitorTrapFlagEnable;
+    }
+    vmwrite(VmcsField::CpuBasedVmExecControl, cpu_based_controls)
+}
+
+/// True if the processor allows the monitor trap flag to be set. The allowed
+/// 1-settings of the controls are in the high half of the capability MSR.
+/// See the Intel manual, Volume 3, Appendix A.3.2 "Primary Processor-Based
+/// VM-Execution Controls".
+fn monitor_trap_flag_supported() -> bool {
+    u64::from(rdmsr(Msr::Ia32VmxProcBasedControls).edx) & CpuBasedControlsMonitorTrapFlagEnable != 0
+}
+
+/// Run the guest for one instruction when it is resumed, then call callback.
+/// Must be called in host context during a VM exit.
+#[allow(dead_code)]
+pub fn step(callback: SingleStepCallback) -> Result<(), SingleStepError> {
+    if !monitor_trap_flag_supported() {
+        return Err(SingleStepError::Unsupported);
+    }
+    let single_step = get_single_step();
+    if single_step.callback.is_some() {
+        return Err(SingleStepError::AlreadyStepping);
+    }
+    trace!("Single stepping guest");
+    *single_step = SingleStep {
+        callback: Some(callback),
+        instruction_emulated: false,
+        event_delivered: false,
+        blocked_interrupts: false,
+    };
+    set_monitor_trap_flag(true)?;
+    Ok(())
+}
+
+/// True if a step is in progress on this core.
+#[allow(dead_code)]
+pub fn is_stepping() -> bool {
+    get_single_step().callback.is_some()
+}
+
+/// Tell the single step engine that the hypervisor emulated a guest
+/// instruction during this VM exit. Called when the guest's instruction
+/// pointer is advanced.
+pub fn instruction_emulated() {
+    let single_step = get_single_step();
+    if single_step.callback.is_some() {
+        single_step.instruction_emulated = true;
+    }
+}
+
+/// Set up the pending events and interruptibility state for a step in
+/// progress. Must be called at the end of every VM exit, after any events have
+/// been injected.
+pub fn prepare_vm_entry() -> Result<(), x86::vmx::VmFail> {
+    let single_step = get_single_step();
+    if single_step.callback.is_none() {
+        return Ok(());
+    }
+
+    let interrupt_info = vmread(VmcsField::VmEntryIntrInfoField)?;
+    if interrupt_info & VM_ENTRY_INTERRUPT_INFO_VALID != 0 {
+        if interrupt_info & VM_ENTRY_INTERRUPT_INFO_TYPE_MASK
+            != VM_ENTRY_INTERRUPT_INFO_TYPE_OTHER_EVENT
+        {
+            single_step.event_delivered = true;
+        }
+        return Ok(());
+    }
+
+    if single_step.instruction_emulated {
+        unblock_interrupts(single_step)?;
+        return vmwrite(
+            VmcsField::VmEntryIntrInfoField,
+            VM_ENTRY_INTERRUPT_INFO_VALID | VM_ENTRY_INTERRUPT_INFO_TYPE_OTHER_EVENT,
+        );
+    }
+
+    // Blocking by STI may only be set if interrupts are enabled, and not along
+    // with blocking by MOV SS, which blocks interrupts anyway.
+    // See the Intel manual, Volume 3, Section 26.3.1.5 "Checks on
+    // Guest Non-Register State".
+    let interruptibility = vmread(VmcsField::GuestInterruptibilityInfo)?;
+    let rflags = vmread(VmcsField::GuestRFlags)?;
+    if rflags & RFLAGS_IF != 0
+        && interruptibility
+            & (GUEST_INTERRUPTIBILITY_BLOCKING_BY_STI | GUEST_INTERRUPTIBILITY_BLOCKING_BY_MOV_SS)
+            == 0
+    {
+        vmwrite(
+            VmcsField::GuestInterruptibilityInfo,
+            interruptibility | GUEST_INTERRUPTIBILITY_BLOCKING_BY_STI,
+        )?;
+        single_step.blocked_interrupts = true;
+    }
+    Ok(())
+}
+
+/// Undo the blocking by STI set by
+/// [prepare_vm_entry](fn.prepare_vm_entry.html). The processor clears it
+/// after the guest executes an instruction, but not if the instruction caused
+/// a VM exit instead.
+fn unblock_interrupts(single_step: &mut SingleStep) -> Result<(), x86::vmx::VmFail> {
+    if !single_step.blocked_interrupts {
+        return Ok(());
+    }
+    single_step.blocked_interrupts = false;
+    let interruptibility = vmread(VmcsField::GuestInterruptibilityInfo)?;
+    vmwrite(
+        VmcsField::GuestInterruptibilityInfo,
+        interruptibility & !GUEST_INTERRUPTIBILITY_BLOCKING_BY_STI,
+    )
+}
+
+/// Handle a monitor trap flag VM exit by ending the step in progress and
+/// calling its callback.
+pub fn handle_monitor_trap(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    set_monitor_trap_flag(false)?;
+    let mut single_step = core::mem::take(get_single_step());
+    unblock_interrupts(&mut single_step)?;
+    let outcome = if single_step.event_delivered {
+        SingleStepOutcome::EventDelivered
+    } else {
+        SingleStepOutcome::InstructionExecuted
+    };
+    trace!("Single step finished {:?}", outcome);
+    match single_step.callback {
+        Some(callback) => callback(gprs, outcome),
+        None => {
+            trace!("Spurious monitor trap flag VM exit");
+            Ok(())
+        }
+    }
+}
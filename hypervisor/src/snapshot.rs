@@ -0,0 +1,97 @@
+//! Defines a point-in-time copy of a guest's register state.
+//! Snapshots are taken from the guest general purpose registers saved by
+//! _host_entrypoint plus the guest fields of the current vmcs, so they can be
+//! written out long after the vmcs has stopped being current, e.g. in a crash
+//! dump.
+use crate::register_state::GeneralPurposeRegisterState;
+use crate::vmcs_fields::VmcsField;
+use crate::vmx::vmread;
+
+/// The guest's register state at the time of a VM exit.
+/// The general purpose registers are in the same order as in
+/// GeneralPurposeRegisterState, followed by the registers which are kept in
+/// the vmcs, followed by the reason for the exit, which is usually why the
+/// snapshot was taken.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct GuestSnapshot {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub dr7: u64,
+    pub efer: u64,
+    pub exit_reason: u64,
+    pub exit_qualification: u64,
+}
+
+impl GuestSnapshot {
+    /// Capture the guest's state from the saved general purpose registers and
+    /// the current vmcs.
+    /// Fields which cannot be read from the vmcs are recorded as 0xbadc0de,
+    /// like in [vmcs_dump](../vmcs_dump/fn.dump.html), so that a partial
+    /// snapshot can still be taken while the hypervisor is falling over.
+    pub fn capture(gprs: &GeneralPurposeRegisterState) -> Self {
+        let read = |field| vmread(field).unwrap_or(0xbadc0de);
+        GuestSnapshot {
+            r15: gprs.r15,
+            r14: gprs.r14,
+            r13: gprs.r13,
+            r12: gprs.r12,
+            r11: gprs.r11,
+            r10: gprs.r10,
+            r9: gprs.r9,
+            r8: gprs.r8,
+            rdi: gprs.rdi,
+            rsi: gprs.rsi,
+            rbp: gprs.rbp,
+            rdx: gprs.rdx,
+            rcx: gprs.rcx,
+            rbx: gprs.rbx,
+            rax: gprs.rax,
+            rsp: read(VmcsField::GuestRsp),
+            rip: read(VmcsField::GuestRip),
+            rflags: read(VmcsField::GuestRFlags),
+            cs: read(VmcsField::GuestCsSelector),
+            ss: read(VmcsField::GuestSsSelector),
+            ds: read(VmcsField::GuestDsSelector),
+            es: read(VmcsField::GuestEsSelector),
+            fs: read(VmcsField::GuestFsSelector),
+            gs: read(VmcsField::GuestGsSelector),
+            fs_base: read(VmcsField::GuestFsBase),
+            gs_base: read(VmcsField::GuestGsBase),
+            cr0: read(VmcsField::GuestCr0),
+            cr3: read(VmcsField::GuestCr3),
+            cr4: read(VmcsField::GuestCr4),
+            dr7: read(VmcsField::GuestDr7),
+            efer: read(VmcsField::GuestIA32Efer),
+            exit_reason: read(VmcsField::VmExitReason),
+            exit_qualification: read(VmcsField::ExitQualificatIon),
+        }
+    }
+}
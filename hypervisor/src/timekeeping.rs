@@ -0,0 +1,343 @@
+//! Virtualizes the time stamp counter, or TSC.
+//!
+//! The guest's TSC on each core is the host's TSC plus an offset kept in the
+//! core's vmcs, which the processor adds without RDTSC or RDTSCP exiting.
+//! Where the processor supports TSC scaling and the loader asks for it, the
+//! host's TSC is first multiplied by a fixed point multiplier.
+//!
+//! The guest's writes to IA32_TSC and IA32_TSC_ADJUST exit through the MSR
+//! bitmap and change the core's offset instead of the hardware, so the host's
+//! TSC, which the hypervisor uses to time VM exits, is left alone. As on real
+//! hardware, writing IA32_TSC adds the change to IA32_TSC_ADJUST and writing
+//! IA32_TSC_ADJUST moves the TSC by the same amount, so the guest always reads
+//! IA32_TSC_ADJUST as the hardware's value plus the core's offset. Every core
+//! starts with an offset of 0, so the guest's TSCs are as synchronized as the
+//! host's until the guest changes them.
+//!
+//! In compensated mode RDTSC and RDTSCP exit as well, and the time each core
+//! spends handling VM exits is subtracted from its guest TSC, so the guest
+//! doesn't see the hypervisor's overhead when it times itself. The time is
+//! taken out of the core's offset at the end of each exit, so the processor's
+//! own view of the guest's TSC agrees with the emulated one. Like the
+//! [exit statistics](../exit_stats/index.html), the time doesn't include the
+//! VM exit and VM entry themselves. Each core falls behind by a different
+//! amount, so the guest may notice its TSCs drifting apart and stop trusting
+//! them.
+//!
+//! The processor compares IA32_TSC_DEADLINE with the host's TSC, so the
+//! guest's deadlines are intercepted and converted from its TSC to the
+//! host's, and an armed deadline is pushed back whenever compensation moves
+//! the guest's TSC back.
+//!
+//! For more information see the Intel manual, Volume 3, Section 17.17
+//! "Time-Stamp Counter" and Section 25.3 "Changes to Instruction Behavior in
+//! VMX Non-Root Operation".
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::msr::{self, rdmsr, rdmsrl, Msr};
+use crate::vcpu::get_current_vcpu;
+use crate::vmcs_fields::{
+    CpuBasedControlsRdtscExiting, CpuBasedControlsTscOffsetting,
+    SecondaryCpuBasedControlsTscScalingEnable, VmcsField,
+};
+use crate::vmx::{vmread, vmwrite};
+use crate::VCpu;
+use log::{trace, warn};
+
+/// TSC multipliers are fixed point numbers with 48 fractional bits.
+const TSC_MULTIPLIER_FRACTION_BITS: u32 = 48;
+
+/// CPUID.(EAX=07H,ECX=0):EBX bit 1 is set if IA32_TSC_ADJUST is supported.
+const CPUID_LEAF_STRUCTURED_EXTENDED_FEATURES: u32 = 7;
+const CPUID_STRUCTURED_EXTENDED_FEATURES_EBX_TSC_ADJUST: u32 = 1 << 1;
+
+/// CPUID.01H:ECX bit 24 is set if the local APIC supports TSC deadline mode.
+const CPUID_LEAF_FEATURES: u32 = 1;
+const CPUID_FEATURES_ECX_TSC_DEADLINE: u32 = 1 << 24;
+
+/// True if RDTSC and RDTSCP exit so that the time spent in the hypervisor can
+/// be hidden from the guest.
+static COMPENSATED: AtomicBool = AtomicBool::new(false);
+
+/// The TSC multiplier, or 0 if TSC scaling is disabled. The same on every
+/// core.
+static TSC_MULTIPLIER: AtomicU64 = AtomicU64::new(0);
+
+/// True if IA32_TSC_DEADLINE is intercepted. The same on every core.
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// The guest's view of a core's time stamp counter, besides the offset in the
+/// core's vmcs.
+/// Each core should have their own VirtualTsc, initialized with
+/// Default::default().
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtualTsc {
+    /// The guest TSC ticks spent handling VM exits on this core, which have
+    /// been taken out of the core's offset. Only counted in compensated mode.
+    root_ticks: u64,
+    /// The host's TSC when the current VM exit started.
+    exit_start: u64,
+}
+
+/// Set how the guest sees the TSC. Only affects cores the hypervisor is loaded
+/// on afterwards. See
+/// [rustyvisor_timekeeping_configure](../fn.rustyvisor_timekeeping_configure.html).
+pub fn configure(compensated: bool, tsc_multiplier: u64) {
+    COMPENSATED.store(compensated, Ordering::SeqCst);
+    TSC_MULTIPLIER.store(tsc_multiplier, Ordering::SeqCst);
+}
+
+/// Convert a host TSC value to the guest's rate.
+fn scale(host_tsc: u64) -> u64 {
+    match TSC_MULTIPLIER.load(Ordering::Relaxed) {
+        0 => host_tsc,
+        multiplier => {
+            ((u128::from(host_tsc) * u128::from(multiplier)) >> TSC_MULTIPLIER_FRACTION_BITS) as u64
+        }
+    }
+}
+
+/// Convert a guest TSC value back to the host's rate.
+fn unscale(guest_tsc: u64) -> u64 {
+    match TSC_MULTIPLIER.load(Ordering::Relaxed) {
+        0 => guest_tsc,
+        multiplier => {
+            ((u128::from(guest_tsc) << TSC_MULTIPLIER_FRACTION_BITS) / u128::from(multiplier))
+                as u64
+        }
+    }
+}
+
+fn tsc_deadline_supported() -> bool {
+    let result = unsafe { core::arch::x86_64::__cpuid(CPUID_LEAF_FEATURES) };
+    result.ecx & CPUID_FEATURES_ECX_TSC_DEADLINE != 0
+}
+
+fn tsc_adjust_supported() -> bool {
+    let result =
+        unsafe { core::arch::x86_64::__cpuid_count(CPUID_LEAF_STRUCTURED_EXTENDED_FEATURES, 0) };
+    result.ebx & CPUID_STRUCTURED_EXTENDED_FEATURES_EBX_TSC_ADJUST != 0
+}
+
+/// Enable TSC offsetting, and scaling and RDTSC exiting if configured, and
+/// intercept the TSC MSRs. Must be called while setting up the current core's
+/// vmcs, after the VM execution controls and the MSR bitmap have been
+/// initialized.
+/// Processors are assumed to be the same on every core, so if a feature isn't
+/// supported it is disabled for every core.
+pub fn init(vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
+    // The allowed 1-settings of the controls are in the high half of the
+    // capability MSRs. See the Intel manual, Volume 3, Appendix A.3.2
+    // "Primary Processor-Based VM-Execution Controls".
+    let allowed_controls = u64::from(rdmsr(Msr::Ia32VmxProcBasedControls).edx);
+    if allowed_controls & CpuBasedControlsTscOffsetting == 0 {
+        warn!("TSC offsetting isn't supported, not virtualizing the TSC");
+        COMPENSATED.store(false, Ordering::SeqCst);
+        TSC_MULTIPLIER.store(0, Ordering::SeqCst);
+        return Ok(());
+    }
+    let mut controls = vmread(VmcsField::CpuBasedVmExecControl)? | CpuBasedControlsTscOffsetting;
+    if COMPENSATED.load(Ordering::SeqCst) {
+        if allowed_controls & CpuBasedControlsRdtscExiting == 0 {
+            warn!("RDTSC exiting isn't supported, not compensating the TSC");
+            COMPENSATED.store(false, Ordering::SeqCst);
+        } else {
+            controls |= CpuBasedControlsRdtscExiting;
+        }
+    }
+    vmwrite(VmcsField::CpuBasedVmExecControl, controls)?;
+    vmwrite(VmcsField::TscOffset, 0)?;
+
+    let multiplier = TSC_MULTIPLIER.load(Ordering::SeqCst);
+    if multiplier != 0 {
+        let allowed_secondary_controls = u64::from(rdmsr(Msr::Ia32VmxProcBasedControls2).edx);
+        if allowed_secondary_controls & SecondaryCpuBasedControlsTscScalingEnable == 0 {
+            warn!("TSC scaling isn't supported, not scaling the TSC");
+            TSC_MULTIPLIER.store(0, Ordering::SeqCst);
+        } else {
+            let secondary_controls = vmread(VmcsField::SecondaryVmExecControl)?;
+            vmwrite(
+                VmcsField::SecondaryVmExecControl,
+                secondary_controls | SecondaryCpuBasedControlsTscScalingEnable,
+            )?;
+            vmwrite(VmcsField::TsxMultiplier, multiplier)?;
+        }
+    }
+
+    // Reads of IA32_TSC are offset and scaled by the processor like RDTSC, so
+    // they only need to exit when RDTSC does.
+    let compensated = COMPENSATED.load(Ordering::SeqCst);
+    msr::intercept_msr(
+        vcpu.msr_bitmap_virt,
+        Msr::Ia32TimeStampCounter,
+        compensated,
+        true,
+    );
+    if tsc_adjust_supported() {
+        msr::intercept_msr(vcpu.msr_bitmap_virt, Msr::Ia32TscAdjust, true, true);
+    }
+    let tsc_deadline = tsc_deadline_supported();
+    TSC_DEADLINE.store(tsc_deadline, Ordering::SeqCst);
+    if tsc_deadline {
+        msr::intercept_msr(vcpu.msr_bitmap_virt, Msr::Ia32TscDeadline, true, true);
+    }
+    trace!(
+        "TSC compensated {} multiplier {:x}",
+        compensated,
+        TSC_MULTIPLIER.load(Ordering::SeqCst)
+    );
+    Ok(())
+}
+
+/// Note the host's TSC when the current VM exit started, as returned by
+/// [exit_stats::start](../exit_stats/fn.start.html). Called at the start of
+/// every VM exit.
+pub fn exit_started(start: u64) {
+    get_current_vcpu().timekeeping.exit_start = start;
+}
+
+/// Hide the time spent handling the current VM exit from the guest, in
+/// compensated mode, by taking it out of the core's offset. Called at the end
+/// of every VM exit.
+pub fn exit_finished() -> Result<(), x86::vmx::VmFail> {
+    if !COMPENSATED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let now = unsafe { core::arch::x86_64::_rdtsc() };
+    let timekeeping = &mut get_current_vcpu().timekeeping;
+    let host_ticks = now.wrapping_sub(timekeeping.exit_start);
+    let ticks = scale(now).wrapping_sub(scale(timekeeping.exit_start));
+    timekeeping.root_ticks = timekeeping.root_ticks.wrapping_add(ticks);
+    adjust_offset(ticks.wrapping_neg())?;
+    // The guest's TSC reaches an armed deadline that much later.
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        let deadline = rdmsrl(Msr::Ia32TscDeadline);
+        if deadline != 0 {
+            write_host_tsc_deadline(deadline.wrapping_add(host_ticks));
+        }
+    }
+    Ok(())
+}
+
+/// The guest's TSC when the current VM exit started. Using the start of the
+/// exit rather than the current time keeps the guest's TSC from going
+/// backwards once the time spent handling the exit is subtracted.
+pub fn guest_tsc() -> Result<u64, x86::vmx::VmFail> {
+    let timekeeping = &get_current_vcpu().timekeeping;
+    Ok(scale(timekeeping.exit_start).wrapping_add(vmread(VmcsField::TscOffset)?))
+}
+
+fn write_host_tsc_deadline(deadline: u64) {
+    msr::wrmsr(
+        Msr::Ia32TscDeadline,
+        msr::MsrValuePair {
+            edx: (deadline >> 32) as u32,
+            eax: deadline as u32,
+        },
+    );
+}
+
+/// The guest's IA32_TSC_DEADLINE, from the host's. 0 means the timer isn't
+/// armed in either.
+fn guest_tsc_deadline() -> Result<u64, x86::vmx::VmFail> {
+    match rdmsrl(Msr::Ia32TscDeadline) {
+        0 => Ok(0),
+        deadline => Ok(scale(deadline).wrapping_add(vmread(VmcsField::TscOffset)?)),
+    }
+}
+
+/// Arm the host's IA32_TSC_DEADLINE for a deadline of the guest's. A deadline
+/// the host's TSC has already passed fires right away.
+fn write_guest_tsc_deadline(deadline: u64) -> Result<(), x86::vmx::VmFail> {
+    if deadline == 0 {
+        write_host_tsc_deadline(0);
+        return Ok(());
+    }
+    let offset = vmread(VmcsField::TscOffset)? as i64;
+    let scaled = i128::from(deadline) - i128::from(offset);
+    // 0 would disarm the timer, so a deadline before the host's TSC started
+    // becomes 1.
+    let host_deadline = if scaled <= 0 {
+        1
+    } else {
+        unscale(scaled as u64).max(1)
+    };
+    write_host_tsc_deadline(host_deadline);
+    Ok(())
+}
+
+/// Move the current core's guest TSC by delta ticks.
+fn adjust_offset(delta: u64) -> Result<(), x86::vmx::VmFail> {
+    let offset = vmread(VmcsField::TscOffset)?;
+    vmwrite(VmcsField::TscOffset, offset.wrapping_add(delta))
+}
+
+/// The guest's IA32_TSC_ADJUST. The hypervisor never writes the hardware's, so
+/// the guest's differs from it by the changes the guest has made to the TSC,
+/// which are the core's offset without the compensation.
+fn guest_tsc_adjust() -> Result<u64, x86::vmx::VmFail> {
+    let root_ticks = get_current_vcpu().timekeeping.root_ticks;
+    Ok(rdmsrl(Msr::Ia32TscAdjust)
+        .wrapping_add(vmread(VmcsField::TscOffset)?)
+        .wrapping_add(root_ticks))
+}
+
+/// The value of IA32_TSC_ADJUST which makes the current core's TSC read the
+/// same as its guest TSC, for when the hypervisor stops virtualizing the TSC.
+/// None if the guest's TSC is scaled or there is no IA32_TSC_ADJUST, in which
+/// case the guest's TSC jumps.
+#[cfg_attr(test, allow(dead_code))]
+pub fn native_tsc_adjust() -> Result<Option<u64>, x86::vmx::VmFail> {
+    if TSC_MULTIPLIER.load(Ordering::Relaxed) != 0 || !tsc_adjust_supported() {
+        return Ok(None);
+    }
+    let root_ticks = get_current_vcpu().timekeeping.root_ticks;
+    Ok(Some(guest_tsc_adjust()?.wrapping_sub(root_ticks)))
+}
+
+/// The value of IA32_TSC_DEADLINE which keeps the guest's armed deadline once
+/// the TSC reads the same as its guest TSC, for when the hypervisor stops
+/// virtualizing the TSC. None if the deadline isn't armed or isn't
+/// intercepted.
+#[cfg_attr(test, allow(dead_code))]
+pub fn native_tsc_deadline() -> Result<Option<u64>, x86::vmx::VmFail> {
+    if !TSC_DEADLINE.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+    match guest_tsc_deadline()? {
+        0 => Ok(None),
+        deadline => Ok(Some(deadline)),
+    }
+}
+
+/// Emulate a guest read of an intercepted MSR. Returns None if the MSR isn't
+/// one of the TSC MSRs.
+pub fn read_msr(msr: u32) -> Result<Option<u64>, x86::vmx::VmFail> {
+    if msr == Msr::Ia32TimeStampCounter as u32 {
+        guest_tsc().map(Some)
+    } else if msr == Msr::Ia32TscAdjust as u32 {
+        guest_tsc_adjust().map(Some)
+    } else if msr == Msr::Ia32TscDeadline as u32 {
+        guest_tsc_deadline().map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Emulate a guest write of an intercepted MSR. Returns false if the MSR isn't
+/// one of the TSC MSRs.
+pub fn write_msr(msr: u32, value: u64) -> Result<bool, x86::vmx::VmFail> {
+    if msr == Msr::Ia32TscDeadline as u32 {
+        write_guest_tsc_deadline(value)?;
+        return Ok(true);
+    }
+    let current = if msr == Msr::Ia32TimeStampCounter as u32 {
+        guest_tsc()?
+    } else if msr == Msr::Ia32TscAdjust as u32 {
+        guest_tsc_adjust()?
+    } else {
+        return Ok(false);
+    };
+    adjust_offset(value.wrapping_sub(current))?;
+    Ok(true)
+}
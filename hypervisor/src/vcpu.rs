@@ -1,7 +1,21 @@
 //! This module defines functions for working with VCpus.
+use crate::msr::{rdmsrl, Msr};
 use crate::VCpu;
+use core::sync::atomic::{AtomicPtr, Ordering};
 use x86::bits64::segmentation::fs_deref;
 
+/// The maximum number of logical cores the hypervisor can be loaded on.
+pub const MAX_VCPUS: usize = 256;
+
+/// Every VCpu the hypervisor has been loaded with, indexed in the order the
+/// cores were loaded. Used when one core needs to look at the state of the
+/// others, e.g. while writing a crash dump.
+static VCPUS: [AtomicPtr<VCpu>; MAX_VCPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NULL_VCPU: AtomicPtr<VCpu> = AtomicPtr::new(core::ptr::null_mut());
+    [NULL_VCPU; MAX_VCPUS]
+};
+
 /// Get a reference to the current VCpu structure.
 /// The reference is static because the VCpu structure must outlive the
 /// hypervisor.
@@ -14,3 +28,57 @@ use x86::bits64::segmentation::fs_deref;
 pub fn get_current_vcpu() -> &'static mut VCpu {
     unsafe { &mut *(fs_deref() as *mut VCpu) }
 }
+
+/// Like [get_current_vcpu](fn.get_current_vcpu.html), but safe to call from
+/// any context, e.g. from the panic handler while the loader is still running.
+/// Returns None unless the fs base is one of the registered VCpus, which is
+/// only the case in hypervisor host context.
+pub fn try_get_current_vcpu() -> Option<&'static mut VCpu> {
+    let fs_base = rdmsrl(Msr::Ia32FsBase) as *mut VCpu;
+    index_of(fs_base)?;
+    Some(unsafe { &mut *fs_base })
+}
+
+/// Remember a VCpu so that it can be found by [vcpus](fn.vcpus.html).
+/// Returns the index of the VCpu, or None if too many VCpus were registered.
+pub fn register(vcpu: *mut VCpu) -> Option<usize> {
+    if let Some(index) = index_of(vcpu) {
+        return Some(index);
+    }
+    for (index, slot) in VCPUS.iter().enumerate() {
+        if slot
+            .compare_exchange(
+                core::ptr::null_mut(),
+                vcpu,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+        {
+            return Some(index);
+        }
+    }
+    None
+}
+
+/// Returns the index a VCpu was registered with.
+pub fn index_of(vcpu: *const VCpu) -> Option<usize> {
+    if vcpu.is_null() {
+        return None;
+    }
+    VCPUS
+        .iter()
+        .position(|slot| core::ptr::eq(slot.load(Ordering::SeqCst), vcpu))
+}
+
+/// Iterate over every registered VCpu along with its index.
+pub fn vcpus() -> impl Iterator<Item = (usize, &'static VCpu)> + Clone {
+    VCPUS.iter().enumerate().filter_map(|(index, slot)| {
+        let vcpu = slot.load(Ordering::SeqCst);
+        if vcpu.is_null() {
+            None
+        } else {
+            Some((index, unsafe { &*vcpu }))
+        }
+    })
+}
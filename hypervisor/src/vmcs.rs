@@ -1,7 +1,11 @@
 //! This module defines functions used for setting up the guest's virtual
 //! machine control structures.
+use crate::control_registers::{self, OriginalControlRegisters};
 use crate::msr::{rdmsr, rdmsrl, Msr};
+use crate::nmi;
+use crate::profiler;
 use crate::segmentation::{get_current_gdt, unpack_gdt_entry};
+use crate::timekeeping;
 use crate::vmcs_fields::*;
 use crate::vmx::{read_dr7, vmread, vmwrite};
 use crate::VCpu;
@@ -57,7 +61,12 @@ pub fn initialize_host_state(vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
 }
 
 /// Initialize the guest state for the currently loaded vmcs.
-pub fn initialize_guest_state(_vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
+/// The guest will see the original values of cr0 and cr4 through the read
+/// shadows.
+pub fn initialize_guest_state(
+    _vcpu: &VCpu,
+    original_control_registers: &OriginalControlRegisters,
+) -> Result<(), x86::vmx::VmFail> {
     trace!("initialize_guest_state");
     vmwrite(VmcsField::VmcsLinkPointer, !0)?;
 
@@ -170,14 +179,13 @@ pub fn initialize_guest_state(_vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
     )?;
     vmwrite(VmcsField::GuestLdtrBase, ldtr_unpacked.base)?;
 
-    let cr4 = unsafe { x86::controlregs::cr4() };
-    vmwrite(VmcsField::GuestCr4, cr4.bits() as u64)?;
-    //vmwrite(VmcsField::GuestCr4ReadShadow, cr4)?;
+    control_registers::initialize_guest_control_registers(original_control_registers)?;
     let cr3 = unsafe { x86::controlregs::cr3() };
     vmwrite(VmcsField::GuestCr3, cr3)?;
-    let cr0 = unsafe { x86::controlregs::cr0() };
-    vmwrite(VmcsField::GuestCr0, cr0.bits() as u64)?;
-    //vmwrite(VmcsField::GuestCr0ReadShadow, cr0)?;
+    // These are only the initial values. The guest's dr7 and IA32_DEBUGCTL are
+    // saved on every VM exit and loaded on every VM entry by the debug
+    // controls. See the debug_registers module for the rest of the debug
+    // registers.
     vmwrite(VmcsField::GuestIA32Debugctl, rdmsrl(Msr::Ia32DebugControl))?;
     let dr7 = read_dr7();
     vmwrite(VmcsField::GuestDr7, dr7)?;
@@ -199,6 +207,12 @@ pub fn adjust_value_based_on_msr(msr: Msr, controls: u64) -> u64 {
     u64::from(fixed1 | (controls & fixed0))
 }
 
+/// The primary processor-based controls requested for the guest.
+/// Accesses to cr8 don't exit, so that the guest's writes go to the task
+/// priority register the processor uses to mask interrupts, which are
+/// delivered straight to the guest.
+const PROCESSOR_BASED_CONTROLS: u64 = CpuBasedControlsMsrBitmaps | CpuBasedControlsSecondaryEnable;
+
 /// Initialize the control values for the currently loaded vmcs.
 pub fn initialize_vm_control_values(vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
     // Configure entry/exit and supported feature controls
@@ -231,31 +245,39 @@ pub fn initialize_vm_control_values(vcpu: &VCpu) -> Result<(), x86::vmx::VmFail>
 
     vmwrite(
         VmcsField::CpuBasedVmExecControl,
-        adjust_value_based_on_msr(
-            Msr::Ia32VmxProcBasedControls,
-            CpuBasedControlsMsrBitmaps |
-                CpuBasedControlsSecondaryEnable
-                //| CpuBasedControlsIoBitmaps
-                //| CpuBasedControlsIoExiting,
-        ),
+        adjust_value_based_on_msr(Msr::Ia32VmxProcBasedControls, PROCESSOR_BASED_CONTROLS),
     )?;
 
     vmwrite(
         VmcsField::VmExitControls,
         adjust_value_based_on_msr(
             Msr::Ia32VmxExitControls,
-            VmExitIa32eMode /*| VmExitAcknowledgeInterruptOnExit*/ | VmExitConcealVmxFromPt,
+            VmExitIa32eMode
+                /*| VmExitAcknowledgeInterruptOnExit*/
+                | VmExitConcealVmxFromPt
+                | VmExitSaveDebugControls,
         ),
     )?;
 
     vmwrite(
         VmcsField::VmEntryControls,
-        adjust_value_based_on_msr(Msr::Ia32VmxEntryControls, VmEntryIa32eMode),
+        adjust_value_based_on_msr(
+            Msr::Ia32VmxEntryControls,
+            VmEntryIa32eMode | VmEntryLoadDebugControls,
+        ),
     )?;
 
     vmwrite(VmcsField::MsrBitmap, vcpu.msr_bitmap as u64)?;
 
-    Ok(())
+    #[cfg(feature = "gdb_stub")]
+    vmwrite(
+        VmcsField::ExceptIonBitmap,
+        crate::gdb_stub::EXCEPTION_BITMAP,
+    )?;
+
+    nmi::init(vcpu)?;
+    timekeeping::init(vcpu)?;
+    profiler::init()
 }
 
 /// Given a vm instruction error number, return a human readable string
@@ -307,3 +329,19 @@ pub fn vm_instruction_error_number_message(vm_instruction_error_number: u64) ->
         _ => "Unknown VM instruction error number.",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_cr8_writes_reach_the_processor() {
+        // A MOV to cr8 which doesn't exit changes the task priority register
+        // the processor uses to mask interrupts delivered to the guest.
+        assert_eq!(
+            PROCESSOR_BASED_CONTROLS
+                & (CpuBasedControlsCr8LdExiting | CpuBasedControlsCr8StExiting),
+            0
+        );
+    }
+}
@@ -2,6 +2,7 @@
 #![allow(non_upper_case_globals)]
 #![allow(unused)]
 
+#[derive(Clone, Copy)]
 #[repr(u64)]
 pub enum VmcsField {
     VirtualProcessorID = 0x0000_0000,
@@ -235,9 +236,12 @@ pub const SecondaryCpuBasedControlsEptExecuteControl: u64 = 1 << 22;
 pub const SecondaryCpuBasedControlsTscScalingEnable: u64 = 1 << 25;
 
 // Table 24-11  sectIon 24.7.1 vol3c
+pub const VmExitSaveDebugControls: u64 = 1 << 2;
 pub const VmExitIa32eMode: u64 = 1 << 9;
 pub const VmExitAcknowledgeInterruptOnExit: u64 = 1 << 15;
+pub const VmExitSaveVmxPreemptionTimerValue: u64 = 1 << 22;
 pub const VmExitConcealVmxFromPt: u64 = 1 << 24;
 
 // Table 24-13  sectIon 24.8.1 vol3c
+pub const VmEntryLoadDebugControls: u64 = 1 << 2;
 pub const VmEntryIa32eMode: u64 = 1 << 9;
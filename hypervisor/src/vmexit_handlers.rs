@@ -1,11 +1,27 @@
 //! This module defines the host's VM exit handlers.
 //use crate::interrupt_controller;
+use crate::control_registers;
+use crate::cpuid_policy;
+use crate::debug_registers;
+use crate::exit_stats;
+use crate::exit_trace;
+#[cfg(feature = "gdb_stub")]
+use crate::gdb_stub;
+#[cfg(feature = "host_gdb_stub")]
+use crate::host_gdb_stub;
+use crate::host_stack;
 use crate::hypercall_handler;
+use crate::interrupt_controller;
+use crate::msr::{rdmsrl, Msr};
+use crate::nmi;
+use crate::profiler;
 use crate::register_state::GeneralPurposeRegisterState;
+use crate::single_step;
+use crate::timekeeping;
+use crate::vcpu::{self, get_current_vcpu};
 use crate::vmcs_dump;
 use crate::vmcs_fields::VmcsField;
 use crate::vmexit_reasons::*;
-use crate::vmx;
 use crate::vmx::vmread;
 use crate::vmx::vmwrite;
 use log::trace;
@@ -15,7 +31,10 @@ use log::trace;
 /// resumes, it will start executing the next instruction.
 /// This function should not be called more than once per VM exit, or the guest
 /// may begin executing illegal or unintended instructions.
+/// If the guest is being single stepped, the emulated instruction counts as the
+/// step.
 fn advance_guest_rip() -> Result<(), x86::vmx::VmFail> {
+    single_step::instruction_emulated();
     let mut rip = vmread(VmcsField::GuestRip)?;
     let len = vmread(VmcsField::VmExitInstructionLen)?;
     rip += len;
@@ -23,35 +42,120 @@ fn advance_guest_rip() -> Result<(), x86::vmx::VmFail> {
 }
 
 /// Handle CPUID
-/// In most cases, report back the host's CPUID values with the following exceptions:
-/// - Clear the VMX available bit
-/// - If RAX has the magic value 'rsty' or 0x72737479 this is a hypercall, so
-///   call the hypercall handler.
-/// - Do not set the hypervisor bit, to be stealthy.
+/// Report back the values chosen by the
+/// [CPUID policy](../cpuid_policy/index.html) for the leaf in RAX and the
+/// subleaf in RCX, which by default are the host's values with the VMX
+/// available bit cleared. If RAX has the magic value 'rsty' or 0x72737479 this
+/// is a hypercall, so call the hypercall handler, unless the
+/// [hypercall policy](../hypercall_policy/index.html) forbids the caller from
+/// making it.
 fn handle_cpuid(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
-    advance_guest_rip()?;
-    if gprs.rax as u32 == hypervisor_abi::HYPERCALL_MAGIC {
-        return hypercall_handler::handle_hypercall(gprs);
+    if gprs.rax as u32 == hypervisor_abi::HYPERCALL_MAGIC
+        && hypercall_handler::handle_hypercall(gprs)?
+    {
+        return advance_guest_rip();
     }
 
-    let mut result = unsafe { core::arch::x86_64::__cpuid(gprs.rax as u32) };
-    if gprs.rax == vmx::CPUIDLeaf::ProcessorInfoAndFeatures as u64 {
-        result.ecx &= vmx::CPUIDLeafProcessorInfoAndFeaturesECXBits::VMXAvailable as u32;
-    }
+    let vcpu_index = vcpu::index_of(get_current_vcpu()).unwrap_or(0);
+    let result = cpuid_policy::emulate(vcpu_index, gprs.rax as u32, gprs.rcx as u32);
     gprs.rax = u64::from(result.eax);
     gprs.rbx = u64::from(result.ebx);
     gprs.rcx = u64::from(result.ecx);
     gprs.rdx = u64::from(result.edx);
 
-    Ok(())
+    advance_guest_rip()
+}
+
+/// Handle VMCALL, which is only used for hypercalls. See the
+/// [hypervisor_abi](../../hypervisor_abi/index.html) crate for the calling
+/// convention. Callers which aren't allowed to make the hypercall get a #UD,
+/// as if the hypervisor wasn't loaded.
+fn handle_vmcall(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    if hypercall_handler::handle_vmcall(gprs)? {
+        advance_guest_rip()
+    } else {
+        interrupt_controller::inject_exception(
+            interrupt_controller::EXCEPTION_VECTOR_INVALID_OPCODE,
+            None,
+        )
+    }
+}
+
+/// Store a 64 bit value in edx:eax, as returned by RDTSC and RDMSR. The high
+/// 32 bits of rax and rdx are cleared.
+fn set_edx_eax(gprs: &mut GeneralPurposeRegisterState, value: u64) {
+    gprs.rax = value & 0xffff_ffff;
+    gprs.rdx = value >> 32;
 }
 
+/// Emulate RDTSC, which only exits in compensated mode. See the
+/// [timekeeping](../timekeeping/index.html) module.
+fn handle_rdtsc(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    set_edx_eax(gprs, timekeeping::guest_tsc()?);
+    advance_guest_rip()
+}
+
+/// Emulate RDTSCP, which is RDTSC that also loads IA32_TSC_AUX into ecx.
+/// IA32_TSC_AUX isn't intercepted, so the hardware holds the guest's value.
+fn handle_rdtscp(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    set_edx_eax(gprs, timekeeping::guest_tsc()?);
+    gprs.rcx = rdmsrl(Msr::Ia32TscAux) & 0xffff_ffff;
+    advance_guest_rip()
+}
+
+/// Emulate RDMSR of an MSR intercepted by the MSR bitmap. MSRs outside the
+/// ranges covered by the bitmap always exit, and are treated as if they don't
+/// exist by injecting a #GP.
+fn handle_rdmsr(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    let msr = gprs.rcx as u32;
+    match timekeeping::read_msr(msr)? {
+        Some(value) => {
+            set_edx_eax(gprs, value);
+            advance_guest_rip()
+        }
+        None => {
+            trace!("Unhandled rdmsr {:x}, injecting #GP", msr);
+            interrupt_controller::inject_exception(
+                interrupt_controller::EXCEPTION_VECTOR_GENERAL_PROTECTION,
+                Some(0),
+            )
+        }
+    }
+}
+
+/// Emulate WRMSR of an MSR intercepted by the MSR bitmap. See
+/// [handle_rdmsr](fn.handle_rdmsr.html).
+fn handle_wrmsr(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    let msr = gprs.rcx as u32;
+    let value = (gprs.rdx << 32) | (gprs.rax & 0xffff_ffff);
+    if timekeeping::write_msr(msr, value)? {
+        advance_guest_rip()
+    } else {
+        trace!("Unhandled wrmsr {:x} value {:x}, injecting #GP", msr, value);
+        interrupt_controller::inject_exception(
+            interrupt_controller::EXCEPTION_VECTOR_GENERAL_PROTECTION,
+            Some(0),
+        )
+    }
+}
+
+/// The access types of a control register access exit qualification.
+/// See the Intel manual, Volume 3, Table 27-3 "Exit Qualification for
+/// Control-Register Accesses".
+const CONTROL_REGISTER_ACCESS_MOV_TO_CR: u64 = 0;
+const CONTROL_REGISTER_ACCESS_MOV_FROM_CR: u64 = 1;
+const CONTROL_REGISTER_ACCESS_CLTS: u64 = 2;
+const CONTROL_REGISTER_ACCESS_LMSW: u64 = 3;
+
 /// Emulate control register access. When a control register access VM exit
 /// occurs, perform that access, e.g. load from the control register or a store
 /// to it, on the underlying hardware (since this is a mostly passthrough
 /// hypervisor).
-/// At present we do not implement the CLTS or LMSW instructions since they are
-/// unused by major operating systems.
+/// Writes to cr0 and cr4 only exit when they would change bits owned by the
+/// hypervisor, and accesses to cr8 don't exit at all. Those writes, along
+/// with accesses to cr3, CLTS and LMSW, are emulated by the
+/// [control_registers](../control_registers/index.html) module. Illegal writes
+/// inject a #GP into the guest instead of being performed.
 fn handle_control_register_access(
     gprs: &mut GeneralPurposeRegisterState,
 ) -> Result<(), x86::vmx::VmFail> {
@@ -60,46 +164,104 @@ fn handle_control_register_access(
 
     let crnum = qualification & 0xf;
     let access_type = (qualification >> 4) & 0x3;
-    // Presently unimplemented, but here when I need it. See comment below.
-    //usize lmsw_type = (qualification >> 6) & 1;
+    let lmsw_operand_is_memory = (qualification >> 6) & 1 != 0;
     let regnum = (qualification >> 8) & 0xf;
+    let lmsw_source_data = (qualification >> 16) & 0xffff;
 
-    let field = match crnum {
-        0 => VmcsField::GuestCr0,
-        3 => VmcsField::GuestCr3,
-        4 => VmcsField::GuestCr4,
-        _ => panic!("Illegal crnum from qualification {:x}", qualification),
-    };
-
-    let register = gprs.by_mod_rm_index(regnum);
-
-    match access_type {
-        // Write
-        0 => {
-            let value = match register {
+    let result = match access_type {
+        CONTROL_REGISTER_ACCESS_MOV_TO_CR => {
+            let value = match gprs.by_mod_rm_index(regnum) {
                 Some(reg) => *reg,
                 None => vmread(VmcsField::GuestRsp)?,
             };
-            vmwrite(field, value)?;
+            control_registers::write(crnum, value)
         }
-        // Read
-        1 => {
-            let value = vmread(field)?;
-            match register {
+        CONTROL_REGISTER_ACCESS_MOV_FROM_CR => {
+            let value = control_registers::read(crnum)?;
+            match gprs.by_mod_rm_index(regnum) {
                 Some(reg) => {
                     *reg = value;
                 }
                 None => vmwrite(VmcsField::GuestRsp, value)?,
             }
+            Ok(())
         }
-        // FIXME: implement LMSW & CLTS.
-        // I don't believe any major OS uses them.
-        _ => {
-            unimplemented!("Unhandled CR access. Qualification {:x}", qualification);
+        CONTROL_REGISTER_ACCESS_CLTS => control_registers::clts(),
+        CONTROL_REGISTER_ACCESS_LMSW => {
+            if lmsw_operand_is_memory {
+                trace!(
+                    "lmsw from memory at {:x}",
+                    vmread(VmcsField::GuestLinearAddress)?
+                );
+            }
+            control_registers::lmsw(lmsw_source_data)
         }
+        _ => unreachable!(),
+    };
+
+    match result {
+        Ok(()) => advance_guest_rip(),
+        Err(e) => control_registers::handle_access_error(e),
     }
-    advance_guest_rip()?;
-    Ok(())
+}
+
+/// Emulate a MOV to or from a debug register. These only exit while the
+/// hypervisor has hardware breakpoints reserved, see the
+/// [debug_registers](../debug_registers/index.html) module. If the hypervisor
+/// has since released them, give the debug registers back to the guest and
+/// let it execute the MOV again without exiting.
+fn handle_mov_dr(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    if debug_registers::return_to_guest_if_unreserved()? {
+        return Ok(());
+    }
+
+    // Table 27-4 vol 3c exit qual for mov dr
+    let qualification = vmread(VmcsField::ExitQualificatIon)?;
+    let drnum = qualification & 0x7;
+    let is_read = (qualification >> 4) & 1 != 0;
+    let regnum = (qualification >> 8) & 0xf;
+
+    let result = if is_read {
+        match debug_registers::read(drnum) {
+            Ok(value) => {
+                match gprs.by_mod_rm_index(regnum) {
+                    Some(reg) => {
+                        *reg = value;
+                    }
+                    None => vmwrite(VmcsField::GuestRsp, value)?,
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        let value = match gprs.by_mod_rm_index(regnum) {
+            Some(reg) => *reg,
+            None => vmread(VmcsField::GuestRsp)?,
+        };
+        debug_registers::write(drnum, value)
+    };
+
+    match result {
+        Ok(()) => advance_guest_rip(),
+        Err(e) => debug_registers::handle_access_error(e),
+    }
+}
+
+/// Handle an NMI taken while the guest ran, see the [nmi](../nmi/index.html)
+/// module, or an exception intercepted for gdb.
+fn handle_nmi_or_exception(gprs: &mut GeneralPurposeRegisterState) -> Result<(), x86::vmx::VmFail> {
+    if nmi::handle_nmi_or_exception_exit()? {
+        return Ok(());
+    }
+    #[cfg(feature = "gdb_stub")]
+    return gdb_stub::handle_exception(gprs);
+    #[cfg(not(feature = "gdb_stub"))]
+    panic!(
+        "Unexpected exception {:x?} in the guest {:x?}",
+        vmread(VmcsField::VmExitIntrInfo),
+        gprs
+    );
 }
 
 /// Handle a VM Exit. This function will be called by the assembly code in
@@ -109,14 +271,42 @@ fn handle_control_register_access(
 /// purpose register state when this function returns.
 #[no_mangle]
 pub extern "C" fn hypervisor_handle_vmexit(gprs: *mut GeneralPurposeRegisterState) {
+    let start = exit_stats::start();
+    debug_registers::save_guest_registers();
+    get_current_vcpu().guest_gprs = gprs;
+    // A snapshot from an earlier exit no longer describes the guest.
+    get_current_vcpu().guest_snapshot_valid = false;
+    // Another core panicked, and this exit may be for its NMI. Stop before
+    // touching anything it may have left locked.
+    #[cfg(not(test))]
+    if crate::panic::in_progress() {
+        crate::panic::stop_this_core_in_vm_exit();
+    }
+    #[cfg(feature = "host_gdb_stub")]
+    host_gdb_stub::poll();
     let gprs = unsafe { &mut *gprs };
     let vmexit_reasion = vmread(VmcsField::VmExitReason).expect("vm exit reason shouldn't error");
     let qualification = vmread(VmcsField::ExitQualificatIon).unwrap_or(0);
+    exit_trace::record(start, vmexit_reasion, qualification, gprs);
+    timekeeping::exit_started(start);
     match vmexit_reasion {
         VMEXIT_REASON_CPUID => handle_cpuid(gprs).unwrap(),
+        VMEXIT_REASON_VMCALL => handle_vmcall(gprs).unwrap(),
         VMEXIT_REASON_CONTROL_REGISTER_ACCESS => {
             handle_control_register_access(gprs).unwrap();
         }
+        VMEXIT_REASON_MOV_DR => handle_mov_dr(gprs).unwrap(),
+        VMEXIT_REASON_RDTSC => handle_rdtsc(gprs).unwrap(),
+        VMEXIT_REASON_RDTSCP => handle_rdtscp(gprs).unwrap(),
+        VMEXIT_REASON_RDMSR => handle_rdmsr(gprs).unwrap(),
+        VMEXIT_REASON_WRMSR => handle_wrmsr(gprs).unwrap(),
+        VMEXIT_REASON_MONITOR_TRAP => single_step::handle_monitor_trap(gprs).unwrap(),
+        VMEXIT_REASON_PREEMPTION_TIMER_EXPIRED => profiler::handle_preemption_timer().unwrap(),
+        VMEXIT_REASON_NMI_OR_EXCEPTION => handle_nmi_or_exception(gprs).unwrap(),
+        VMEXIT_REASON_NMI_WINDOWS => nmi::handle_nmi_window().unwrap(),
+        // The panic handler writes the core dump, after stopping the other
+        // cores so that they are in it too.
+        VMEXIT_REASON_TRIPLE_FAULT => panic!("Guest triple faulted"),
         /*
         VMEXIT_REASON_EXTERNAL_INTERRUPT => {
             trace!("Got external interrupt {:x?}", vmread(VmcsField::GuestRip));
@@ -142,6 +332,15 @@ pub extern "C" fn hypervisor_handle_vmexit(gprs: *mut GeneralPurposeRegisterStat
             );
         }
     }
+    #[cfg(feature = "gdb_stub")]
+    gdb_stub::poll(gprs).unwrap();
+    single_step::prepare_vm_entry().unwrap();
+    nmi::prepare_vm_entry().unwrap();
+    timekeeping::exit_finished().unwrap();
+    debug_registers::load_guest_registers();
+    exit_stats::record(vmexit_reasion, start);
+    host_stack::check(get_current_vcpu());
+    get_current_vcpu().guest_gprs = core::ptr::null_mut();
 }
 
 /// Called by [_host_entrypoint](../vmcs/fn._host_entrypoint.html) when a VM
@@ -1,68 +1,5 @@
-//! Defines the codes representing the reason a VM exit occurred.
-#![allow(dead_code)]
+//! Defines the codes representing the reason a VM exit occurred. The codes
+//! are shared with tools like rustyvctl through hypervisor_abi.
+#![allow(unused_imports)]
 
-pub const VMEXIT_REASON_NMI_OR_EXCEPTION: u64 = 0;
-pub const VMEXIT_REASON_EXTERNAL_INTERRUPT: u64 = 1;
-pub const VMEXIT_REASON_TRIPLE_FAULT: u64 = 2;
-pub const VMEXIT_REASON_INIT_SIGNAL: u64 = 3;
-pub const VMEXIT_REASON_START_UP_IPI: u64 = 4;
-pub const VMEXIT_REASON_IO_SMI: u64 = 5;
-pub const VMEXIT_REASON_OTHER_SMI: u64 = 6;
-pub const VMEXIT_REASON_INTERRUPT_WINDOW: u64 = 7;
-pub const VMEXIT_REASON_NMI_WINDOWS: u64 = 8;
-pub const VMEXIT_REASON_TASK_SWITCH: u64 = 9;
-pub const VMEXIT_REASON_CPUID: u64 = 10;
-pub const VMEXIT_REASON_GETSEC: u64 = 11;
-pub const VMEXIT_REASON_HLT: u64 = 12;
-pub const VMEXIT_REASON_INVD: u64 = 13;
-pub const VMEXIT_REASON_INVLPG: u64 = 14;
-pub const VMEXIT_REASON_RDPMC: u64 = 15;
-pub const VMEXIT_REASON_RDTSC: u64 = 16;
-pub const VMEXIT_REASON_RSM: u64 = 17;
-pub const VMEXIT_REASON_VMCALL: u64 = 18;
-pub const VMEXIT_REASON_VMCLEAR: u64 = 19;
-pub const VMEXIT_REASON_VMLAUNCH: u64 = 20;
-pub const VMEXIT_REASON_VMPTRLD: u64 = 21;
-pub const VMEXIT_REASON_VMPTRST: u64 = 22;
-pub const VMEXIT_REASON_VMREAD: u64 = 23;
-pub const VMEXIT_REASON_VMRESUME: u64 = 24;
-pub const VMEXIT_REASON_VMWRITE: u64 = 25;
-pub const VMEXIT_REASON_VMXOFF: u64 = 26;
-pub const VMEXIT_REASON_VMXON: u64 = 27;
-pub const VMEXIT_REASON_CONTROL_REGISTER_ACCESS: u64 = 28;
-pub const VMEXIT_REASON_MOV_DR: u64 = 29;
-pub const VMEXIT_REASON_IO_INSTRUCTION: u64 = 30;
-pub const VMEXIT_REASON_RDMSR: u64 = 31;
-pub const VMEXIT_REASON_WRMSR: u64 = 32;
-pub const VMEXIT_REASON_INVALID_GUEST_STATE: u64 = 33;
-pub const VMEXIT_REASON_VM_ENTRY_FAILURE_DUE_TO_MSR_LOADING: u64 = 34;
-pub const VMEXIT_REASON_MWAIT: u64 = 36;
-pub const VMEXIT_REASON_MONITOR_TRAP: u64 = 37;
-pub const VMEXIT_REASON_MONITOR: u64 = 39;
-pub const VMEXIT_REASON_PAUSE: u64 = 40;
-pub const VMEXIT_REASON_VM_ENTRY_DUE_TO_MACHINE_CHECK_EVENT: u64 = 41;
-pub const VMEXIT_REASON_TPR_BELOW_THRESHOLD: u64 = 43;
-pub const VMEXIT_REASON_APIC_ACCES: u64 = 44;
-pub const VMEXIT_REASON_VIRTUALIZED_EOI: u64 = 45;
-pub const VMEXIT_REASON_ACCESSING_GDT_OR_IDTR: u64 = 46;
-pub const VMEXIT_REASON_ACCESSING_LDTR_OR_TR: u64 = 47;
-pub const VMEXIT_REASON_EPT_VIOLATION: u64 = 48;
-pub const VMEXIT_REASON_EPT_MISCONFIGURATION: u64 = 49;
-pub const VMEXIT_REASON_INVEPT: u64 = 50;
-pub const VMEXIT_REASON_RDTSCP: u64 = 51;
-pub const VMEXIT_REASON_PREEMPTION_TIMER_EXPIRED: u64 = 52;
-pub const VMEXIT_REASON_INVVPID: u64 = 53;
-pub const VMEXIT_REASON_WBINVD: u64 = 54;
-pub const VMEXIT_REASON_XSETBV: u64 = 55;
-pub const VMEXIT_REASON_APIC_WRITE: u64 = 56;
-pub const VMEXIT_REASON_RDRAND: u64 = 57;
-pub const VMEXIT_REASON_INVPCID: u64 = 58;
-pub const VMEXIT_REASON_VMFUNC: u64 = 59;
-pub const VMEXIT_REASON_ENCLS: u64 = 60;
-pub const VMEXIT_REASON_RDSEED: u64 = 61;
-pub const VMEXIT_REASON_PAGE_MODIFICATION_LOG_FULL: u64 = 62;
-pub const VMEXIT_REASON_XSAVES: u64 = 63;
-pub const VMEXIT_REASON_XRSTORS: u64 = 64;
-pub const VMEXIT_REASON_SPP_RELATED: u64 = 66;
-pub const VMEXIT_REASON_UMWAIT: u64 = 67;
-pub const VMEXIT_REASON_TPAUSE: u64 = 68;
+pub use hypervisor_abi::vmexit_reasons::*;
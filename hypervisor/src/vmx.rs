@@ -4,6 +4,7 @@ use x86;
 
 use core::{mem, ptr};
 
+use crate::control_registers::OriginalControlRegisters;
 use crate::msr::{rdmsr, rdmsrl, wrmsr, Msr};
 use crate::vmcs_fields::VmcsField;
 use crate::{vmcs, VCpu};
@@ -53,6 +54,37 @@ pub fn read_dr7() -> u64 {
     ret
 }
 
+/// The INVVPID type which invalidates all of the non-global linear mappings
+/// tagged with a VPID.
+const INVVPID_SINGLE_CONTEXT_RETAINING_GLOBALS: u64 = 3;
+
+/// Invalidate the non-global TLB entries tagged with the given VPID.
+/// See the Intel manual, Volume 3, Section 28.3.3.3 "Guidelines for Use of the
+/// INVVPID Instruction".
+pub fn invvpid_single_context_retaining_globals(vpid: u16) -> Result<(), x86::vmx::VmFail> {
+    // The descriptor is the VPID in bits 15:0 followed by a linear address,
+    // which is unused for this type.
+    let descriptor: [u64; 2] = [u64::from(vpid), 0];
+    let flags: u64;
+    unsafe {
+        asm!(
+            "invvpid {}, [{}]",
+            "pushfq",
+            "pop {}",
+            in(reg)(INVVPID_SINGLE_CONTEXT_RETAINING_GLOBALS),
+            in(reg)(&descriptor),
+            lateout(reg)(flags),
+        );
+    }
+    if flags & x86::bits64::rflags::RFlags::FLAGS_CF.bits() != 0 {
+        Err(x86::vmx::VmFail::VmFailInvalid)
+    } else if flags & x86::bits64::rflags::RFlags::FLAGS_ZF.bits() != 0 {
+        Err(x86::vmx::VmFail::VmFailValid)
+    } else {
+        Ok(())
+    }
+}
+
 /// Returns true if the Intel vmx extensions are available and a hypervisor is not present, false otherwise.
 fn vmx_available() -> bool {
     let result = unsafe { core::arch::x86_64::__cpuid(CPUIDLeaf::ProcessorInfoAndFeatures as u32) };
@@ -69,26 +101,34 @@ fn get_vmcs_revision_identifier() -> u32 {
     vmcs_revision_identifier
 }
 
-fn set_cr0_bits() {
+/// Set the bits of cr0 required for VMX operation.
+/// Returns the original value of cr0.
+fn set_cr0_bits() -> u64 {
     let fixed0 = rdmsrl(Msr::Ia32VmxCr0Fixed0);
     let fixed1 = rdmsrl(Msr::Ia32VmxCr0Fixed1);
     let mut cr0 = unsafe { x86::controlregs::cr0() };
+    let original_cr0 = cr0.bits() as u64;
     cr0 |= x86::controlregs::Cr0::from_bits_truncate(fixed0 as usize);
     cr0 &= x86::controlregs::Cr0::from_bits_truncate(fixed1 as usize);
     unsafe {
         x86::controlregs::cr0_write(cr0);
     }
+    original_cr0
 }
 
-fn set_cr4_bits() {
+/// Set the bits of cr4 required for VMX operation, including cr4.VMXE.
+/// Returns the original value of cr4.
+fn set_cr4_bits() -> u64 {
     let fixed0 = rdmsrl(Msr::Ia32VmxCr4Fixed0);
     let fixed1 = rdmsrl(Msr::Ia32VmxCr4Fixed1);
     let mut cr4 = unsafe { x86::controlregs::cr4() };
+    let original_cr4 = cr4.bits() as u64;
     cr4 |= x86::controlregs::Cr4::from_bits_truncate(fixed0 as usize);
     cr4 &= x86::controlregs::Cr4::from_bits_truncate(fixed1 as usize);
     unsafe {
         x86::controlregs::cr4_write(cr4);
     }
+    original_cr4
 }
 
 fn set_lock_bit() -> Result<(), ()> {
@@ -126,11 +166,14 @@ pub enum VmxEnablementError {
     VmxOnFailure,
 }
 
+/// Enable VMX operation on the current core.
+/// Returns the values of cr0 and cr4 from before they were adjusted for VMX
+/// operation, which are the values the guest expects to see.
 pub fn enable(
     vmxon_region: *mut u32,
     vmxon_region_phys: u64,
     vmxon_region_size: usize,
-) -> Result<(), VmxEnablementError> {
+) -> Result<OriginalControlRegisters, VmxEnablementError> {
     assert!(is_page_aligned(vmxon_region as u64));
     assert!(is_page_aligned(vmxon_region_phys));
 
@@ -151,9 +194,9 @@ pub fn enable(
     })?;
 
     trace!("Setting cr0 bits");
-    set_cr0_bits();
+    let cr0 = set_cr0_bits();
     trace!("Setting cr4 bits");
-    set_cr4_bits();
+    let cr4 = set_cr4_bits();
 
     trace!("Preparing vmxon region");
     prepare_vmx_memory_region(vmxon_region, vmxon_region_size);
@@ -162,7 +205,7 @@ pub fn enable(
     match unsafe { x86::bits64::vmx::vmxon(vmxon_region_phys) } {
         Ok(()) => {
             trace!("vmxon succeeded");
-            Ok(())
+            Ok(OriginalControlRegisters { cr0, cr4 })
         }
         Err(e) => {
             error!("vmxon failed {:x?}", e);
@@ -179,7 +222,10 @@ extern "C" {
     fn _guest_first_entry() -> usize;
 }
 
-pub fn load_vm(vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
+pub fn load_vm(
+    vcpu: &VCpu,
+    original_control_registers: &OriginalControlRegisters,
+) -> Result<(), x86::vmx::VmFail> {
     trace!(
         "Loading vmm with vcpu {:x?} {:x?}",
         vcpu,
@@ -206,7 +252,7 @@ pub fn load_vm(vcpu: &VCpu) -> Result<(), x86::vmx::VmFail> {
     trace!("Initializing host state");
     vmcs::initialize_host_state(vcpu)?;
     trace!("Initializing guest state");
-    vmcs::initialize_guest_state(vcpu)?;
+    vmcs::initialize_guest_state(vcpu, original_control_registers)?;
 
     trace!("Launching...");
 
@@ -1,22 +1,310 @@
 //! Hypercall ABI
-//! The hypervisor will handle hypercalls via the CPUID instruction.
-//! Hypercalls must have a magic number HYPERCALL_MAGIC in RAX and a valid
-//! hypercall reason in RBX.
-//! Values will be returned in RAX, RBX, RCX, and RDX according to the
-//! hypercall reason.
+//! The hypervisor handles hypercalls made with either the CPUID or the VMCALL
+//! instruction. Both reach the same hypercalls, named by a hypercall reason.
+//!
+//! Hypercalls made with CPUID must have a magic number HYPERCALL_MAGIC in RAX
+//! and a valid hypercall reason in RCX. Hypercalls which take an argument
+//! expect it in RDX. Values will be returned in RAX, RBX, RCX, and RDX
+//! according to the hypercall reason, truncated to 32 bits like any other
+//! CPUID result. See [invoke_hypercall](fn.invoke_hypercall.html).
+//!
+//! Hypercalls made with VMCALL have the hypercall reason in RAX and up to
+//! VMCALL_MAX_ARGUMENTS arguments in RDI, RSI, RDX, R10, R8 and R9, where the
+//! first is the argument passed in RDX to CPUID. RBX and RCX may give the
+//! address and size of a buffer for data which doesn't fit in registers, or
+//! RCX is 0 if there is none. The buffer's address is a virtual address in the
+//! caller's address space, and only a caller running at CPL 0 may pass one.
+//! The hypervisor accesses it through the caller's page tables with the
+//! caller's access rights. A HYPERCALL_STATUS is
+//! returned in RAX, and on success the values CPUID would return in RAX, RBX,
+//! RCX and RDX are returned in full in RDI, RSI, RDX and R10. Executing VMCALL
+//! when the hypervisor isn't loaded raises #UD, so check with CPUID first. See
+//! [invoke_vmcall](fn.invoke_vmcall.html).
+//!
+//! A hypercall made with CPUID which fails returns HYPERCALL_ERROR in RAX, RCX
+//! and RDX and a HYPERCALL_STATUS in RBX. Both transports' wrappers return
+//! failures as a [HypercallError](enum.HypercallError.html). The hypercalls
+//! the hypervisor supports can be listed with
+//! [read_capabilities](fn.read_capabilities.html).
+//!
+//! The hypervisor may restrict who can make each hypercall, e.g. to CPL 0, to
+//! one address space, or to code which has completed a
+//! [handshake](fn.handshake.html) with a secret the hypervisor was loaded
+//! with. A hypercall the caller isn't allowed to make behaves as if the
+//! hypervisor wasn't there: CPUID returns the processor's values, and VMCALL
+//! raises #UD. The capabilities hypercall only lists the hypercalls the caller
+//! is allowed to make.
 
 #![no_std]
 
 use core::arch::x86_64::__cpuid_count;
 
+pub mod ring;
+pub mod vmexit_reasons;
+
 /// Magic number which must be in RAX if this is a hypercall.
 pub const HYPERCALL_MAGIC: u32 = 0x72737479;
 
-/// Hypercall reasons must be in RBX. If RBX=1, the reason is version.
+/// Hypercall reasons are in RCX, or RAX for VMCALL. If RCX=1, the reason is
+/// version.
 /// The major, minor, and patch version numbers from this crate's Cargo.toml
 /// will be returned in rax, rbx, and rcx respectively. Rdx is reserved zero.
 pub const HYPERCALL_REASON_VERSION: u32 = 0x1;
 
+/// If RCX=2, the reason is profile sample. Removes the oldest guest RIP sample
+/// taken by the hypervisor's profiler on any core, see
+/// [read_profile_sample](fn.read_profile_sample.html). The low and high halves
+/// of the sampled RIP are returned in rax and rbx, and the page frame number
+/// of the sampled CR3 in rcx. Rdx holds PROFILE_SAMPLE_VALID, the index of
+/// the core which took the sample in bits 23:8 and the sampled CPL in bits
+/// 1:0. If there are no samples, all four are zero.
+pub const HYPERCALL_REASON_PROFILE_SAMPLE: u32 = 0x2;
+
+/// Set in rdx by the profile sample hypercall if a sample was returned.
+pub const PROFILE_SAMPLE_VALID: u32 = 1 << 31;
+/// The shift of the core index in rdx returned by the profile sample
+/// hypercall.
+pub const PROFILE_SAMPLE_VCPU_SHIFT: u32 = 8;
+const PROFILE_SAMPLE_VCPU_MASK: u32 = 0xffff;
+const PROFILE_SAMPLE_CPL_MASK: u32 = 3;
+
+/// If RCX=3, the reason is exit stats. Returns part of the VM exit
+/// statistics kept for one VM exit reason on one core, see
+/// [read_exit_stats](fn.read_exit_stats.html). Takes an argument in rdx,
+/// holding the index of the core in bits 47:32, the exit reason in bits 23:8
+/// and which part of the statistics to return in bits 7:0:
+/// - EXIT_STATS_QUERY_TOTALS returns the number of exits in rax and rbx, and
+///   the total TSC ticks spent handling them in rcx and rdx, low half first.
+/// - EXIT_STATS_QUERY_HISTOGRAM plus n returns histogram buckets 4n to 4n+3
+///   in rax, rbx, rcx and rdx.
+///
+/// If the core, exit reason or query is out of range, the hypercall fails with
+/// HYPERCALL_STATUS_INVALID_ARGUMENT.
+pub const HYPERCALL_REASON_EXIT_STATS: u32 = 0x3;
+
+/// Query the number of exits and the time spent handling them.
+pub const EXIT_STATS_QUERY_TOTALS: u32 = 0;
+/// Query four buckets of the histogram of time spent handling exits.
+pub const EXIT_STATS_QUERY_HISTOGRAM: u32 = 1;
+/// Exit reasons at or above this aren't counted.
+pub const EXIT_STATS_MAX_REASONS: u32 = 80;
+/// Bucket n of an exit stats histogram counts exits which took at least 2^n
+/// TSC ticks to handle, but fewer than 2^(n+1). The first bucket also counts
+/// exits which took less than one tick, and the last every exit which took
+/// longer.
+pub const EXIT_STATS_HISTOGRAM_BUCKETS: usize = 32;
+/// The shift of the core index in the exit stats hypercall's argument.
+pub const EXIT_STATS_VCPU_SHIFT: u32 = 32;
+/// The shift of the exit reason in the exit stats hypercall's argument.
+pub const EXIT_STATS_REASON_SHIFT: u32 = 8;
+
+/// If RCX=4, the reason is exit trace. Returns part of the trace of recent VM
+/// exits kept by one core, see [ExitTraceRecord](struct.ExitTraceRecord.html).
+/// Each core numbers its records with a 32 bit sequence number which wraps
+/// around. Takes an argument in rdx, holding which part of the trace to return
+/// in bits 55:48, the index of the core in bits 47:32 and a sequence number in
+/// bits 31:0:
+/// - EXIT_TRACE_QUERY_BOUNDS returns the sequence number of the oldest record
+///   in rax, the sequence number the next record will have in rbx, and
+///   EXIT_TRACE_RECORD_SIZE in rcx. The sequence number in the argument is
+///   ignored.
+/// - EXIT_TRACE_QUERY_RECORD plus n returns bytes 16n to 16n+15 of the record
+///   with the given sequence number in rax, rbx, rcx and rdx.
+///
+/// If the core or query is out of range, or the record has been overwritten,
+/// the hypercall fails with HYPERCALL_STATUS_INVALID_ARGUMENT.
+///
+/// Made with VMCALL and a buffer, EXIT_TRACE_QUERY_RECORD instead writes as
+/// many consecutive records as fit in the buffer, starting with the one with
+/// the given sequence number, and returns the number written in rdi. See
+/// [read_exit_trace_records](fn.read_exit_trace_records.html).
+pub const HYPERCALL_REASON_EXIT_TRACE: u32 = 0x4;
+
+/// Query the sequence numbers of the records in the trace.
+pub const EXIT_TRACE_QUERY_BOUNDS: u32 = 0;
+/// Query 16 bytes of a record.
+pub const EXIT_TRACE_QUERY_RECORD: u32 = 1;
+/// The shift of the core index in the exit trace hypercall's argument.
+pub const EXIT_TRACE_VCPU_SHIFT: u32 = 32;
+/// The shift of the query in the exit trace hypercall's argument.
+pub const EXIT_TRACE_QUERY_SHIFT: u32 = 48;
+/// The size in bytes of an encoded exit trace record.
+pub const EXIT_TRACE_RECORD_SIZE: usize = 64;
+
+/// If RCX=5, the reason is capabilities. Returns HYPERVISOR_INTERFACE_VERSION
+/// in rax and the number of hypercalls the hypervisor supports in rbx. Takes
+/// an argument in rdx, n, and returns a bitmap of which of the hypercall
+/// reasons 64n to 64n+63 are supported in rcx and rdx, low half first. See
+/// [read_capabilities](fn.read_capabilities.html).
+pub const HYPERCALL_REASON_CAPABILITIES: u32 = 0x5;
+/// The number of hypercall reasons
+/// [read_capabilities](fn.read_capabilities.html) asks about.
+pub const CAPABILITIES_MAX_REASONS: u32 = 256;
+
+/// If RCX=6, the reason is handshake. Takes a secret in rdx. If it is the
+/// secret the hypervisor was loaded with, the caller's address space, named by
+/// its CR3, may make hypercalls which require a handshake, and
+/// HYPERCALL_MAGIC is returned in rax. Otherwise the hypercall behaves like
+/// any other the caller isn't allowed to make. See
+/// [handshake](fn.handshake.html).
+pub const HYPERCALL_REASON_HANDSHAKE: u32 = 0x6;
+
+/// If RAX=7, the reason is channel, which is only available with VMCALL. A
+/// channel is a pair of [rings](ring/index.html) in guest memory, one for
+/// messages to the hypervisor and one for its replies. The first argument is
+/// a CHANNEL_OPERATION. See [register_channel](fn.register_channel.html).
+/// Only the address space which registered a channel, named by its CR3, may
+/// ring its doorbell or unregister it.
+///
+/// Channels are limited in two ways:
+/// - They are only offered under the UEFI loader, where the hypervisor can
+///   reach the rings by their guest physical addresses. Under the Linux
+///   loader the reason isn't supported, fails with
+///   HYPERCALL_STATUS_UNKNOWN_REASON, and isn't reported by
+///   [read_capabilities](fn.read_capabilities.html).
+/// - The rings are neither pinned nor protected. The hypervisor doesn't use
+///   EPT, so the guest can free, reuse or overwrite the rings' memory while
+///   they are registered, and it is up to the guest not to. The hypervisor
+///   treats everything in the rings as untrusted, so doing so only breaks
+///   the channel.
+pub const HYPERCALL_REASON_CHANNEL: u32 = 0x7;
+/// Register a channel. The second and third arguments are the guest physical
+/// address and size of the ring for messages to the hypervisor, and the
+/// fourth and fifth are those of the ring for its replies. Both must have
+/// been initialized with [ring::init](ring/fn.init.html), must be aligned to
+/// RING_ALIGN, no larger than CHANNEL_MAX_RING_SIZE, and must not overlap.
+/// Returns the channel's number in rdi.
+pub const CHANNEL_OPERATION_REGISTER: u64 = 0;
+/// Ring a channel's doorbell. The second argument is the channel's number.
+/// The hypervisor handles every message in the ring to it, writing any
+/// replies to the other ring, and returns the number of messages handled in
+/// rdi and the number of bytes waiting in the ring of replies in rsi.
+pub const CHANNEL_OPERATION_DOORBELL: u64 = 1;
+/// Unregister a channel. The second argument is the channel's number. The
+/// hypervisor no longer touches its rings afterwards.
+pub const CHANNEL_OPERATION_UNREGISTER: u64 = 2;
+/// The largest ring a channel can use, in bytes.
+pub const CHANNEL_MAX_RING_SIZE: u64 = 1 << 20;
+/// The largest message the hypervisor accepts on a channel, in bytes.
+/// Larger messages are dropped.
+pub const CHANNEL_MAX_MESSAGE_SIZE: usize = 1024;
+/// A message the hypervisor replies to with a copy of itself.
+pub const CHANNEL_MESSAGE_ECHO: u32 = 0;
+
+/// If RCX=8, the reason is log. Pages out the hypervisor's in-memory log, see
+/// [LogRecord](struct.LogRecord.html). The hypervisor numbers its log
+/// records with a 64 bit sequence number, and keeps the most recent
+/// LOG_RING_RECORDS.
+///
+/// Made with CPUID, returns the low 32 bits of the sequence number of the
+/// oldest record in rax and of the sequence number the next record will have
+/// in rbx, and LOG_RECORD_SIZE in rcx.
+///
+/// Made with VMCALL and a buffer, takes a sequence number as its first
+/// argument and writes as many records as fit in the buffer, starting with
+/// the oldest whose sequence number is at least the argument. Returns the
+/// number written in rdi and the sequence number to pass to the next call in
+/// rsi, so that no record is read twice. Records which were overwritten
+/// before they were read are skipped, leaving gaps in the sequence numbers.
+/// See [read_log_records](fn.read_log_records.html).
+pub const HYPERCALL_REASON_LOG: u32 = 0x8;
+/// The number of records the hypervisor's in-memory log keeps.
+pub const LOG_RING_RECORDS: usize = 512;
+/// The size of a [LogRecord](struct.LogRecord.html) in its binary layout.
+pub const LOG_RECORD_SIZE: usize = 128;
+/// The most bytes of text a log record holds. Longer messages are truncated.
+pub const LOG_RECORD_TEXT_SIZE: usize = 104;
+/// The CPU of a record logged outside hypervisor host context, e.g. by the
+/// loader, where the hypervisor can't tell which core it is on.
+pub const LOG_CPU_UNKNOWN: u16 = u16::MAX;
+/// Starts every [LogFrameHeader](struct.LogFrameHeader.html), so that a
+/// decoder can find frames in a stream mixed with text.
+pub const LOG_FRAME_MAGIC: [u8; 4] = *b"RVLG";
+/// The size of a [LogFrameHeader](struct.LogFrameHeader.html) in its binary
+/// layout.
+pub const LOG_FRAME_HEADER_SIZE: usize = 32;
+/// A log argument with no value.
+pub const LOG_ARGUMENT_NONE: u8 = 0;
+/// A log argument holding a u64, in 8 bytes.
+pub const LOG_ARGUMENT_U64: u8 = 1;
+/// A log argument holding an i64, in 8 bytes.
+pub const LOG_ARGUMENT_I64: u8 = 2;
+/// A log argument holding a u128, in 16 bytes.
+pub const LOG_ARGUMENT_U128: u8 = 3;
+/// A log argument holding an i128, in 16 bytes.
+pub const LOG_ARGUMENT_I128: u8 = 4;
+/// A log argument holding an f64, in 8 bytes.
+pub const LOG_ARGUMENT_F64: u8 = 5;
+/// A log argument holding a bool, in 1 byte.
+pub const LOG_ARGUMENT_BOOL: u8 = 6;
+/// A log argument holding a char, as a u32.
+pub const LOG_ARGUMENT_CHAR: u8 = 7;
+/// A log argument holding text, as a u16 length and that many bytes of UTF-8.
+/// Strings are copied, and any other value is formatted by the hypervisor.
+pub const LOG_ARGUMENT_TEXT: u8 = 8;
+
+/// If RCX=9, the reason is log level. Changes which log records one of the
+/// hypervisor's log sinks keeps. The first argument is a LOG_SINK shifted
+/// left by LOG_LEVEL_SINK_SHIFT, ORed with a level: 0 keeps nothing, and 1 to
+/// 5 keep records from errors up to traces, like log::LevelFilter.
+///
+/// Made with CPUID, sets the level of records the sink keeps from targets
+/// without a filter of their own.
+///
+/// Made with VMCALL and a buffer holding a log target, like
+/// `hypervisor::interrupt_controller`, sets a filter for the target and the
+/// modules under it on that sink instead. LOG_LEVEL_INHERIT removes the
+/// filter. The buffer is at most LOG_TARGET_MAX_SIZE bytes of UTF-8.
+///
+/// Returns the sink or filter's previous level in rax, or LOG_LEVEL_INHERIT
+/// if the target had no filter. See [set_log_level](fn.set_log_level.html).
+pub const HYPERCALL_REASON_LOG_LEVEL: u32 = 0x9;
+/// The bit the sink starts at in the log level hypercall's argument.
+pub const LOG_LEVEL_SINK_SHIFT: u32 = 8;
+/// Passed as the level to remove a target's filter, so the target uses the
+/// sink's level again.
+pub const LOG_LEVEL_INHERIT: u32 = 0xff;
+/// The longest log target a filter can be set for, in bytes.
+pub const LOG_TARGET_MAX_SIZE: usize = 64;
+/// The loader's console: COM1 under UEFI, or the kernel log under Linux.
+pub const LOG_SINK_CONSOLE: u32 = 0;
+/// The in-memory log read with HYPERCALL_REASON_LOG.
+pub const LOG_SINK_MEMORY: u32 = 1;
+/// I/O port 0xe9, which Bochs and QEMU can print to their console. Keeps
+/// nothing until its level is set, since the port may belong to a device on
+/// real hardware.
+pub const LOG_SINK_DEBUG_PORT: u32 = 2;
+/// The number of log sinks.
+pub const LOG_SINKS: u32 = 3;
+
+/// The number of arguments a hypercall made with VMCALL can pass in
+/// registers.
+pub const VMCALL_MAX_ARGUMENTS: usize = 6;
+/// Returned in RAX, RCX and RDX by a hypercall made with CPUID which failed.
+/// RBX holds a HYPERCALL_STATUS.
+pub const HYPERCALL_ERROR: u32 = u32::MAX;
+/// Returned in RAX by a successful VMCALL hypercall.
+pub const HYPERCALL_STATUS_SUCCESS: u64 = 0;
+/// The hypercall reason is unknown.
+pub const HYPERCALL_STATUS_UNKNOWN_REASON: u64 = 1;
+/// The hypercall's arguments are out of range.
+pub const HYPERCALL_STATUS_INVALID_ARGUMENT: u64 = 2;
+/// The hypervisor can't write the hypercall's buffer.
+pub const HYPERCALL_STATUS_INVALID_BUFFER: u64 = 3;
+
+/// The first of the hypervisor's CPUID leaves, which are only reported if the
+/// loader enables them. Returns the highest hypervisor leaf in eax and
+/// CPUID_HYPERVISOR_SIGNATURE in ebx, ecx and edx.
+pub const CPUID_LEAF_HYPERVISOR_BASE: u32 = 0x4000_0000;
+/// Returns HYPERVISOR_INTERFACE_VERSION in eax. Ebx, ecx and edx are reserved
+/// zero.
+pub const CPUID_LEAF_HYPERVISOR_INTERFACE: u32 = 0x4000_0001;
+/// The vendor signature reported by CPUID_LEAF_HYPERVISOR_BASE.
+pub const CPUID_HYPERVISOR_SIGNATURE: &[u8; 12] = b"rustyvisor\0\0";
+/// The version of the interface described by this crate. Changed whenever
+/// a hypercall changes incompatibly.
+pub const HYPERVISOR_INTERFACE_VERSION: u32 = 3;
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct HyperCallResults {
     // The hypercall "reason" or discriminant. Similar to a syscall number.
@@ -25,11 +313,647 @@ pub struct HyperCallResults {
     pub results: [u32; 4],
 }
 
-pub fn invoke_hypercall(reason: u32) -> HyperCallResults {
+impl HyperCallResults {
+    /// Turn the registers returned by CPUID into a Result.
+    fn from_registers(reason: u32, results: [u32; 4]) -> Result<Self, HypercallError> {
+        let [rax, rbx, rcx, rdx] = results;
+        if rax == HYPERCALL_ERROR && rcx == HYPERCALL_ERROR && rdx == HYPERCALL_ERROR {
+            return Err(HypercallError::from_status(u64::from(rbx))
+                .unwrap_or(HypercallError::Other(u64::from(rbx))));
+        }
+        Ok(HyperCallResults { reason, results })
+    }
+}
+
+pub fn invoke_hypercall(reason: u32) -> Result<HyperCallResults, HypercallError> {
     let results = unsafe { __cpuid_count(HYPERCALL_MAGIC, reason) };
 
-    HyperCallResults {
-        reason,
-        results: [results.eax, results.ebx, results.ecx, results.edx],
+    HyperCallResults::from_registers(reason, [results.eax, results.ebx, results.ecx, results.edx])
+}
+
+/// Invoke a hypercall which takes an argument in RDX.
+pub fn invoke_hypercall_with_argument(
+    reason: u32,
+    argument: u64,
+) -> Result<HyperCallResults, HypercallError> {
+    let eax: u32;
+    let ebx: u64;
+    let ecx: u32;
+    let edx: u64;
+    // rbx is reserved by LLVM, so save it around cpuid.
+    unsafe {
+        core::arch::asm!(
+            "mov {ebx}, rbx",
+            "cpuid",
+            "xchg {ebx}, rbx",
+            ebx = out(reg) ebx,
+            inout("eax") HYPERCALL_MAGIC => eax,
+            inout("ecx") reason => ecx,
+            inout("rdx") argument => edx,
+        );
+    }
+
+    HyperCallResults::from_registers(reason, [eax, ebx as u32, ecx, edx as u32])
+}
+
+/// A buffer passed to a hypercall made with VMCALL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmcallBuffer {
+    /// The address of the buffer, in the caller's address space.
+    pub address: u64,
+    /// The size of the buffer in bytes.
+    pub len: u64,
+}
+
+/// The reasons a hypercall can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HypercallError {
+    /// See [HYPERCALL_STATUS_UNKNOWN_REASON](constant.HYPERCALL_STATUS_UNKNOWN_REASON.html).
+    UnknownReason,
+    /// See [HYPERCALL_STATUS_INVALID_ARGUMENT](constant.HYPERCALL_STATUS_INVALID_ARGUMENT.html).
+    InvalidArgument,
+    /// See [HYPERCALL_STATUS_INVALID_BUFFER](constant.HYPERCALL_STATUS_INVALID_BUFFER.html).
+    InvalidBuffer,
+    /// A status this version of the ABI doesn't know about.
+    Other(u64),
+}
+
+impl HypercallError {
+    /// The error for a HYPERCALL_STATUS, or None if it is
+    /// HYPERCALL_STATUS_SUCCESS.
+    pub fn from_status(status: u64) -> Option<Self> {
+        match status {
+            HYPERCALL_STATUS_SUCCESS => None,
+            HYPERCALL_STATUS_UNKNOWN_REASON => Some(HypercallError::UnknownReason),
+            HYPERCALL_STATUS_INVALID_ARGUMENT => Some(HypercallError::InvalidArgument),
+            HYPERCALL_STATUS_INVALID_BUFFER => Some(HypercallError::InvalidBuffer),
+            status => Some(HypercallError::Other(status)),
+        }
+    }
+
+    /// The HYPERCALL_STATUS for the error.
+    pub fn status(&self) -> u64 {
+        match self {
+            HypercallError::UnknownReason => HYPERCALL_STATUS_UNKNOWN_REASON,
+            HypercallError::InvalidArgument => HYPERCALL_STATUS_INVALID_ARGUMENT,
+            HypercallError::InvalidBuffer => HYPERCALL_STATUS_INVALID_BUFFER,
+            HypercallError::Other(status) => *status,
+        }
+    }
+}
+
+/// Invoke a hypercall with VMCALL, and return the four values the CPUID
+/// transport would return, in full.
+///
+/// # Safety
+/// The hypervisor must be loaded, or VMCALL raises #UD. If a buffer is given,
+/// the hypervisor may write anywhere in it.
+pub unsafe fn invoke_vmcall(
+    reason: u32,
+    arguments: [u64; VMCALL_MAX_ARGUMENTS],
+    buffer: Option<VmcallBuffer>,
+) -> Result<[u64; 4], HypercallError> {
+    let (buffer_address, buffer_len) = match buffer {
+        Some(buffer) => (buffer.address, buffer.len),
+        None => (0, 0),
+    };
+    let status: u64;
+    let mut results = [0; 4];
+    // rbx is reserved by LLVM, so swap the buffer address in and out of it.
+    core::arch::asm!(
+        "xchg {buffer}, rbx",
+        "vmcall",
+        "xchg {buffer}, rbx",
+        buffer = inout(reg) buffer_address => _,
+        inout("rax") u64::from(reason) => status,
+        inout("rcx") buffer_len => _,
+        inout("rdi") arguments[0] => results[0],
+        inout("rsi") arguments[1] => results[1],
+        inout("rdx") arguments[2] => results[2],
+        inout("r10") arguments[3] => results[3],
+        inout("r8") arguments[4] => _,
+        inout("r9") arguments[5] => _,
+    );
+    match HypercallError::from_status(status) {
+        None => Ok(results),
+        Some(e) => Err(e),
+    }
+}
+
+/// The hypercalls the hypervisor supports.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The hypervisor's HYPERVISOR_INTERFACE_VERSION.
+    pub interface_version: u32,
+    /// The number of hypercalls the hypervisor supports.
+    pub hypercall_count: u32,
+    /// A bitmap of the supported hypercall reasons below
+    /// CAPABILITIES_MAX_REASONS.
+    pub reasons: [u64; CAPABILITIES_MAX_REASONS as usize / 64],
+}
+
+impl Capabilities {
+    /// True if the hypervisor supports the hypercall with this reason.
+    pub fn supports(&self, reason: u32) -> bool {
+        matches!(self.reasons.get(reason as usize / 64), Some(word) if word & (1 << (reason % 64)) != 0)
+    }
+}
+
+/// Ask the hypervisor which hypercalls it supports.
+pub fn read_capabilities() -> Result<Capabilities, HypercallError> {
+    let mut capabilities = Capabilities::default();
+    for (index, word) in capabilities.reasons.iter_mut().enumerate() {
+        let [version, count, low, high] =
+            invoke_hypercall_with_argument(HYPERCALL_REASON_CAPABILITIES, index as u64)?.results;
+        capabilities.interface_version = version;
+        capabilities.hypercall_count = count;
+        *word = u64::from(high) << 32 | u64::from(low);
+    }
+    Ok(capabilities)
+}
+
+/// Give the hypervisor the secret it was loaded with, so that this address
+/// space may make hypercalls which require a handshake. Returns false if the
+/// secret is wrong or the hypervisor doesn't accept handshakes.
+pub fn handshake(secret: u64) -> bool {
+    match invoke_hypercall_with_argument(HYPERCALL_REASON_HANDSHAKE, secret) {
+        Ok(results) => results.results[0] == HYPERCALL_MAGIC,
+        Err(_) => false,
+    }
+}
+
+/// Register a channel, given the guest physical address and size of a ring
+/// for messages to the hypervisor and a ring for its replies, both already
+/// initialized with [ring::init](ring/fn.init.html). Returns the channel's
+/// number.
+///
+/// The hypervisor doesn't use EPT, so it can't stop the guest from freeing or
+/// reusing the rings' memory, and the guest must keep it allocated until
+/// [unregister_channel](fn.unregister_channel.html) returns.
+///
+/// # Safety
+/// The hypervisor must be loaded. The hypervisor writes to both rings until
+/// the channel is unregistered.
+pub unsafe fn register_channel(
+    to_hypervisor: VmcallBuffer,
+    to_guest: VmcallBuffer,
+) -> Result<u64, HypercallError> {
+    let results = invoke_vmcall(
+        HYPERCALL_REASON_CHANNEL,
+        [
+            CHANNEL_OPERATION_REGISTER,
+            to_hypervisor.address,
+            to_hypervisor.len,
+            to_guest.address,
+            to_guest.len,
+            0,
+        ],
+        None,
+    )?;
+    Ok(results[0])
+}
+
+/// Ask the hypervisor to handle the messages sent on a channel. Returns the
+/// number of messages it handled, and the number of bytes waiting in the ring
+/// of replies.
+///
+/// # Safety
+/// The hypervisor must be loaded.
+pub unsafe fn ring_channel_doorbell(channel: u64) -> Result<(u64, u64), HypercallError> {
+    let results = invoke_vmcall(
+        HYPERCALL_REASON_CHANNEL,
+        [CHANNEL_OPERATION_DOORBELL, channel, 0, 0, 0, 0],
+        None,
+    )?;
+    Ok((results[0], results[1]))
+}
+
+/// Unregister a channel, after which its rings' memory may be reused.
+///
+/// # Safety
+/// The hypervisor must be loaded.
+pub unsafe fn unregister_channel(channel: u64) -> Result<(), HypercallError> {
+    invoke_vmcall(
+        HYPERCALL_REASON_CHANNEL,
+        [CHANNEL_OPERATION_UNREGISTER, channel, 0, 0, 0, 0],
+        None,
+    )?;
+    Ok(())
+}
+
+/// A message from the hypervisor's in-memory log.
+///
+/// A record is LOG_RECORD_SIZE bytes in this little endian layout:
+///
+/// | Offset | Size | Field          |
+/// |--------|------|----------------|
+/// | 0      | 8    | sequence       |
+/// | 8      | 1    | level          |
+/// | 9      | 1    | len            |
+/// | 10     | 2    | cpu            |
+/// | 12     | 4    | reserved, zero |
+/// | 16     | 8    | tsc            |
+/// | 24     | 104  | text           |
+///
+/// The layout is stable, new fields may only be added in the reserved bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogRecord {
+    /// The record's sequence number.
+    pub sequence: u64,
+    /// The level the message was logged at, from 1 for errors to 5 for
+    /// traces, like log::Level.
+    pub level: u8,
+    /// The number of bytes of text.
+    pub len: u8,
+    /// The index of the vCPU which logged the message, or LOG_CPU_UNKNOWN.
+    pub cpu: u16,
+    /// The host's TSC when the message was logged.
+    pub tsc: u64,
+    /// The message as UTF-8, truncated to LOG_RECORD_TEXT_SIZE bytes.
+    pub text: [u8; LOG_RECORD_TEXT_SIZE],
+}
+
+impl LogRecord {
+    /// The message, or as much of it as is valid UTF-8.
+    pub fn text(&self) -> &str {
+        let text = &self.text[..usize::from(self.len).min(LOG_RECORD_TEXT_SIZE)];
+        match core::str::from_utf8(text) {
+            Ok(text) => text,
+            Err(e) => core::str::from_utf8(&text[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+
+    /// Encode the record in its binary layout.
+    pub fn to_bytes(&self) -> [u8; LOG_RECORD_SIZE] {
+        let mut bytes = [0; LOG_RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8] = self.level;
+        bytes[9] = self.len;
+        bytes[10..12].copy_from_slice(&self.cpu.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.tsc.to_le_bytes());
+        bytes[24..].copy_from_slice(&self.text);
+        bytes
+    }
+
+    /// Decode a record from its binary layout.
+    pub fn from_bytes(bytes: &[u8; LOG_RECORD_SIZE]) -> Self {
+        let mut sequence = [0; 8];
+        sequence.copy_from_slice(&bytes[0..8]);
+        let mut tsc = [0; 8];
+        tsc.copy_from_slice(&bytes[16..24]);
+        let mut text = [0; LOG_RECORD_TEXT_SIZE];
+        text.copy_from_slice(&bytes[24..]);
+        LogRecord {
+            sequence: u64::from_le_bytes(sequence),
+            level: bytes[8],
+            len: bytes[9],
+            cpu: u16::from_le_bytes([bytes[10], bytes[11]]),
+            tsc: u64::from_le_bytes(tsc),
+            text,
+        }
+    }
+}
+
+/// Starts each log record the hypervisor writes to a sink in binary form,
+/// like COM1 or the debug port, and is followed by target_len bytes of the
+/// record's target, len bytes of its message, both UTF-8, and args_len bytes
+/// of its arguments. Every field is little endian. Each sink numbers its
+/// records separately, so a gap in the sequence numbers means records were
+/// lost.
+///
+/// Formatting is left to the decoder, scripts/log_decode.py. A message is
+/// its format string, unless the format string interpolates values inline, in
+/// which case the hypervisor has to format it. Values passed as the log
+/// crate's key-value arguments, e.g. `trace!(value = cr0; "Guest cr0
+/// write")`, are encoded instead, each as a one byte key length, the key, a
+/// LOG_ARGUMENT kind and the value, whose size depends on the kind.
+///
+/// | Offset | Size | Field          |
+/// |--------|------|----------------|
+/// | 0      | 4    | LOG_FRAME_MAGIC|
+/// | 4      | 2    | len            |
+/// | 6      | 1    | level          |
+/// | 7      | 1    | target_len     |
+/// | 8      | 8    | sequence       |
+/// | 16     | 8    | tsc            |
+/// | 24     | 2    | cpu            |
+/// | 26     | 2    | args_len       |
+/// | 28     | 4    | reserved, zero |
+///
+/// The layout is stable, new fields may only be added in the reserved bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogFrameHeader {
+    /// The number of bytes of message after the target.
+    pub len: u16,
+    /// The level the message was logged at, like LogRecord's.
+    pub level: u8,
+    /// The number of bytes of target after the header.
+    pub target_len: u8,
+    /// The record's sequence number on the sink.
+    pub sequence: u64,
+    /// The host's TSC when the message was logged.
+    pub tsc: u64,
+    /// The index of the vCPU which logged the message, or LOG_CPU_UNKNOWN.
+    pub cpu: u16,
+    /// The number of bytes of encoded arguments after the message.
+    pub args_len: u16,
+}
+
+impl LogFrameHeader {
+    /// Encode the header in its binary layout.
+    pub fn to_bytes(&self) -> [u8; LOG_FRAME_HEADER_SIZE] {
+        let mut bytes = [0; LOG_FRAME_HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&LOG_FRAME_MAGIC);
+        bytes[4..6].copy_from_slice(&self.len.to_le_bytes());
+        bytes[6] = self.level;
+        bytes[7] = self.target_len;
+        bytes[8..16].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.tsc.to_le_bytes());
+        bytes[24..26].copy_from_slice(&self.cpu.to_le_bytes());
+        bytes[26..28].copy_from_slice(&self.args_len.to_le_bytes());
+        bytes
+    }
+}
+
+/// Read records from the hypervisor's in-memory log in a single VMCALL
+/// hypercall, starting with the oldest whose sequence number is at least
+/// first. Returns the number of records read and the sequence number to pass
+/// as first next time.
+///
+/// # Safety
+/// The hypervisor must be loaded, and the caller must be running at CPL 0.
+pub unsafe fn read_log_records(
+    first: u64,
+    records: &mut [[u8; LOG_RECORD_SIZE]],
+) -> Result<(usize, u64), HypercallError> {
+    let buffer = VmcallBuffer {
+        address: records.as_mut_ptr() as u64,
+        len: (records.len() * LOG_RECORD_SIZE) as u64,
+    };
+    let [count, next, _, _] =
+        invoke_vmcall(HYPERCALL_REASON_LOG, [first, 0, 0, 0, 0, 0], Some(buffer))?;
+    Ok((count as usize, next))
+}
+
+/// Set the level of records a log sink keeps, see
+/// [HYPERCALL_REASON_LOG_LEVEL](constant.HYPERCALL_REASON_LOG_LEVEL.html).
+/// Returns the sink's previous level.
+pub fn set_log_level(sink: u32, level: u32) -> Result<u32, HypercallError> {
+    let argument = u64::from(sink) << LOG_LEVEL_SINK_SHIFT | u64::from(level);
+    Ok(invoke_hypercall_with_argument(HYPERCALL_REASON_LOG_LEVEL, argument)?.results[0])
+}
+
+/// Set the level of records a log sink keeps from a target and the modules
+/// under it, or remove the target's filter with LOG_LEVEL_INHERIT. Returns
+/// the filter's previous level, or LOG_LEVEL_INHERIT if it had none.
+///
+/// # Safety
+/// The hypervisor must be loaded, and the caller must be running at CPL 0.
+pub unsafe fn set_log_target_level(
+    sink: u32,
+    level: u32,
+    target: &str,
+) -> Result<u32, HypercallError> {
+    // Without a buffer the hypercall would set the sink's level instead.
+    if target.is_empty() {
+        return Err(HypercallError::InvalidArgument);
+    }
+    let argument = u64::from(sink) << LOG_LEVEL_SINK_SHIFT | u64::from(level);
+    let buffer = VmcallBuffer {
+        address: target.as_ptr() as u64,
+        len: target.len() as u64,
+    };
+    let [previous, _, _, _] = invoke_vmcall(
+        HYPERCALL_REASON_LOG_LEVEL,
+        [argument, 0, 0, 0, 0, 0],
+        Some(buffer),
+    )?;
+    Ok(previous as u32)
+}
+
+/// Where the guest was running when the hypervisor's profiler took a sample.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileSample {
+    /// The index of the core which took the sample.
+    pub vcpu: u32,
+    /// The guest's instruction pointer.
+    pub rip: u64,
+    /// The guest's cr3, without the PCID or flags in its low 12 bits.
+    pub cr3: u64,
+    /// The guest's current privilege level.
+    pub cpl: u8,
+}
+
+impl ProfileSample {
+    /// Decode the results of the profile sample hypercall. Returns None if
+    /// there were no samples left.
+    pub fn from_results(results: &HyperCallResults) -> Option<Self> {
+        let [rip_low, rip_high, cr3_page, flags] = results.results;
+        if flags & PROFILE_SAMPLE_VALID == 0 {
+            return None;
+        }
+        Some(ProfileSample {
+            vcpu: (flags >> PROFILE_SAMPLE_VCPU_SHIFT) & PROFILE_SAMPLE_VCPU_MASK,
+            rip: u64::from(rip_high) << 32 | u64::from(rip_low),
+            cr3: u64::from(cr3_page) << 12,
+            cpl: (flags & PROFILE_SAMPLE_CPL_MASK) as u8,
+        })
+    }
+}
+
+/// Remove the oldest guest RIP sample taken by the hypervisor's profiler.
+/// Returns None once every core's samples have been drained.
+pub fn read_profile_sample() -> Option<ProfileSample> {
+    ProfileSample::from_results(&invoke_hypercall(HYPERCALL_REASON_PROFILE_SAMPLE).ok()?)
+}
+
+/// The VM exit statistics kept for one exit reason on one core.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStats {
+    /// The number of exits.
+    pub count: u64,
+    /// The total number of TSC ticks spent handling the exits.
+    pub total_ticks: u64,
+    /// How many exits took how long to handle, see
+    /// [EXIT_STATS_HISTOGRAM_BUCKETS](constant.EXIT_STATS_HISTOGRAM_BUCKETS.html).
+    pub histogram: [u32; EXIT_STATS_HISTOGRAM_BUCKETS],
+}
+
+fn query_exit_stats(vcpu: u32, reason: u32, query: u32) -> Option<[u32; 4]> {
+    let argument = u64::from(vcpu) << EXIT_STATS_VCPU_SHIFT
+        | u64::from(reason) << EXIT_STATS_REASON_SHIFT
+        | u64::from(query);
+    invoke_hypercall_with_argument(HYPERCALL_REASON_EXIT_STATS, argument)
+        .ok()
+        .map(|results| results.results)
+}
+
+/// Read the VM exit statistics for an exit reason on a core. Returns None if
+/// the hypervisor isn't loaded on that core, or if the exit reason isn't
+/// counted.
+pub fn read_exit_stats(vcpu: u32, reason: u32) -> Option<ExitStats> {
+    let [count_low, count_high, ticks_low, ticks_high] =
+        query_exit_stats(vcpu, reason, EXIT_STATS_QUERY_TOTALS)?;
+    let mut stats = ExitStats {
+        count: u64::from(count_high) << 32 | u64::from(count_low),
+        total_ticks: u64::from(ticks_high) << 32 | u64::from(ticks_low),
+        histogram: [0; EXIT_STATS_HISTOGRAM_BUCKETS],
+    };
+    for (index, buckets) in stats.histogram.chunks_mut(4).enumerate() {
+        let results = query_exit_stats(vcpu, reason, EXIT_STATS_QUERY_HISTOGRAM + index as u32)?;
+        buckets.copy_from_slice(&results);
+    }
+    Some(stats)
+}
+
+/// A VM exit recorded in a core's exit trace.
+///
+/// Records are encoded in EXIT_TRACE_RECORD_SIZE bytes, with every field
+/// little endian at these offsets:
+///
+/// | Offset | Size | Field          |
+/// |--------|------|----------------|
+/// | 0      | 8    | tsc            |
+/// | 8      | 8    | rip            |
+/// | 16     | 8    | qualification  |
+/// | 24     | 8    | rax            |
+/// | 32     | 8    | rbx            |
+/// | 40     | 8    | rcx            |
+/// | 48     | 8    | rdx            |
+/// | 56     | 4    | exit_reason    |
+/// | 60     | 2    | vcpu           |
+/// | 62     | 2    | reserved, zero |
+///
+/// The layout is stable, new fields may only be added in the reserved bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExitTraceRecord {
+    /// The time stamp counter when the hypervisor started handling the exit.
+    pub tsc: u64,
+    /// The guest's instruction pointer.
+    pub rip: u64,
+    /// The exit qualification, whose meaning depends on the exit reason.
+    pub qualification: u64,
+    /// The guest's rax.
+    pub rax: u64,
+    /// The guest's rbx.
+    pub rbx: u64,
+    /// The guest's rcx.
+    pub rcx: u64,
+    /// The guest's rdx.
+    pub rdx: u64,
+    /// The exit reason, see [vmexit_reasons](vmexit_reasons/index.html).
+    pub exit_reason: u32,
+    /// The index of the core which took the exit.
+    pub vcpu: u16,
+}
+
+impl ExitTraceRecord {
+    /// Encode the record in its binary layout.
+    pub fn to_bytes(&self) -> [u8; EXIT_TRACE_RECORD_SIZE] {
+        let mut bytes = [0; EXIT_TRACE_RECORD_SIZE];
+        let fields = [
+            self.tsc,
+            self.rip,
+            self.qualification,
+            self.rax,
+            self.rbx,
+            self.rcx,
+            self.rdx,
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(8).zip(fields.iter()) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes[56..60].copy_from_slice(&self.exit_reason.to_le_bytes());
+        bytes[60..62].copy_from_slice(&self.vcpu.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a record from its binary layout.
+    pub fn from_bytes(bytes: &[u8; EXIT_TRACE_RECORD_SIZE]) -> Self {
+        let u64_at = |offset: usize| {
+            let mut field = [0; 8];
+            field.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_le_bytes(field)
+        };
+        ExitTraceRecord {
+            tsc: u64_at(0),
+            rip: u64_at(8),
+            qualification: u64_at(16),
+            rax: u64_at(24),
+            rbx: u64_at(32),
+            rcx: u64_at(40),
+            rdx: u64_at(48),
+            exit_reason: u32::from_le_bytes([bytes[56], bytes[57], bytes[58], bytes[59]]),
+            vcpu: u16::from_le_bytes([bytes[60], bytes[61]]),
+        }
+    }
+}
+
+/// Read consecutive records from a core's exit trace, starting with the
+/// record with sequence number first, in a single VMCALL hypercall. Returns
+/// the number of records read, which stops short at the end of the trace, or
+/// None if the first record isn't in the trace.
+///
+/// # Safety
+/// The hypervisor must be loaded, and the caller must be running at CPL 0.
+pub unsafe fn read_exit_trace_records(
+    vcpu: u32,
+    first: u32,
+    records: &mut [[u8; EXIT_TRACE_RECORD_SIZE]],
+) -> Option<usize> {
+    let argument = u64::from(EXIT_TRACE_QUERY_RECORD) << EXIT_TRACE_QUERY_SHIFT
+        | u64::from(vcpu) << EXIT_TRACE_VCPU_SHIFT
+        | u64::from(first);
+    let buffer = VmcallBuffer {
+        address: records.as_mut_ptr() as u64,
+        len: (records.len() * EXIT_TRACE_RECORD_SIZE) as u64,
+    };
+    let [count, _, _, _] = invoke_vmcall(
+        HYPERCALL_REASON_EXIT_TRACE,
+        [argument, 0, 0, 0, 0, 0],
+        Some(buffer),
+    )
+    .ok()?;
+    Some(count as usize)
+}
+
+/// The sequence numbers of the records in a core's exit trace.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExitTraceBounds {
+    /// The sequence number of the oldest record.
+    pub oldest: u32,
+    /// The sequence number the next record will have. The trace is empty if
+    /// this is equal to oldest.
+    pub next: u32,
+}
+
+fn query_exit_trace(vcpu: u32, query: u32, sequence: u32) -> Option<[u32; 4]> {
+    let argument = u64::from(query) << EXIT_TRACE_QUERY_SHIFT
+        | u64::from(vcpu) << EXIT_TRACE_VCPU_SHIFT
+        | u64::from(sequence);
+    invoke_hypercall_with_argument(HYPERCALL_REASON_EXIT_TRACE, argument)
+        .ok()
+        .map(|results| results.results)
+}
+
+/// Read the sequence numbers of the records in a core's exit trace. Returns
+/// None if the hypervisor isn't loaded on that core.
+pub fn read_exit_trace_bounds(vcpu: u32) -> Option<ExitTraceBounds> {
+    let [oldest, next, _, _] = query_exit_trace(vcpu, EXIT_TRACE_QUERY_BOUNDS, 0)?;
+    Some(ExitTraceBounds { oldest, next })
+}
+
+/// Read a record from a core's exit trace. Returns None if the record isn't
+/// in the trace, e.g. because the core has taken so many exits since that it
+/// has been overwritten. A record overwritten part way through being read is
+/// no longer in the trace, so the rest of it can't be read.
+pub fn read_exit_trace_record(vcpu: u32, sequence: u32) -> Option<ExitTraceRecord> {
+    let mut bytes = [0; EXIT_TRACE_RECORD_SIZE];
+    for (index, chunk) in bytes.chunks_exact_mut(16).enumerate() {
+        let results = query_exit_trace(vcpu, EXIT_TRACE_QUERY_RECORD + index as u32, sequence)?;
+        for (word, result) in chunk.chunks_exact_mut(4).zip(results.iter()) {
+            word.copy_from_slice(&result.to_le_bytes());
+        }
     }
+    Some(ExitTraceRecord::from_bytes(&bytes))
 }
@@ -0,0 +1,474 @@
+//! A ring buffer of framed messages in memory shared by one producer and one
+//! consumer, used by both the guest and the hypervisor for
+//! [channels](../constant.HYPERCALL_REASON_CHANNEL.html).
+//!
+//! A ring starts with a RING_HEADER_SIZE byte header:
+//!
+//! | Offset | Size | Field                                            |
+//! |--------|------|--------------------------------------------------|
+//! | 0      | 4    | RING_MAGIC                                       |
+//! | 4      | 4    | The size of the data area, a power of two        |
+//! | 8      | 4    | Head, the number of bytes consumed, mod 2^32     |
+//! | 64     | 4    | Tail, the number of bytes produced, mod 2^32     |
+//!
+//! The data area follows the header. Only the consumer writes the head and
+//! only the producer writes the tail, each in its own cache line. Each message
+//! is a frame of a 4 byte payload length, a 4 byte kind, and the payload,
+//! padded to a multiple of RING_FRAME_ALIGN bytes. Frames wrap around the end
+//! of the data area.
+//!
+//! Each side keeps its own copy of the size and of the index it writes, and
+//! checks the other side's index before using it, so a corrupt or malicious
+//! peer can make messages fail with RingError::Corrupt but can't make either
+//! side read or write outside the ring.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The first four bytes of an initialized ring, "ring".
+pub const RING_MAGIC: u32 = 0x676e_6972;
+/// The size of a ring's header. The data area starts right after it.
+pub const RING_HEADER_SIZE: usize = 128;
+/// The alignment rings must have.
+pub const RING_ALIGN: usize = 8;
+/// The size of the length and kind at the start of each frame.
+pub const RING_FRAME_HEADER_SIZE: usize = 8;
+/// Frames are padded to a multiple of this many bytes.
+pub const RING_FRAME_ALIGN: usize = 8;
+/// The smallest data area a ring can have.
+pub const RING_MIN_DATA_SIZE: usize = 64;
+
+const MAGIC_OFFSET: usize = 0;
+const SIZE_OFFSET: usize = 4;
+const HEAD_OFFSET: usize = 8;
+const TAIL_OFFSET: usize = 64;
+
+/// The reasons a ring operation can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingError {
+    /// The memory is misaligned or too small to hold a ring.
+    InvalidLayout,
+    /// The ring doesn't start with RING_MAGIC, or its size doesn't fit in
+    /// the memory.
+    NotInitialized,
+    /// There isn't room for the message until the consumer catches up.
+    Full,
+    /// The message is larger than the ring, or the buffer it is received
+    /// into. The message is left in the ring.
+    MessageTooLarge,
+    /// The other side's index or a frame's length is impossible.
+    Corrupt,
+}
+
+/// A message taken from a ring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingMessage {
+    /// The kind given by the producer.
+    pub kind: u32,
+    /// The length of the payload.
+    pub len: usize,
+}
+
+/// The size of the frame holding a payload of len bytes.
+fn frame_size(len: usize) -> usize {
+    (RING_FRAME_HEADER_SIZE + len + RING_FRAME_ALIGN - 1) & !(RING_FRAME_ALIGN - 1)
+}
+
+/// The size of the data area of a ring in len bytes of memory.
+fn data_size(len: usize) -> Option<usize> {
+    let available = len.checked_sub(RING_HEADER_SIZE)?;
+    if available < RING_MIN_DATA_SIZE {
+        return None;
+    }
+    // The largest power of two which fits, which the indices can wrap around
+    // evenly.
+    let mut size = RING_MIN_DATA_SIZE;
+    while size * 2 <= available && size < 1 << 31 {
+        size *= 2;
+    }
+    Some(size)
+}
+
+/// The memory shared by both sides of a ring.
+struct SharedRing {
+    base: *mut u8,
+    size: usize,
+}
+
+impl SharedRing {
+    /// Check that len bytes at base hold an initialized ring.
+    unsafe fn attach(base: *mut u8, len: usize) -> Result<Self, RingError> {
+        if base.is_null() || base as usize & (RING_ALIGN - 1) != 0 {
+            return Err(RingError::InvalidLayout);
+        }
+        let max_size = data_size(len).ok_or(RingError::InvalidLayout)?;
+        let ring = SharedRing {
+            base,
+            size: max_size,
+        };
+        if ring.field(MAGIC_OFFSET).load(Ordering::Acquire) != RING_MAGIC {
+            return Err(RingError::NotInitialized);
+        }
+        let size = ring.field(SIZE_OFFSET).load(Ordering::Relaxed) as usize;
+        if !size.is_power_of_two() || size < RING_MIN_DATA_SIZE || size > max_size {
+            return Err(RingError::NotInitialized);
+        }
+        Ok(SharedRing { base, size })
+    }
+
+    fn field(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base.add(offset) as *const AtomicU32) }
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { self.base.add(RING_HEADER_SIZE) }
+    }
+
+    /// The number of bytes between head and tail, or Corrupt if it is more
+    /// than the ring holds.
+    fn used(&self, head: u32, tail: u32) -> Result<usize, RingError> {
+        let used = tail.wrapping_sub(head) as usize;
+        if used > self.size {
+            Err(RingError::Corrupt)
+        } else {
+            Ok(used)
+        }
+    }
+
+    /// Copy data into the ring starting at index, wrapping around the end.
+    fn write(&self, index: u32, data: &[u8]) {
+        let start = index as usize & (self.size - 1);
+        let first = data.len().min(self.size - start);
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), self.data().add(start), first);
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr().add(first),
+                self.data(),
+                data.len() - first,
+            );
+        }
+    }
+
+    /// Copy data out of the ring starting at index, wrapping around the end.
+    fn read(&self, index: u32, data: &mut [u8]) {
+        let start = index as usize & (self.size - 1);
+        let first = data.len().min(self.size - start);
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.data().add(start), data.as_mut_ptr(), first);
+            core::ptr::copy_nonoverlapping(
+                self.data(),
+                data.as_mut_ptr().add(first),
+                data.len() - first,
+            );
+        }
+    }
+}
+
+/// Initialize an empty ring in len bytes of memory at base, using the largest
+/// data area which fits. Returns the size of the data area.
+///
+/// # Safety
+/// base must point to len writable bytes which nothing else is using.
+pub unsafe fn init(base: *mut u8, len: usize) -> Result<usize, RingError> {
+    if base.is_null() || base as usize & (RING_ALIGN - 1) != 0 {
+        return Err(RingError::InvalidLayout);
+    }
+    let size = data_size(len).ok_or(RingError::InvalidLayout)?;
+    core::ptr::write_bytes(base, 0, RING_HEADER_SIZE);
+    let ring = SharedRing { base, size };
+    ring.field(SIZE_OFFSET)
+        .store(size as u32, Ordering::Relaxed);
+    ring.field(MAGIC_OFFSET)
+        .store(RING_MAGIC, Ordering::Release);
+    Ok(size)
+}
+
+/// The side of a ring which sends messages.
+pub struct RingProducer {
+    ring: SharedRing,
+    tail: u32,
+}
+
+// The ring is shared memory, which the producer only writes through its tail
+// and the free part of the data area.
+unsafe impl Send for RingProducer {}
+
+impl RingProducer {
+    /// Attach to an initialized ring in len bytes of memory at base.
+    ///
+    /// # Safety
+    /// base must point to len bytes which stay readable and writable while
+    /// the producer exists, and there must be no other producer.
+    pub unsafe fn attach(base: *mut u8, len: usize) -> Result<Self, RingError> {
+        let ring = SharedRing::attach(base, len)?;
+        let tail = ring.field(TAIL_OFFSET).load(Ordering::Relaxed);
+        let head = ring.field(HEAD_OFFSET).load(Ordering::Acquire);
+        ring.used(head, tail)?;
+        Ok(RingProducer { ring, tail })
+    }
+
+    /// The size of the largest payload which could be sent once the ring is
+    /// empty.
+    pub fn max_payload(&self) -> usize {
+        self.ring.size - RING_FRAME_HEADER_SIZE
+    }
+
+    /// The number of bytes free in the ring, including frame headers and
+    /// padding.
+    pub fn free(&self) -> Result<usize, RingError> {
+        let head = self.ring.field(HEAD_OFFSET).load(Ordering::Acquire);
+        Ok(self.ring.size - self.ring.used(head, self.tail)?)
+    }
+
+    /// The number of bytes the consumer hasn't consumed yet.
+    pub fn pending(&self) -> Result<usize, RingError> {
+        Ok(self.ring.size - self.free()?)
+    }
+
+    /// Send a message.
+    pub fn send(&mut self, kind: u32, payload: &[u8]) -> Result<(), RingError> {
+        if payload.len() > self.max_payload() {
+            return Err(RingError::MessageTooLarge);
+        }
+        let frame_size = frame_size(payload.len());
+        if frame_size > self.free()? {
+            return Err(RingError::Full);
+        }
+        let mut header = [0; RING_FRAME_HEADER_SIZE];
+        header[..4].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        header[4..].copy_from_slice(&kind.to_le_bytes());
+        self.ring.write(self.tail, &header);
+        self.ring.write(
+            self.tail.wrapping_add(RING_FRAME_HEADER_SIZE as u32),
+            payload,
+        );
+        self.tail = self.tail.wrapping_add(frame_size as u32);
+        self.ring
+            .field(TAIL_OFFSET)
+            .store(self.tail, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// The side of a ring which receives messages.
+pub struct RingConsumer {
+    ring: SharedRing,
+    head: u32,
+}
+
+// The ring is shared memory, which the consumer only writes through its head.
+unsafe impl Send for RingConsumer {}
+
+impl RingConsumer {
+    /// Attach to an initialized ring in len bytes of memory at base.
+    ///
+    /// # Safety
+    /// base must point to len bytes which stay readable and writable while
+    /// the consumer exists, and there must be no other consumer.
+    pub unsafe fn attach(base: *mut u8, len: usize) -> Result<Self, RingError> {
+        let ring = SharedRing::attach(base, len)?;
+        let head = ring.field(HEAD_OFFSET).load(Ordering::Relaxed);
+        let tail = ring.field(TAIL_OFFSET).load(Ordering::Acquire);
+        ring.used(head, tail)?;
+        Ok(RingConsumer { ring, head })
+    }
+
+    /// Read the header of the next frame without consuming it. Returns None if
+    /// the ring is empty.
+    fn peek(&self) -> Result<Option<RingMessage>, RingError> {
+        let tail = self.ring.field(TAIL_OFFSET).load(Ordering::Acquire);
+        let used = self.ring.used(self.head, tail)?;
+        if used == 0 {
+            return Ok(None);
+        }
+        if used < RING_FRAME_HEADER_SIZE {
+            return Err(RingError::Corrupt);
+        }
+        let mut header = [0; RING_FRAME_HEADER_SIZE];
+        self.ring.read(self.head, &mut header);
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if len > used - RING_FRAME_HEADER_SIZE || frame_size(len) > used {
+            return Err(RingError::Corrupt);
+        }
+        Ok(Some(RingMessage { kind, len }))
+    }
+
+    fn consume(&mut self, message: &RingMessage) {
+        self.head = self.head.wrapping_add(frame_size(message.len) as u32);
+        self.ring
+            .field(HEAD_OFFSET)
+            .store(self.head, Ordering::Release);
+    }
+
+    /// Receive the next message, copying its payload to the start of buffer.
+    /// Returns None if the ring is empty. If the payload doesn't fit in
+    /// buffer, fails with MessageTooLarge and leaves the message in the ring.
+    pub fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<RingMessage>, RingError> {
+        let message = match self.peek()? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let payload = buffer
+            .get_mut(..message.len)
+            .ok_or(RingError::MessageTooLarge)?;
+        self.ring.read(
+            self.head.wrapping_add(RING_FRAME_HEADER_SIZE as u32),
+            payload,
+        );
+        self.consume(&message);
+        Ok(Some(message))
+    }
+
+    /// Drop the next message without reading its payload, e.g. after receive
+    /// failed with MessageTooLarge. Returns None if the ring is empty.
+    pub fn discard(&mut self) -> Result<Option<RingMessage>, RingError> {
+        let message = self.peek()?;
+        if let Some(message) = &message {
+            self.consume(message);
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough memory for a ring with a 256 byte data area, aligned for it.
+    const MEMORY_SIZE: usize = RING_HEADER_SIZE + 256;
+
+    #[repr(align(64))]
+    struct Memory([u8; MEMORY_SIZE]);
+
+    fn new_ring(memory: &mut Memory) -> (RingProducer, RingConsumer) {
+        let base = memory.0.as_mut_ptr();
+        unsafe {
+            assert_eq!(init(base, MEMORY_SIZE), Ok(256));
+            (
+                RingProducer::attach(base, MEMORY_SIZE).unwrap(),
+                RingConsumer::attach(base, MEMORY_SIZE).unwrap(),
+            )
+        }
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let mut memory = Memory([0; MEMORY_SIZE]);
+        let (mut producer, mut consumer) = new_ring(&mut memory);
+        let mut buffer = [0; 64];
+        assert_eq!(consumer.receive(&mut buffer), Ok(None));
+        producer.send(7, b"hello").unwrap();
+        producer.send(8, b"").unwrap();
+        assert_eq!(
+            consumer.receive(&mut buffer),
+            Ok(Some(RingMessage { kind: 7, len: 5 }))
+        );
+        assert_eq!(&buffer[..5], b"hello");
+        assert_eq!(
+            consumer.receive(&mut buffer),
+            Ok(Some(RingMessage { kind: 8, len: 0 }))
+        );
+        assert_eq!(consumer.receive(&mut buffer), Ok(None));
+        assert_eq!(producer.free(), Ok(256));
+    }
+
+    #[test]
+    fn messages_wrap_around() {
+        let mut memory = Memory([0; MEMORY_SIZE]);
+        let (mut producer, mut consumer) = new_ring(&mut memory);
+        let mut buffer = [0; 100];
+        // 100 byte payloads take 112 byte frames, which don't divide the ring
+        // evenly, so frames and payloads are split across the end.
+        for i in 0..20u8 {
+            let payload = [i; 100];
+            producer.send(u32::from(i), &payload).unwrap();
+            assert_eq!(
+                consumer.receive(&mut buffer),
+                Ok(Some(RingMessage {
+                    kind: u32::from(i),
+                    len: 100
+                }))
+            );
+            assert_eq!(buffer, payload);
+        }
+    }
+
+    #[test]
+    fn full_ring() {
+        let mut memory = Memory([0; MEMORY_SIZE]);
+        let (mut producer, mut consumer) = new_ring(&mut memory);
+        let payload = [0xaa; 120];
+        producer.send(1, &payload).unwrap();
+        producer.send(2, &payload).unwrap();
+        assert_eq!(producer.send(3, &[0]), Err(RingError::Full));
+        assert_eq!(
+            consumer.discard(),
+            Ok(Some(RingMessage { kind: 1, len: 120 }))
+        );
+        producer.send(3, &[0]).unwrap();
+    }
+
+    #[test]
+    fn message_too_large() {
+        let mut memory = Memory([0; MEMORY_SIZE]);
+        let (mut producer, mut consumer) = new_ring(&mut memory);
+        assert_eq!(producer.send(1, &[0; 256]), Err(RingError::MessageTooLarge));
+        producer.send(1, &[0; 32]).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(
+            consumer.receive(&mut buffer),
+            Err(RingError::MessageTooLarge)
+        );
+        // The message is still there for a larger buffer.
+        let mut buffer = [0; 32];
+        assert_eq!(
+            consumer.receive(&mut buffer),
+            Ok(Some(RingMessage { kind: 1, len: 32 }))
+        );
+    }
+
+    #[test]
+    fn corrupt_indices_and_lengths() {
+        let mut memory = Memory([0; MEMORY_SIZE]);
+        let (mut producer, mut consumer) = new_ring(&mut memory);
+        producer.send(1, &[0; 8]).unwrap();
+        let mut buffer = [0; 256];
+
+        // A frame whose length runs past the tail.
+        memory.0[RING_HEADER_SIZE] = 0xff;
+        assert_eq!(consumer.receive(&mut buffer), Err(RingError::Corrupt));
+
+        // A tail more than the ring's size ahead of the head.
+        memory.0[TAIL_OFFSET..TAIL_OFFSET + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert_eq!(consumer.receive(&mut buffer), Err(RingError::Corrupt));
+
+        // A head ahead of the producer's tail.
+        memory.0[HEAD_OFFSET..HEAD_OFFSET + 4].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(producer.send(1, &[0]), Err(RingError::Corrupt));
+    }
+
+    #[test]
+    fn attach_checks_layout() {
+        let mut memory = Memory([0; MEMORY_SIZE]);
+        let base = memory.0.as_mut_ptr();
+        unsafe {
+            assert!(matches!(
+                RingConsumer::attach(base, MEMORY_SIZE),
+                Err(RingError::NotInitialized)
+            ));
+            assert!(matches!(
+                RingConsumer::attach(base.add(1), MEMORY_SIZE - 1),
+                Err(RingError::InvalidLayout)
+            ));
+            assert_eq!(
+                init(base, RING_HEADER_SIZE + RING_MIN_DATA_SIZE - 1),
+                Err(RingError::InvalidLayout)
+            );
+            // A ring initialized in more memory than it is attached with.
+            init(base, MEMORY_SIZE).unwrap();
+            assert!(matches!(
+                RingProducer::attach(base, MEMORY_SIZE - 1),
+                Err(RingError::NotInitialized)
+            ));
+        }
+    }
+}
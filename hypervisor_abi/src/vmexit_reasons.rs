@@ -0,0 +1,85 @@
+//! Defines the codes representing the reason a VM exit occurred, along with
+//! their names so that tools like rustyvctl can display them.
+//! See the Intel manual, Volume 3, Appendix C "VMX Basic Exit Reasons".
+
+macro_rules! vmexit_reasons {
+    ($($name:ident = $value:literal,)*) => {
+        $(pub const $name: u64 = $value;)*
+
+        /// The name of a VM exit reason, without the VMEXIT_REASON_ prefix.
+        pub fn name(reason: u64) -> Option<&'static str> {
+            match reason {
+                $($value => Some(&stringify!($name)["VMEXIT_REASON_".len()..]),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+vmexit_reasons! {
+    VMEXIT_REASON_NMI_OR_EXCEPTION = 0,
+    VMEXIT_REASON_EXTERNAL_INTERRUPT = 1,
+    VMEXIT_REASON_TRIPLE_FAULT = 2,
+    VMEXIT_REASON_INIT_SIGNAL = 3,
+    VMEXIT_REASON_START_UP_IPI = 4,
+    VMEXIT_REASON_IO_SMI = 5,
+    VMEXIT_REASON_OTHER_SMI = 6,
+    VMEXIT_REASON_INTERRUPT_WINDOW = 7,
+    VMEXIT_REASON_NMI_WINDOWS = 8,
+    VMEXIT_REASON_TASK_SWITCH = 9,
+    VMEXIT_REASON_CPUID = 10,
+    VMEXIT_REASON_GETSEC = 11,
+    VMEXIT_REASON_HLT = 12,
+    VMEXIT_REASON_INVD = 13,
+    VMEXIT_REASON_INVLPG = 14,
+    VMEXIT_REASON_RDPMC = 15,
+    VMEXIT_REASON_RDTSC = 16,
+    VMEXIT_REASON_RSM = 17,
+    VMEXIT_REASON_VMCALL = 18,
+    VMEXIT_REASON_VMCLEAR = 19,
+    VMEXIT_REASON_VMLAUNCH = 20,
+    VMEXIT_REASON_VMPTRLD = 21,
+    VMEXIT_REASON_VMPTRST = 22,
+    VMEXIT_REASON_VMREAD = 23,
+    VMEXIT_REASON_VMRESUME = 24,
+    VMEXIT_REASON_VMWRITE = 25,
+    VMEXIT_REASON_VMXOFF = 26,
+    VMEXIT_REASON_VMXON = 27,
+    VMEXIT_REASON_CONTROL_REGISTER_ACCESS = 28,
+    VMEXIT_REASON_MOV_DR = 29,
+    VMEXIT_REASON_IO_INSTRUCTION = 30,
+    VMEXIT_REASON_RDMSR = 31,
+    VMEXIT_REASON_WRMSR = 32,
+    VMEXIT_REASON_INVALID_GUEST_STATE = 33,
+    VMEXIT_REASON_VM_ENTRY_FAILURE_DUE_TO_MSR_LOADING = 34,
+    VMEXIT_REASON_MWAIT = 36,
+    VMEXIT_REASON_MONITOR_TRAP = 37,
+    VMEXIT_REASON_MONITOR = 39,
+    VMEXIT_REASON_PAUSE = 40,
+    VMEXIT_REASON_VM_ENTRY_DUE_TO_MACHINE_CHECK_EVENT = 41,
+    VMEXIT_REASON_TPR_BELOW_THRESHOLD = 43,
+    VMEXIT_REASON_APIC_ACCES = 44,
+    VMEXIT_REASON_VIRTUALIZED_EOI = 45,
+    VMEXIT_REASON_ACCESSING_GDT_OR_IDTR = 46,
+    VMEXIT_REASON_ACCESSING_LDTR_OR_TR = 47,
+    VMEXIT_REASON_EPT_VIOLATION = 48,
+    VMEXIT_REASON_EPT_MISCONFIGURATION = 49,
+    VMEXIT_REASON_INVEPT = 50,
+    VMEXIT_REASON_RDTSCP = 51,
+    VMEXIT_REASON_PREEMPTION_TIMER_EXPIRED = 52,
+    VMEXIT_REASON_INVVPID = 53,
+    VMEXIT_REASON_WBINVD = 54,
+    VMEXIT_REASON_XSETBV = 55,
+    VMEXIT_REASON_APIC_WRITE = 56,
+    VMEXIT_REASON_RDRAND = 57,
+    VMEXIT_REASON_INVPCID = 58,
+    VMEXIT_REASON_VMFUNC = 59,
+    VMEXIT_REASON_ENCLS = 60,
+    VMEXIT_REASON_RDSEED = 61,
+    VMEXIT_REASON_PAGE_MODIFICATION_LOG_FULL = 62,
+    VMEXIT_REASON_XSAVES = 63,
+    VMEXIT_REASON_XRSTORS = 64,
+    VMEXIT_REASON_SPP_RELATED = 66,
+    VMEXIT_REASON_UMWAIT = 67,
+    VMEXIT_REASON_TPAUSE = 68,
+}
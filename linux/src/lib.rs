@@ -17,13 +17,17 @@ extern "C" {
     fn rustyvisor_linux_virt_to_phys(x: *mut u8) -> u64;
     /// A function implemented in C for allocating memory. The memory will be zeroed.
     fn rustyvisor_linux_kmalloc(size: usize) -> *mut u8;
+    /// A function implemented in C for allocating virtually contiguous
+    /// memory, with an unmapped guard page below it. The memory will be
+    /// zeroed.
+    fn rustyvisor_linux_vzalloc(size: usize) -> *mut u8;
 }
 use hypervisor::segmentation::{GdtEntry, GdtEntry64};
 
-use hypervisor::segmentation::Tss;
+use hypervisor::segmentation::{InterruptStack, Tss, INTERRUPT_STACK_PAGES};
 
-/// Allocate and initialize a VCpu.
-fn rustyvisor_linux_create_vcpu() -> Result<&'static mut hypervisor::VCpu, ()> {
+/// Allocate and initialize a VCpu with a host stack of stack_pages pages.
+fn rustyvisor_linux_create_vcpu(stack_pages: usize) -> Result<&'static mut hypervisor::VCpu, ()> {
     unsafe {
         let vcpu = rustyvisor_linux_kmalloc(core::mem::size_of::<hypervisor::VCpu>())
             as *mut hypervisor::VCpu;
@@ -56,12 +60,19 @@ fn rustyvisor_linux_create_vcpu() -> Result<&'static mut hypervisor::VCpu, ()> {
         }
         let vmcs_phys = rustyvisor_linux_virt_to_phys(vmcs as *mut u8);
 
-        let stack_pages = 1;
-        let stack = rustyvisor_linux_kmalloc(stack_pages * PAGE_SIZE);
+        let stack = rustyvisor_linux_vzalloc(stack_pages * PAGE_SIZE);
         if stack.is_null() {
             return Err(());
         }
 
+        let mut interrupt_stacks = [core::ptr::null_mut(); InterruptStack::ALL.len()];
+        for interrupt_stack in interrupt_stacks.iter_mut() {
+            *interrupt_stack = rustyvisor_linux_kmalloc(INTERRUPT_STACK_PAGES * PAGE_SIZE);
+            if interrupt_stack.is_null() {
+                return Err(());
+            }
+        }
+
         let msr_bitmap = rustyvisor_linux_kmalloc(PAGE_SIZE);
         if msr_bitmap.is_null() {
             return Err(());
@@ -80,6 +91,16 @@ fn rustyvisor_linux_create_vcpu() -> Result<&'static mut hypervisor::VCpu, ()> {
         (*vcpu).this_vcpu = vcpu;
 
         (*vcpu).loaded_successfully = false;
+        (*vcpu).guest_gprs = core::ptr::null_mut();
+        (*vcpu).guest_snapshot_valid = false;
+        (*vcpu).debug_registers = Default::default();
+        (*vcpu).single_step = Default::default();
+        (*vcpu).profiler = Default::default();
+        // Too large to build on the stack and move.
+        core::ptr::write_bytes(core::ptr::addr_of_mut!((*vcpu).exit_stats), 0, 1);
+        core::ptr::write_bytes(core::ptr::addr_of_mut!((*vcpu).exit_trace), 0, 1);
+        (*vcpu).timekeeping = Default::default();
+        (*vcpu).nmi = Default::default();
 
         (*vcpu).vmcs_phys = vmcs_phys;
         (*vcpu).vmcs_size = PAGE_SIZE;
@@ -92,6 +113,7 @@ fn rustyvisor_linux_create_vcpu() -> Result<&'static mut hypervisor::VCpu, ()> {
         (*vcpu).vmxon_region = vmxon_region;
 
         (*vcpu).msr_bitmap = msr_bitmap_phys;
+        (*vcpu).msr_bitmap_virt = msr_bitmap;
         (*vcpu).stack_base = stack;
         (*vcpu).stack_size = stack_pages * PAGE_SIZE; // Page size
         (*vcpu).stack_top = (*vcpu).stack_base.add((*vcpu).stack_size);
@@ -99,6 +121,11 @@ fn rustyvisor_linux_create_vcpu() -> Result<&'static mut hypervisor::VCpu, ()> {
         (*vcpu).host_gdt_base = host_gdt as *mut u64;
         (*vcpu).host_gdt_limit = host_gdt_size as u64 - 1;
 
+        let tss = tss as *mut Tss;
+        for (stack, base) in InterruptStack::ALL.iter().zip(interrupt_stacks.iter()) {
+            let top = base.add(INTERRUPT_STACK_PAGES * PAGE_SIZE);
+            (*tss).set_interrupt_stack(*stack, top as u64);
+        }
         let tss_base = tss as u64;
         (*vcpu).tr_base = tss_base;
         (*vcpu).tr_selector =
@@ -116,10 +143,11 @@ fn rustyvisor_linux_create_vcpu() -> Result<&'static mut hypervisor::VCpu, ()> {
     }
 }
 
-/// Load the hypervisor on the current core.
+/// Load the hypervisor on the current core, with a host stack of stack_pages
+/// pages.
 #[no_mangle]
-pub extern "C" fn rustyvisor_linux_core_load(_ptr: usize) -> i32 {
-    let vcpu = match rustyvisor_linux_create_vcpu() {
+pub extern "C" fn rustyvisor_linux_core_load(stack_pages: usize) -> i32 {
+    let vcpu = match rustyvisor_linux_create_vcpu(stack_pages) {
         Ok(vcpu) => vcpu,
         Err(_) => return -1,
     };
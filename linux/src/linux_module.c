@@ -4,15 +4,96 @@
 #include <linux/module.h>
 #include <linux/semaphore.h>
 #include <linux/slab.h>
+#include <linux/vmalloc.h>
 
 #define MODULE_NAME "Rustyvisor"
 
 struct semaphore init_lock;
 atomic_t failure_count;
 
+static unsigned long profile_period;
+module_param(profile_period, ulong, 0444);
+MODULE_PARM_DESC(profile_period, "How often to sample where the guest is running, in TSC ticks. 0 disables the profiler.");
 
-extern int rustyvisor_linux_core_load(void *_);
+static bool tsc_compensated;
+module_param(tsc_compensated, bool, 0444);
+MODULE_PARM_DESC(tsc_compensated, "Hide the time the hypervisor spends handling VM exits from the guest's TSC.");
+
+static unsigned long tsc_multiplier;
+module_param(tsc_multiplier, ulong, 0444);
+MODULE_PARM_DESC(tsc_multiplier, "The guest's TSC rate relative to the host's, with 48 fractional bits. 0 disables TSC scaling.");
+
+static char *cpuid_policy = "";
+module_param(cpuid_policy, charp, 0444);
+MODULE_PARM_DESC(cpuid_policy, "Changes to what the guest sees when it executes CPUID, e.g. \"0x1 ecx&=0xfffffffe; 0x7.0 ebx=0\".");
+
+static bool cpuid_hypervisor_leaves;
+module_param(cpuid_hypervisor_leaves, bool, 0444);
+MODULE_PARM_DESC(cpuid_hypervisor_leaves, "Report the hypervisor's CPUID leaves to the guest.");
+
+static unsigned int hypercall_policy = 1;
+module_param(hypercall_policy, uint, 0444);
+MODULE_PARM_DESC(hypercall_policy, "What the caller of any hypercall needs: 1 CPL 0, 2 hypercall_cr3, 4 RIP between hypercall_code_start and hypercall_code_end, 8 a handshake. The default is 1, and 0 allows anyone, including user space.");
+
+static unsigned long hypercall_cr3;
+module_param(hypercall_cr3, ulong, 0444);
+MODULE_PARM_DESC(hypercall_cr3, "The CR3 hypercalls must be made with, if hypercall_policy requires it.");
+
+static unsigned long hypercall_code_start;
+module_param(hypercall_code_start, ulong, 0444);
+MODULE_PARM_DESC(hypercall_code_start, "The start of the code hypercalls must be made from, if hypercall_policy requires it.");
+
+static unsigned long hypercall_code_end;
+module_param(hypercall_code_end, ulong, 0444);
+MODULE_PARM_DESC(hypercall_code_end, "The end of the code hypercalls must be made from, exclusive.");
+
+/* Not readable through sysfs, so user space can't read it back. */
+static unsigned long hypercall_secret;
+module_param(hypercall_secret, ulong, 0);
+MODULE_PARM_DESC(hypercall_secret, "The secret the handshake hypercall expects. 0 rejects every handshake.");
+
+static char *log_console = "";
+module_param(log_console, charp, 0444);
+MODULE_PARM_DESC(log_console, "Which log records go to the kernel log, e.g. \"info,hypervisor::interrupt_controller=trace\". Empty keeps every record.");
+
+static char *log_memory = "";
+module_param(log_memory, charp, 0444);
+MODULE_PARM_DESC(log_memory, "Which log records the hypervisor's in-memory log keeps. Empty keeps every record.");
+
+static char *log_debug_port = "";
+module_param(log_debug_port, charp, 0444);
+MODULE_PARM_DESC(log_debug_port, "Which log records are written to I/O port 0xe9. Empty writes none.");
+
+static bool log_debug_port_binary;
+module_param(log_debug_port_binary, bool, 0444);
+MODULE_PARM_DESC(log_debug_port_binary, "Write log records to I/O port 0xe9 in binary form, to be decoded with scripts/log_decode.py.");
+
+static bool devirtualize_on_panic;
+module_param(devirtualize_on_panic, bool, 0444);
+MODULE_PARM_DESC(devirtualize_on_panic, "Hand every core back to the kernel instead of halting when the hypervisor panics.");
+
+static unsigned int host_stack_pages = 4;
+module_param(host_stack_pages, uint, 0444);
+MODULE_PARM_DESC(host_stack_pages, "The size in pages of each core's host stack. Each stack also gets an unmapped guard page below it.");
+
+#define HYPERCALL_POLICY_DEFAULT 0xffffffff
+
+#define LOG_SINK_CONSOLE 0
+#define LOG_SINK_MEMORY 1
+#define LOG_SINK_DEBUG_PORT 2
+
+extern int rustyvisor_linux_core_load(void *stack_pages);
 extern int rustyvisor_load(void);
+extern void rustyvisor_profiler_set_period(uint64_t period);
+extern void rustyvisor_timekeeping_configure(bool compensated, uint64_t tsc_multiplier);
+extern int rustyvisor_cpuid_add_policy(const char *policy, size_t len);
+extern void rustyvisor_cpuid_set_hypervisor_leaves(bool enabled);
+extern int rustyvisor_hypercall_set_policy(uint32_t reason, uint32_t flags, uint64_t cr3);
+extern int rustyvisor_hypercall_add_code_range(uint64_t start, uint64_t end);
+extern void rustyvisor_hypercall_set_secret(uint64_t secret);
+extern int rustyvisor_log_add_filters(uint32_t sink, const char *directives, size_t len);
+extern int rustyvisor_log_set_binary(uint32_t sink, bool binary);
+extern void rustyvisor_set_devirtualize_on_panic(bool enabled);
 
 extern int rustyvisor_core_unload(void *_);
 extern int rustyvisor_unload(void);
@@ -26,6 +107,12 @@ void *rustyvisor_linux_kmalloc(uintptr_t bytes) {
     return ptr;
 }
 
+/* vmalloc leaves an unmapped guard page after every area, so the page below
+ * the returned memory is never mapped. */
+void *rustyvisor_linux_vzalloc(uintptr_t bytes) {
+    return vzalloc(bytes);
+}
+
 uintptr_t rustyvisor_linux_virt_to_phys(void *virt) {
     return virt_to_phys(virt);
 }
@@ -58,13 +145,47 @@ static int __init rustyvisor_init(void) {
 	int err;
 	struct task_struct *task;
 
+	if (host_stack_pages == 0) {
+		printk(KERN_ERR "Invalid host_stack_pages\n");
+		return -EINVAL;
+	}
+
 	rustyvisor_load();
+	if (rustyvisor_log_add_filters(LOG_SINK_CONSOLE, log_console, strlen(log_console)) != 0 ||
+	    rustyvisor_log_add_filters(LOG_SINK_MEMORY, log_memory, strlen(log_memory)) != 0 ||
+	    rustyvisor_log_add_filters(LOG_SINK_DEBUG_PORT, log_debug_port, strlen(log_debug_port)) != 0 ||
+	    rustyvisor_log_set_binary(LOG_SINK_DEBUG_PORT, log_debug_port_binary) != 0) {
+		printk(KERN_ERR "Invalid log filters\n");
+		rustyvisor_unload();
+		return -EINVAL;
+	}
+	rustyvisor_set_devirtualize_on_panic(devirtualize_on_panic);
+	rustyvisor_profiler_set_period(profile_period);
+	rustyvisor_timekeeping_configure(tsc_compensated, tsc_multiplier);
+	rustyvisor_cpuid_set_hypervisor_leaves(cpuid_hypervisor_leaves);
+	if (rustyvisor_cpuid_add_policy(cpuid_policy, strlen(cpuid_policy)) != 0) {
+		printk(KERN_ERR "Invalid cpuid_policy\n");
+		rustyvisor_unload();
+		return -EINVAL;
+	}
+	rustyvisor_hypercall_set_secret(hypercall_secret);
+	if (rustyvisor_hypercall_set_policy(HYPERCALL_POLICY_DEFAULT, hypercall_policy, hypercall_cr3) != 0) {
+		printk(KERN_ERR "Invalid hypercall_policy\n");
+		rustyvisor_unload();
+		return -EINVAL;
+	}
+	if (hypercall_code_end != 0 &&
+	    rustyvisor_hypercall_add_code_range(hypercall_code_start, hypercall_code_end) != 0) {
+		printk(KERN_ERR "Invalid hypercall code range\n");
+		rustyvisor_unload();
+		return -EINVAL;
+	}
 
 	sema_init(&init_lock, 1);
 	atomic_set(&failure_count, 0);
 
 	for_each_online_cpu(cpu) {
-		task = kthread_create(rustyvisor_linux_core_load, NULL, "rustyvisor_linux_core_load");
+		task = kthread_create(rustyvisor_linux_core_load, (void *)(uintptr_t)host_stack_pages, "rustyvisor_linux_core_load");
 		kthread_bind(task, cpu);
 
 		down(&init_lock);
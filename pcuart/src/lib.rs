@@ -26,7 +26,7 @@ pub enum UartComPort {
 }
 
 const UART_OFFSET_TRANSMITTER_HOLDING_BUFFER: u16 = 0;
-//const UART_OFFSET_RECEIVER_BUFFER: u16 = 0;
+const UART_OFFSET_RECEIVER_BUFFER: u16 = 0;
 const UART_OFFSET_DIVISOR_LATCH_LOW: u16 = 0;
 const UART_OFFSET_INTERRUPT_ENABLE: u16 = 1;
 const UART_OFFSET_DIVISOR_LATCH_HIGH: u16 = 1;
@@ -97,14 +97,46 @@ fn inb(port: u16) -> u8 {
     unsafe { x86::io::inb(port) }
 }
 
+impl Uart {
+    /// Writes raw bytes to the UART without any translation, e.g. for
+    /// streaming binary data to whatever is listening on the other end.
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        for b in bytes {
+            self.write_byte(*b);
+        }
+    }
+
+    /// Reads a byte if one has been received, without waiting.
+    pub fn try_read_byte(&self) -> Option<u8> {
+        if (inb(self.io_port_base + UART_OFFSET_LINE_STATUS) & 0x01) == 0 {
+            None
+        } else {
+            Some(inb(self.io_port_base + UART_OFFSET_RECEIVER_BUFFER))
+        }
+    }
+
+    /// Waits for a byte to be received and reads it.
+    pub fn read_byte(&self) -> u8 {
+        loop {
+            if let Some(b) = self.try_read_byte() {
+                return b;
+            }
+        }
+    }
+
+    fn write_byte(&self, b: u8) {
+        while (inb(self.io_port_base + UART_OFFSET_LINE_STATUS) & 0x20) == 0 {}
+        outb(
+            self.io_port_base + UART_OFFSET_TRANSMITTER_HOLDING_BUFFER,
+            b,
+        );
+    }
+}
+
 impl fmt::Write for Uart {
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
         for c in s.chars() {
-            while (inb(self.io_port_base + UART_OFFSET_LINE_STATUS) & 0x20) == 0 {}
-            outb(
-                self.io_port_base + UART_OFFSET_TRANSMITTER_HOLDING_BUFFER,
-                c as u8,
-            );
+            self.write_byte(c as u8);
         }
         Ok(())
     }
@@ -49,6 +49,15 @@ impl UartLogger {
         self.port.lock().init(false, UartBaudRate::Baud115200);
     }
 
+    /// Writes each chunk of raw bytes to the UART in turn, without letting
+    /// other cores write in between, e.g. for binary log records.
+    pub fn write_chunks(&self, chunks: &[&[u8]]) {
+        let port = self.lock_port_with_timeout();
+        for chunk in chunks {
+            port.write_bytes(chunk);
+        }
+    }
+
     fn lock_port_with_timeout(&self) -> spin::MutexGuard<Uart> {
         let timeout = 0x1000;
         let mut count = 0;
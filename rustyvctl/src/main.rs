@@ -17,8 +17,44 @@
 //!           2 File(s)     433,807 bytes
 //!           0 Dir(s)
 //! FS0:\> .\rustyvctl.efi
-//! FS0:\>
+//! Hypervisor version 0.1.0
+//! FS0:\> .\rustyvctl.efi capabilities
+//! Interface version 3, 8 hypercalls
+//! 1 2 3 4 5 7 8 9
+//! FS0:\> .\rustyvctl.efi profile
+//! 0 fffff80012345678 1aa000 0
+//! FS0:\> .\rustyvctl.efi stats
+//! vCPU 0
+//!   CPUID: 1024 exits, 2110 ticks on average
+//!     2^11: 1000 2^12: 24
+//! FS0:\> .\rustyvctl.efi trace
+//! 3c8a1f20e6010000...
+//! FS0:\> .\rustyvctl.efi log
+//! 0 1a2b3c4d5e cpu- INFO rustyvisor_load
+//! FS0:\> .\rustyvctl.efi log-level memory trace hypervisor::interrupt_controller
+//! Previous level INHERIT
 //! ```
+//!
+//! With no arguments, prints the hypervisor's version.
+//! `capabilities` prints the hypervisor's interface version and the reasons of
+//! the hypercalls it supports.
+//! `stats` prints how many VM exits of each reason each core has taken, and a
+//! histogram of how many TSC ticks they took to handle. Each bucket is the
+//! power of two at the start of its range.
+//! `trace` prints the recent VM exits recorded by every core, one hex encoded
+//! record per line. Render them with scripts/exit_trace_decode.py.
+//! `log` prints the hypervisor's in-memory log, one line per record with its
+//! sequence number, TSC, the vCPU which logged it and its level. Records lost
+//! before they were read show up as gaps in the sequence numbers.
+//! `log-level <sink> <level> [target]` changes which records one of the
+//! hypervisor's log sinks, `console`, `memory` or `debug-port`, keeps. The
+//! level is one of off, error, warn, info, debug and trace. With a target,
+//! like `hypervisor::interrupt_controller`, only that target and the modules
+//! under it change, and the level `inherit` makes them use the sink's level
+//! again.
+//! `profile` drains the guest RIP samples taken by the hypervisor's profiler,
+//! printing one line per sample with the index of the core, RIP, CR3 and CPL.
+//! Fold the output into a flamegraph with scripts/profile_fold.py.
 
 #![no_std]
 #![no_main]
@@ -33,24 +69,236 @@ extern crate uefi;
 extern crate log;
 
 use core::fmt::Write;
+use core::str::FromStr;
 
 use uefi::prelude::*;
+use uefi::proto::loaded_image::LoadedImage;
+
+/// The longest command line rustyvctl accepts, in bytes.
+const MAX_LOAD_OPTIONS_SIZE: usize = 256;
+
+/// The number of exit trace records to read with each hypercall.
+const TRACE_RECORDS_PER_HYPERCALL: usize = 16;
+
+/// The number of log records to read with each hypercall.
+const LOG_RECORDS_PER_HYPERCALL: usize = 8;
+
+/// Print the hypervisor's version.
+fn print_version(stdout: &mut impl Write) -> core::fmt::Result {
+    match hypervisor_abi::invoke_hypercall(hypervisor_abi::HYPERCALL_REASON_VERSION) {
+        Ok(results) => write!(
+            stdout,
+            "Hypervisor version {}.{}.{}\r\n",
+            results.results[0], results.results[1], results.results[2]
+        ),
+        Err(e) => write!(stdout, "Version hypercall failed {:?}\r\n", e),
+    }
+}
+
+/// Print the hypervisor's interface version and supported hypercalls.
+fn print_capabilities(stdout: &mut impl Write) -> core::fmt::Result {
+    let capabilities = match hypervisor_abi::read_capabilities() {
+        Ok(capabilities) => capabilities,
+        Err(e) => return write!(stdout, "Capabilities hypercall failed {:?}\r\n", e),
+    };
+    write!(
+        stdout,
+        "Interface version {}, {} hypercalls\r\n",
+        capabilities.interface_version, capabilities.hypercall_count
+    )?;
+    for reason in 0..hypervisor_abi::CAPABILITIES_MAX_REASONS {
+        if capabilities.supports(reason) {
+            write!(stdout, "{} ", reason)?;
+        }
+    }
+    write!(stdout, "\r\n")
+}
+
+/// Drain and print the hypervisor's profiler samples.
+fn print_profile(stdout: &mut impl Write) -> core::fmt::Result {
+    while let Some(sample) = hypervisor_abi::read_profile_sample() {
+        write!(
+            stdout,
+            "{} {:x} {:x} {}\r\n",
+            sample.vcpu, sample.rip, sample.cr3, sample.cpl
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the VM exit statistics for every core.
+fn print_stats(stdout: &mut impl Write) -> core::fmt::Result {
+    for vcpu in 0.. {
+        if hypervisor_abi::read_exit_stats(vcpu, 0).is_none() {
+            break;
+        }
+        write!(stdout, "vCPU {}\r\n", vcpu)?;
+        for reason in 0..hypervisor_abi::EXIT_STATS_MAX_REASONS {
+            let stats = match hypervisor_abi::read_exit_stats(vcpu, reason) {
+                Some(stats) if stats.count != 0 => stats,
+                _ => continue,
+            };
+            match hypervisor_abi::vmexit_reasons::name(u64::from(reason)) {
+                Some(name) => write!(stdout, "  {}", name)?,
+                None => write!(stdout, "  Reason {}", reason)?,
+            }
+            write!(
+                stdout,
+                ": {} exits, {} ticks on average\r\n   ",
+                stats.count,
+                stats.total_ticks / stats.count
+            )?;
+            for (bucket, count) in stats.histogram.iter().enumerate() {
+                if *count != 0 {
+                    write!(stdout, " 2^{}: {}", bucket, count)?;
+                }
+            }
+            write!(stdout, "\r\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the exit trace of every core.
+fn print_trace(stdout: &mut impl Write) -> core::fmt::Result {
+    let mut records = [[0; hypervisor_abi::EXIT_TRACE_RECORD_SIZE]; TRACE_RECORDS_PER_HYPERCALL];
+    for vcpu in 0.. {
+        let bounds = match hypervisor_abi::read_exit_trace_bounds(vcpu) {
+            Some(bounds) => bounds,
+            None => break,
+        };
+        let mut sequence = bounds.oldest;
+        while sequence != bounds.next {
+            let wanted = core::cmp::min(
+                bounds.next.wrapping_sub(sequence) as usize,
+                TRACE_RECORDS_PER_HYPERCALL,
+            );
+            let count = unsafe {
+                hypervisor_abi::read_exit_trace_records(vcpu, sequence, &mut records[..wanted])
+            };
+            // Records overwritten since the bounds were read are skipped.
+            let count = match count {
+                Some(count) if count > 0 => count,
+                _ => {
+                    sequence = sequence.wrapping_add(1);
+                    continue;
+                }
+            };
+            for record in records[..count].iter() {
+                for byte in record.iter() {
+                    write!(stdout, "{:02x}", byte)?;
+                }
+                write!(stdout, "\r\n")?;
+            }
+            sequence = sequence.wrapping_add(count as u32);
+        }
+    }
+    Ok(())
+}
+
+/// The name of a log level the hypervisor returned, like log::LevelFilter.
+fn log_level_name(level: u32) -> &'static str {
+    match level {
+        0 => "OFF",
+        1 => "ERROR",
+        2 => "WARN",
+        3 => "INFO",
+        4 => "DEBUG",
+        5 => "TRACE",
+        hypervisor_abi::LOG_LEVEL_INHERIT => "INHERIT",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Print the hypervisor's in-memory log, oldest record first.
+fn print_log(stdout: &mut impl Write) -> core::fmt::Result {
+    let mut records = [[0; hypervisor_abi::LOG_RECORD_SIZE]; LOG_RECORDS_PER_HYPERCALL];
+    let mut sequence = 0;
+    loop {
+        let (count, next) =
+            match unsafe { hypervisor_abi::read_log_records(sequence, &mut records) } {
+                Ok(result) => result,
+                Err(e) => return write!(stdout, "Log hypercall failed {:?}\r\n", e),
+            };
+        for record in records[..count].iter() {
+            let record = hypervisor_abi::LogRecord::from_bytes(record);
+            let level = log_level_name(u32::from(record.level));
+            write!(stdout, "{} {:x} ", record.sequence, record.tsc)?;
+            if record.cpu == hypervisor_abi::LOG_CPU_UNKNOWN {
+                write!(stdout, "cpu-")?;
+            } else {
+                write!(stdout, "cpu{}", record.cpu)?;
+            }
+            write!(stdout, " {} {}\r\n", level, record.text())?;
+        }
+        // Stop once caught up, rather than chasing records logged since.
+        if count < LOG_RECORDS_PER_HYPERCALL {
+            return Ok(());
+        }
+        sequence = next;
+    }
+}
+
+/// Change the level of one of the hypervisor's log sinks, or of a target on
+/// it, given the rest of the command line.
+fn set_log_level<'a>(
+    stdout: &mut impl Write,
+    mut arguments: impl Iterator<Item = &'a str>,
+) -> core::fmt::Result {
+    let sink = match arguments.next() {
+        Some("console") => hypervisor_abi::LOG_SINK_CONSOLE,
+        Some("memory") => hypervisor_abi::LOG_SINK_MEMORY,
+        Some("debug-port") => hypervisor_abi::LOG_SINK_DEBUG_PORT,
+        _ => return write!(stdout, "Expected console, memory or debug-port\r\n"),
+    };
+    let level = match arguments.next() {
+        Some("inherit") => hypervisor_abi::LOG_LEVEL_INHERIT,
+        Some(level) => match log::LevelFilter::from_str(level) {
+            Ok(level) => level as u32,
+            Err(_) => return write!(stdout, "Unknown log level {}\r\n", level),
+        },
+        None => return write!(stdout, "Expected a log level\r\n"),
+    };
+    let result = match arguments.next() {
+        Some(target) => unsafe { hypervisor_abi::set_log_target_level(sink, level, target) },
+        None => hypervisor_abi::set_log_level(sink, level),
+    };
+    match result {
+        Ok(previous) => write!(stdout, "Previous level {}\r\n", log_level_name(previous)),
+        Err(e) => write!(stdout, "Log level hypercall failed {:?}\r\n", e),
+    }
+}
 
 /// The entrypoint of the UEFI application.
 #[no_mangle]
 pub extern "efiapi" fn efi_main(
-    _image_handle: uefi::Handle,
+    image_handle: uefi::Handle,
     system_table: SystemTable<Boot>,
 ) -> Status {
-    let results = hypervisor_abi::invoke_hypercall(hypervisor_abi::HYPERCALL_REASON_VERSION);
-
-    let io_result = write!(
-        system_table.stdout(),
-        "Hypervisor version {}.{}.{}\r\n",
-        results.results[0],
-        results.results[1],
-        results.results[2]
-    );
+    let loaded_image = system_table
+        .boot_services()
+        .handle_protocol::<LoadedImage>(image_handle)
+        .expect_success("Loaded image protocol not found");
+    let loaded_image = unsafe { &*loaded_image.get() };
+    let mut load_options_buffer = [0; MAX_LOAD_OPTIONS_SIZE];
+    let load_options = loaded_image
+        .load_options(&mut load_options_buffer)
+        .unwrap_or("");
+    // The shell passes the whole command line, including the image name.
+    let mut arguments = load_options.split_whitespace().skip(1);
+    let command = arguments.next();
+
+    let stdout = system_table.stdout();
+    let io_result = match command {
+        None => print_version(stdout),
+        Some("capabilities") => print_capabilities(stdout),
+        Some("log") => print_log(stdout),
+        Some("log-level") => set_log_level(stdout, arguments),
+        Some("profile") => print_profile(stdout),
+        Some("stats") => print_stats(stdout),
+        Some("trace") => print_trace(stdout),
+        Some(command) => write!(stdout, "Unknown command {}\r\n", command),
+    };
 
     match io_result {
         Ok(()) => Status::SUCCESS,
@@ -0,0 +1,161 @@
+#!/usr/bin/env python3
+"""Decode the hypervisor's VM exit traces.
+
+Reads either a capture of the serial port the hypervisor writes its exit
+traces to when it panics, or the output of `rustyvctl trace`, and prints the
+exits as a text timeline or as Chrome trace event JSON, which can be opened in
+chrome://tracing or https://ui.perfetto.dev.
+
+    $ scripts/exit_trace_decode.py com1.log
+    $ scripts/exit_trace_decode.py --chrome --tsc-mhz 2400 com1.log > trace.json
+
+The layout of a record is documented on ExitTraceRecord in hypervisor_abi.
+Exit reasons are named using hypervisor_abi/src/vmexit_reasons.rs.
+"""
+
+import argparse
+import json
+import os
+import re
+import struct
+import sys
+
+RECORD = struct.Struct("<QQQQQQQIHH")
+FRAME_BEGIN = re.compile(rb"rustyvisor exit trace begin ([0-9a-f]+) bytes vCPU (\d+)\r\n")
+HEX_LINE = re.compile(r"^[0-9a-fA-F]{%d}$" % (RECORD.size * 2))
+
+VMEXIT_REASONS_PATH = os.path.join(
+    os.path.dirname(os.path.abspath(__file__)),
+    "..", "hypervisor_abi", "src", "vmexit_reasons.rs")
+
+
+def load_reason_names():
+    """Map exit reason numbers to names, without the VMEXIT_REASON_ prefix."""
+    names = {}
+    try:
+        with open(VMEXIT_REASONS_PATH) as f:
+            for match in re.finditer(r"VMEXIT_REASON_(\w+) = (\d+),", f.read()):
+                names[int(match.group(2))] = match.group(1)
+    except OSError:
+        pass
+    return names
+
+
+def decode_record(data):
+    (tsc, rip, qualification, rax, rbx, rcx, rdx, exit_reason, vcpu,
+     _reserved) = RECORD.unpack(data)
+    return {
+        "tsc": tsc,
+        "rip": rip,
+        "qualification": qualification,
+        "rax": rax,
+        "rbx": rbx,
+        "rcx": rcx,
+        "rdx": rdx,
+        "exit_reason": exit_reason,
+        "vcpu": vcpu,
+    }
+
+
+def records_from_capture(data):
+    """Yield the records in every exit trace frame in a serial capture."""
+    for match in FRAME_BEGIN.finditer(data):
+        size = int(match.group(1), 16)
+        start = match.end()
+        frame = data[start:start + size]
+        if len(frame) < size:
+            print("warning: exit trace for vCPU %s is truncated" % match.group(2),
+                  file=sys.stderr)
+        for offset in range(0, len(frame) - RECORD.size + 1, RECORD.size):
+            yield decode_record(frame[offset:offset + RECORD.size])
+
+
+def records_from_hex(text):
+    """Yield the records printed by rustyvctl trace."""
+    for line in text.splitlines():
+        line = line.strip()
+        if HEX_LINE.match(line):
+            yield decode_record(bytes.fromhex(line))
+
+
+def read_records(data):
+    if FRAME_BEGIN.search(data):
+        return list(records_from_capture(data))
+    return list(records_from_hex(data.decode("ascii", errors="replace")))
+
+
+def reason_name(names, exit_reason):
+    # Bits 31:16 are flags, e.g. bit 31 is set on VM entry failures.
+    basic = exit_reason & 0xffff
+    name = names.get(basic, "REASON_%d" % basic)
+    if exit_reason & (1 << 31):
+        name += " (ENTRY FAILURE)"
+    return name
+
+
+def print_timeline(records, names, tsc_mhz):
+    if not records:
+        return
+    first = min(record["tsc"] for record in records)
+    for record in records:
+        delta = record["tsc"] - first
+        if tsc_mhz:
+            when = "%14.3fus" % (delta / tsc_mhz)
+        else:
+            when = "%16d" % delta
+        print("%s vcpu%-3d %-28s rip=%016x qual=%x rax=%x rbx=%x rcx=%x rdx=%x" % (
+            when, record["vcpu"], reason_name(names, record["exit_reason"]),
+            record["rip"], record["qualification"], record["rax"],
+            record["rbx"], record["rcx"], record["rdx"]))
+
+
+def print_chrome(records, names, tsc_mhz):
+    first = min((record["tsc"] for record in records), default=0)
+    events = []
+    for record in records:
+        events.append({
+            "name": reason_name(names, record["exit_reason"]),
+            "ph": "i",
+            "s": "t",
+            "pid": 0,
+            "tid": record["vcpu"],
+            # Timestamps are in microseconds. Without the TSC frequency,
+            # show one tick per microsecond.
+            "ts": (record["tsc"] - first) / (tsc_mhz or 1),
+            "args": {
+                "rip": "%#x" % record["rip"],
+                "qualification": "%#x" % record["qualification"],
+                "rax": "%#x" % record["rax"],
+                "rbx": "%#x" % record["rbx"],
+                "rcx": "%#x" % record["rcx"],
+                "rdx": "%#x" % record["rdx"],
+            },
+        })
+    json.dump({"traceEvents": events, "displayTimeUnit": "ns"}, sys.stdout,
+              indent=1)
+    print()
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("input", nargs="?", type=argparse.FileType("rb"),
+                        default=sys.stdin.buffer,
+                        help="a serial capture or rustyvctl output, default stdin")
+    parser.add_argument("--chrome", action="store_true",
+                        help="print Chrome trace event JSON")
+    parser.add_argument("--tsc-mhz", type=float, default=0,
+                        help="the TSC frequency, to show times in microseconds")
+    args = parser.parse_args()
+
+    records = read_records(args.input.read())
+    records.sort(key=lambda record: record["tsc"])
+    names = load_reason_names()
+    if args.chrome:
+        print_chrome(records, names, args.tsc_mhz)
+    else:
+        print_timeline(records, names, args.tsc_mhz)
+
+
+if __name__ == "__main__":
+    main()
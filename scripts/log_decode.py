@@ -0,0 +1,153 @@
+#!/usr/bin/env python3
+"""Decode the hypervisor's binary log records.
+
+Reads a capture of COM1 or of I/O port 0xe9 from a hypervisor loaded with
+binary logging, and prints each record as a line of text. Anything between
+records, like text the loader or the panic handler wrote, is printed as is.
+
+    $ scripts/log_decode.py com1.log
+    $ scripts/log_decode.py --tsc-mhz 2400 --sort debugcon.log
+
+The layout of a record is documented on LogFrameHeader in hypervisor_abi.
+Key-value arguments are printed after the message as key=value, with unsigned
+integers in hex, the same way the hypervisor prints them as text.
+"""
+
+import argparse
+import struct
+import sys
+
+MAGIC = b"RVLG"
+HEADER = struct.Struct("<4sHBBQQHH4x")
+LEVELS = {1: "ERROR", 2: "WARN", 3: "INFO", 4: "DEBUG", 5: "TRACE"}
+CPU_UNKNOWN = 0xffff
+
+# The LOG_ARGUMENT kinds, and how to decode and print the fixed size ones.
+ARGUMENT_NONE = 0
+ARGUMENT_TEXT = 8
+ARGUMENT_VALUES = {
+    1: (8, lambda value: hex(int.from_bytes(value, "little"))),
+    2: (8, lambda value: str(int.from_bytes(value, "little", signed=True))),
+    3: (16, lambda value: hex(int.from_bytes(value, "little"))),
+    4: (16, lambda value: str(int.from_bytes(value, "little", signed=True))),
+    5: (8, lambda value: repr(struct.unpack("<d", value)[0])),
+    6: (1, lambda value: "true" if value[0] else "false"),
+    7: (4, lambda value: chr(int.from_bytes(value, "little"))),
+}
+
+
+def decode_arguments(data):
+    """Return the key=value text for a record's encoded arguments."""
+    text = ""
+    offset = 0
+    while offset < len(data):
+        key_len = data[offset]
+        key = data[offset + 1:offset + 1 + key_len].decode("utf-8", errors="replace")
+        offset += 1 + key_len
+        kind = data[offset]
+        offset += 1
+        if kind == ARGUMENT_NONE:
+            value = "None"
+        elif kind == ARGUMENT_TEXT:
+            (length,) = struct.unpack_from("<H", data, offset)
+            offset += 2
+            value = data[offset:offset + length].decode("utf-8", errors="replace")
+            offset += length
+        elif kind in ARGUMENT_VALUES:
+            size, show = ARGUMENT_VALUES[kind]
+            value = show(data[offset:offset + size])
+            offset += size
+        else:
+            # The size of an unknown kind isn't known, so the rest can't be
+            # decoded.
+            return text + " %s=<unknown kind %d>" % (key, kind)
+        text += " %s=%s" % (key, value)
+    return text
+
+
+def decode_stream(data):
+    """Yield ("record", record) for each record in data, and ("text", bytes)
+    for anything between them."""
+    offset = 0
+    while offset < len(data):
+        start = data.find(MAGIC, offset)
+        if start < 0:
+            yield "text", data[offset:]
+            return
+        if start > offset:
+            yield "text", data[offset:start]
+        if start + HEADER.size > len(data):
+            print("warning: log record at %#x is truncated" % start, file=sys.stderr)
+            return
+        (_magic, length, level, target_len, sequence, tsc, cpu,
+         args_len) = HEADER.unpack_from(data, start)
+        body = start + HEADER.size
+        message_end = body + target_len + length
+        end = message_end + args_len
+        if end > len(data):
+            print("warning: log record at %#x is truncated" % start, file=sys.stderr)
+            return
+        yield "record", {
+            "sequence": sequence,
+            "tsc": tsc,
+            "cpu": cpu,
+            "level": LEVELS.get(level, "LEVEL%d" % level),
+            "target": data[body:body + target_len].decode("utf-8", errors="replace"),
+            "message": data[body + target_len:message_end].decode("utf-8", errors="replace")
+                       + decode_arguments(data[message_end:end]),
+        }
+        offset = end
+
+
+def format_record(record, first_tsc, tsc_mhz):
+    delta = record["tsc"] - first_tsc
+    if tsc_mhz:
+        when = "%14.3fus" % (delta / tsc_mhz)
+    else:
+        when = "%16d" % delta
+    if record["cpu"] == CPU_UNKNOWN:
+        cpu = "cpu-"
+    else:
+        cpu = "cpu%d" % record["cpu"]
+    return "%8d %s %-6s %-5s %s: %s" % (
+        record["sequence"], when, cpu, record["level"], record["target"],
+        record["message"])
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("input", nargs="?", type=argparse.FileType("rb"),
+                        default=sys.stdin.buffer,
+                        help="a capture of the serial port or debug port, default stdin")
+    parser.add_argument("--tsc-mhz", type=float, default=0,
+                        help="the TSC frequency, to show times in microseconds")
+    parser.add_argument("--sort", action="store_true",
+                        help="print only records, sorted by TSC")
+    args = parser.parse_args()
+
+    items = list(decode_stream(args.input.read()))
+    records = [item for kind, item in items if kind == "record"]
+    first_tsc = min((record["tsc"] for record in records), default=0)
+
+    # Each sink numbers its records, so a gap means some were lost.
+    expected = None
+    for record in records:
+        if expected is not None and record["sequence"] > expected:
+            print("warning: %d records lost before record %d" % (
+                record["sequence"] - expected, record["sequence"]), file=sys.stderr)
+        expected = record["sequence"] + 1
+
+    if args.sort:
+        for record in sorted(records, key=lambda record: record["tsc"]):
+            print(format_record(record, first_tsc, args.tsc_mhz))
+        return
+    for kind, item in items:
+        if kind == "record":
+            print(format_record(item, first_tsc, args.tsc_mhz))
+        else:
+            sys.stdout.write(item.decode("utf-8", errors="replace"))
+
+
+if __name__ == "__main__":
+    main()
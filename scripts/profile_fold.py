@@ -0,0 +1,121 @@
+#!/usr/bin/env python3
+"""Fold the hypervisor's guest RIP samples into flamegraph input.
+
+Reads the samples printed by `rustyvctl profile`, one per line:
+
+    <vcpu> <rip> <cr3> <cpl>
+
+with rip and cr3 in hex, and symbolizes each RIP with one or more symbol maps
+in the format printed by `nm` or found in a Linux System.map:
+
+    <address> <type> <name>
+
+A map for an image which was relocated when it was loaded, like a UEFI
+driver, can be given a load base with MAP@BASE, which is added to every
+address in the map.
+
+Prints one line per stack in the folded format read by flamegraph.pl and
+inferno-flamegraph. The root frame is the CPL the guest was running at,
+optionally followed by the address space (CR3) and the vCPU:
+
+    $ scripts/profile_fold.py samples.txt -m System.map > folded.txt
+    $ flamegraph.pl folded.txt > profile.svg
+"""
+
+import argparse
+import bisect
+import collections
+import sys
+
+
+def parse_map_argument(argument):
+    """Split MAP@BASE into the path and the base address."""
+    path, separator, base = argument.rpartition("@")
+    if not separator:
+        return argument, 0
+    return path, int(base, 16)
+
+
+def load_symbols(path, base):
+    """Return a list of (address, name) for the text symbols in a map."""
+    symbols = []
+    with open(path) as f:
+        for line in f:
+            fields = line.split()
+            if len(fields) < 3:
+                continue
+            address, kind, name = fields[0], fields[1], fields[2]
+            if kind not in "tTwW":
+                continue
+            try:
+                symbols.append((int(address, 16) + base, name))
+            except ValueError:
+                continue
+    return symbols
+
+
+class Symbolizer:
+    """Map addresses to the nearest preceding symbol."""
+
+    def __init__(self, symbols):
+        symbols.sort()
+        self.addresses = [address for address, _ in symbols]
+        self.names = [name for _, name in symbols]
+
+    def lookup(self, address):
+        index = bisect.bisect_right(self.addresses, address) - 1
+        if index < 0:
+            return None
+        return self.names[index]
+
+
+def read_samples(f):
+    """Yield (vcpu, rip, cr3, cpl) for every well formed line."""
+    for line in f:
+        fields = line.split()
+        if len(fields) != 4:
+            continue
+        try:
+            yield (int(fields[0]), int(fields[1], 16), int(fields[2], 16),
+                   int(fields[3]))
+        except ValueError:
+            continue
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description=__doc__, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("samples", nargs="?", type=argparse.FileType("r"),
+                        default=sys.stdin,
+                        help="the output of rustyvctl profile, default stdin")
+    parser.add_argument("-m", "--map", action="append", default=[],
+                        metavar="MAP[@BASE]",
+                        help="a symbol map, may be given more than once")
+    parser.add_argument("--by-cr3", action="store_true",
+                        help="add a frame for the address space")
+    parser.add_argument("--by-vcpu", action="store_true",
+                        help="add a frame for the vCPU")
+    args = parser.parse_args()
+
+    symbols = []
+    for argument in args.map:
+        path, base = parse_map_argument(argument)
+        symbols.extend(load_symbols(path, base))
+    symbolizer = Symbolizer(symbols)
+
+    stacks = collections.Counter()
+    for vcpu, rip, cr3, cpl in read_samples(args.samples):
+        frames = ["ring%d" % cpl]
+        if args.by_cr3:
+            frames.append("cr3=%x" % cr3)
+        if args.by_vcpu:
+            frames.append("vcpu%d" % vcpu)
+        frames.append(symbolizer.lookup(rip) or "[unknown]")
+        stacks[";".join(frames)] += 1
+
+    for stack, count in sorted(stacks.items()):
+        print("%s %d" % (stack, count))
+
+
+if __name__ == "__main__":
+    main()
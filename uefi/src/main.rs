@@ -35,7 +35,7 @@ use core::convert::TryFrom;
 use hypervisor::segmentation;
 use hypervisor::segmentation::{GdtEntry, GdtEntry64};
 
-use hypervisor::segmentation::Tss;
+use hypervisor::segmentation::{InterruptStack, Tss, INTERRUPT_STACK_PAGES};
 use uefi::proto::pi::mp::MpServices;
 use uefi::{prelude::*, table::boot::MemoryType};
 
@@ -54,6 +54,88 @@ extern "C" {
 /// Size of a page in bytes.
 const PAGE_SIZE: usize = 0x1000;
 
+/// How often to sample where the guest is running, in TSC ticks. 0 disables
+/// the profiler.
+const PROFILE_PERIOD: u64 = 0;
+
+/// Whether to hide the time the hypervisor spends handling VM exits from the
+/// guest's TSC.
+const TSC_COMPENSATED: bool = false;
+
+/// The guest's TSC rate relative to the host's, as a fixed point number with
+/// 48 fractional bits. 0 disables TSC scaling.
+const TSC_MULTIPLIER: u64 = 0;
+
+/// Changes to what the guest sees when it executes CPUID. See the hypervisor's
+/// cpuid_policy module for the format.
+const CPUID_POLICY: &str = "";
+
+/// Whether to report the hypervisor's CPUID leaves to the guest.
+const CPUID_HYPERVISOR_LEAVES: bool = false;
+
+/// What the caller of any hypercall needs, as a combination of the
+/// hypervisor's HYPERCALL_POLICY_REQUIRE flags. 0 allows anyone, including
+/// user space, to make any hypercall.
+const HYPERCALL_POLICY: u32 = hypervisor::HYPERCALL_POLICY_REQUIRE_CPL0;
+
+/// The CR3 hypercalls must be made with, if HYPERCALL_POLICY includes
+/// HYPERCALL_POLICY_REQUIRE_CR3.
+const HYPERCALL_CR3: u64 = 0;
+
+/// The ranges of guest virtual addresses hypercalls must be made from, if
+/// HYPERCALL_POLICY includes HYPERCALL_POLICY_REQUIRE_CODE_RANGE. The end of
+/// each range is exclusive.
+const HYPERCALL_CODE_RANGES: &[(u64, u64)] = &[];
+
+/// The secret the handshake hypercall expects. 0 rejects every handshake.
+const HYPERCALL_SECRET: u64 = 0;
+
+/// Which log records are written to COM1, e.g.
+/// "info,hypervisor::interrupt_controller=trace". See the hypervisor's
+/// log_sinks module for the format. Empty keeps every record.
+const LOG_CONSOLE_FILTERS: &str = "";
+
+/// Which log records the hypervisor's in-memory log keeps. Empty keeps every
+/// record.
+const LOG_MEMORY_FILTERS: &str = "";
+
+/// Which log records are written to I/O port 0xe9. Empty writes none.
+const LOG_DEBUG_PORT_FILTERS: &str = "";
+
+/// Whether to write log records to COM1 in binary form, which is faster and
+/// can be decoded with scripts/log_decode.py.
+const LOG_CONSOLE_BINARY: bool = false;
+
+/// Whether to write log records to I/O port 0xe9 in binary form.
+const LOG_DEBUG_PORT_BINARY: bool = false;
+
+/// Whether to hand every core back to the guest instead of halting when the
+/// hypervisor panics.
+const DEVIRTUALIZE_ON_PANIC: bool = false;
+
+/// The size in pages of each core's host stack. Each stack also gets an
+/// unmapped guard page below it.
+const HOST_STACK_PAGES: usize = 4;
+
+/// Allocate a zeroed page for the hypervisor to split a large page with.
+fn efi_allocate_page_table(system_table: &SystemTable<Boot>) -> Option<u64> {
+    let table = system_table
+        .boot_services()
+        .allocate_pages(
+            uefi::table::boot::AllocateType::AnyPages,
+            MemoryType::RUNTIME_SERVICES_DATA,
+            1,
+        )
+        .ok()?
+        .expect("Allocation completed");
+    unsafe {
+        system_table
+            .boot_services()
+            .memset(efi_phys_to_virt(table), PAGE_SIZE, 0);
+    }
+    Some(table)
+}
+
 /// Allocate and initialize a VCpu.
 fn efi_create_vcpu(system_table: &SystemTable<Boot>) -> uefi::Result<*mut hypervisor::VCpu> {
     let vcpu = system_table
@@ -93,12 +175,39 @@ fn efi_create_vcpu(system_table: &SystemTable<Boot>) -> uefi::Result<*mut hyperv
         1,
     )?;
 
-    let stack_pages = 1;
-    let stack = system_table.boot_services().allocate_pages(
-        uefi::table::boot::AllocateType::AnyPages,
-        MemoryType::RUNTIME_SERVICES_DATA,
-        stack_pages,
-    )?;
+    let stack_pages = HOST_STACK_PAGES;
+    let guard_page = system_table
+        .boot_services()
+        .allocate_pages(
+            uefi::table::boot::AllocateType::AnyPages,
+            MemoryType::RUNTIME_SERVICES_DATA,
+            stack_pages + 1,
+        )?
+        .expect("Stack");
+    let stack = guard_page + PAGE_SIZE as u64;
+    // Without a guard page, overflows are still caught by the hypervisor's
+    // stack canary.
+    if let Err(e) = unsafe {
+        hypervisor::host_paging::unmap_page(guard_page, || efi_allocate_page_table(system_table))
+    } {
+        log::warn!(
+            "No guard page below the host stack at {:x}, only the stack canary catches overflows {:?}",
+            stack,
+            e
+        );
+    }
+
+    let mut interrupt_stacks = [0; InterruptStack::ALL.len()];
+    for interrupt_stack in interrupt_stacks.iter_mut() {
+        *interrupt_stack = system_table
+            .boot_services()
+            .allocate_pages(
+                uefi::table::boot::AllocateType::AnyPages,
+                MemoryType::RUNTIME_SERVICES_DATA,
+                INTERRUPT_STACK_PAGES,
+            )?
+            .expect("Interrupt stack");
+    }
 
     let msr_bitmap = system_table
         .boot_services()
@@ -119,6 +228,12 @@ fn efi_create_vcpu(system_table: &SystemTable<Boot>) -> uefi::Result<*mut hyperv
         .expect("Completion failed?");
 
     unsafe {
+        system_table.boot_services().memset(
+            vcpu as *mut u8,
+            core::mem::size_of::<hypervisor::VCpu>(),
+            0,
+        );
+
         (*vcpu).this_vcpu = vcpu;
 
         system_table.boot_services().memmove(
@@ -128,6 +243,16 @@ fn efi_create_vcpu(system_table: &SystemTable<Boot>) -> uefi::Result<*mut hyperv
         );
 
         (*vcpu).loaded_successfully = false;
+        (*vcpu).guest_gprs = core::ptr::null_mut();
+        (*vcpu).guest_snapshot_valid = false;
+        (*vcpu).debug_registers = Default::default();
+        (*vcpu).single_step = Default::default();
+        (*vcpu).profiler = Default::default();
+        // Too large to build on the stack and move.
+        core::ptr::write_bytes(core::ptr::addr_of_mut!((*vcpu).exit_stats), 0, 1);
+        core::ptr::write_bytes(core::ptr::addr_of_mut!((*vcpu).exit_trace), 0, 1);
+        (*vcpu).timekeeping = Default::default();
+        (*vcpu).nmi = Default::default();
 
         (*vcpu).vmcs_phys = vmcs_phys.expect("vmcs allocation");
         (*vcpu).vmcs_size = PAGE_SIZE;
@@ -156,11 +281,12 @@ fn efi_create_vcpu(system_table: &SystemTable<Boot>) -> uefi::Result<*mut hyperv
         );
 
         (*vcpu).msr_bitmap = msr_bitmap;
+        (*vcpu).msr_bitmap_virt = efi_phys_to_virt((*vcpu).msr_bitmap);
         system_table
             .boot_services()
-            .memset(efi_phys_to_virt((*vcpu).msr_bitmap), PAGE_SIZE, 0);
+            .memset((*vcpu).msr_bitmap_virt, PAGE_SIZE, 0);
 
-        (*vcpu).stack_base = efi_phys_to_virt(stack.expect("Stack"));
+        (*vcpu).stack_base = efi_phys_to_virt(stack);
         (*vcpu).stack_size = stack_pages * PAGE_SIZE; // Page size
         (*vcpu).stack_top = (*vcpu).stack_base.add((*vcpu).stack_size);
 
@@ -170,6 +296,10 @@ fn efi_create_vcpu(system_table: &SystemTable<Boot>) -> uefi::Result<*mut hyperv
         system_table
             .boot_services()
             .memset(tss, core::mem::size_of::<segmentation::Tss>(), 0);
+        for (stack, base) in InterruptStack::ALL.iter().zip(interrupt_stacks.iter()) {
+            let top = efi_phys_to_virt::<u8>(*base).add(INTERRUPT_STACK_PAGES * PAGE_SIZE);
+            (*(tss as *mut Tss)).set_interrupt_stack(*stack, top as u64);
+        }
         let tss_base = tss as u64;
         (*vcpu).tr_base = tss_base;
         (*vcpu).tr_selector =
@@ -206,6 +336,50 @@ pub extern "efiapi" fn efi_main(
     system_table: SystemTable<Boot>,
 ) -> Status {
     hypervisor::rustyvisor_load();
+    let log_sinks = [
+        (
+            hypervisor::LOG_SINK_CONSOLE,
+            LOG_CONSOLE_FILTERS,
+            LOG_CONSOLE_BINARY,
+        ),
+        (hypervisor::LOG_SINK_MEMORY, LOG_MEMORY_FILTERS, false),
+        (
+            hypervisor::LOG_SINK_DEBUG_PORT,
+            LOG_DEBUG_PORT_FILTERS,
+            LOG_DEBUG_PORT_BINARY,
+        ),
+    ];
+    if log_sinks.iter().any(|(sink, filters, binary)| unsafe {
+        hypervisor::rustyvisor_log_add_filters(*sink, filters.as_ptr(), filters.len()) != 0
+            || hypervisor::rustyvisor_log_set_binary(*sink, *binary) != 0
+    }) {
+        hypervisor::rustyvisor_unload();
+        return Status::INVALID_PARAMETER;
+    }
+    hypervisor::rustyvisor_set_devirtualize_on_panic(DEVIRTUALIZE_ON_PANIC);
+    hypervisor::rustyvisor_profiler_set_period(PROFILE_PERIOD);
+    hypervisor::rustyvisor_timekeeping_configure(TSC_COMPENSATED, TSC_MULTIPLIER);
+    hypervisor::rustyvisor_cpuid_set_hypervisor_leaves(CPUID_HYPERVISOR_LEAVES);
+    if unsafe { hypervisor::rustyvisor_cpuid_add_policy(CPUID_POLICY.as_ptr(), CPUID_POLICY.len()) }
+        != 0
+    {
+        hypervisor::rustyvisor_unload();
+        return Status::INVALID_PARAMETER;
+    }
+    hypervisor::rustyvisor_hypercall_set_secret(HYPERCALL_SECRET);
+    let hypercall_policy_result = hypervisor::rustyvisor_hypercall_set_policy(
+        hypervisor::HYPERCALL_POLICY_DEFAULT,
+        HYPERCALL_POLICY,
+        HYPERCALL_CR3,
+    );
+    if hypercall_policy_result != 0
+        || HYPERCALL_CODE_RANGES
+            .iter()
+            .any(|(start, end)| hypervisor::rustyvisor_hypercall_add_code_range(*start, *end) != 0)
+    {
+        hypervisor::rustyvisor_unload();
+        return Status::INVALID_PARAMETER;
+    }
 
     efi_core_load(&system_table as *const SystemTable<Boot> as *mut c_void);
 